@@ -1,7 +1,28 @@
 use crate::clipboard;
+use crate::e2ee::RoomKey;
+use crate::error::ServerError;
+use crate::identity::KnownUsers;
+use crate::keys::Keymap;
+use crate::theme::Theme;
+use crate::vim::{Mode, Vim};
 use chrono::{DateTime, Local, Utc};
-use terma_shared::ChatMessage;
+use std::collections::BTreeSet;
+use std::time::{Duration, Instant};
+use terma_shared::{ChatMessage, Limits, SearchResult};
 use tui_textarea::TextArea;
+use uuid::Uuid;
+
+/// Shown after the names of senders who joined with a verified key
+pub const VERIFIED_BADGE: &str = "✓";
+
+/// How long to keep redialling a server that doesn't say how long it holds
+/// a dropped session
+const DEFAULT_RESUME_WINDOW: Duration = Duration::from_secs(10);
+
+pub fn local_time(time: DateTime<Utc>, format: &str) -> String {
+    let local: DateTime<Local> = time.into();
+    local.format(format).to_string()
+}
 
 pub struct App {
     pub room_id: String,
@@ -12,22 +33,76 @@ pub struct App {
     pub online_count: usize,
     pub scroll_offset: usize, // Lines scrolled back from bottom (0 = at bottom)
     pub connected: bool,
+    /// Which redial is under way while the connection is down
+    pub redial_attempt: Option<u32>,
+    /// Round trip time to the server, from its latest pong
+    pub rtt: Option<Duration>,
     pub should_quit: bool,
     pub selection: SelectionState,
     pub render_cache: RenderCache,
+    pub search: Option<SearchOverlay>,
+    pub jump_to: Option<Uuid>,
+    pub highlighted: Option<Uuid>,
+    pub keymap: Keymap,
+    pub theme: Theme,
+    pub vim: Option<Vim>,
+    pub show_help: bool,
+    /// Fingerprint of our own signing key
+    pub fingerprint: Option<String>,
+    pub known_users: KnownUsers,
+    /// Set in end-to-end encrypted rooms
+    pub room_key: Option<RoomKey>,
+    /// We own the room and may moderate it
+    pub owner: bool,
+    /// Why the server removed us, shown after the UI closes
+    pub removed_reason: Option<ServerError>,
+    /// Set by the room's owner; the header falls back to the room ID
+    pub room_name: Option<String>,
+    pub topic: Option<String>,
+    /// When the server deletes the room
+    pub expires_at: Option<DateTime<Utc>>,
+    /// Seconds members must wait between messages; zero when off
+    pub slow_mode: u32,
+    /// Sending is held until then by slow mode or the rate limit
+    pub send_wait_until: Option<Instant>,
+    /// The last message typed, restored if the server turns it away
+    pub last_sent: Option<String>,
+    /// Features the server advertised; `None` if it predates advertising
+    pub capabilities: Option<BTreeSet<String>>,
+    /// What the server allows each connection, if it said
+    pub limits: Option<Limits>,
+    /// Our session from the latest welcome, picked up again on reconnect
+    pub session_id: Option<String>,
 }
 
 #[derive(Clone)]
 pub struct DisplayMessage {
+    pub id: Option<Uuid>,
+    pub user_id: String,
     pub username: String,
     pub content: String,
     pub timestamp: DateTime<Utc>,
     pub is_system: bool,
     pub is_own_message: bool,
+    /// Set when the sender joined with a verified key
+    pub fingerprint: Option<String>,
+}
+
+pub struct SearchOverlay {
+    pub query: String,
+    pub results: Vec<SearchResult>,
+    pub selected: usize,
+    pub loading: bool,
 }
 
 impl App {
-    pub fn new(room_id: String, user_id: String, username: String) -> Self {
+    pub fn new(
+        room_id: String,
+        user_id: String,
+        username: String,
+        keymap: Keymap,
+        theme: Theme,
+    ) -> Self {
         // Line numbers are disabled by default in TextArea
         let input = TextArea::default();
 
@@ -40,42 +115,204 @@ impl App {
             online_count: 0,
             scroll_offset: 0,
             connected: false,
+            redial_attempt: None,
+            rtt: None,
             should_quit: false,
             selection: SelectionState::default(),
             render_cache: RenderCache::default(),
+            search: None,
+            jump_to: None,
+            highlighted: None,
+            vim: keymap.vim_mode.then(Vim::new),
+            keymap,
+            theme,
+            show_help: false,
+            fingerprint: None,
+            known_users: KnownUsers::default(),
+            room_key: None,
+            owner: false,
+            removed_reason: None,
+            room_name: None,
+            topic: None,
+            expires_at: None,
+            slow_mode: 0,
+            send_wait_until: None,
+            last_sent: None,
+            capabilities: None,
+            limits: None,
+            session_id: None,
         }
     }
 
+    /// Whether the server offers a feature from `capability`. Servers that
+    /// don't advertise are assumed to have everything.
+    pub fn server_supports(&self, capability: &str) -> bool {
+        self.capabilities
+            .as_ref()
+            .is_none_or(|capabilities| capabilities.contains(capability))
+    }
+
     pub fn add_message(&mut self, message: DisplayMessage) {
         self.messages.push(message);
         // Auto-scroll to bottom
         self.scroll_offset = 0;
+        self.highlighted = None;
         self.selection.clear();
     }
 
     pub fn add_chat_message(&mut self, msg: ChatMessage) {
         let is_own = msg.user_id == self.user_id;
+        if !is_own {
+            self.check_identity(&msg.username, msg.fingerprint.as_deref());
+        }
         self.add_message(DisplayMessage {
+            id: Some(msg.id),
+            user_id: msg.user_id,
             username: msg.username,
             content: msg.content,
             timestamp: msg.timestamp,
             is_system: false,
             is_own_message: is_own,
+            fingerprint: msg.fingerprint,
         });
     }
 
+    /// The user ID behind `username`'s messages here, if only one person has
+    /// posted under it. Moderation aims at them rather than the name.
+    pub fn user_id_named(&self, username: &str) -> Option<String> {
+        let mut user_ids = self
+            .messages
+            .iter()
+            .filter(|msg| !msg.is_system && !msg.is_own_message && msg.username == username)
+            .map(|msg| msg.user_id.as_str());
+        let first = user_ids.next()?;
+        user_ids
+            .all(|user_id| user_id == first)
+            .then(|| first.to_string())
+    }
+
+    /// Decrypt a message if this is an encrypted room
+    pub fn decrypt(&self, mut msg: ChatMessage) -> ChatMessage {
+        if let Some(key) = &self.room_key {
+            key.open(&self.room_id, &mut msg);
+        }
+        msg
+    }
+
+    /// The server can't search ciphertext, so encrypted rooms search the
+    /// loaded history instead, newest first
+    pub fn search_local(&self, query: &str) -> Vec<SearchResult> {
+        let needle = query.to_ascii_lowercase();
+        self.chat_history()
+            .into_iter()
+            .rev()
+            .filter_map(|message| {
+                // ASCII lowercasing keeps byte offsets valid for slicing
+                let start = message.content.to_ascii_lowercase().find(&needle)?;
+                let end = start + needle.len();
+                let snippet = format!(
+                    "{}**{}**{}",
+                    &message.content[..start],
+                    &message.content[start..end],
+                    &message.content[end..]
+                );
+                Some(SearchResult { message, snippet })
+            })
+            .collect()
+    }
+
+    /// Warn when a username we've seen before shows up with another key, or
+    /// without one
+    pub fn check_identity(&mut self, username: &str, fingerprint: Option<&str>) {
+        if let Some(warning) = self.known_users.check(username, fingerprint) {
+            self.add_system_message(warning);
+        }
+    }
+
     pub fn add_system_message(&mut self, content: String) {
         self.add_system_message_with_time(content, Utc::now());
     }
 
     pub fn add_system_message_with_time(&mut self, content: String, timestamp: DateTime<Utc>) {
-        self.add_message(DisplayMessage {
-            username: "system".to_string(),
-            content,
-            timestamp,
-            is_system: true,
-            is_own_message: false,
-        });
+        self.add_message(system_message(content, timestamp));
+    }
+
+    /// Slot an older system line into the history by time, e.g. past
+    /// moderation actions arriving after the messages they interleave with
+    /// Insert a system line in time order. One already shown, as when the
+    /// moderation log is replayed after a reconnect, is skipped.
+    pub fn insert_system_message(&mut self, content: String, timestamp: DateTime<Utc>) {
+        let shown = self
+            .messages
+            .iter()
+            .any(|msg| msg.is_system && msg.timestamp == timestamp && msg.content == content);
+        if shown {
+            return;
+        }
+        let index = self
+            .messages
+            .iter()
+            .rposition(|msg| msg.timestamp <= timestamp)
+            .map_or(0, |index| index + 1);
+        self.messages
+            .insert(index, system_message(content, timestamp));
+    }
+
+    /// The loaded chat history (system lines excluded) as protocol messages
+    pub fn chat_history(&self) -> Vec<ChatMessage> {
+        self.messages
+            .iter()
+            .filter(|msg| !msg.is_system)
+            .map(|msg| ChatMessage {
+                id: msg.id.unwrap_or_else(Uuid::new_v4),
+                room_id: self.room_id.clone(),
+                user_id: msg.user_id.clone(),
+                username: msg.username.clone(),
+                content: msg.content.clone(),
+                timestamp: msg.timestamp,
+                fingerprint: msg.fingerprint.clone(),
+            })
+            .collect()
+    }
+
+    /// Whether a chat message is already on screen
+    pub fn has_message(&self, id: Uuid) -> bool {
+        self.messages.iter().any(|msg| msg.id == Some(id))
+    }
+
+    /// How long to keep redialling after the connection drops: as long as
+    /// the server holds our session, if it said
+    pub fn resume_window(&self) -> Duration {
+        match &self.limits {
+            Some(limits) if limits.session_grace_seconds > 0 => {
+                Duration::from_secs(limits.session_grace_seconds)
+            }
+            _ => DEFAULT_RESUME_WINDOW,
+        }
+    }
+
+    pub fn room_display_name(&self) -> &str {
+        self.room_name.as_deref().unwrap_or(&self.room_id)
+    }
+
+    /// How long until we may send again, if we're being held back
+    pub fn send_wait(&self) -> Option<Duration> {
+        self.send_wait_until
+            .map(|until| until.saturating_duration_since(Instant::now()))
+            .filter(|wait| !wait.is_zero())
+    }
+
+    pub fn hold_sends(&mut self, wait: Duration) {
+        self.send_wait_until = Some(Instant::now() + wait);
+    }
+
+    /// Put a message the server refused back into an empty input box
+    pub fn restore_last_sent(&mut self) {
+        if let Some(text) = self.last_sent.take() {
+            if self.input.is_empty() {
+                self.input.insert_str(text);
+            }
+        }
     }
 
     pub fn input_take(&mut self) -> String {
@@ -84,6 +321,19 @@ impl App {
         lines.join("\n")
     }
 
+    pub fn vim_mode(&self) -> Option<Mode> {
+        self.vim.as_ref().map(|vim| vim.mode)
+    }
+
+    /// Whether bare-character shortcuts (like `?`) should fire instead of
+    /// being typed: in vim normal/visual mode, or while the input is empty.
+    pub fn accepts_char_shortcuts(&self) -> bool {
+        match self.vim_mode() {
+            Some(Mode::Normal) | Some(Mode::Visual) => true,
+            _ => self.input.is_empty(),
+        }
+    }
+
     pub fn scroll_up(&mut self) {
         // Scroll up by 3 lines for smoother scrolling
         self.scroll_offset = self.scroll_offset.saturating_add(3);
@@ -97,14 +347,65 @@ impl App {
     /// Clamp scroll offset based on actual content dimensions
     /// Call this before rendering to ensure scroll_offset is valid
     pub fn clamp_scroll(&mut self, total_lines: usize, visible_height: usize) {
-        let max_scroll = if total_lines > visible_height {
-            total_lines - visible_height
-        } else {
-            0
-        };
+        let max_scroll = total_lines.saturating_sub(visible_height);
         self.scroll_offset = self.scroll_offset.min(max_scroll);
     }
 
+    pub fn begin_search(&mut self, query: String) {
+        self.search = Some(SearchOverlay {
+            query,
+            results: Vec::new(),
+            selected: 0,
+            loading: true,
+        });
+    }
+
+    pub fn show_search_results(&mut self, query: String, results: Vec<SearchResult>) {
+        self.search = Some(SearchOverlay {
+            query,
+            results,
+            selected: 0,
+            loading: false,
+        });
+    }
+
+    pub fn close_search(&mut self) {
+        self.search = None;
+    }
+
+    pub fn search_select_prev(&mut self) {
+        if let Some(search) = &mut self.search {
+            search.selected = search.selected.saturating_sub(1);
+        }
+    }
+
+    pub fn search_select_next(&mut self) {
+        if let Some(search) = &mut self.search {
+            if search.selected + 1 < search.results.len() {
+                search.selected += 1;
+            }
+        }
+    }
+
+    /// Close the overlay and scroll the selected result into view
+    pub fn jump_to_selected_result(&mut self) {
+        let Some(search) = self.search.take() else {
+            return;
+        };
+        let Some(result) = search.results.get(search.selected) else {
+            return;
+        };
+
+        let id = result.message.id;
+        if self.messages.iter().any(|msg| msg.id == Some(id)) {
+            self.jump_to = Some(id);
+            self.highlighted = Some(id);
+            self.selection.clear();
+        } else {
+            self.add_system_message("That message is no longer in the loaded history.".to_string());
+        }
+    }
+
     pub fn quit(&mut self) {
         self.should_quit = true;
     }
@@ -217,12 +518,33 @@ impl App {
     }
 }
 
+fn system_message(content: String, timestamp: DateTime<Utc>) -> DisplayMessage {
+    DisplayMessage {
+        id: None,
+        user_id: String::new(),
+        username: "system".to_string(),
+        content,
+        timestamp,
+        is_system: true,
+        is_own_message: false,
+        fingerprint: None,
+    }
+}
+
 impl DisplayMessage {
     pub fn format_time(&self) -> String {
         let local: DateTime<Local> = self.timestamp.into();
         local.format("%H:%M:%S").to_string()
     }
 
+    /// Username with a badge when the sender's key was verified
+    fn display_name(&self) -> String {
+        match self.fingerprint {
+            Some(_) => format!("{} {}", self.username, VERIFIED_BADGE),
+            None => self.username.clone(),
+        }
+    }
+
     pub fn format_lines_for_display(&self) -> Vec<String> {
         let mut lines = Vec::new();
         let mut content_lines = self.content.split('\n');
@@ -232,7 +554,12 @@ impl DisplayMessage {
             if self.is_system {
                 lines.push(format!("[{}] {}", time, first_line));
             } else {
-                lines.push(format!("[{}] {}: {}", time, self.username, first_line));
+                lines.push(format!(
+                    "[{}] {}: {}",
+                    time,
+                    self.display_name(),
+                    first_line
+                ));
             }
         }
 
@@ -242,7 +569,7 @@ impl DisplayMessage {
             if self.is_system {
                 lines.push(format!("[{}] {}", time, ""));
             } else {
-                lines.push(format!("[{}] {}: {}", time, self.username, ""));
+                lines.push(format!("[{}] {}: {}", time, self.display_name(), ""));
             }
         }
 
@@ -299,6 +626,7 @@ impl SelectionState {
 pub struct RenderedLine {
     pub text: String,
     pub kind: LineKind,
+    pub highlighted: bool,
 }
 
 #[derive(Clone, Copy)]
@@ -324,16 +652,99 @@ pub struct RenderCache {
 }
 
 fn extract_range(text: &str, start_col: usize, end_col: usize) -> String {
-    let mut result = String::new();
-    let mut current_col = 0;
-    for ch in text.chars() {
-        if current_col >= end_col {
-            break;
-        }
-        if current_col >= start_col {
-            result.push(ch);
+    text.chars()
+        .enumerate()
+        .take_while(|(col, _)| *col < end_col)
+        .filter(|(col, _)| *col >= start_col)
+        .map(|(_, ch)| ch)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::KeyConfig;
+
+    fn app() -> App {
+        let keymap = Keymap::from_config(&KeyConfig::default()).unwrap();
+        App::new(
+            "room".to_string(),
+            "me".to_string(),
+            "me".to_string(),
+            keymap,
+            Theme::dark(),
+        )
+    }
+
+    fn limits(session_grace_seconds: u64) -> Limits {
+        Limits {
+            max_message_length: 4096,
+            message_burst: 5,
+            messages_per_second: 1.0,
+            session_grace_seconds,
         }
-        current_col += 1;
     }
-    result
+
+    #[test]
+    fn redials_for_as_long_as_the_server_holds_the_session() {
+        let mut app = app();
+        assert_eq!(app.resume_window(), DEFAULT_RESUME_WINDOW);
+        app.limits = Some(limits(30));
+        assert_eq!(app.resume_window(), Duration::from_secs(30));
+        // Servers from before the grace period was sent say zero
+        app.limits = Some(limits(0));
+        assert_eq!(app.resume_window(), DEFAULT_RESUME_WINDOW);
+    }
+
+    #[test]
+    fn replayed_history_and_notices_are_not_shown_twice() {
+        let mut app = app();
+        let id = Uuid::new_v4();
+        assert!(!app.has_message(id));
+        app.add_message(DisplayMessage {
+            id: Some(id),
+            user_id: "bob-id".to_string(),
+            username: "bob".to_string(),
+            content: "hi".to_string(),
+            timestamp: Utc::now(),
+            is_system: false,
+            is_own_message: false,
+            fingerprint: None,
+        });
+        assert!(app.has_message(id));
+
+        let at = Utc::now();
+        app.insert_system_message("bob was muted".to_string(), at);
+        app.insert_system_message("bob was muted".to_string(), at);
+        let notices = app.messages.iter().filter(|msg| msg.is_system).count();
+        assert_eq!(notices, 1);
+    }
+
+    #[test]
+    fn names_point_at_one_poster_or_none() {
+        let mut app = app();
+        let mut post = |user_id: &str, username: &str, own: bool| {
+            app.add_message(DisplayMessage {
+                id: Some(Uuid::new_v4()),
+                user_id: user_id.to_string(),
+                username: username.to_string(),
+                content: "hi".to_string(),
+                timestamp: Utc::now(),
+                is_system: false,
+                is_own_message: own,
+                fingerprint: None,
+            });
+        };
+        post("bob-1", "bob", false);
+        post("bob-1", "bob", false);
+        post("me", "carol", true);
+        post("carol-2", "carol", false);
+        post("dave-1", "dave", false);
+        post("dave-2", "dave", false);
+        assert_eq!(app.user_id_named("bob").as_deref(), Some("bob-1"));
+        // Our own messages don't count
+        assert_eq!(app.user_id_named("carol").as_deref(), Some("carol-2"));
+        assert_eq!(app.user_id_named("dave"), None);
+        assert_eq!(app.user_id_named("erin"), None);
+    }
 }
@@ -0,0 +1,147 @@
+use clap::{Args, Parser, Subcommand};
+use terma_shared::{ExportFormat, Retention};
+
+#[derive(Debug, Parser)]
+#[command(
+    name = "terma",
+    version,
+    about = "Terminal chat rooms",
+    override_usage = concat!(
+        "terma [OPTIONS] <ROOM_ID>\n",
+        "       terma [OPTIONS] <HOST> <ROOM_ID>\n",
+        "       terma [OPTIONS] <COMMAND>",
+    ),
+    after_help = concat!(
+        "Examples:\n",
+        "  terma abc123\n",
+        "  terma localhost:3000 abc123\n",
+        "  terma --profile work join abc123\n",
+        "  terma new --password --join\n",
+        "  terma new --encrypted --name \"Release planning\"\n",
+        "  echo 'deploy done' | terma send abc123\n",
+        "  terma export abc123 --format md > abc123.md",
+    )
+)]
+pub struct Cli {
+    #[command(flatten)]
+    pub global: GlobalArgs,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Shorthand for `join`: `terma <room_id>` or `terma <host> <room_id>`
+    #[arg(value_name = "ROOM", num_args = 0..=2)]
+    pub shorthand: Vec<String>,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct GlobalArgs {
+    /// Server profile from ~/.terma/config.json
+    #[arg(long, short, global = true)]
+    pub profile: Option<String>,
+
+    /// Server to connect to, e.g. chat.example.com or localhost:3000
+    #[arg(long, global = true)]
+    pub host: Option<String>,
+
+    /// Name shown to others in the room
+    #[arg(long, short, global = true)]
+    pub username: Option<String>,
+
+    /// Connect without TLS (ws:// and http://)
+    #[arg(long, global = true)]
+    pub insecure: bool,
+
+    /// Invite token for a password-protected room
+    #[arg(long, global = true)]
+    pub token: Option<String>,
+
+    /// Room key for an end-to-end encrypted room (remembered once used)
+    #[arg(long, global = true)]
+    pub key: Option<String>,
+
+    /// Owner token from creating a room elsewhere; needed once per user ID
+    #[arg(long, global = true)]
+    pub owner_token: Option<String>,
+}
+
+#[derive(Debug, Args)]
+pub struct NewArgs {
+    /// Join the room right away
+    #[arg(long, short)]
+    pub join: bool,
+    /// Protect the room with a password (prompted for)
+    #[arg(long)]
+    pub password: bool,
+    /// End-to-end encrypt messages with a key the server never sees
+    #[arg(long)]
+    pub encrypted: bool,
+    /// Name shown in the header instead of the room ID
+    #[arg(long)]
+    pub name: Option<String>,
+    /// What the room is about; the owner can change it later with /topic
+    #[arg(long)]
+    pub topic: Option<String>,
+    /// Message of the day, shown to everyone who joins
+    #[arg(long)]
+    pub motd: Option<String>,
+    /// Delete the room and its messages after this long, e.g. 90m, 12h or 7d
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    pub expires_in: Option<u64>,
+    /// History to keep: a message count (default 1000), days like 30d, or unlimited
+    #[arg(long, value_name = "N|Nd|unlimited")]
+    pub keep: Option<Retention>,
+}
+
+/// "90s", "90m", "12h" or "7d" as seconds; a bare number is minutes
+fn parse_duration(text: &str) -> Result<u64, String> {
+    let text = text.trim();
+    let (number, unit) = match text.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => text.split_at(index),
+        None => (text, "m"),
+    };
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("expected a duration like 90m, 12h or 7d, not {:?}", text))?;
+    let unit = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(format!("unknown unit {:?}; use s, m, h or d", unit)),
+    };
+    Ok(number * unit)
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Join a room
+    Join { room_id: String },
+    /// Create a new room and print its invite command
+    New(NewArgs),
+    /// Send a single message and exit
+    Send {
+        room_id: String,
+        /// Message text; read from stdin when omitted
+        message: Vec<String>,
+    },
+    /// Stream a room transcript to stdout
+    Export {
+        room_id: String,
+        #[arg(long, short, default_value = "json")]
+        format: ExportFormat,
+    },
+    /// Read or change settings in ~/.terma/config.json
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ConfigAction {
+    /// Print a setting, or the whole config when no key is given
+    Get { key: Option<String> },
+    /// Change a setting (an empty value clears it)
+    Set { key: String, value: String },
+}
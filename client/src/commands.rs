@@ -0,0 +1,265 @@
+/// Slash commands typed into the input box
+pub enum Command {
+    Search {
+        query: String,
+    },
+    Export {
+        path: Option<String>,
+    },
+    Trust {
+        username: String,
+    },
+    Kick {
+        username: String,
+        /// From `name#<start of user ID>`, to pick one of several people
+        /// using the name
+        target_id: Option<String>,
+        reason: Option<String>,
+    },
+    Ban {
+        username: String,
+        target_id: Option<String>,
+        reason: Option<String>,
+    },
+    Mute {
+        username: String,
+        target_id: Option<String>,
+        minutes: Option<u32>,
+        reason: Option<String>,
+    },
+    Unban {
+        username: String,
+        target_id: Option<String>,
+    },
+    SlowMode {
+        seconds: u32,
+    },
+    /// Show the topic, or change it when given one
+    Topic {
+        topic: Option<String>,
+    },
+}
+
+/// What a submitted line of input should turn into
+pub enum Input {
+    Message(String),
+    Command(Command),
+    Invalid(String),
+}
+
+pub fn parse(text: String) -> Input {
+    let Some(rest) = text.strip_prefix('/') else {
+        return Input::Message(text);
+    };
+
+    // "//" escapes a message that would otherwise be read as a command
+    if rest.starts_with('/') {
+        return Input::Message(rest.to_string());
+    }
+
+    let (name, args) = match rest.split_once(char::is_whitespace) {
+        Some((name, args)) => (name, args.trim()),
+        None => (rest.trim(), ""),
+    };
+
+    match name {
+        "search" | "s" => {
+            if args.is_empty() {
+                Input::Invalid("Usage: /search <query>".to_string())
+            } else {
+                Input::Command(Command::Search {
+                    query: args.to_string(),
+                })
+            }
+        }
+        "export" => Input::Command(Command::Export {
+            path: (!args.is_empty()).then(|| args.to_string()),
+        }),
+        "trust" => {
+            if args.is_empty() {
+                Input::Invalid("Usage: /trust <username>".to_string())
+            } else {
+                Input::Command(Command::Trust {
+                    username: args.to_string(),
+                })
+            }
+        }
+        "kick" | "ban" | "mute" | "unban" => {
+            let (target, rest) = match args.split_once(char::is_whitespace) {
+                Some((target, rest)) => (target, rest.trim()),
+                None => (args, ""),
+            };
+            let (username, target_id) = split_tag(target);
+            if username.is_empty() {
+                let usage = match name {
+                    "mute" => "/mute <username>[#id] [minutes] [reason]",
+                    "unban" => "/unban <username>[#id]",
+                    _ => "/kick or /ban <username>[#id] [reason]",
+                };
+                return Input::Invalid(format!("Usage: {}", usage));
+            }
+            let reason = |text: &str| (!text.is_empty()).then(|| text.to_string());
+
+            Input::Command(match name {
+                "kick" => Command::Kick {
+                    username,
+                    target_id,
+                    reason: reason(rest),
+                },
+                "ban" => Command::Ban {
+                    username,
+                    target_id,
+                    reason: reason(rest),
+                },
+                "mute" => {
+                    // A leading number is the duration; anything else is the reason
+                    let (first, tail) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+                    let (minutes, rest) = match first.parse() {
+                        Ok(minutes) => (Some(minutes), tail.trim()),
+                        Err(_) => (None, rest),
+                    };
+                    Command::Mute {
+                        username,
+                        target_id,
+                        minutes,
+                        reason: reason(rest),
+                    }
+                }
+                _ => Command::Unban {
+                    username,
+                    target_id,
+                },
+            })
+        }
+        "topic" => Input::Command(Command::Topic {
+            topic: match args {
+                "" => None,
+                // Sending an empty topic clears it
+                "off" => Some(String::new()),
+                _ => Some(args.to_string()),
+            },
+        }),
+        "slow" => match args {
+            "off" => Input::Command(Command::SlowMode { seconds: 0 }),
+            _ => match args.parse() {
+                Ok(seconds) => Input::Command(Command::SlowMode { seconds }),
+                Err(_) => Input::Invalid("Usage: /slow <seconds|off>".to_string()),
+            },
+        },
+        // Not a command, so it's sent as typed, as before commands existed
+        _ => Input::Message(text),
+    }
+}
+
+/// Split `name#1a2b3c4d` into the name and the start of a user ID. A `#`
+/// followed by anything but ID characters is part of the name.
+fn split_tag(target: &str) -> (String, Option<String>) {
+    match target.rsplit_once('#') {
+        Some((username, tag))
+            if !tag.is_empty() && tag.chars().all(|c| c.is_ascii_hexdigit() || c == '-') =>
+        {
+            (username.to_string(), Some(tag.to_ascii_lowercase()))
+        }
+        _ => (target.to_string(), None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Input {
+        super::parse(text.to_string())
+    }
+
+    #[test]
+    fn search_takes_the_rest_of_the_line() {
+        for text in ["/search  deploy failed ", "/s deploy failed"] {
+            assert!(
+                matches!(parse(text), Input::Command(Command::Search { query }) if query == "deploy failed"),
+                "{}",
+                text
+            );
+        }
+        assert!(matches!(parse("/search"), Input::Invalid(usage) if usage.contains("/search")));
+    }
+
+    #[test]
+    fn export_path_is_optional() {
+        assert!(matches!(
+            parse("/export"),
+            Input::Command(Command::Export { path: None })
+        ));
+        assert!(matches!(
+            parse("/export  ~/room log.html "),
+            Input::Command(Command::Export { path: Some(path) }) if path == "~/room log.html"
+        ));
+    }
+
+    #[test]
+    fn moderation_takes_a_name_then_a_reason() {
+        assert!(matches!(
+            parse("/kick bob  too loud "),
+            Input::Command(Command::Kick { username, reason: Some(reason), .. })
+                if username == "bob" && reason == "too loud"
+        ));
+        assert!(matches!(
+            parse("/ban bob"),
+            Input::Command(Command::Ban { username, reason: None, .. }) if username == "bob"
+        ));
+        assert!(matches!(parse("/unban"), Input::Invalid(usage) if usage.contains("/unban")));
+    }
+
+    #[test]
+    fn a_tag_picks_one_of_several_people() {
+        assert_eq!(
+            split_tag("bob#AAAA1111"),
+            ("bob".to_string(), Some("aaaa1111".to_string()))
+        );
+        assert_eq!(split_tag("bob"), ("bob".to_string(), None));
+        // Not an ID, so part of the name
+        assert_eq!(split_tag("c#"), ("c#".to_string(), None));
+        assert_eq!(split_tag("we#rd"), ("we#rd".to_string(), None));
+        assert!(matches!(
+            parse("/unban bob#ab12"),
+            Input::Command(Command::Unban { username, target_id: Some(id) })
+                if username == "bob" && id == "ab12"
+        ));
+    }
+
+    #[test]
+    fn mute_reads_a_leading_number_as_minutes() {
+        assert!(matches!(
+            parse("/mute bob 10 spam links"),
+            Input::Command(Command::Mute { minutes: Some(10), reason: Some(reason), .. })
+                if reason == "spam links"
+        ));
+        assert!(matches!(
+            parse("/mute bob spam"),
+            Input::Command(Command::Mute { minutes: None, reason: Some(reason), .. })
+                if reason == "spam"
+        ));
+    }
+
+    #[test]
+    fn slow_mode_takes_seconds_or_off() {
+        assert!(matches!(
+            parse("/slow 30"),
+            Input::Command(Command::SlowMode { seconds: 30 })
+        ));
+        assert!(matches!(
+            parse("/slow off"),
+            Input::Command(Command::SlowMode { seconds: 0 })
+        ));
+        assert!(matches!(parse("/slow -1"), Input::Invalid(_)));
+        assert!(matches!(parse("/slow"), Input::Invalid(_)));
+    }
+
+    #[test]
+    fn plain_text_and_unknown_commands_are_messages() {
+        assert!(matches!(parse("hello"), Input::Message(text) if text == "hello"));
+        assert!(matches!(parse("/shrug ok"), Input::Message(text) if text == "/shrug ok"));
+        // A doubled slash sends the rest as typed
+        assert!(matches!(parse("//search x"), Input::Message(text) if text == "/search x"));
+    }
+}
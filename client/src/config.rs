@@ -1,14 +1,88 @@
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fs;
 use std::io::{self, Write};
 use std::path::PathBuf;
+use uuid::Uuid;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Config {
-    pub username: String,
+    /// Stable identity sent in `Join`, generated on first connect
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    /// Server used when neither --host nor --profile is given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    #[serde(default)]
+    pub keys: KeyConfig,
+    /// Built-in theme name or a file stem in ~/.terma/themes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub theme: Option<String>,
+    /// Override color detection: truecolor, 256, 16 or mono
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub color_mode: Option<String>,
+    /// Seconds between pings to the server; 0 turns them off
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ping_interval: Option<u64>,
+    /// Seconds without hearing from the server before giving up on it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ping_timeout: Option<u64>,
+    /// How messages are encoded on the wire: json (the default) or msgpack
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wire_format: Option<String>,
+    /// Largest compression window, as a power of two from 9 to 15; 0 turns
+    /// compression off
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression_window_bits: Option<u8>,
+    /// Messages shorter than this many bytes go uncompressed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression_threshold: Option<usize>,
+    /// Named servers, picked with --profile
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub profiles: BTreeMap<String, Profile>,
 }
 
+/// A named server with its own username
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Profile {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub insecure: bool,
+}
+
+/// The `keys` section: action name -> list of key chords, plus vim mode.
+/// Actions that aren't listed keep their default bindings.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct KeyConfig {
+    #[serde(default)]
+    pub vim_mode: bool,
+    #[serde(flatten)]
+    pub bindings: BTreeMap<String, Vec<String>>,
+}
+
+/// Settings readable and writable with `terma config get/set`
+pub const SETTINGS: &[&str] = &[
+    "username",
+    "host",
+    "theme",
+    "color_mode",
+    "vim_mode",
+    "ping_interval",
+    "ping_timeout",
+    "wire_format",
+    "compression_window_bits",
+    "compression_threshold",
+];
+
+/// Settings a profile can override
+pub const PROFILE_SETTINGS: &[&str] = &["username", "host", "insecure"];
+
 impl Config {
     fn config_dir() -> Result<PathBuf> {
         let home = dirs::home_dir().context("Could not find home directory")?;
@@ -19,19 +93,19 @@ impl Config {
         Ok(Self::config_dir()?.join("config.json"))
     }
 
-    fn load() -> Result<Self> {
+    pub fn load() -> Result<Option<Self>> {
         let path = Self::config_file()?;
         if !path.exists() {
-            return Err(anyhow::anyhow!("Config file does not exist"));
+            return Ok(None);
         }
         let contents = fs::read_to_string(&path)
             .with_context(|| format!("Failed to read config file: {}", path.display()))?;
         let config: Config = serde_json::from_str(&contents)
             .with_context(|| format!("Failed to parse config file: {}", path.display()))?;
-        Ok(config)
+        Ok(Some(config))
     }
 
-    fn save(&self) -> Result<()> {
+    pub fn save(&self) -> Result<()> {
         let dir = Self::config_dir()?;
         fs::create_dir_all(&dir)
             .with_context(|| format!("Failed to create config directory: {}", dir.display()))?;
@@ -42,31 +116,184 @@ impl Config {
             .with_context(|| format!("Failed to write config file: {}", path.display()))?;
         Ok(())
     }
-}
 
-pub fn get_or_prompt_username() -> Result<String> {
-    match Config::load() {
-        Ok(config) => Ok(config.username),
-        Err(_) => {
-            // Prompt for username
-            print!("Enter your username: ");
-            io::stdout().flush()?;
+    /// This client's user ID, created and saved the first time it's needed
+    pub fn user_id(&mut self) -> Result<String> {
+        if let Some(user_id) = &self.user_id {
+            return Ok(user_id.clone());
+        }
 
-            let mut username = String::new();
-            io::stdin().read_line(&mut username)?;
-            let username = username.trim().to_string();
+        let user_id = Uuid::new_v4().to_string();
+        self.user_id = Some(user_id.clone());
+        self.save()?;
+        Ok(user_id)
+    }
 
-            if username.is_empty() {
-                return Err(anyhow::anyhow!("Username cannot be empty"));
+    pub fn profile(&self, name: &str) -> Result<&Profile> {
+        self.profiles.get(name).ok_or_else(|| {
+            let names: Vec<&str> = self.profiles.keys().map(String::as_str).collect();
+            if names.is_empty() {
+                anyhow!(
+                    "unknown profile '{}' (no profiles configured; create one with \
+                     `terma --profile {} config set host <host>`)",
+                    name,
+                    name
+                )
+            } else {
+                anyhow!(
+                    "unknown profile '{}' (available: {})",
+                    name,
+                    names.join(", ")
+                )
             }
+        })
+    }
 
-            // Save config
-            let config = Config {
-                username: username.clone(),
+    /// Read a setting, from `profile` when one is given
+    pub fn get(&self, profile: Option<&str>, key: &str) -> Result<Option<String>> {
+        if let Some(name) = profile {
+            let profile = self.profile(name)?;
+            return match key {
+                "username" => Ok(profile.username.clone()),
+                "host" => Ok(profile.host.clone()),
+                "insecure" => Ok(Some(profile.insecure.to_string())),
+                _ => Err(unknown_setting(key, PROFILE_SETTINGS)),
             };
-            config.save()?;
+        }
 
-            Ok(username)
+        match key {
+            "username" => Ok(self.username.clone()),
+            "host" => Ok(self.host.clone()),
+            "theme" => Ok(self.theme.clone()),
+            "color_mode" => Ok(self.color_mode.clone()),
+            "vim_mode" => Ok(Some(self.keys.vim_mode.to_string())),
+            "ping_interval" => Ok(self.ping_interval.map(|seconds| seconds.to_string())),
+            "ping_timeout" => Ok(self.ping_timeout.map(|seconds| seconds.to_string())),
+            "wire_format" => Ok(self.wire_format.clone()),
+            "compression_window_bits" => {
+                Ok(self.compression_window_bits.map(|bits| bits.to_string()))
+            }
+            "compression_threshold" => {
+                Ok(self.compression_threshold.map(|bytes| bytes.to_string()))
+            }
+            _ => Err(unknown_setting(key, SETTINGS)),
         }
     }
+
+    /// Change a setting, creating the profile if needed. An empty value
+    /// clears optional settings.
+    pub fn set(&mut self, profile: Option<&str>, key: &str, value: &str) -> Result<()> {
+        let value = value.trim();
+        let optional = (!value.is_empty()).then(|| value.to_string());
+
+        if let Some(name) = profile {
+            if !PROFILE_SETTINGS.contains(&key) {
+                return Err(unknown_setting(key, PROFILE_SETTINGS));
+            }
+            let profile = self.profiles.entry(name.to_string()).or_default();
+            match key {
+                "username" => profile.username = optional,
+                "host" => profile.host = optional,
+                _ => profile.insecure = parse_bool(value)?,
+            }
+            return Ok(());
+        }
+
+        match key {
+            "username" => self.username = optional,
+            "host" => self.host = optional,
+            "theme" => self.theme = optional,
+            "color_mode" => self.color_mode = optional,
+            "vim_mode" => self.keys.vim_mode = parse_bool(value)?,
+            "ping_interval" => self.ping_interval = parse_seconds(value)?,
+            "ping_timeout" => self.ping_timeout = parse_seconds(value)?,
+            "wire_format" => self.wire_format = parse_wire_format(value)?,
+            "compression_window_bits" => self.compression_window_bits = parse_window_bits(value)?,
+            "compression_threshold" => self.compression_threshold = parse_bytes(value)?,
+            _ => return Err(unknown_setting(key, SETTINGS)),
+        }
+        Ok(())
+    }
+}
+
+fn unknown_setting(key: &str, known: &[&str]) -> anyhow::Error {
+    anyhow!(
+        "unknown setting '{}' (expected one of: {})",
+        key,
+        known.join(", ")
+    )
+}
+
+fn parse_bool(value: &str) -> Result<bool> {
+    match value.to_ascii_lowercase().as_str() {
+        "true" | "yes" | "on" | "1" => Ok(true),
+        "false" | "no" | "off" | "0" | "" => Ok(false),
+        _ => Err(anyhow!("expected true or false, got '{}'", value)),
+    }
+}
+
+/// Whole seconds, or `None` to go back to the default
+fn parse_seconds(value: &str) -> Result<Option<u64>> {
+    if value.is_empty() {
+        return Ok(None);
+    }
+    value
+        .parse()
+        .map(Some)
+        .map_err(|_| anyhow!("expected a number of seconds, got '{}'", value))
+}
+
+/// `json` or `msgpack`, or `None` to go back to the default
+fn parse_wire_format(value: &str) -> Result<Option<String>> {
+    match value {
+        "" => Ok(None),
+        "json" | "msgpack" => Ok(Some(value.to_string())),
+        _ => Err(anyhow!("expected json or msgpack, got '{}'", value)),
+    }
+}
+
+/// 0 or 9 to 15, or `None` to go back to the default
+fn parse_window_bits(value: &str) -> Result<Option<u8>> {
+    if value.is_empty() {
+        return Ok(None);
+    }
+    match value.parse() {
+        Ok(bits @ (0 | 9..=15)) => Ok(Some(bits)),
+        _ => Err(anyhow!("expected 0, or 9 to 15, got '{}'", value)),
+    }
+}
+
+/// A number of bytes, or `None` to go back to the default
+fn parse_bytes(value: &str) -> Result<Option<usize>> {
+    if value.is_empty() {
+        return Ok(None);
+    }
+    value
+        .parse()
+        .map(Some)
+        .map_err(|_| anyhow!("expected a number of bytes, got '{}'", value))
+}
+
+/// Load the config, or an empty one on first run
+pub fn load() -> Result<Config> {
+    Ok(Config::load()?.unwrap_or_default())
+}
+
+/// Prompt for a username and remember it, in the profile when one is in use
+pub fn prompt_username(config: &mut Config, profile: Option<&str>) -> Result<String> {
+    print!("Enter your username: ");
+    io::stdout().flush()?;
+
+    let mut username = String::new();
+    io::stdin().read_line(&mut username)?;
+    let username = username.trim().to_string();
+
+    if username.is_empty() {
+        return Err(anyhow!("Username cannot be empty"));
+    }
+
+    config.set(profile, "username", &username)?;
+    config.save()?;
+
+    Ok(username)
 }
@@ -1,70 +1,501 @@
 use anyhow::{Context, Result};
 use futures::{SinkExt, StreamExt};
-use terma_shared::{ClientMessage, ServerMessage};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use terma_shared::deflate::{Deflater, Inflater, MAX_WINDOW_BITS};
+use terma_shared::{
+    ClientMessage, Codec, Compression, ErrorCode, Frame, Room, ServerMessage, MIN_PROTOCOL_VERSION,
+    PROTOCOL_VERSION,
+};
+use tokio::net::TcpStream;
 use tokio::sync::mpsc;
-use tokio_tungstenite::{connect_async, tungstenite::Message};
+use tokio::task::JoinHandle;
+use tokio::time::Interval;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::error::{Error as WsError, ProtocolError, SubProtocolError};
+use tokio_tungstenite::tungstenite::http::header::SEC_WEBSOCKET_PROTOCOL;
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+use tokio_tungstenite::{connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream};
+
+use crate::config::Config;
+use crate::error::{ConnectionLost, ServerError};
+use crate::identity::Identity;
+
+/// How long to wait for the server's join challenge before joining unsigned
+const CHALLENGE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// How long to give queued messages and the close frame to go out on quit
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Wait before the first reconnect attempt, doubling after each failure
+const RECONNECT_DELAY: Duration = Duration::from_millis(250);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(2);
+
+/// Ping the server this often unless `ping_interval` says otherwise
+const DEFAULT_PING_INTERVAL: u64 = 15;
+/// Give up on a server silent this long unless `ping_timeout` says otherwise
+const DEFAULT_PING_TIMEOUT: u64 = 45;
+
+/// Messages shorter than this go uncompressed unless `compression_threshold`
+/// says otherwise
+const DEFAULT_COMPRESSION_THRESHOLD: usize = 256;
+
+/// Most a compressed frame from the server may inflate to. History comes in
+/// one frame, so this is far above the longest message.
+const MAX_INFLATED_LENGTH: usize = 64 << 20;
+
+/// How often to ping the server, and how long it may stay silent
+#[derive(Debug, Clone, Copy)]
+pub struct Heartbeat {
+    /// Zero turns pinging, and so the timeout, off
+    pub interval: Duration,
+    pub timeout: Duration,
+}
+
+impl Heartbeat {
+    fn from_config(config: &Config) -> Self {
+        let seconds = |value: Option<u64>, default| Duration::from_secs(value.unwrap_or(default));
+        Self {
+            interval: seconds(config.ping_interval, DEFAULT_PING_INTERVAL),
+            timeout: seconds(config.ping_timeout, DEFAULT_PING_TIMEOUT),
+        }
+    }
+}
+
+/// What's been learned about the connection as it runs
+#[derive(Debug, Default)]
+struct Health {
+    /// Round trip time of the latest ping
+    rtt: Option<Duration>,
+    /// The server stopped answering
+    timed_out: bool,
+    /// The code and reason the server closed the connection with
+    closed: Option<(u16, String)>,
+}
+
+/// Where a server lives and whether to reach it over TLS
+#[derive(Debug, Clone)]
+pub struct Endpoint {
+    pub host: String,
+    /// Use plain ws:// and http:// even for non-local hosts
+    pub insecure: bool,
+}
+
+impl Endpoint {
+    pub fn new(host: impl Into<String>, insecure: bool) -> Self {
+        Self {
+            host: host.into(),
+            insecure,
+        }
+    }
+
+    fn uses_tls(&self) -> bool {
+        !self.insecure && !is_local_host(&self.host)
+    }
+
+    pub fn ws_url(&self, room_id: &str) -> String {
+        let scheme = if self.uses_tls() { "wss" } else { "ws" };
+        format!("{}://{}/ws/{}", scheme, self.host, room_id)
+    }
+
+    /// Base URL for the server's HTTP API
+    pub fn http_base_url(&self) -> String {
+        let scheme = if self.uses_tls() { "https" } else { "http" };
+        format!("{}://{}", scheme, self.host)
+    }
+}
+
+/// Proof of access to a password-protected room, and of owning a room
+#[derive(Debug, Clone, Default)]
+pub struct RoomAccess {
+    pub password: Option<String>,
+    pub join_token: Option<String>,
+    pub owner_token: Option<String>,
+}
+
+impl RoomAccess {
+    /// The secret to send as an HTTP bearer token
+    pub fn bearer(&self) -> Option<&str> {
+        self.join_token.as_deref().or(self.password.as_deref())
+    }
+}
+
+/// Room details, or `None` if the server doesn't know the room
+pub async fn fetch_room(endpoint: &Endpoint, room_id: &str) -> Result<Option<Room>> {
+    let url = format!("{}/api/rooms/{}", endpoint.http_base_url(), room_id);
+    let response = reqwest::get(&url)
+        .await
+        .context("Failed to connect to server")?;
+
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    if response.status() == reqwest::StatusCode::GONE {
+        // The body says why: expired, or deleted after going unused
+        let reason = response.text().await.unwrap_or_default();
+        return Err(ServerError::new(ErrorCode::RoomGone, reason.trim()).into());
+    }
+    let room = response
+        .error_for_status()?
+        .json()
+        .await
+        .context("Unexpected response from server")?;
+    Ok(Some(room))
+}
+
+/// Who is joining which room, and how to get in. Kept so a dropped
+/// connection can be dialled again.
+#[derive(Debug, Clone)]
+pub struct JoinRequest {
+    pub endpoint: Endpoint,
+    pub room_id: String,
+    pub user_id: String,
+    pub username: String,
+    pub access: RoomAccess,
+}
 
 pub struct Connection {
     tx: mpsc::UnboundedSender<ClientMessage>,
+    writer: JoinHandle<()>,
+    health: Arc<Mutex<Health>>,
+    heartbeat: Heartbeat,
 }
 
 impl Connection {
+    /// Join a room, picking up `session_id` if the server still holds it
     pub async fn connect(
-        host: &str,
-        room_id: &str,
-        user_id: String,
-        username: String,
+        join: &JoinRequest,
+        identity: &Identity,
+        config: &Config,
+        session_id: Option<String>,
     ) -> Result<(Self, mpsc::UnboundedReceiver<ServerMessage>)> {
-        let url = if host.starts_with("localhost") || host.starts_with("127.0.0.1") {
-            format!("ws://{}/ws/{}", host, room_id)
-        } else {
-            format!("wss://{}/ws/{}", host, room_id)
-        };
+        let room_id = join.room_id.as_str();
+        let access = &join.access;
+        let url = join.endpoint.ws_url(room_id);
+        let heartbeat = Heartbeat::from_config(config);
 
-        let (ws_stream, _) = connect_async(&url)
-            .await
-            .context("Failed to connect to server")?;
+        let (ws_stream, codec) = open_socket(&url, preferred_codec(config)).await?;
 
         let (mut write, mut read) = ws_stream.split();
 
-        // Send Join message immediately
-        let join_msg = ClientMessage::Join { user_id, username };
-        let join_json = join_msg.to_json()?;
-        write.send(Message::Text(join_json)).await?;
+        // Sign the server's challenge; servers without one get an unsigned join
+        let nonce = match tokio::time::timeout(CHALLENGE_TIMEOUT, read.next()).await {
+            Ok(Some(Ok(msg))) => match decode(codec, None, msg) {
+                Some(Ok(ServerMessage::Challenge { nonce })) => Some(nonce),
+                // e.g. the room expired
+                Some(Ok(ServerMessage::Error { code, message, .. })) => {
+                    return Err(ServerError::new(code, message).into())
+                }
+                _ => None,
+            },
+            Ok(Some(Err(e))) => return Err(e).context("Connection failed"),
+            Ok(None) => anyhow::bail!("Server closed the connection"),
+            _ => None,
+        };
+
+        let (public_key, signature) = match nonce {
+            Some(nonce) => (
+                Some(identity.public_key()),
+                Some(identity.sign_join(&nonce, room_id, &join.user_id)),
+            ),
+            None => (None, None),
+        };
+        // Once offered, compressed frames may come from the welcome on
+        let offer = match config.compression_window_bits.unwrap_or(MAX_WINDOW_BITS) {
+            0 => None,
+            window_bits => Some(Compression { window_bits }),
+        };
+        let inflater = offer.map(|_| Inflater {
+            max_size: MAX_INFLATED_LENGTH,
+        });
+        let join_msg = ClientMessage::Join {
+            protocol_version: PROTOCOL_VERSION,
+            client_version: Some(env!("CARGO_PKG_VERSION").to_string()),
+            user_id: join.user_id.clone(),
+            username: join.username.clone(),
+            public_key,
+            signature,
+            password: access.password.clone(),
+            join_token: access.join_token.clone(),
+            owner_token: access.owner_token.clone(),
+            session_id,
+            compression: offer,
+        };
+        write.send(encode(codec, None, &join_msg)?).await?;
+
+        // Wait until the server accepts or refuses the join
+        let welcome = loop {
+            match read.next().await {
+                Some(Ok(msg)) => match decode(codec, inflater, msg) {
+                    Some(Ok(
+                        msg @ ServerMessage::Welcome {
+                            protocol_version, ..
+                        },
+                    )) => {
+                        if protocol_version < MIN_PROTOCOL_VERSION {
+                            anyhow::bail!(
+                                "The server is too old for this version of terma (protocol {}, \
+                                 which needs {} or newer). Ask its operator to upgrade it.",
+                                protocol_version,
+                                MIN_PROTOCOL_VERSION
+                            );
+                        }
+                        break msg;
+                    }
+                    Some(Ok(ServerMessage::Error { code, message, .. })) => {
+                        return Err(ServerError::new(code, message).into())
+                    }
+                    _ => {}
+                },
+                Some(Err(e)) => return Err(e).context("Connection failed"),
+                None => anyhow::bail!("Server closed the connection"),
+            }
+        };
+
+        // Compress what we send the way the server agreed to, if it did
+        let deflater = match &welcome {
+            ServerMessage::Welcome {
+                compression: Some(compression),
+                ..
+            } => Some(Deflater {
+                window_bits: compression.window_bits,
+                threshold: config
+                    .compression_threshold
+                    .unwrap_or(DEFAULT_COMPRESSION_THRESHOLD),
+            }),
+            _ => None,
+        };
 
         // Create channels
         let (outgoing_tx, mut outgoing_rx) = mpsc::unbounded_channel::<ClientMessage>();
         let (incoming_tx, incoming_rx) = mpsc::unbounded_channel::<ServerMessage>();
+        let _ = incoming_tx.send(welcome);
 
-        // Spawn task to send messages
-        tokio::spawn(async move {
-            while let Some(msg) = outgoing_rx.recv().await {
-                if let Ok(json) = msg.to_json() {
-                    if write.send(Message::Text(json)).await.is_err() {
-                        break;
+        // Pings carry when they were sent, so each pong gives a round trip
+        let started = Instant::now();
+        let health = Arc::new(Mutex::new(Health::default()));
+
+        // Spawn task to send messages and pings, saying goodbye once there
+        // are no more messages
+        let writer = tokio::spawn(async move {
+            let mut ticker =
+                (!heartbeat.interval.is_zero()).then(|| tokio::time::interval(heartbeat.interval));
+            loop {
+                let msg = tokio::select! {
+                    msg = outgoing_rx.recv() => match msg.map(|msg| encode(codec, deflater, &msg)) {
+                        Some(Ok(msg)) => msg,
+                        Some(Err(_)) => continue,
+                        None => break,
+                    },
+                    _ = tick(&mut ticker) => {
+                        let sent = started.elapsed().as_micros() as u64;
+                        Message::Ping(sent.to_be_bytes().to_vec())
                     }
+                };
+                if write.send(msg).await.is_err() {
+                    return;
                 }
             }
+            let _ = write.send(Message::Close(None)).await;
         });
 
-        // Spawn task to receive messages
+        // Spawn task to receive messages, giving up if the server goes quiet
+        // for longer than it should take to answer a ping
+        let reader_health = health.clone();
         tokio::spawn(async move {
-            while let Some(Ok(msg)) = read.next().await {
-                if let Message::Text(text) = msg {
-                    if let Ok(server_msg) = ServerMessage::from_json(&text) {
-                        if incoming_tx.send(server_msg).is_err() {
+            loop {
+                let next = if heartbeat.interval.is_zero() {
+                    read.next().await
+                } else {
+                    match tokio::time::timeout(heartbeat.timeout, read.next()).await {
+                        Ok(next) => next,
+                        Err(_) => {
+                            reader_health.lock().unwrap().timed_out = true;
                             break;
                         }
                     }
+                };
+                match next {
+                    Some(Ok(Message::Pong(payload))) => {
+                        if let Ok(sent) = <[u8; 8]>::try_from(payload.as_slice()) {
+                            let sent = Duration::from_micros(u64::from_be_bytes(sent));
+                            let rtt = started.elapsed().saturating_sub(sent);
+                            reader_health.lock().unwrap().rtt = Some(rtt);
+                        }
+                    }
+                    Some(Ok(Message::Close(frame))) => {
+                        reader_health.lock().unwrap().closed =
+                            frame.map(|frame| (frame.code.into(), frame.reason.into_owned()));
+                        break;
+                    }
+                    Some(Ok(msg)) => {
+                        if let Some(Ok(server_msg)) = decode(codec, inflater, msg) {
+                            if incoming_tx.send(server_msg).is_err() {
+                                break;
+                            }
+                        }
+                    }
+                    Some(Err(_)) | None => break,
                 }
             }
         });
 
-        Ok((Connection { tx: outgoing_tx }, incoming_rx))
+        let conn = Connection {
+            tx: outgoing_tx,
+            writer,
+            health,
+            heartbeat,
+        };
+        Ok((conn, incoming_rx))
+    }
+
+    /// Dial again after the connection dropped, resuming `session_id`.
+    /// Keeps trying for `window`, the time the server holds the session,
+    /// unless the server turns us away. `on_attempt` hears each attempt's
+    /// number before it's dialled.
+    pub async fn reconnect(
+        join: &JoinRequest,
+        identity: &Identity,
+        config: &Config,
+        session_id: &str,
+        window: Duration,
+        on_attempt: impl Fn(u32),
+    ) -> Result<(Self, mpsc::UnboundedReceiver<ServerMessage>)> {
+        let deadline = Instant::now() + window;
+        let mut delay = RECONNECT_DELAY;
+        for attempt in 1.. {
+            tokio::time::sleep(delay).await;
+            on_attempt(attempt);
+            let session_id = Some(session_id.to_string());
+            match Self::connect(join, identity, config, session_id).await {
+                Ok(connected) => return Ok(connected),
+                Err(e) if e.is::<ServerError>() || Instant::now() + delay >= deadline => {
+                    return Err(e)
+                }
+                Err(_) => delay = (delay * 2).min(MAX_RECONNECT_DELAY),
+            }
+        }
+        unreachable!("attempts never run out before the deadline")
     }
 
     pub fn send(&self, message: ClientMessage) -> Result<()> {
         self.tx.send(message).context("Failed to send message")?;
         Ok(())
     }
+
+    /// Round trip time to the server, once a ping has been answered
+    pub fn rtt(&self) -> Option<Duration> {
+        self.health.lock().unwrap().rtt
+    }
+
+    /// Why the connection ended, once it has
+    pub fn disconnect_error(&self) -> anyhow::Error {
+        let health = self.health.lock().unwrap();
+        if health.timed_out {
+            let message = format!(
+                "Lost connection: no reply from the server in {}s",
+                self.heartbeat.timeout.as_secs()
+            );
+            return ConnectionLost(message).into();
+        }
+        let closed = health.closed.as_ref();
+        match closed.and_then(|(code, reason)| ServerError::from_close(*code, reason)) {
+            Some(e) => e.into(),
+            None => match closed {
+                Some((_, reason)) if !reason.is_empty() => ConnectionLost(reason.clone()).into(),
+                _ => ConnectionLost("The server closed the connection".to_string()).into(),
+            },
+        }
+    }
+
+    /// Hang up cleanly, so the room sees us leave now rather than after the
+    /// server's grace period for dropped connections
+    pub async fn close(self) {
+        drop(self.tx);
+        let _ = tokio::time::timeout(CLOSE_TIMEOUT, self.writer).await;
+    }
+}
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// The wire format the config asks for
+fn preferred_codec(config: &Config) -> Codec {
+    match config.wire_format.as_deref() {
+        Some("msgpack") => Codec::MessagePack,
+        _ => Codec::Json,
+    }
+}
+
+/// Open the socket, asking for `codec` by its subprotocol, and find out
+/// which codec the server agreed to. Servers from before wire formats were
+/// negotiated don't answer, and only speak JSON.
+async fn open_socket(url: &str, codec: Codec) -> Result<(Socket, Codec)> {
+    if codec.subprotocol() != Codec::Json.subprotocol() {
+        let mut request = url.into_client_request()?;
+        request.headers_mut().insert(
+            SEC_WEBSOCKET_PROTOCOL,
+            HeaderValue::from_static(codec.subprotocol()),
+        );
+        match connect_async(request).await {
+            Ok((socket, response)) => {
+                let subprotocol = response
+                    .headers()
+                    .get(SEC_WEBSOCKET_PROTOCOL)
+                    .and_then(|value| value.to_str().ok());
+                return Ok((socket, Codec::for_subprotocol(subprotocol)));
+            }
+            Err(WsError::Protocol(ProtocolError::SecWebSocketSubProtocolError(
+                SubProtocolError::NoSubProtocol,
+            ))) => {}
+            Err(e) => return Err(e).context("Failed to connect to server"),
+        }
+    }
+    let (socket, _) = connect_async(url)
+        .await
+        .context("Failed to connect to server")?;
+    Ok((socket, Codec::Json))
+}
+
+fn encode(codec: Codec, deflater: Option<Deflater>, msg: &ClientMessage) -> Result<Message> {
+    let frame = msg.encode(codec)?;
+    let data = match &frame {
+        Frame::Text(text) => text.as_bytes(),
+        Frame::Binary(bytes) => bytes.as_slice(),
+    };
+    if let Some(compressed) = deflater.and_then(|deflater| deflater.compress(data)) {
+        return Ok(Message::Binary(compressed));
+    }
+    Ok(match frame {
+        Frame::Text(text) => Message::Text(text),
+        Frame::Binary(bytes) => Message::Binary(bytes),
+    })
+}
+
+/// Decode a text or binary frame, inflating it if compression was offered;
+/// control frames give `None`
+fn decode(codec: Codec, inflater: Option<Inflater>, msg: Message) -> Option<Result<ServerMessage>> {
+    let frame = match msg {
+        Message::Text(text) => Frame::Text(text),
+        Message::Binary(bytes) => Frame::Binary(bytes),
+        _ => return None,
+    };
+    let frame = match inflater {
+        Some(inflater) => inflater.expand(frame),
+        None => Ok(frame),
+    };
+    Some(frame.and_then(|frame| ServerMessage::decode(codec, frame)))
+}
+
+/// Wait for the next ping, or forever if pinging is off
+async fn tick(ticker: &mut Option<Interval>) {
+    match ticker {
+        Some(ticker) => {
+            ticker.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+fn is_local_host(host: &str) -> bool {
+    host.starts_with("localhost") || host.starts_with("127.0.0.1")
 }
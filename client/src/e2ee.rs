@@ -0,0 +1,192 @@
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    XChaCha20Poly1305, XNonce,
+};
+use std::str::FromStr;
+use terma_shared::{ChatMessage, ENCRYPTED_CONTENT_PREFIX};
+
+const NONCE_LEN: usize = 24;
+
+/// Shown in place of messages that don't open with the room key
+pub const UNDECRYPTABLE: &str =
+    "🔒 Unable to decrypt this message (sent with a different room key)";
+
+/// Symmetric key for an end-to-end encrypted room. It only ever travels in
+/// invite links and install commands, never to the server.
+#[derive(Clone)]
+pub struct RoomKey {
+    bytes: [u8; 32],
+}
+
+impl RoomKey {
+    pub fn generate() -> Self {
+        Self {
+            bytes: XChaCha20Poly1305::generate_key(&mut OsRng).into(),
+        }
+    }
+
+    fn cipher(&self) -> XChaCha20Poly1305 {
+        XChaCha20Poly1305::new(&self.bytes.into())
+    }
+
+    /// Seal message content. The sender's user ID is authenticated too, so the
+    /// server can't pass one person's ciphertext off as another's.
+    pub fn encrypt(&self, room_id: &str, user_id: &str, plaintext: &str) -> Result<String> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let aad = associated_data(room_id, user_id);
+        let ciphertext = self
+            .cipher()
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plaintext.as_bytes(),
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| anyhow!("Failed to encrypt message"))?;
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&ciphertext);
+        Ok(format!(
+            "{}{}",
+            ENCRYPTED_CONTENT_PREFIX,
+            URL_SAFE_NO_PAD.encode(sealed)
+        ))
+    }
+
+    /// Open sealed content; `None` if it wasn't sealed with this key
+    pub fn decrypt(&self, room_id: &str, user_id: &str, content: &str) -> Option<String> {
+        let sealed = URL_SAFE_NO_PAD
+            .decode(content.strip_prefix(ENCRYPTED_CONTENT_PREFIX)?)
+            .ok()?;
+        if sealed.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let nonce = XNonce::from(<[u8; NONCE_LEN]>::try_from(nonce).ok()?);
+        let aad = associated_data(room_id, user_id);
+        let plaintext = self
+            .cipher()
+            .decrypt(
+                &nonce,
+                Payload {
+                    msg: ciphertext,
+                    aad: &aad,
+                },
+            )
+            .ok()?;
+        String::from_utf8(plaintext).ok()
+    }
+
+    /// Decrypt a received message in place. Ones that won't open are replaced
+    /// with a marker rather than shown as ciphertext.
+    pub fn open(&self, room_id: &str, msg: &mut ChatMessage) {
+        msg.content = self
+            .decrypt(room_id, &msg.user_id, &msg.content)
+            .unwrap_or_else(|| UNDECRYPTABLE.to_string());
+    }
+}
+
+impl FromStr for RoomKey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let bytes = URL_SAFE_NO_PAD
+            .decode(s.trim())
+            .ok()
+            .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+            .ok_or_else(|| anyhow!("invalid room key"))?;
+        Ok(Self { bytes })
+    }
+}
+
+impl std::fmt::Display for RoomKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&URL_SAFE_NO_PAD.encode(self.bytes))
+    }
+}
+
+fn associated_data(room_id: &str, user_id: &str) -> Vec<u8> {
+    format!("terma-e2ee-v1\n{}\n{}", room_id, user_id).into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_with_the_same_key() {
+        let key = RoomKey::generate();
+        let sealed = key.encrypt("room", "alice", "meet at 5 🕔").unwrap();
+        assert!(sealed.starts_with(ENCRYPTED_CONTENT_PREFIX));
+        assert!(!sealed.contains("meet"));
+        assert_eq!(
+            key.decrypt("room", "alice", &sealed).as_deref(),
+            Some("meet at 5 🕔")
+        );
+        // A fresh nonce each time
+        assert_ne!(
+            sealed,
+            key.encrypt("room", "alice", "meet at 5 🕔").unwrap()
+        );
+    }
+
+    #[test]
+    fn refuses_other_keys_senders_and_rooms() {
+        let key = RoomKey::generate();
+        let sealed = key.encrypt("room", "alice", "hi").unwrap();
+        assert_eq!(RoomKey::generate().decrypt("room", "alice", &sealed), None);
+        assert_eq!(key.decrypt("room", "mallory", &sealed), None);
+        assert_eq!(key.decrypt("other-room", "alice", &sealed), None);
+    }
+
+    #[test]
+    fn refuses_tampered_and_malformed_content() {
+        let key = RoomKey::generate();
+        let sealed = key.encrypt("room", "alice", "hi").unwrap();
+        let mut tampered = sealed.clone().into_bytes();
+        // Past the nonce, in the ciphertext itself
+        let at = ENCRYPTED_CONTENT_PREFIX.len() + 40;
+        tampered[at] = if tampered[at] == b'A' { b'B' } else { b'A' };
+        let tampered = String::from_utf8(tampered).unwrap();
+        assert_eq!(key.decrypt("room", "alice", &tampered), None);
+
+        let payload = sealed.strip_prefix(ENCRYPTED_CONTENT_PREFIX).unwrap();
+        assert_eq!(key.decrypt("room", "alice", payload), None);
+        let short = format!("{}AAAA", ENCRYPTED_CONTENT_PREFIX);
+        assert_eq!(key.decrypt("room", "alice", &short), None);
+        let not_base64 = format!("{}!!!", ENCRYPTED_CONTENT_PREFIX);
+        assert_eq!(key.decrypt("room", "alice", &not_base64), None);
+    }
+
+    #[test]
+    fn open_marks_messages_it_cannot_read() {
+        let key = RoomKey::generate();
+        let mut msg = ChatMessage::new(
+            "room".to_string(),
+            "alice".to_string(),
+            "alice".to_string(),
+            key.encrypt("room", "alice", "hi").unwrap(),
+        );
+        let mut readable = msg.clone();
+        key.open("room", &mut readable);
+        assert_eq!(readable.content, "hi");
+        RoomKey::generate().open("room", &mut msg);
+        assert_eq!(msg.content, UNDECRYPTABLE);
+    }
+
+    #[test]
+    fn keys_survive_display_and_parse() {
+        let key = RoomKey::generate();
+        let parsed: RoomKey = format!(" {} ", key).parse().unwrap();
+        let sealed = key.encrypt("room", "alice", "hi").unwrap();
+        assert_eq!(
+            parsed.decrypt("room", "alice", &sealed).as_deref(),
+            Some("hi")
+        );
+        assert!("too-short".parse::<RoomKey>().is_err());
+        assert!("".parse::<RoomKey>().is_err());
+    }
+}
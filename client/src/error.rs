@@ -0,0 +1,165 @@
+//! Why the client gave up, as a message worth showing and an exit code
+//! scripts can act on
+
+use std::fmt;
+use terma_shared::ErrorCode;
+
+/// Exit codes. Clap's own usage errors exit with 2.
+pub mod exit {
+    /// Anything not covered below
+    pub const FAILURE: i32 = 1;
+    /// The room doesn't exist, or has expired or been deleted
+    pub const ROOM_UNAVAILABLE: i32 = 3;
+    /// Not let in: a wrong password or token, someone else's user ID, or
+    /// banned or kicked
+    pub const NOT_ADMITTED: i32 = 4;
+    /// The server turned down a message or command
+    pub const REFUSED: i32 = 5;
+    /// This client is too old for the server
+    pub const UPGRADE_REQUIRED: i32 = 6;
+    /// The connection dropped or the server stopped answering
+    pub const CONNECTION_LOST: i32 = 7;
+}
+
+/// The server refused or ended something and said why
+#[derive(Debug)]
+pub struct ServerError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl ServerError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+
+    /// The error behind a close frame, if its code is one of the server's
+    pub fn from_close(code: u16, reason: &str) -> Option<Self> {
+        let code = ErrorCode::from_close_code(code)?;
+        let message = if reason.is_empty() {
+            "The server closed the connection"
+        } else {
+            reason
+        };
+        Some(Self::new(code, message))
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        match self.code {
+            ErrorCode::RoomNotFound | ErrorCode::RoomGone => exit::ROOM_UNAVAILABLE,
+            ErrorCode::Unauthorized
+            | ErrorCode::IdentityMismatch
+            | ErrorCode::Banned
+            | ErrorCode::Kicked => exit::NOT_ADMITTED,
+            ErrorCode::Muted
+            | ErrorCode::Forbidden
+            | ErrorCode::MessageTooLong
+            | ErrorCode::RateLimited
+            | ErrorCode::EncryptionRequired
+            | ErrorCode::InvalidRequest => exit::REFUSED,
+            ErrorCode::UpgradeRequired | ErrorCode::UnsupportedMessage => exit::UPGRADE_REQUIRED,
+            ErrorCode::TooSlow => exit::CONNECTION_LOST,
+            ErrorCode::Internal | ErrorCode::Unknown => exit::FAILURE,
+        }
+    }
+
+    /// What to do about it, where the message alone doesn't say
+    fn hint(&self) -> Option<&'static str> {
+        match self.code {
+            ErrorCode::RoomNotFound => {
+                Some("Check the room ID, or create a room with `terma new`.")
+            }
+            ErrorCode::Unauthorized => Some(
+                "Join with the room's invite token (--token), or its password when asked \
+                 (or in TERMA_ROOM_PASSWORD).",
+            ),
+            ErrorCode::IdentityMismatch => Some(
+                "This user ID was first used with another key. Copy ~/.terma from the device \
+                 you first joined with.",
+            ),
+            ErrorCode::TooSlow => {
+                Some("Your connection couldn't keep up with the room. Rejoin to catch up.")
+            }
+            ErrorCode::Internal => Some("Something went wrong on the server. Try again shortly."),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)?;
+        if let Some(hint) = self.hint() {
+            write!(f, "\n{}", hint)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ServerError {}
+
+/// The connection died without the server saying why
+#[derive(Debug)]
+pub struct ConnectionLost(pub String);
+
+impl fmt::Display for ConnectionLost {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for ConnectionLost {}
+
+/// The exit code for an error, looking past any context added to it
+pub fn exit_code(e: &anyhow::Error) -> i32 {
+    if let Some(e) = e.downcast_ref::<ServerError>() {
+        return e.exit_code();
+    }
+    if e.downcast_ref::<ConnectionLost>().is_some() {
+        return exit::CONNECTION_LOST;
+    }
+    exit::FAILURE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn close_frames_carry_the_servers_error() {
+        let e = ServerError::from_close(4403, "You are banned from this room").unwrap();
+        assert_eq!(e.code, ErrorCode::Banned);
+        assert_eq!(e.exit_code(), exit::NOT_ADMITTED);
+        let e = ServerError::from_close(4426, "").unwrap();
+        assert_eq!(e.message, "The server closed the connection");
+        assert_eq!(e.exit_code(), exit::UPGRADE_REQUIRED);
+        // A normal close isn't an error
+        assert!(ServerError::from_close(1000, "bye").is_none());
+    }
+
+    #[test]
+    fn exit_codes_look_past_context() {
+        let e = anyhow::Error::new(ServerError::new(ErrorCode::RoomGone, "Room expired"))
+            .context("Failed to join");
+        assert_eq!(exit_code(&e), exit::ROOM_UNAVAILABLE);
+        let e = anyhow::Error::new(ConnectionLost("Server stopped answering".to_string()));
+        assert_eq!(exit_code(&e), exit::CONNECTION_LOST);
+        assert_eq!(exit_code(&anyhow::anyhow!("disk full")), exit::FAILURE);
+    }
+
+    #[test]
+    fn hints_follow_the_message() {
+        let e = ServerError::new(ErrorCode::RoomNotFound, "No such room");
+        assert_eq!(
+            e.to_string(),
+            "No such room\nCheck the room ID, or create a room with `terma new`."
+        );
+        assert_eq!(
+            ServerError::new(ErrorCode::Muted, "Muted").to_string(),
+            "Muted"
+        );
+    }
+}
@@ -1,97 +1,77 @@
 use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
-use tui_textarea::{Input, Key};
+use tui_textarea::Input;
 
-pub fn handle_key_event(app: &mut crate::app::App, key: KeyEvent) -> Option<String> {
-    if matches!(key.code, KeyCode::Char('c'))
-        && (key.modifiers.contains(KeyModifiers::CONTROL)
-            || key.modifiers.contains(KeyModifiers::SUPER))
-    {
-        if app.has_selection() {
-            if let Err(err) = app.copy_selection() {
-                app.add_system_message(format!("Copy failed: {}", err));
-            }
-            return None;
+use crate::app::App;
+use crate::keys::Action;
+
+pub fn handle_key_event(app: &mut App, key: KeyEvent) -> Option<String> {
+    // The search overlay captures navigation keys while it is open
+    if app.search.is_some() {
+        match key.code {
+            KeyCode::Esc => app.close_search(),
+            KeyCode::Up => app.search_select_prev(),
+            KeyCode::Down => app.search_select_next(),
+            KeyCode::Enter => app.jump_to_selected_result(),
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => app.quit(),
+            _ => {}
         }
+        return None;
     }
 
-    // Convert crossterm KeyEvent to tui_textarea Input first
-    let input = Input::from(key);
+    // Any key dismisses the help overlay
+    if app.show_help {
+        app.show_help = false;
+        return None;
+    }
 
-    // Match on the tui_textarea Input struct to properly detect Shift+Enter
-    match input {
-        // Ctrl+C: quit application
-        Input {
-            key: Key::Char('c'),
-            ctrl: true,
-            shift: false,
-            ..
-        } => {
-            app.quit();
-            None
-        }
-        // Shift+Enter or Ctrl+J: insert newline (multi-line message)
-        // Note: Many terminals send Shift+Enter as Ctrl+J
-        Input {
-            key: Key::Enter,
-            shift: true,
-            ..
+    if let Some((action, chord)) = app.keymap.action_for(&key) {
+        // Bare characters are only shortcuts when they can't be meant as text
+        if !chord.is_plain_char() || app.accepts_char_shortcuts() {
+            return perform_action(app, action);
         }
-        | Input {
-            key: Key::Char('j'),
-            ctrl: true,
-            ..
-        } => {
-            app.input.insert_newline();
-            None
+    }
+
+    if let Some(vim) = &mut app.vim {
+        if vim.handle_key(&mut app.input, key) {
+            return None;
         }
-        // Plain Enter (without shift): send message
-        Input {
-            key: Key::Enter,
-            shift: false,
-            ..
-        } => {
+    }
+
+    // Forward all other inputs to TextArea for normal editing
+    app.input.input(Input::from(key));
+    None
+}
+
+fn perform_action(app: &mut App, action: Action) -> Option<String> {
+    match action {
+        Action::Send => {
             let message = app.input_take();
             if !message.trim().is_empty() {
-                Some(message)
-            } else {
-                None
+                return Some(message);
             }
         }
-        // Ctrl+M: also send message
-        Input {
-            key: Key::Char('m'),
-            ctrl: true,
-            ..
-        } => {
-            let message = app.input_take();
-            if !message.trim().is_empty() {
-                Some(message)
+        Action::Newline => app.input.insert_newline(),
+        Action::ScrollUp => app.scroll_up(),
+        Action::ScrollDown => app.scroll_down(),
+        Action::Copy => copy_selection(app),
+        Action::Help => app.show_help = true,
+        Action::Quit => {
+            // With text selected, the quit chord copies instead
+            if app.has_selection() {
+                copy_selection(app);
             } else {
-                None
+                app.quit();
             }
         }
-        // Alt+Up: scroll messages up
-        Input {
-            key: Key::Up,
-            alt: true,
-            ..
-        } => {
-            app.scroll_up();
-            None
-        }
-        // Alt+Down: scroll messages down
-        Input {
-            key: Key::Down,
-            alt: true,
-            ..
-        } => {
-            app.scroll_down();
-            None
-        }
-        // Forward all other inputs to TextArea for normal editing
-        input => {
-            app.input.input(input);
-            None
-        }
+    }
+    None
+}
+
+fn copy_selection(app: &mut App) {
+    if !app.has_selection() {
+        return;
+    }
+    if let Err(err) = app.copy_selection() {
+        app.add_system_message(format!("Copy failed: {}", err));
     }
 }
@@ -0,0 +1,131 @@
+use anyhow::{Context, Result};
+use chrono::Local;
+use serde::Deserialize;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use terma_shared::{
+    export::{self, ExportFormat},
+    ChatMessage,
+};
+
+use crate::{
+    app::App,
+    connection::{Endpoint, RoomAccess},
+    e2ee::RoomKey,
+    identity::Identity,
+};
+
+#[derive(Deserialize)]
+struct JsonTranscript {
+    messages: Vec<ChatMessage>,
+}
+
+/// Stream a room transcript from the server to stdout. Encrypted rooms are
+/// fetched as JSON and decrypted and rendered here instead.
+pub async fn export_room(
+    endpoint: &Endpoint,
+    room_id: &str,
+    format: ExportFormat,
+    access: &RoomAccess,
+    room_key: Option<&RoomKey>,
+    user_id: &str,
+    identity: &Identity,
+) -> Result<()> {
+    let fetch_format = match room_key {
+        Some(_) => ExportFormat::Json,
+        None => format,
+    };
+    let url = format!(
+        "{}/api/rooms/{}/export?format={}",
+        endpoint.http_base_url(),
+        room_id,
+        fetch_format
+    );
+
+    let mut request = reqwest::Client::new().get(&url);
+    if let Some(secret) = access.bearer() {
+        request = request.bearer_auth(secret);
+    }
+    // The server turns away anyone banned or kicked, so say who's asking
+    for (name, value) in identity.request_headers(room_id, user_id) {
+        request = request.header(name, value);
+    }
+
+    let mut response = request
+        .send()
+        .await
+        .context("Failed to connect to server")?;
+
+    let status = response.status();
+    if !status.is_success() {
+        let body = response.text().await.unwrap_or_default();
+        anyhow::bail!("Export failed ({}): {}", status, body.trim());
+    }
+
+    if let Some(key) = room_key {
+        let mut transcript: JsonTranscript = response
+            .json()
+            .await
+            .context("Unexpected response from server")?;
+        for msg in &mut transcript.messages {
+            key.open(room_id, msg);
+        }
+        let mut stdout = io::stdout().lock();
+        stdout.write_all(export::render(format, room_id, &transcript.messages).as_bytes())?;
+        return Ok(stdout.flush()?);
+    }
+
+    let mut stdout = io::stdout().lock();
+    while let Some(chunk) = response
+        .chunk()
+        .await
+        .context("Connection lost during export")?
+    {
+        stdout.write_all(&chunk)?;
+    }
+    stdout.flush()?;
+
+    Ok(())
+}
+
+/// Write the locally loaded history to a file. The format follows the file
+/// extension and defaults to Markdown.
+pub fn export_local(app: &App, path: Option<&str>) -> Result<PathBuf> {
+    let path = match path {
+        Some(path) => PathBuf::from(path),
+        None => PathBuf::from(format!(
+            "terma-{}-{}.md",
+            app.room_id,
+            Local::now().format("%Y%m%d-%H%M%S")
+        )),
+    };
+
+    let format = format_for_path(&path)?;
+    let transcript = export::render(format, &app.room_id, &app.chat_history());
+
+    std::fs::write(&path, transcript)
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+
+    Ok(path)
+}
+
+fn format_for_path(path: &Path) -> Result<ExportFormat> {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some(ext) => ext.parse(),
+        None => Ok(ExportFormat::Markdown),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_follows_the_extension() {
+        let format = |path: &str| format_for_path(Path::new(path));
+        assert_eq!(format("notes.html").unwrap(), ExportFormat::Html);
+        assert_eq!(format("dir.d/log.TXT").unwrap(), ExportFormat::Text);
+        assert_eq!(format("transcript").unwrap(), ExportFormat::Markdown);
+        assert!(format("slides.pdf").is_err());
+    }
+}
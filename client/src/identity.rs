@@ -0,0 +1,192 @@
+use anyhow::{anyhow, Context, Result};
+use ed25519_dalek::{Signer, SigningKey};
+use rand_core::OsRng;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::path::PathBuf;
+use terma_shared::identity;
+
+pub fn terma_dir() -> Result<PathBuf> {
+    let home = dirs::home_dir().context("Could not find home directory")?;
+    Ok(home.join(".terma"))
+}
+
+/// This client's Ed25519 signing key, kept in ~/.terma/identity.key
+#[derive(Clone)]
+pub struct Identity {
+    key: SigningKey,
+}
+
+impl Identity {
+    pub fn load_or_create() -> Result<Self> {
+        let path = terma_dir()?.join("identity.key");
+
+        if path.exists() {
+            let contents = fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read {}", path.display()))?;
+            let seed: [u8; 32] = hex::decode(contents.trim())
+                .ok()
+                .and_then(|bytes| bytes.try_into().ok())
+                .ok_or_else(|| anyhow!("{} is not a valid identity key", path.display()))?;
+            return Ok(Self {
+                key: SigningKey::from_bytes(&seed),
+            });
+        }
+
+        let key = SigningKey::generate(&mut OsRng);
+        fs::create_dir_all(terma_dir()?)?;
+        write_private(&path, &hex::encode(key.to_bytes()))
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+
+        Ok(Self { key })
+    }
+
+    pub fn public_key(&self) -> String {
+        hex::encode(self.key.verifying_key().to_bytes())
+    }
+
+    pub fn fingerprint(&self) -> String {
+        identity::fingerprint(&self.key.verifying_key().to_bytes())
+    }
+
+    /// Answer the server's join challenge
+    pub fn sign_join(&self, nonce: &str, room_id: &str, user_id: &str) -> String {
+        let signature = self
+            .key
+            .sign(&identity::join_payload(nonce, room_id, user_id));
+        hex::encode(signature.to_bytes())
+    }
+
+    /// Headers saying who is making an HTTP request about a room, signed
+    /// with this key
+    pub fn request_headers(&self, room_id: &str, user_id: &str) -> [(&'static str, String); 4] {
+        let timestamp = chrono::Utc::now().timestamp();
+        let signature = self
+            .key
+            .sign(&identity::request_payload(timestamp, room_id, user_id));
+        [
+            (identity::USER_HEADER, user_id.to_string()),
+            (identity::PUBLIC_KEY_HEADER, self.public_key()),
+            (identity::TIMESTAMP_HEADER, timestamp.to_string()),
+            (
+                identity::SIGNATURE_HEADER,
+                hex::encode(signature.to_bytes()),
+            ),
+        ]
+    }
+}
+
+#[cfg(unix)]
+fn write_private(path: &std::path::Path, contents: &str) -> std::io::Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?;
+    file.write_all(contents.as_bytes())
+}
+
+#[cfg(not(unix))]
+fn write_private(path: &std::path::Path, contents: &str) -> std::io::Result<()> {
+    fs::write(path, contents)
+}
+
+/// Trust-on-first-use record of which key each username has used, per
+/// server, kept in ~/.terma/known_users.json
+#[derive(Default)]
+pub struct KnownUsers {
+    host: String,
+    path: Option<PathBuf>,
+    servers: BTreeMap<String, BTreeMap<String, String>>,
+    /// Latest unrecognised key per username, for /trust
+    pending: HashMap<String, String>,
+    warned: HashSet<(String, String)>,
+}
+
+impl KnownUsers {
+    pub fn load(host: &str) -> Result<Self> {
+        let path = terma_dir()?.join("known_users.json");
+        let servers = if path.exists() {
+            let contents = fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read {}", path.display()))?;
+            serde_json::from_str(&contents)
+                .with_context(|| format!("Failed to parse {}", path.display()))?
+        } else {
+            BTreeMap::new()
+        };
+
+        Ok(Self {
+            host: host.to_string(),
+            path: Some(path),
+            servers,
+            pending: HashMap::new(),
+            warned: HashSet::new(),
+        })
+    }
+
+    /// Remember a username's key the first time it is seen. Returns a warning
+    /// (once per session) when the name shows up with a different key, or
+    /// with no verified key at all.
+    pub fn check(&mut self, username: &str, fingerprint: Option<&str>) -> Option<String> {
+        let users = self.servers.entry(self.host.clone()).or_default();
+        let Some(fingerprint) = fingerprint else {
+            let known = users.get(username)?;
+            if !self.warned.insert((username.to_string(), String::new())) {
+                return None;
+            }
+            return Some(format!(
+                "⚠ {} has no verified key, but used {} before. It may be someone else.",
+                username, known
+            ));
+        };
+        match users.get(username) {
+            Some(known) if known == fingerprint => None,
+            Some(known) => {
+                self.pending
+                    .insert(username.to_string(), fingerprint.to_string());
+                let key = (username.to_string(), fingerprint.to_string());
+                if !self.warned.insert(key) {
+                    return None;
+                }
+                Some(format!(
+                    "⚠ {} is using a different key than before ({} → {}). \
+                     It may be someone else. Use /trust {} to accept the new key.",
+                    username, known, fingerprint, username
+                ))
+            }
+            None => {
+                users.insert(username.to_string(), fingerprint.to_string());
+                let _ = self.save();
+                None
+            }
+        }
+    }
+
+    /// Accept the key a username was last seen with
+    pub fn trust(&mut self, username: &str) -> Result<String> {
+        let fingerprint = self
+            .pending
+            .remove(username)
+            .ok_or_else(|| anyhow!("No new key to trust for {}", username))?;
+
+        self.servers
+            .entry(self.host.clone())
+            .or_default()
+            .insert(username.to_string(), fingerprint.clone());
+        self.warned.retain(|(name, _)| name != username);
+        self.save()?;
+
+        Ok(fingerprint)
+    }
+
+    fn save(&self) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let contents = serde_json::to_string_pretty(&self.servers)?;
+        fs::write(path, contents).with_context(|| format!("Failed to write {}", path.display()))
+    }
+}
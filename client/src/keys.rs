@@ -0,0 +1,333 @@
+use anyhow::{anyhow, Result};
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::config::KeyConfig;
+
+/// Things a key chord can be bound to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Action {
+    Send,
+    Newline,
+    ScrollUp,
+    ScrollDown,
+    Copy,
+    Help,
+    Quit,
+}
+
+impl Action {
+    pub const ALL: [Action; 7] = [
+        Action::Send,
+        Action::Newline,
+        Action::ScrollUp,
+        Action::ScrollDown,
+        Action::Copy,
+        Action::Help,
+        Action::Quit,
+    ];
+
+    /// Name used in the `keys` section of the config file
+    pub fn name(&self) -> &'static str {
+        match self {
+            Action::Send => "send",
+            Action::Newline => "newline",
+            Action::ScrollUp => "scroll_up",
+            Action::ScrollDown => "scroll_down",
+            Action::Copy => "copy",
+            Action::Help => "help",
+            Action::Quit => "quit",
+        }
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            Action::Send => "Send message",
+            Action::Newline => "Insert new line",
+            Action::ScrollUp => "Scroll messages up",
+            Action::ScrollDown => "Scroll messages down",
+            Action::Copy => "Copy selected text",
+            Action::Help => "Show this help",
+            Action::Quit => "Quit (copies instead while text is selected)",
+        }
+    }
+
+    fn default_chords(&self) -> &'static [&'static str] {
+        match self {
+            Action::Send => &["enter", "ctrl+m"],
+            // Many terminals send Shift+Enter as Ctrl+J
+            Action::Newline => &["shift+enter", "ctrl+j"],
+            Action::ScrollUp => &["alt+up"],
+            Action::ScrollDown => &["alt+down"],
+            Action::Copy => &["super+c"],
+            Action::Help => &["?", "f1"],
+            Action::Quit => &["ctrl+c"],
+        }
+    }
+}
+
+impl FromStr for Action {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Action::ALL
+            .into_iter()
+            .find(|action| action.name() == s)
+            .ok_or_else(|| {
+                let names: Vec<&str> = Action::ALL.iter().map(|a| a.name()).collect();
+                anyhow!(
+                    "unknown action '{}' (expected one of: {})",
+                    s,
+                    names.join(", ")
+                )
+            })
+    }
+}
+
+/// A key plus modifiers, e.g. `ctrl+j` or `alt+up`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct KeyChord {
+    code: KeyCode,
+    modifiers: KeyModifiers,
+}
+
+impl KeyChord {
+    fn new(code: KeyCode, modifiers: KeyModifiers) -> Self {
+        let mut modifiers = modifiers
+            & (KeyModifiers::CONTROL
+                | KeyModifiers::ALT
+                | KeyModifiers::SHIFT
+                | KeyModifiers::SUPER);
+
+        // Shift is already encoded in the character itself
+        let code = match code {
+            KeyCode::Char(c) => {
+                let c = if modifiers.contains(KeyModifiers::SHIFT) && c.is_ascii_alphabetic() {
+                    c.to_ascii_uppercase()
+                } else {
+                    c
+                };
+                modifiers.remove(KeyModifiers::SHIFT);
+                KeyCode::Char(c)
+            }
+            KeyCode::BackTab => {
+                modifiers.remove(KeyModifiers::SHIFT);
+                KeyCode::BackTab
+            }
+            code => code,
+        };
+
+        Self { code, modifiers }
+    }
+
+    pub fn from_event(key: &KeyEvent) -> Self {
+        Self::new(key.code, key.modifiers)
+    }
+
+    /// A bare printable character, which would otherwise be typed into the input
+    pub fn is_plain_char(&self) -> bool {
+        matches!(self.code, KeyCode::Char(_)) && self.modifiers.is_empty()
+    }
+}
+
+impl FromStr for KeyChord {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        if s.is_empty() {
+            return Err(anyhow!("empty key chord"));
+        }
+
+        // A lone "+" is a key, not a separator
+        let (mods, key) = match s.rsplit_once('+') {
+            Some((mods, "")) => (mods.strip_suffix('+').unwrap_or(mods), "+"),
+            Some((mods, key)) => (mods, key),
+            None => ("", s),
+        };
+
+        let mut modifiers = KeyModifiers::NONE;
+        for part in mods.split('+').filter(|part| !part.is_empty()) {
+            modifiers |= match part.to_ascii_lowercase().as_str() {
+                "ctrl" | "control" => KeyModifiers::CONTROL,
+                "alt" | "meta" | "option" => KeyModifiers::ALT,
+                "shift" => KeyModifiers::SHIFT,
+                "super" | "cmd" | "command" => KeyModifiers::SUPER,
+                other => return Err(anyhow!("unknown modifier '{}' in '{}'", other, s)),
+            };
+        }
+
+        let code = match key.to_ascii_lowercase().as_str() {
+            "enter" | "return" => KeyCode::Enter,
+            "esc" | "escape" => KeyCode::Esc,
+            "tab" => KeyCode::Tab,
+            "backtab" => KeyCode::BackTab,
+            "backspace" => KeyCode::Backspace,
+            "delete" | "del" => KeyCode::Delete,
+            "insert" | "ins" => KeyCode::Insert,
+            "home" => KeyCode::Home,
+            "end" => KeyCode::End,
+            "pageup" | "pgup" => KeyCode::PageUp,
+            "pagedown" | "pgdn" => KeyCode::PageDown,
+            "up" => KeyCode::Up,
+            "down" => KeyCode::Down,
+            "left" => KeyCode::Left,
+            "right" => KeyCode::Right,
+            "space" => KeyCode::Char(' '),
+            lower => {
+                if let Some(n) = lower.strip_prefix('f').and_then(|n| n.parse::<u8>().ok()) {
+                    if (1..=12).contains(&n) {
+                        KeyCode::F(n)
+                    } else {
+                        return Err(anyhow!("unknown key '{}' in '{}'", key, s));
+                    }
+                } else {
+                    let mut chars = key.chars();
+                    match (chars.next(), chars.next()) {
+                        (Some(c), None) => KeyCode::Char(c),
+                        _ => return Err(anyhow!("unknown key '{}' in '{}'", key, s)),
+                    }
+                }
+            }
+        };
+
+        Ok(KeyChord::new(code, modifiers))
+    }
+}
+
+impl fmt::Display for KeyChord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.modifiers.contains(KeyModifiers::CONTROL) {
+            f.write_str("Ctrl+")?;
+        }
+        if self.modifiers.contains(KeyModifiers::ALT) {
+            f.write_str("Alt+")?;
+        }
+        if self.modifiers.contains(KeyModifiers::SUPER) {
+            f.write_str("Cmd+")?;
+        }
+        if self.modifiers.contains(KeyModifiers::SHIFT) {
+            f.write_str("Shift+")?;
+        }
+
+        match self.code {
+            KeyCode::Enter => f.write_str("Enter"),
+            KeyCode::Esc => f.write_str("Esc"),
+            KeyCode::Tab => f.write_str("Tab"),
+            KeyCode::BackTab => f.write_str("Shift+Tab"),
+            KeyCode::Backspace => f.write_str("Backspace"),
+            KeyCode::Delete => f.write_str("Delete"),
+            KeyCode::Insert => f.write_str("Insert"),
+            KeyCode::Home => f.write_str("Home"),
+            KeyCode::End => f.write_str("End"),
+            KeyCode::PageUp => f.write_str("PageUp"),
+            KeyCode::PageDown => f.write_str("PageDown"),
+            KeyCode::Up => f.write_str("↑"),
+            KeyCode::Down => f.write_str("↓"),
+            KeyCode::Left => f.write_str("←"),
+            KeyCode::Right => f.write_str("→"),
+            KeyCode::F(n) => write!(f, "F{}", n),
+            KeyCode::Char(' ') => f.write_str("Space"),
+            KeyCode::Char(c) if self.modifiers.is_empty() => write!(f, "{}", c),
+            KeyCode::Char(c) if c.is_ascii_uppercase() => write!(f, "Shift+{}", c),
+            KeyCode::Char(c) => write!(f, "{}", c.to_ascii_uppercase()),
+            _ => f.write_str("?"),
+        }
+    }
+}
+
+/// The active key bindings, built from defaults plus the user's `keys` config
+pub struct Keymap {
+    bindings: HashMap<KeyChord, Action>,
+    chords: HashMap<Action, Vec<KeyChord>>,
+    pub vim_mode: bool,
+}
+
+impl Keymap {
+    pub fn from_config(config: &KeyConfig) -> Result<Self> {
+        let mut chords: HashMap<Action, Vec<KeyChord>> = HashMap::new();
+        for action in Action::ALL {
+            let defaults = action
+                .default_chords()
+                .iter()
+                .map(|chord| chord.parse())
+                .collect::<Result<Vec<KeyChord>>>()?;
+            chords.insert(action, defaults);
+        }
+
+        // User bindings replace the defaults for that action
+        for (name, values) in &config.bindings {
+            let action: Action = name.parse()?;
+            let parsed = values
+                .iter()
+                .map(|value| {
+                    value
+                        .parse()
+                        .map_err(|e| anyhow!("invalid binding for '{}': {}", name, e))
+                })
+                .collect::<Result<Vec<KeyChord>>>()?;
+            chords.insert(action, parsed);
+        }
+
+        let mut bindings = HashMap::new();
+        for action in Action::ALL {
+            for chord in &chords[&action] {
+                if let Some(existing) = bindings.insert(*chord, action) {
+                    if existing != action {
+                        return Err(anyhow!(
+                            "'{}' is bound to both '{}' and '{}'",
+                            chord,
+                            existing.name(),
+                            action.name()
+                        ));
+                    }
+                }
+            }
+        }
+
+        if chords[&Action::Quit].is_empty() {
+            return Err(anyhow!("'quit' must have at least one key binding"));
+        }
+
+        Ok(Self {
+            bindings,
+            chords,
+            vim_mode: config.vim_mode,
+        })
+    }
+
+    pub fn action_for(&self, key: &KeyEvent) -> Option<(Action, KeyChord)> {
+        let chord = KeyChord::from_event(key);
+        self.bindings.get(&chord).map(|action| (*action, chord))
+    }
+
+    /// Human-readable chords for an action, e.g. "Alt+↑"
+    pub fn describe(&self, action: Action) -> String {
+        let chords = &self.chords[&action];
+        if chords.is_empty() {
+            return "unbound".to_string();
+        }
+        chords
+            .iter()
+            .map(|chord| chord.to_string())
+            .collect::<Vec<_>>()
+            .join(" / ")
+    }
+
+    /// Shortest hint for titles: just the first chord
+    pub fn hint(&self, action: Action) -> String {
+        self.chords[&action]
+            .first()
+            .map(|chord| chord.to_string())
+            .unwrap_or_else(|| "unbound".to_string())
+    }
+
+    pub fn help_entries(&self) -> Vec<(String, &'static str)> {
+        Action::ALL
+            .iter()
+            .map(|action| (self.describe(*action), action.description()))
+            .collect()
+    }
+}
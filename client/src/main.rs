@@ -1,13 +1,27 @@
 mod app;
+mod cli;
 mod clipboard;
+mod commands;
 mod config;
 mod connection;
+mod e2ee;
+mod error;
 mod events;
+mod export;
+mod identity;
+mod keys;
 mod notifications;
+mod saved_rooms;
+mod theme;
 mod ui;
+mod vim;
 
 use anyhow::{Context, Result};
 use app::App;
+use clap::{CommandFactory, Parser};
+use cli::{Cli, Command, ConfigAction, GlobalArgs, NewArgs};
+use config::Config;
+use connection::{Endpoint, JoinRequest, RoomAccess};
 use crossterm::{
     event::{
         self, DisableBracketedPaste, DisableMouseCapture, EnableBracketedPaste, EnableMouseCapture,
@@ -16,53 +30,147 @@ use crossterm::{
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
+use e2ee::RoomKey;
+use error::{ConnectionLost, ServerError};
+use identity::{Identity, KnownUsers};
 use ratatui::{backend::CrosstermBackend, Terminal};
-use std::io;
+use saved_rooms::SavedRooms;
+use serde::Deserialize;
+use std::io::{self, Read};
 use std::time::Duration;
-use terma_shared::{ClientMessage, ServerMessage};
-use uuid::Uuid;
+use terma_shared::{capability, ClientMessage, ErrorCode, ModerationAction, Room, ServerMessage};
+use tokio::sync::mpsc::UnboundedReceiver;
+
+// Get default host from compile-time environment variable or use localhost:3000
+const DEFAULT_HOST: &str = match option_env!("TERMA_DEFAULT_HOST") {
+    Some(host) => host,
+    None => "localhost:3000",
+};
+
+/// Room password for non-interactive use, e.g. `terma send` from scripts
+const PASSWORD_ENV: &str = "TERMA_ROOM_PASSWORD";
+
+/// How long `terma send` waits for each server reply
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
 
 #[tokio::main]
-async fn main() -> Result<()> {
-    let args: Vec<String> = std::env::args().collect();
+async fn main() {
+    if let Err(e) = run().await {
+        eprintln!("Error: {:?}", e);
+        std::process::exit(error::exit_code(&e));
+    }
+}
 
-    // Get default host from compile-time environment variable or use localhost:3000
-    const DEFAULT_HOST: &str = match option_env!("TERMA_DEFAULT_HOST") {
-        Some(host) => host,
-        None => "localhost:3000",
-    };
+async fn run() -> Result<()> {
+    let cli = Cli::parse();
+    let global = &cli.global;
+    let mut config = config::load().context("Failed to load config")?;
 
-    let (host, room_id) = match args.len() {
-        2 => {
-            // Shorthand: terma <room_id>
-            (DEFAULT_HOST.to_string(), args[1].clone())
+    match cli.command {
+        Some(Command::Join { room_id }) => join_room(global, &mut config, room_id).await,
+        Some(Command::New(args)) => new_room(global, &mut config, args).await,
+        Some(Command::Send { room_id, message }) => {
+            send_message(global, &mut config, &room_id, message).await
         }
-        3 => {
-            // Full: terma <host> <room_id>
-            (args[1].clone(), args[2].clone())
+        Some(Command::Export { room_id, format }) => {
+            let endpoint = resolve_endpoint(global, &config)?;
+            let (access, room_key) = open_room(global, &endpoint, &room_id).await?;
+            let user_id = config.user_id()?;
+            let identity = Identity::load_or_create().context("Failed to load identity key")?;
+            let room_key = room_key.as_ref();
+            export::export_room(
+                &endpoint, &room_id, format, &access, room_key, &user_id, &identity,
+            )
+            .await
         }
-        _ => {
-            eprintln!("Usage: {} <room_id>", args[0]);
-            eprintln!("   or: {} <host> <room_id>", args[0]);
-            eprintln!();
-            eprintln!("Examples:");
-            eprintln!("  {} abc123", args[0]);
-            eprintln!("  {} localhost:3000 abc123", args[0]);
-            std::process::exit(1);
+        Some(Command::Config { action }) => run_config(global, &mut config, action),
+        None => match cli.shorthand.as_slice() {
+            [room_id] => join_room(global, &mut config, room_id.clone()).await,
+            [host, room_id] => {
+                if global.host.is_some() {
+                    anyhow::bail!("Give the host either as an argument or with --host, not both");
+                }
+                let global = GlobalArgs {
+                    host: Some(host.clone()),
+                    ..global.clone()
+                };
+                join_room(&global, &mut config, room_id.clone()).await
+            }
+            _ => {
+                Cli::command().print_help()?;
+                std::process::exit(2);
+            }
+        },
+    }
+}
+
+/// Host and TLS from --host/--insecure, then the profile, then the config
+fn resolve_endpoint(global: &GlobalArgs, config: &Config) -> Result<Endpoint> {
+    let profile = match &global.profile {
+        Some(name) => Some(config.profile(name)?),
+        None => None,
+    };
+
+    let host = global
+        .host
+        .clone()
+        .or_else(|| profile.and_then(|profile| profile.host.clone()))
+        .or_else(|| config.host.clone())
+        .unwrap_or_else(|| DEFAULT_HOST.to_string());
+    let insecure = global.insecure || profile.is_some_and(|profile| profile.insecure);
+
+    Ok(Endpoint::new(host, insecure))
+}
+
+/// Username from --username, then the profile, then the config
+fn configured_username(global: &GlobalArgs, config: &Config) -> Result<Option<String>> {
+    if let Some(username) = &global.username {
+        if username.trim().is_empty() {
+            anyhow::bail!("Username cannot be empty");
         }
+        return Ok(Some(username.trim().to_string()));
+    }
+
+    let profile_username = match &global.profile {
+        Some(name) => config.profile(name)?.username.clone(),
+        None => None,
+    };
+    Ok(profile_username.or_else(|| config.username.clone()))
+}
+
+async fn join_room(global: &GlobalArgs, config: &mut Config, room_id: String) -> Result<()> {
+    let endpoint = resolve_endpoint(global, config)?;
+    let username = match configured_username(global, config)? {
+        Some(username) => username,
+        // First run: ask once and remember it
+        None => config::prompt_username(config, global.profile.as_deref())?,
     };
 
-    // Get or prompt for username
-    let username = config::get_or_prompt_username().context("Failed to get username")?;
+    let keymap = keys::Keymap::from_config(&config.keys)
+        .context("Invalid \"keys\" section in ~/.terma/config.json")?;
+    let color_support = match config.color_mode.as_deref() {
+        Some(mode) => theme::ColorSupport::parse(mode).context("Invalid \"color_mode\"")?,
+        None => theme::ColorSupport::detect(),
+    };
+    let theme = theme::Theme::load(config.theme.as_deref())
+        .context("Failed to load theme")?
+        .adapt(color_support);
 
-    // Generate a random user ID
-    let user_id = Uuid::new_v4().to_string()[..8].to_string();
+    let (access, room_key) = open_room(global, &endpoint, &room_id).await?;
+    let user_id = config.user_id()?;
+    let identity = Identity::load_or_create().context("Failed to load identity key")?;
 
     // Connect to server
-    let (conn, mut rx) =
-        connection::Connection::connect(&host, &room_id, user_id.clone(), username.clone())
-            .await
-            .context("Failed to establish connection")?;
+    let join = JoinRequest {
+        endpoint: endpoint.clone(),
+        room_id: room_id.clone(),
+        user_id: user_id.clone(),
+        username: username.clone(),
+        access,
+    };
+    let (mut conn, mut rx) = connection::Connection::connect(&join, &identity, config, None)
+        .await
+        .context("Failed to establish connection")?;
 
     // Setup terminal
     enable_raw_mode().context("Failed to enable raw mode. Make sure you're running in a terminal (not via pipe or redirect).")?;
@@ -78,10 +186,22 @@ async fn main() -> Result<()> {
     terminal.show_cursor()?;
 
     // Create app
-    let mut app = App::new(room_id, user_id, username);
+    let mut app = App::new(room_id, user_id, username, keymap, theme);
+    app.known_users = KnownUsers::load(&endpoint.host).context("Failed to load known users")?;
+    // Our own name is pinned to our key, so anyone borrowing it is flagged
+    let fingerprint = identity.fingerprint();
+    app.check_identity(&app.username.clone(), Some(&fingerprint));
+    app.fingerprint = Some(fingerprint);
+    app.room_key = room_key;
 
     // Run app
-    let result = run_app(&mut terminal, &mut app, &conn, &mut rx).await;
+    let session = Session {
+        join: &join,
+        identity: &identity,
+        config,
+    };
+    let result = run_app(&mut terminal, &mut app, session, &mut conn, &mut rx).await;
+    conn.close().await;
 
     // Restore terminal
     disable_raw_mode()?;
@@ -93,28 +213,414 @@ async fn main() -> Result<()> {
     )?;
     terminal.show_cursor()?;
 
+    // Being removed explains the connection closing, so it comes first
+    if let Some(removed) = app.removed_reason {
+        eprintln!("{}", removed.message);
+        std::process::exit(removed.exit_code());
+    }
     if let Err(e) = result {
         eprintln!("Error: {}", e);
-        std::process::exit(1);
+        std::process::exit(error::exit_code(&e));
+    }
+
+    Ok(())
+}
+
+/// Look a room up and gather what's needed to get in and read it
+async fn open_room(
+    global: &GlobalArgs,
+    endpoint: &Endpoint,
+    room_id: &str,
+) -> Result<(RoomAccess, Option<RoomKey>)> {
+    let room = connection::fetch_room(endpoint, room_id)
+        .await?
+        .ok_or_else(|| {
+            ServerError::new(
+                ErrorCode::RoomNotFound,
+                format!("Room {} not found", room_id),
+            )
+        })?;
+    let mut saved_rooms = SavedRooms::load().context("Failed to load saved rooms")?;
+    let mut access = room_access(global, &room)?;
+    // Only needed the first time; the server remembers who owns the room
+    access.owner_token = global
+        .owner_token
+        .clone()
+        .or_else(|| saved_rooms.get(&endpoint.host, room_id).owner_token);
+    let room_key = room_key(global, endpoint, &room, &mut saved_rooms)?;
+    Ok((access, room_key))
+}
+
+/// How to get into a room: the invite token, $TERMA_ROOM_PASSWORD or a prompt
+fn room_access(global: &GlobalArgs, room: &Room) -> Result<RoomAccess> {
+    if let Some(token) = &global.token {
+        return Ok(RoomAccess {
+            join_token: Some(token.clone()),
+            ..RoomAccess::default()
+        });
+    }
+    if !room.password_protected {
+        return Ok(RoomAccess::default());
+    }
+    let room_id = &room.id;
+
+    let password = match std::env::var(PASSWORD_ENV) {
+        Ok(password) if !password.is_empty() => password,
+        // Reads from the terminal, so this works even when stdin is piped
+        _ => rpassword::prompt_password(format!("Password for room {}: ", room_id)).with_context(
+            || {
+                format!(
+                    "Room {} is password protected. Set {} or pass --token.",
+                    room_id, PASSWORD_ENV
+                )
+            },
+        )?,
+    };
+
+    Ok(RoomAccess {
+        password: Some(password),
+        ..RoomAccess::default()
+    })
+}
+
+/// The key for an encrypted room: --key (remembered for next time), or one
+/// remembered earlier for this server
+fn room_key(
+    global: &GlobalArgs,
+    endpoint: &Endpoint,
+    room: &Room,
+    saved_rooms: &mut SavedRooms,
+) -> Result<Option<RoomKey>> {
+    if !room.encrypted {
+        return Ok(None);
+    }
+
+    if let Some(key) = &global.key {
+        let key: RoomKey = key.parse()?;
+        saved_rooms.update(&endpoint.host, &room.id, |saved| {
+            saved.key = Some(key.to_string())
+        })?;
+        return Ok(Some(key));
     }
 
+    let key = saved_rooms
+        .get(&endpoint.host, &room.id)
+        .key
+        .with_context(|| {
+            format!(
+                "Room {} is end-to-end encrypted. Pass the --key from your invite.",
+                room.id
+            )
+        })?;
+    key.parse()
+        .with_context(|| format!("Invalid saved key for room {}", room.id))
+        .map(Some)
+}
+
+#[derive(Deserialize)]
+struct CreateRoomResponse {
+    room_id: String,
+    install_command: String,
+    join_token: Option<String>,
+    owner_token: Option<String>,
+}
+
+/// `terma new [--join] [--password] [--encrypted] [--name] [--topic] [--motd]`:
+/// the room ID goes to stdout so scripts can capture it
+async fn new_room(global: &GlobalArgs, config: &mut Config, args: NewArgs) -> Result<()> {
+    let endpoint = resolve_endpoint(global, config)?;
+    let url = format!("{}/api/rooms", endpoint.http_base_url());
+    let encrypted = args.encrypted;
+
+    let mut body = serde_json::Map::new();
+    for (field, value) in [
+        ("name", args.name),
+        ("topic", args.topic),
+        ("motd", args.motd),
+    ] {
+        if let Some(value) = value {
+            body.insert(field.to_string(), value.into());
+        }
+    }
+    if let Some(seconds) = args.expires_in {
+        body.insert("ttl_seconds".to_string(), seconds.into());
+    }
+    if let Some(retention) = args.keep {
+        body.insert("retention".to_string(), serde_json::to_value(retention)?);
+    }
+    if args.password {
+        let password = rpassword::prompt_password("Room password: ")?;
+        if password.is_empty() {
+            anyhow::bail!("Password cannot be empty");
+        }
+        if rpassword::prompt_password("Repeat password: ")? != password {
+            anyhow::bail!("Passwords do not match");
+        }
+        body.insert("password".to_string(), password.into());
+    }
+    if encrypted {
+        body.insert("encrypted".to_string(), true.into());
+    }
+
+    let response = reqwest::Client::new()
+        .post(&url)
+        .json(&body)
+        .send()
+        .await
+        .context("Failed to connect to server")?;
+    let status = response.status();
+    if !status.is_success() {
+        let body = response.text().await.unwrap_or_default();
+        anyhow::bail!("Could not create room ({}): {}", status, body.trim());
+    }
+    let room: CreateRoomResponse = response
+        .json()
+        .await
+        .context("Unexpected response from server")?;
+
+    // The key is made here and only ever travels in the invite
+    let room_key = encrypted.then(RoomKey::generate);
+    let mut install_command = room.install_command;
+    if let Some(key) = &room_key {
+        install_command = format!("{} sh --key {}", install_command, key);
+    }
+    SavedRooms::load()?.update(&endpoint.host, &room.room_id, |saved| {
+        saved.key = room_key.as_ref().map(RoomKey::to_string);
+        saved.owner_token = room.owner_token.clone();
+    })?;
+
+    if args.join {
+        let global = GlobalArgs {
+            token: room.join_token.or_else(|| global.token.clone()),
+            ..global.clone()
+        };
+        return join_room(&global, config, room.room_id).await;
+    }
+
+    eprintln!("Created room {}. Invite others with:", room.room_id);
+    eprintln!("  {}", install_command);
+    if let Some(key) = &room_key {
+        eprintln!(
+            "Or join with: terma --key {} {}. Anyone with the key can read the room.",
+            key, room.room_id
+        );
+    }
+    if let Some(token) = &room.owner_token {
+        eprintln!(
+            "You own this room. To moderate it from another machine: terma --owner-token {} {}",
+            token, room.room_id
+        );
+    }
+    if let Some(token) = &room.join_token {
+        eprintln!(
+            "Or share the password, or join with: terma --token {} {}",
+            token, room.room_id
+        );
+    }
+    println!("{}", room.room_id);
+    Ok(())
+}
+
+/// `terma send <room_id> [message...]`: join, post one message and leave
+async fn send_message(
+    global: &GlobalArgs,
+    config: &mut Config,
+    room_id: &str,
+    message: Vec<String>,
+) -> Result<()> {
+    let endpoint = resolve_endpoint(global, config)?;
+    // Never prompt here: stdin may be carrying the message
+    let username = configured_username(global, config)?.context(
+        "No username configured. Pass --username or run `terma config set username <name>`",
+    )?;
+
+    let content = if message.is_empty() {
+        let mut content = String::new();
+        io::stdin()
+            .read_to_string(&mut content)
+            .context("Failed to read message from stdin")?;
+        content
+    } else {
+        message.join(" ")
+    };
+    let content = content.trim_end().to_string();
+    if content.trim().is_empty() {
+        anyhow::bail!("Nothing to send");
+    }
+
+    let (access, room_key) = open_room(global, &endpoint, room_id).await?;
+    let user_id = config.user_id()?;
+    let content = match &room_key {
+        Some(key) => key.encrypt(room_id, &user_id, &content)?,
+        None => content,
+    };
+    let identity = Identity::load_or_create().context("Failed to load identity key")?;
+    let join = JoinRequest {
+        endpoint,
+        room_id: room_id.to_string(),
+        user_id: user_id.clone(),
+        username,
+        access,
+    };
+    let (conn, mut rx) = connection::Connection::connect(&join, &identity, config, None)
+        .await
+        .context("Failed to establish connection")?;
+
+    wait_for(&mut rx, |msg| matches!(msg, ServerMessage::Welcome { .. })).await?;
+    conn.send(ClientMessage::SendMessage { content })?;
+
+    // The server echoes our message back once it has been broadcast
+    let sent = wait_for(
+        &mut rx,
+        |msg| matches!(msg, ServerMessage::Message { message } if message.user_id == user_id),
+    )
+    .await;
+    conn.close().await;
+    sent
+}
+
+/// Wait for a matching server message, failing on errors, disconnects and timeouts
+async fn wait_for(
+    rx: &mut tokio::sync::mpsc::UnboundedReceiver<ServerMessage>,
+    matches: impl Fn(&ServerMessage) -> bool,
+) -> Result<()> {
+    let wait = async {
+        loop {
+            match rx.recv().await {
+                Some(ServerMessage::Error { code, message, .. }) => {
+                    return Err(ServerError::new(code, message).into())
+                }
+                Some(msg) if matches(&msg) => return Ok(()),
+                Some(_) => {}
+                None => return Err(ConnectionLost("Connection closed by server".into()).into()),
+            }
+        }
+    };
+
+    tokio::time::timeout(SEND_TIMEOUT, wait)
+        .await
+        .context("Timed out waiting for the server")?
+}
+
+/// `terma [--profile <name>] config get [key]` / `config set <key> <value>`
+fn run_config(global: &GlobalArgs, config: &mut Config, action: ConfigAction) -> Result<()> {
+    let profile = global.profile.as_deref();
+    match action {
+        ConfigAction::Get { key: None } => {
+            let json = match profile {
+                Some(name) => serde_json::to_string_pretty(config.profile(name)?)?,
+                None => serde_json::to_string_pretty(config)?,
+            };
+            println!("{}", json);
+        }
+        ConfigAction::Get { key: Some(key) } => {
+            if let Some(value) = config.get(profile, &key)? {
+                println!("{}", value);
+            }
+        }
+        ConfigAction::Set { key, value } => {
+            config.set(profile, &key, &value)?;
+            config.save()?;
+        }
+    }
     Ok(())
 }
 
+/// What the chat loop needs to dial the room again
+#[derive(Clone, Copy)]
+struct Session<'a> {
+    join: &'a JoinRequest,
+    identity: &'a Identity,
+    config: &'a Config,
+}
+
+/// A live connection and the messages arriving on it
+type Connected = (connection::Connection, UnboundedReceiver<ServerMessage>);
+
+/// What a background redial tells the event loop
+enum Redial {
+    /// Dialling for the `n`th time
+    Attempt(u32),
+    Done(Result<Connected>),
+}
+
+/// A redial running while the connection is down
+struct Redialling {
+    task: tokio::task::JoinHandle<()>,
+    updates: UnboundedReceiver<Redial>,
+}
+
+impl Redialling {
+    /// Keep dialling `session_id` in the background so the UI stays live.
+    /// Giving up reports `lost`, unless the server turned us away.
+    fn start(
+        session: &Session<'_>,
+        session_id: String,
+        window: Duration,
+        lost: anyhow::Error,
+    ) -> Self {
+        let (tx, updates) = tokio::sync::mpsc::unbounded_channel();
+        let join = session.join.clone();
+        let identity = session.identity.clone();
+        let config = session.config.clone();
+        let task = tokio::spawn(async move {
+            let progress = tx.clone();
+            let on_attempt = move |attempt| {
+                let _ = progress.send(Redial::Attempt(attempt));
+            };
+            let redialled = connection::Connection::reconnect(
+                &join,
+                &identity,
+                &config,
+                &session_id,
+                window,
+                on_attempt,
+            )
+            .await
+            .map_err(|e| if e.is::<ServerError>() { e } else { lost });
+            let _ = tx.send(Redial::Done(redialled));
+        });
+        Self { task, updates }
+    }
+
+    /// Note any new attempts, and the connection once it's back
+    fn poll(&mut self, app: &mut App) -> Option<Result<Connected>> {
+        loop {
+            match self.updates.try_recv() {
+                Ok(Redial::Attempt(attempt)) => app.redial_attempt = Some(attempt),
+                Ok(Redial::Done(redialled)) => return Some(redialled),
+                Err(tokio::sync::mpsc::error::TryRecvError::Empty) => return None,
+                Err(tokio::sync::mpsc::error::TryRecvError::Disconnected) => {
+                    let stopped = ConnectionLost("Lost connection: reconnecting stopped".into());
+                    return Some(Err(stopped.into()));
+                }
+            }
+        }
+    }
+}
+
 async fn run_app(
     terminal: &mut Terminal<CrosstermBackend<io::Stdout>>,
     app: &mut App,
-    conn: &connection::Connection,
-    rx: &mut tokio::sync::mpsc::UnboundedReceiver<ServerMessage>,
+    session: Session<'_>,
+    conn: &mut connection::Connection,
+    rx: &mut UnboundedReceiver<ServerMessage>,
 ) -> Result<()> {
+    let mut redialling: Option<Redialling> = None;
     loop {
         let mut did_work = false;
 
         // Draw UI
         terminal.draw(|f| ui::render(f, app))?;
 
+        // A dropped connection's last round trip means nothing now
+        app.rtt = redialling.is_none().then(|| conn.rtt()).flatten();
+
         // Check for quit
         if app.should_quit {
+            if let Some(redialling) = redialling {
+                redialling.task.abort();
+            }
             break;
         }
 
@@ -124,7 +630,12 @@ async fn run_app(
             match event::read()? {
                 Event::Key(key) => {
                     if let Some(message) = events::handle_key_event(app, key) {
-                        conn.send(ClientMessage::SendMessage { content: message })?;
+                        if redialling.is_some() {
+                            // Nothing to send it on yet
+                            app.input.insert_str(message);
+                        } else {
+                            submit_input(app, conn, message)?;
+                        }
                     }
                 }
                 Event::Mouse(mouse) => {
@@ -138,18 +649,42 @@ async fn run_app(
             }
         }
 
-        // Check for incoming WebSocket messages (non-blocking)
-        loop {
-            match rx.try_recv() {
-                Ok(msg) => {
-                    handle_server_message(app, msg);
-                    did_work = true;
-                }
-                Err(tokio::sync::mpsc::error::TryRecvError::Empty) => {
-                    break;
-                }
-                Err(tokio::sync::mpsc::error::TryRecvError::Disconnected) => {
-                    return Ok(());
+        if let Some(current) = &mut redialling {
+            if let Some(redialled) = current.poll(app) {
+                let (new_conn, new_rx) = redialled?;
+                redialling = None;
+                app.redial_attempt = None;
+                std::mem::replace(conn, new_conn).close().await;
+                *rx = new_rx;
+                did_work = true;
+            }
+        } else {
+            // Check for incoming WebSocket messages (non-blocking)
+            loop {
+                match rx.try_recv() {
+                    Ok(msg) => {
+                        handle_server_message(app, msg);
+                        did_work = true;
+                    }
+                    Err(tokio::sync::mpsc::error::TryRecvError::Empty) => {
+                        break;
+                    }
+                    Err(tokio::sync::mpsc::error::TryRecvError::Disconnected) => {
+                        let lost = conn.disconnect_error();
+                        let Some(session_id) = app.session_id.clone() else {
+                            return Err(lost);
+                        };
+                        // Only a dropped line is worth redialling, not being
+                        // turned away
+                        if !lost.is::<ConnectionLost>() || app.removed_reason.is_some() {
+                            return Err(lost);
+                        }
+                        app.connected = false;
+                        app.add_system_message(format!("{} Reconnecting...", lost));
+                        let window = app.resume_window();
+                        redialling = Some(Redialling::start(&session, session_id, window, lost));
+                        break;
+                    }
                 }
             }
         }
@@ -163,6 +698,131 @@ async fn run_app(
     Ok(())
 }
 
+fn submit_input(app: &mut App, conn: &connection::Connection, text: String) -> Result<()> {
+    match commands::parse(text.clone()) {
+        commands::Input::Message(content) => {
+            if app.send_wait().is_some() {
+                // Hold on to the text until the countdown runs out
+                app.input.insert_str(text);
+                return Ok(());
+            }
+            app.last_sent = Some(text);
+            let content = match &app.room_key {
+                Some(key) => key.encrypt(&app.room_id, &app.user_id, &content)?,
+                None => content,
+            };
+            // Save a round trip for messages the server would refuse anyway
+            if let Some(limits) = &app.limits {
+                if content.len() > limits.max_message_length {
+                    app.add_system_message(format!(
+                        "Message too long. Maximum length is {} characters{}.",
+                        limits.max_message_length,
+                        if app.room_key.is_some() {
+                            " once encrypted"
+                        } else {
+                            ""
+                        }
+                    ));
+                    app.input
+                        .insert_str(app.last_sent.take().unwrap_or_default());
+                    return Ok(());
+                }
+            }
+            conn.send(ClientMessage::SendMessage { content })?;
+        }
+        commands::Input::Command(commands::Command::Search { query }) => {
+            // Without the server's help, search what we've loaded
+            if app.room_key.is_some() || !app.server_supports(capability::SEARCH) {
+                let results = app.search_local(&query);
+                app.show_search_results(query, results);
+            } else {
+                app.begin_search(query.clone());
+                conn.send(ClientMessage::Search { query, limit: None })?;
+            }
+        }
+        commands::Input::Command(commands::Command::Export { path }) => {
+            match export::export_local(app, path.as_deref()) {
+                Ok(path) => app.add_system_message(format!(
+                    "Exported {} message(s) to {}",
+                    app.chat_history().len(),
+                    path.display()
+                )),
+                Err(err) => app.add_system_message(format!("Export failed: {}", err)),
+            }
+        }
+        commands::Input::Command(commands::Command::Trust { username }) => {
+            match app.known_users.trust(&username) {
+                Ok(fingerprint) => app
+                    .add_system_message(format!("Trusted {} with key {}.", username, fingerprint)),
+                Err(err) => app.add_system_message(err.to_string()),
+            }
+        }
+        commands::Input::Command(commands::Command::Kick {
+            username,
+            target_id,
+            reason,
+        }) => {
+            let target_id = target_id.or_else(|| app.user_id_named(&username));
+            conn.send(ClientMessage::Kick {
+                username,
+                target_id,
+                reason,
+            })?;
+        }
+        commands::Input::Command(commands::Command::Ban {
+            username,
+            target_id,
+            reason,
+        }) => {
+            let target_id = target_id.or_else(|| app.user_id_named(&username));
+            conn.send(ClientMessage::Ban {
+                username,
+                target_id,
+                reason,
+            })?;
+        }
+        commands::Input::Command(commands::Command::Mute {
+            username,
+            target_id,
+            minutes,
+            reason,
+        }) => {
+            let target_id = target_id.or_else(|| app.user_id_named(&username));
+            conn.send(ClientMessage::Mute {
+                username,
+                target_id,
+                minutes,
+                reason,
+            })?;
+        }
+        commands::Input::Command(commands::Command::Unban {
+            username,
+            target_id,
+        }) => {
+            let target_id = target_id.or_else(|| app.user_id_named(&username));
+            conn.send(ClientMessage::Unban {
+                username,
+                target_id,
+            })?;
+        }
+        commands::Input::Command(commands::Command::SlowMode { seconds }) => {
+            conn.send(ClientMessage::SetSlowMode { seconds })?;
+        }
+        commands::Input::Command(commands::Command::Topic { topic: Some(topic) }) => {
+            conn.send(ClientMessage::SetTopic { topic })?;
+        }
+        commands::Input::Command(commands::Command::Topic { topic: None }) => {
+            let notice = match &app.topic {
+                Some(topic) => format!("Topic: {}", topic),
+                None => "This room has no topic.".to_string(),
+            };
+            app.add_system_message(notice);
+        }
+        commands::Input::Invalid(error) => app.add_system_message(error),
+    }
+    Ok(())
+}
+
 fn handle_mouse_event(app: &mut App, mouse: crossterm::event::MouseEvent) {
     match mouse.kind {
         MouseEventKind::ScrollUp => {
@@ -185,11 +845,9 @@ fn handle_mouse_event(app: &mut App, mouse: crossterm::event::MouseEvent) {
                 app.update_selection(pos);
             }
         }
-        MouseEventKind::Up(MouseButton::Right) => {
-            if app.has_selection() {
-                if let Err(err) = app.copy_selection() {
-                    app.add_system_message(format!("Copy failed: {}", err));
-                }
+        MouseEventKind::Up(MouseButton::Right) if app.has_selection() => {
+            if let Err(err) = app.copy_selection() {
+                app.add_system_message(format!("Copy failed: {}", err));
             }
         }
         _ => {}
@@ -204,23 +862,93 @@ fn handle_paste_event(app: &mut App, text: String) {
 
 fn handle_server_message(app: &mut App, msg: ServerMessage) {
     match msg {
-        ServerMessage::Welcome { online_count, .. } => {
+        ServerMessage::Welcome {
+            protocol_version,
+            capabilities,
+            limits,
+            online_count,
+            owner,
+            slow_mode_seconds,
+            name,
+            topic,
+            motd,
+            expires_at,
+            session_id,
+            ..
+        } => {
             app.connected = true;
+            // Unversioned servers send an empty set, having never heard of them
+            app.capabilities = (protocol_version > 1).then_some(capabilities);
+            app.limits = limits;
             app.online_count = online_count;
+            app.owner = owner;
+            app.slow_mode = slow_mode_seconds;
+            app.room_name = name;
+            app.topic = topic;
+            app.expires_at = expires_at;
+            // Servers from before sessions send none
+            let reconnected = app.session_id.is_some();
+            app.session_id = (!session_id.is_empty()).then_some(session_id);
+            if reconnected {
+                app.add_system_message(format!("Reconnected. {} user(s) online.", online_count));
+                return;
+            }
             app.add_system_message(format!(
                 "Connected to room {}. {} user(s) online.",
-                app.room_id, online_count
+                app.room_display_name(),
+                online_count
             ));
+            if let Some(topic) = &app.topic {
+                app.add_system_message(format!("Topic: {}", topic));
+            }
+            if let Some(motd) = motd {
+                app.add_system_message(format!("Message of the day: {}", motd));
+            }
+            if let Some(expires_at) = expires_at {
+                app.add_system_message(format!(
+                    "This room and its messages will be deleted at {}.",
+                    app::local_time(expires_at, "%Y-%m-%d %H:%M")
+                ));
+            }
+            if owner {
+                app.add_system_message(
+                    "You own this room. Moderate it with /kick, /ban, /mute and /unban, \
+                     and set the topic with /topic."
+                        .to_string(),
+                );
+            }
+            if let Some(fingerprint) = &app.fingerprint {
+                app.add_system_message(format!("Your key fingerprint is {}.", fingerprint));
+            }
+            if slow_mode_seconds > 0 {
+                app.add_system_message(format!(
+                    "Slow mode is on: one message every {}s.",
+                    slow_mode_seconds
+                ));
+            }
         }
         ServerMessage::History { messages } => {
+            // After a reconnect, only what was missed while away is new
             for msg in messages {
-                app.add_chat_message(msg);
+                if !app.has_message(msg.id) {
+                    app.add_chat_message(app.decrypt(msg));
+                }
             }
         }
         ServerMessage::Message { message } => {
+            // Anything posted while we joined arrives in the history too
+            if app.has_message(message.id) {
+                return;
+            }
+            let message = app.decrypt(message);
             // Send notification for messages from other users
             if message.user_id != app.user_id {
                 notifications::send_notification(&message.username, &message.content);
+            } else {
+                app.last_sent = None;
+                if app.slow_mode > 0 && !app.owner {
+                    app.hold_sends(Duration::from_secs(app.slow_mode.into()));
+                }
             }
             app.add_chat_message(message);
         }
@@ -229,11 +957,17 @@ fn handle_server_message(app: &mut App, msg: ServerMessage) {
             username,
             timestamp,
             online_count,
+            fingerprint,
         } => {
             app.online_count = online_count;
             if user_id != app.user_id {
+                app.check_identity(&username, fingerprint.as_deref());
+                let name = match fingerprint {
+                    Some(_) => format!("{} {}", username, app::VERIFIED_BADGE),
+                    None => username,
+                };
                 app.add_system_message_with_time(
-                    format!("{} joined. {} user(s) online.", username, online_count),
+                    format!("{} joined. {} user(s) online.", name, online_count),
                     timestamp,
                 );
             }
@@ -250,9 +984,86 @@ fn handle_server_message(app: &mut App, msg: ServerMessage) {
                 timestamp,
             );
         }
-        ServerMessage::Error { message } => {
+        ServerMessage::Moderation { event } => {
+            if event.target_id == app.user_id
+                && matches!(event.action, ModerationAction::Kick | ModerationAction::Ban)
+            {
+                let code = match event.action {
+                    ModerationAction::Ban => ErrorCode::Banned,
+                    _ => ErrorCode::Kicked,
+                };
+                let message = format!("Removed from the room: {}", event.describe());
+                app.removed_reason = Some(ServerError::new(code, message));
+            }
+            app.add_system_message_with_time(event.describe(), event.timestamp);
+        }
+        ServerMessage::ModerationLog { events } => {
+            for event in events {
+                app.insert_system_message(event.describe(), event.timestamp);
+            }
+        }
+        ServerMessage::SlowMode {
+            seconds,
+            moderator,
+            timestamp,
+        } => {
+            app.slow_mode = seconds;
+            let notice = if seconds > 0 {
+                format!(
+                    "{} turned on slow mode: one message every {}s.",
+                    moderator, seconds
+                )
+            } else {
+                app.send_wait_until = None;
+                format!("{} turned off slow mode.", moderator)
+            };
+            app.add_system_message_with_time(notice, timestamp);
+        }
+        ServerMessage::TopicChanged {
+            topic,
+            changed_by,
+            timestamp,
+        } => {
+            let notice = match &topic {
+                Some(topic) => format!("{} changed the topic to: {}", changed_by, topic),
+                None => format!("{} cleared the topic.", changed_by),
+            };
+            app.topic = topic;
+            app.add_system_message_with_time(notice, timestamp);
+        }
+        ServerMessage::RoomExpiring { expires_at } => {
+            let minutes = (expires_at - chrono::Utc::now()).num_minutes().max(1);
+            app.add_system_message(format!(
+                "This room expires in {} minute(s), at {}. Use /export to keep a copy.",
+                minutes,
+                app::local_time(expires_at, "%H:%M")
+            ));
+        }
+        ServerMessage::RoomClosed { reason } => {
+            app.add_system_message(reason.clone());
+            app.removed_reason = Some(ServerError::new(ErrorCode::RoomGone, reason));
+        }
+        ServerMessage::SearchResults { query, results } => {
+            app.show_search_results(query, results);
+        }
+        ServerMessage::Error {
+            code,
+            message,
+            retry_after_ms,
+        } => {
+            if app.search.as_ref().is_some_and(|search| search.loading) {
+                app.close_search();
+            }
+            if let Some(retry_after_ms) = retry_after_ms {
+                app.hold_sends(Duration::from_millis(retry_after_ms));
+                app.restore_last_sent();
+            } else if code == ErrorCode::MessageTooLong {
+                // Give it back to be shortened
+                app.restore_last_sent();
+            }
             app.add_system_message(format!("Error: {}", message));
         }
-        ServerMessage::Pong => {}
+        // Answered during the handshake in Connection::connect
+        ServerMessage::Challenge { .. } | ServerMessage::Pong => {}
     }
 }
@@ -0,0 +1,90 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::identity::terma_dir;
+
+/// Secrets for one room that are worth keeping between runs
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SavedRoom {
+    /// Room key of an end-to-end encrypted room
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    /// Token from creating the room, for moderating it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner_token: Option<String>,
+}
+
+/// Saved room secrets per server, kept in ~/.terma/rooms.json so encrypted
+/// and owned rooms can be rejoined without the invite
+pub struct SavedRooms {
+    path: PathBuf,
+    servers: BTreeMap<String, BTreeMap<String, SavedRoom>>,
+}
+
+impl SavedRooms {
+    pub fn load() -> Result<Self> {
+        let path = terma_dir()?.join("rooms.json");
+        let servers = if path.exists() {
+            let contents = fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read {}", path.display()))?;
+            serde_json::from_str(&contents)
+                .with_context(|| format!("Failed to parse {}", path.display()))?
+        } else {
+            BTreeMap::new()
+        };
+        Ok(Self { path, servers })
+    }
+
+    pub fn get(&self, host: &str, room_id: &str) -> SavedRoom {
+        self.servers
+            .get(host)
+            .and_then(|rooms| rooms.get(room_id))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Change a room's saved secrets and write the file
+    pub fn update(
+        &mut self,
+        host: &str,
+        room_id: &str,
+        change: impl FnOnce(&mut SavedRoom),
+    ) -> Result<()> {
+        change(
+            self.servers
+                .entry(host.to_string())
+                .or_default()
+                .entry(room_id.to_string())
+                .or_default(),
+        );
+
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let contents = serde_json::to_string_pretty(&self.servers)?;
+        write_private(&self.path, &contents)
+            .with_context(|| format!("Failed to write {}", self.path.display()))
+    }
+}
+
+#[cfg(unix)]
+fn write_private(path: &std::path::Path, contents: &str) -> std::io::Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+    file.write_all(contents.as_bytes())
+}
+
+#[cfg(not(unix))]
+fn write_private(path: &std::path::Path, contents: &str) -> std::io::Result<()> {
+    fs::write(path, contents)
+}
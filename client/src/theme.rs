@@ -0,0 +1,453 @@
+use anyhow::{anyhow, Context, Result};
+use ratatui::style::{Color, Modifier, Style};
+use serde::Deserialize;
+use std::fs;
+use std::path::PathBuf;
+
+pub const BUILTIN_THEMES: [&str; 3] = ["dark", "light", "high-contrast"];
+
+/// How many colors the terminal can show
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorSupport {
+    TrueColor,
+    Ansi256,
+    Ansi16,
+    Monochrome,
+}
+
+impl ColorSupport {
+    /// Detect from the environment, honoring NO_COLOR, COLORTERM and TERM
+    pub fn detect() -> Self {
+        if std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty()) {
+            return ColorSupport::Monochrome;
+        }
+
+        let term = std::env::var("TERM").unwrap_or_default();
+        if term == "dumb" {
+            return ColorSupport::Monochrome;
+        }
+
+        let colorterm = std::env::var("COLORTERM").unwrap_or_default();
+        if colorterm == "truecolor" || colorterm == "24bit" {
+            ColorSupport::TrueColor
+        } else if term.contains("256color") {
+            ColorSupport::Ansi256
+        } else {
+            ColorSupport::Ansi16
+        }
+    }
+
+    pub fn parse(s: &str) -> Result<Self> {
+        match s {
+            "truecolor" | "24bit" => Ok(ColorSupport::TrueColor),
+            "256" => Ok(ColorSupport::Ansi256),
+            "16" => Ok(ColorSupport::Ansi16),
+            "mono" | "none" => Ok(ColorSupport::Monochrome),
+            other => Err(anyhow!(
+                "unknown color mode '{}' (expected truecolor, 256, 16 or mono)",
+                other
+            )),
+        }
+    }
+}
+
+/// Named colors for every part of the UI
+#[derive(Debug, Clone)]
+pub struct Theme {
+    pub name: String,
+    pub text: Color,
+    pub muted: Color,
+    pub border: Color,
+    pub own: Color,
+    pub system: Color,
+    pub accent: Color,
+    pub online: Color,
+    pub offline: Color,
+    pub selection: Color,
+    pub highlight: Color,
+    monochrome: bool,
+}
+
+impl Theme {
+    pub fn dark() -> Self {
+        Self {
+            name: "dark".to_string(),
+            text: Color::White,
+            muted: Color::Gray,
+            border: Color::White,
+            own: Color::Cyan,
+            system: Color::Yellow,
+            accent: Color::Cyan,
+            online: Color::Green,
+            offline: Color::Red,
+            selection: Color::Rgb(90, 90, 90),
+            highlight: Color::DarkGray,
+            monochrome: false,
+        }
+    }
+
+    pub fn light() -> Self {
+        Self {
+            name: "light".to_string(),
+            text: Color::Black,
+            muted: Color::Rgb(96, 96, 96),
+            border: Color::Rgb(64, 64, 64),
+            own: Color::Rgb(0, 85, 170),
+            system: Color::Rgb(150, 90, 0),
+            accent: Color::Rgb(0, 110, 110),
+            online: Color::Rgb(0, 130, 0),
+            offline: Color::Rgb(190, 0, 0),
+            selection: Color::Rgb(190, 205, 230),
+            highlight: Color::Rgb(225, 225, 200),
+            monochrome: false,
+        }
+    }
+
+    pub fn high_contrast() -> Self {
+        Self {
+            name: "high-contrast".to_string(),
+            text: Color::White,
+            muted: Color::White,
+            border: Color::White,
+            own: Color::LightCyan,
+            system: Color::LightYellow,
+            accent: Color::LightYellow,
+            online: Color::LightGreen,
+            offline: Color::LightRed,
+            selection: Color::Blue,
+            highlight: Color::Magenta,
+            monochrome: false,
+        }
+    }
+
+    /// Pick the default for this terminal. COLORFGBG (set by several
+    /// terminals as "fg;bg") tells us when the background is light.
+    pub fn auto() -> Self {
+        let light_background = std::env::var("COLORFGBG")
+            .ok()
+            .and_then(|value| value.rsplit(';').next()?.parse::<u8>().ok())
+            .is_some_and(|bg| bg == 7 || bg == 15);
+
+        if light_background {
+            Self::light()
+        } else {
+            Self::dark()
+        }
+    }
+
+    /// Load a built-in theme or `~/.terma/themes/<name>.toml`
+    pub fn load(name: Option<&str>) -> Result<Self> {
+        match name {
+            None | Some("auto") => Ok(Self::auto()),
+            Some(name) => Self::builtin(name).map_or_else(|| Self::load_user(name), Ok),
+        }
+    }
+
+    fn builtin(name: &str) -> Option<Self> {
+        match name {
+            "dark" => Some(Self::dark()),
+            "light" => Some(Self::light()),
+            "high-contrast" => Some(Self::high_contrast()),
+            _ => None,
+        }
+    }
+
+    fn themes_dir() -> Result<PathBuf> {
+        let home = dirs::home_dir().context("Could not find home directory")?;
+        Ok(home.join(".terma").join("themes"))
+    }
+
+    fn load_user(name: &str) -> Result<Self> {
+        let dir = Self::themes_dir()?;
+        let path = dir.join(format!("{}.toml", name));
+
+        if !path.exists() {
+            let mut available: Vec<String> = BUILTIN_THEMES
+                .iter()
+                .map(|theme| theme.to_string())
+                .collect();
+            available.extend(user_theme_names(&dir));
+            return Err(anyhow!(
+                "unknown theme '{}' (available: {})",
+                name,
+                available.join(", ")
+            ));
+        }
+
+        let contents = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read theme file: {}", path.display()))?;
+        let file: ThemeFile = toml::from_str(&contents)
+            .with_context(|| format!("Failed to parse theme file: {}", path.display()))?;
+
+        let base = match file.base.as_deref() {
+            None => Self::dark(),
+            Some(base) => Self::builtin(base)
+                .ok_or_else(|| anyhow!("unknown base theme '{}' in {}", base, path.display()))?,
+        };
+
+        let mut theme = base;
+        theme.name = name.to_string();
+        file.colors
+            .apply(&mut theme)
+            .with_context(|| format!("Invalid color in theme file: {}", path.display()))?;
+        Ok(theme)
+    }
+
+    /// Degrade colors to what the terminal can actually show
+    pub fn adapt(mut self, support: ColorSupport) -> Self {
+        if support == ColorSupport::Monochrome {
+            self.monochrome = true;
+        }
+        for color in [
+            &mut self.text,
+            &mut self.muted,
+            &mut self.border,
+            &mut self.own,
+            &mut self.system,
+            &mut self.accent,
+            &mut self.online,
+            &mut self.offline,
+            &mut self.selection,
+            &mut self.highlight,
+        ] {
+            *color = degrade(*color, support);
+        }
+        self
+    }
+
+    pub fn text_style(&self) -> Style {
+        Style::default().fg(self.text)
+    }
+
+    pub fn muted_style(&self) -> Style {
+        Style::default().fg(self.muted)
+    }
+
+    pub fn border_style(&self) -> Style {
+        Style::default().fg(self.border)
+    }
+
+    pub fn own_style(&self) -> Style {
+        let style = Style::default().fg(self.own);
+        if self.monochrome {
+            style.add_modifier(Modifier::BOLD)
+        } else {
+            style
+        }
+    }
+
+    pub fn system_style(&self) -> Style {
+        Style::default()
+            .fg(self.system)
+            .add_modifier(Modifier::ITALIC)
+    }
+
+    pub fn accent_style(&self) -> Style {
+        Style::default()
+            .fg(self.accent)
+            .add_modifier(Modifier::BOLD)
+    }
+
+    pub fn status_style(&self, connected: bool) -> Style {
+        Style::default().fg(if connected { self.online } else { self.offline })
+    }
+
+    /// Selected text; reverse video when there are no colors to use
+    pub fn selection_style(&self, base: Style) -> Style {
+        if self.monochrome {
+            base.add_modifier(Modifier::REVERSED | Modifier::BOLD)
+        } else {
+            base.bg(self.selection).add_modifier(Modifier::BOLD)
+        }
+    }
+
+    /// Jump targets and the selected row of list overlays
+    pub fn highlight_style(&self, base: Style) -> Style {
+        if self.monochrome {
+            base.add_modifier(Modifier::UNDERLINED)
+        } else {
+            base.bg(self.highlight)
+        }
+    }
+}
+
+/// A user theme file. Every color is optional and falls back to `base`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ThemeFile {
+    base: Option<String>,
+    #[serde(default)]
+    colors: ThemeColors,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ThemeColors {
+    text: Option<String>,
+    muted: Option<String>,
+    border: Option<String>,
+    own: Option<String>,
+    system: Option<String>,
+    accent: Option<String>,
+    online: Option<String>,
+    offline: Option<String>,
+    selection: Option<String>,
+    highlight: Option<String>,
+}
+
+impl ThemeColors {
+    fn apply(&self, theme: &mut Theme) -> Result<()> {
+        for (value, slot) in [
+            (&self.text, &mut theme.text),
+            (&self.muted, &mut theme.muted),
+            (&self.border, &mut theme.border),
+            (&self.own, &mut theme.own),
+            (&self.system, &mut theme.system),
+            (&self.accent, &mut theme.accent),
+            (&self.online, &mut theme.online),
+            (&self.offline, &mut theme.offline),
+            (&self.selection, &mut theme.selection),
+            (&self.highlight, &mut theme.highlight),
+        ] {
+            if let Some(value) = value {
+                *slot = parse_color(value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn user_theme_names(dir: &PathBuf) -> Vec<String> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut names: Vec<String> = entries
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            if path.extension()? != "toml" {
+                return None;
+            }
+            Some(path.file_stem()?.to_str()?.to_string())
+        })
+        .collect();
+    names.sort();
+    names
+}
+
+/// Accepts "#rrggbb", a 0-255 palette index, or a named color like "yellow"
+fn parse_color(value: &str) -> Result<Color> {
+    let value = value.trim();
+    if let Some(hex) = value.strip_prefix('#') {
+        if hex.len() == 6 {
+            if let Ok(rgb) = u32::from_str_radix(hex, 16) {
+                return Ok(Color::Rgb((rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8));
+            }
+        }
+        return Err(anyhow!("invalid hex color '{}'", value));
+    }
+    if let Ok(index) = value.parse::<u8>() {
+        return Ok(Color::Indexed(index));
+    }
+    value
+        .parse::<Color>()
+        .map_err(|_| anyhow!("unknown color '{}'", value))
+}
+
+// xterm's default RGB values for the 16 ANSI colors
+const ANSI16: [(Color, (u8, u8, u8)); 16] = [
+    (Color::Black, (0, 0, 0)),
+    (Color::Red, (205, 0, 0)),
+    (Color::Green, (0, 205, 0)),
+    (Color::Yellow, (205, 205, 0)),
+    (Color::Blue, (0, 0, 238)),
+    (Color::Magenta, (205, 0, 205)),
+    (Color::Cyan, (0, 205, 205)),
+    (Color::Gray, (229, 229, 229)),
+    (Color::DarkGray, (127, 127, 127)),
+    (Color::LightRed, (255, 0, 0)),
+    (Color::LightGreen, (0, 255, 0)),
+    (Color::LightYellow, (255, 255, 0)),
+    (Color::LightBlue, (92, 92, 255)),
+    (Color::LightMagenta, (255, 0, 255)),
+    (Color::LightCyan, (0, 255, 255)),
+    (Color::White, (255, 255, 255)),
+];
+
+const CUBE_LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+fn degrade(color: Color, support: ColorSupport) -> Color {
+    match (support, color) {
+        (ColorSupport::Monochrome, _) => Color::Reset,
+        (ColorSupport::TrueColor, _) => color,
+        (ColorSupport::Ansi256, Color::Rgb(r, g, b)) => Color::Indexed(nearest_256(r, g, b)),
+        (ColorSupport::Ansi16, Color::Rgb(r, g, b)) => nearest_16(r, g, b),
+        (ColorSupport::Ansi16, Color::Indexed(index)) => {
+            let (r, g, b) = indexed_to_rgb(index);
+            nearest_16(r, g, b)
+        }
+        _ => color,
+    }
+}
+
+fn distance((r1, g1, b1): (u8, u8, u8), (r2, g2, b2): (u8, u8, u8)) -> u32 {
+    let dr = r1 as i32 - r2 as i32;
+    let dg = g1 as i32 - g2 as i32;
+    let db = b1 as i32 - b2 as i32;
+    (dr * dr + dg * dg + db * db) as u32
+}
+
+fn nearest_16(r: u8, g: u8, b: u8) -> Color {
+    ANSI16
+        .iter()
+        .min_by_key(|(_, rgb)| distance(*rgb, (r, g, b)))
+        .map(|(color, _)| *color)
+        .unwrap_or(Color::Reset)
+}
+
+/// Closest entry in the xterm 6x6x6 cube or grayscale ramp
+fn nearest_256(r: u8, g: u8, b: u8) -> u8 {
+    let level = |v: u8| {
+        CUBE_LEVELS
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, level)| (**level as i32 - v as i32).abs())
+            .map(|(idx, _)| idx as u8)
+            .unwrap_or(0)
+    };
+    let (ri, gi, bi) = (level(r), level(g), level(b));
+    let cube_index = 16 + 36 * ri + 6 * gi + bi;
+    let cube_rgb = (
+        CUBE_LEVELS[ri as usize],
+        CUBE_LEVELS[gi as usize],
+        CUBE_LEVELS[bi as usize],
+    );
+
+    let avg = ((r as u32 + g as u32 + b as u32) / 3) as u8;
+    let gray_step = (avg.saturating_sub(8) / 10).min(23);
+    let gray_value = 8 + gray_step * 10;
+    let gray_index = 232 + gray_step;
+
+    if distance((gray_value, gray_value, gray_value), (r, g, b)) < distance(cube_rgb, (r, g, b)) {
+        gray_index
+    } else {
+        cube_index
+    }
+}
+
+fn indexed_to_rgb(index: u8) -> (u8, u8, u8) {
+    match index {
+        0..=15 => ANSI16[index as usize].1,
+        16..=231 => {
+            let i = index - 16;
+            (
+                CUBE_LEVELS[(i / 36) as usize],
+                CUBE_LEVELS[((i / 6) % 6) as usize],
+                CUBE_LEVELS[(i % 6) as usize],
+            )
+        }
+        _ => {
+            let value = 8 + (index - 232) * 10;
+            (value, value, value)
+        }
+    }
+}
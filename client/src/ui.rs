@@ -1,9 +1,12 @@
 use crate::app::{App, ContentArea, LineKind, RenderedLine};
+use crate::keys::Action;
+use crate::theme::Theme;
+use crate::vim::Mode;
 use ratatui::{
     layout::{Constraint, Direction, Layout, Rect},
-    style::{Color, Modifier, Style},
+    style::{Modifier, Style},
     text::{Line, Span},
-    widgets::{Block, Borders, Paragraph, Wrap},
+    widgets::{Block, Borders, Clear, Paragraph, Wrap},
     Frame,
 };
 
@@ -28,7 +31,7 @@ pub fn render(frame: &mut Frame, app: &mut App) {
             if available_width == 0 {
                 1
             } else {
-                ((line.len() + available_width - 1) / available_width).max(1)
+                line.len().div_ceil(available_width).max(1)
             }
         })
         .sum();
@@ -37,39 +40,84 @@ pub fn render(frame: &mut Frame, app: &mut App) {
     render_header(frame, app, chunks[0]);
     render_messages(frame, app, chunks[1]);
     render_input(frame, app, chunks[2]);
+
+    if app.search.is_some() {
+        render_search_overlay(frame, app, frame.area());
+    }
+
+    if app.show_help {
+        render_help_overlay(frame, app, frame.area());
+    }
 }
 
 fn render_header(frame: &mut Frame, app: &App, area: Rect) {
+    let theme = &app.theme;
     let status = if app.connected { "●" } else { "○" };
-    let status_color = if app.connected {
-        Color::Green
-    } else {
-        Color::Red
-    };
 
-    let header_text = vec![
-        Span::styled(status, Style::default().fg(status_color)),
+    let mut header_text = vec![
+        Span::styled(status, theme.status_style(app.connected)),
         Span::raw(" "),
         Span::styled(
-            format!("Room: {} ", app.room_id),
-            Style::default().fg(Color::White),
+            format!("Room: {} ", app.room_display_name()),
+            theme.text_style(),
         ),
         Span::raw(" | "),
+    ];
+    if let Some(attempt) = app.redial_attempt {
+        header_text.push(Span::styled(
+            format!("Reconnecting (attempt {}) ", attempt),
+            theme.accent_style(),
+        ));
+        header_text.push(Span::raw(" | "));
+    }
+    if app.room_key.is_some() {
+        header_text.push(Span::styled("🔒 Encrypted ", theme.accent_style()));
+        header_text.push(Span::raw(" | "));
+    }
+    if let Some(expires_at) = app.expires_at {
+        header_text.push(Span::styled(
+            format!(
+                "Expires {} ",
+                crate::app::local_time(expires_at, "%m-%d %H:%M")
+            ),
+            theme.accent_style(),
+        ));
+        header_text.push(Span::raw(" | "));
+    }
+    if app.slow_mode > 0 {
+        header_text.push(Span::styled(
+            format!("Slow mode: {}s ", app.slow_mode),
+            theme.accent_style(),
+        ));
+        header_text.push(Span::raw(" | "));
+    }
+    header_text.extend([
         Span::styled(
             format!("Online: {} ", app.online_count),
-            Style::default().fg(Color::Gray),
+            theme.muted_style(),
         ),
         Span::raw(" | "),
-        Span::styled(
-            format!("You: {}", app.username),
-            Style::default().fg(Color::Cyan),
-        ),
-    ];
+    ]);
+    if let Some(rtt) = app.rtt {
+        header_text.push(Span::styled(
+            format!("RTT: {}ms ", rtt.as_millis()),
+            theme.muted_style(),
+        ));
+        header_text.push(Span::raw(" | "));
+    }
+    header_text.extend([Span::styled(
+        format!("You: {}", app.username),
+        theme.own_style(),
+    )]);
+    if let Some(topic) = &app.topic {
+        header_text.push(Span::raw(" | "));
+        header_text.push(Span::styled(topic.clone(), theme.muted_style()));
+    }
 
     let header = Paragraph::new(Line::from(header_text)).block(
         Block::default()
             .borders(Borders::ALL)
-            .border_style(Style::default().fg(Color::White))
+            .border_style(theme.border_style())
             .title(" Terma "),
     );
 
@@ -87,8 +135,14 @@ fn render_messages(frame: &mut Frame, app: &mut App, area: Rect) {
     // Build all rendered lines with wrapping and style metadata
     let wrap_width = available_width.max(1);
     let mut cache_lines: Vec<RenderedLine> = Vec::new();
+    let mut jump_line = None;
 
     for msg in &app.messages {
+        let highlighted = msg.id.is_some() && msg.id == app.highlighted;
+        if msg.id.is_some() && msg.id == app.jump_to {
+            jump_line = Some(total_lines);
+        }
+
         let kind = if msg.is_system {
             LineKind::System
         } else if msg.is_own_message {
@@ -102,6 +156,7 @@ fn render_messages(frame: &mut Frame, app: &mut App, area: Rect) {
                 cache_lines.push(RenderedLine {
                     text: logical_line.clone(),
                     kind,
+                    highlighted,
                 });
                 total_lines += 1;
                 continue;
@@ -112,6 +167,7 @@ fn render_messages(frame: &mut Frame, app: &mut App, area: Rect) {
                 cache_lines.push(RenderedLine {
                     text: segment,
                     kind,
+                    highlighted,
                 });
                 total_lines += 1;
             }
@@ -122,6 +178,15 @@ fn render_messages(frame: &mut Frame, app: &mut App, area: Rect) {
     // When scroll_offset = 0: show bottom (skip most lines)
     // When scroll_offset increases: show older (skip fewer lines)
     // Note: scroll_offset is already clamped in the render() function
+    if app.jump_to.take().is_some() {
+        if let Some(line) = jump_line {
+            // Put the first line of the jump target at the top of the view
+            app.scroll_offset = total_lines
+                .saturating_sub(visible_height)
+                .saturating_sub(line);
+        }
+    }
+
     let scroll_value = if total_lines > visible_height {
         total_lines
             .saturating_sub(visible_height)
@@ -132,7 +197,7 @@ fn render_messages(frame: &mut Frame, app: &mut App, area: Rect) {
 
     app.update_render_cache(
         cache_lines,
-        scroll_value as usize,
+        scroll_value,
         Some(ContentArea {
             x: area.x,
             y: area.y,
@@ -144,15 +209,21 @@ fn render_messages(frame: &mut Frame, app: &mut App, area: Rect) {
     let selection_range = app.selection.range();
     let mut rendered_lines: Vec<Line> = Vec::with_capacity(app.render_cache.lines.len());
     for (idx, rendered_line) in app.render_cache.lines.iter().enumerate() {
-        rendered_lines.push(build_line(rendered_line, idx, selection_range));
+        rendered_lines.push(build_line(rendered_line, idx, selection_range, &app.theme));
     }
 
+    let title = format!(
+        " Messages (Click+drag to select • {}/{} scroll) ",
+        app.keymap.hint(Action::ScrollUp),
+        app.keymap.hint(Action::ScrollDown)
+    );
+
     let messages_widget = Paragraph::new(rendered_lines)
         .block(
             Block::default()
                 .borders(Borders::ALL)
-                .border_style(Style::default().fg(Color::White))
-                .title(" Messages (Click+drag to select • Alt+↑/↓ scroll) "),
+                .border_style(app.theme.border_style())
+                .title(title),
         )
         .wrap(Wrap { trim: true })
         .scroll((scroll_value as u16, 0));
@@ -161,13 +232,42 @@ fn render_messages(frame: &mut Frame, app: &mut App, area: Rect) {
 }
 
 fn render_input(frame: &mut Frame, app: &mut App, area: Rect) {
+    let keymap = &app.keymap;
+    let hints = format!(
+        "{}: send • {}: new line • {}: help • {}: quit",
+        keymap.hint(Action::Send),
+        keymap.hint(Action::Newline),
+        keymap.hint(Action::Help),
+        keymap.hint(Action::Quit)
+    );
+    let title = match (app.send_wait(), app.vim_mode()) {
+        (Some(wait), _) => format!(
+            " Slow down: you can send again in {}s ",
+            wait.as_millis().div_ceil(1000)
+        ),
+        (None, Some(mode)) => format!(" -- {} -- ({}) ", mode.label(), hints),
+        (None, None) => format!(" Type a message ({}) ", hints),
+    };
+
     // Set textarea block styling
     app.input.set_block(
-            Block::default()
-                .borders(Borders::ALL)
-                .border_style(Style::default().fg(Color::White))
-                .title(" Type a message (Enter: send • Shift+Enter: new line • Alt+↑/↓: scroll • Ctrl+C: quit) "),
-        );
+        Block::default()
+            .borders(Borders::ALL)
+            .border_style(app.theme.border_style())
+            .title(title),
+    );
+
+    // A block cursor reads as "normal mode" in vim-style editing
+    let cursor_style = match app.vim_mode() {
+        Some(Mode::Normal) | Some(Mode::Visual) => {
+            Style::default().add_modifier(Modifier::REVERSED | Modifier::SLOW_BLINK)
+        }
+        _ => Style::default().add_modifier(Modifier::REVERSED),
+    };
+    app.input.set_cursor_style(cursor_style);
+    app.input.set_style(app.theme.text_style());
+    app.input
+        .set_selection_style(app.theme.selection_style(Style::default()));
 
     // Remove cursor line styling (no underline)
     app.input.set_cursor_line_style(Style::default());
@@ -179,17 +279,217 @@ fn render_input(frame: &mut Frame, app: &mut App, area: Rect) {
     frame.render_widget(&app.input, area);
 }
 
+fn render_search_overlay(frame: &mut Frame, app: &App, area: Rect) {
+    let Some(search) = &app.search else {
+        return;
+    };
+    let theme = &app.theme;
+
+    let popup = centered_rect(area, 80, 60);
+    let inner_width = popup.width.saturating_sub(2) as usize;
+
+    let lines: Vec<Line> = if search.loading {
+        vec![Line::from(Span::styled(
+            "Searching...",
+            theme.muted_style(),
+        ))]
+    } else if search.results.is_empty() {
+        vec![Line::from(Span::styled("No matches.", theme.muted_style()))]
+    } else {
+        search
+            .results
+            .iter()
+            .enumerate()
+            .map(|(idx, result)| {
+                let local: chrono::DateTime<chrono::Local> = result.message.timestamp.into();
+                let prefix = format!(
+                    "[{}] {}: ",
+                    local.format("%Y-%m-%d %H:%M"),
+                    result.message.username
+                );
+                let snippet = result.snippet.replace('\n', " ");
+                let budget = inner_width.saturating_sub(prefix.chars().count());
+
+                let mut spans = vec![Span::styled(prefix, theme.muted_style())];
+                spans.extend(snippet_spans(&snippet, budget, theme));
+
+                let line = Line::from(spans);
+                if idx == search.selected {
+                    line.style(theme.highlight_style(Style::default()))
+                } else {
+                    line
+                }
+            })
+            .collect()
+    };
+
+    // Keep the selected result visible in long result lists
+    let visible = popup.height.saturating_sub(2) as usize;
+    let scroll = search.selected.saturating_sub(visible.saturating_sub(1));
+
+    let overlay = Paragraph::new(lines)
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .border_style(theme.border_style())
+                .title(format!(
+                    " Search: {} (↑/↓ select • Enter: jump • Esc: close) ",
+                    search.query
+                )),
+        )
+        .scroll((scroll as u16, 0));
+
+    frame.render_widget(Clear, popup);
+    frame.render_widget(overlay, popup);
+}
+
+fn render_help_overlay(frame: &mut Frame, app: &App, area: Rect) {
+    let theme = &app.theme;
+    let key_style = theme.accent_style();
+    let text_style = theme.text_style();
+
+    let entries = app.keymap.help_entries();
+    let key_width = entries
+        .iter()
+        .map(|(keys, _)| keys.chars().count())
+        .max()
+        .unwrap_or(0);
+
+    let mut lines: Vec<Line> = entries
+        .into_iter()
+        .map(|(keys, description)| {
+            Line::from(vec![
+                Span::styled(format!("{:<width$}  ", keys, width = key_width), key_style),
+                Span::styled(description, text_style),
+            ])
+        })
+        .collect();
+
+    lines.push(Line::from(""));
+    lines.push(Line::from(vec![
+        Span::styled("/search <query>  ", key_style),
+        Span::styled("Search this room", text_style),
+    ]));
+    lines.push(Line::from(vec![
+        Span::styled("/export [file]   ", key_style),
+        Span::styled("Save loaded history to a file", text_style),
+    ]));
+    lines.push(Line::from(vec![
+        Span::styled("/topic           ", key_style),
+        Span::styled("Show the room topic", text_style),
+    ]));
+    lines.push(Line::from(vec![
+        Span::styled("//text           ", key_style),
+        Span::styled("Send a message that starts with /", text_style),
+    ]));
+    if app.owner {
+        lines.push(Line::from(vec![
+            Span::styled("/kick /ban /mute /unban <name>  ", key_style),
+            Span::styled(
+                "Moderate this room; name#id picks one of a name",
+                text_style,
+            ),
+        ]));
+        lines.push(Line::from(vec![
+            Span::styled("/topic <text|off>               ", key_style),
+            Span::styled("Change the room topic", text_style),
+        ]));
+        lines.push(Line::from(vec![
+            Span::styled("/slow <seconds|off>             ", key_style),
+            Span::styled("Limit how often members can post", text_style),
+        ]));
+    }
+
+    if app.vim.is_some() {
+        lines.push(Line::from(""));
+        lines.push(Line::from(Span::styled(
+            "Vim mode: Esc normal • i/a/I/A/o/O insert • v visual • h/j/k/l w/b/e 0/$ gg/G move",
+            text_style,
+        )));
+        lines.push(Line::from(Span::styled(
+            "          x/X/D/C dd dw yy p delete/yank/paste • u/Ctrl+R undo/redo",
+            text_style,
+        )));
+    }
+
+    lines.push(Line::from(""));
+    lines.push(Line::from(Span::styled(
+        "Rebind keys in the \"keys\" section of ~/.terma/config.json • press any key to close",
+        theme.muted_style(),
+    )));
+
+    let popup = centered_rect(area, 80, 70);
+    let overlay = Paragraph::new(lines)
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .border_style(theme.border_style())
+                .title(" Key bindings "),
+        )
+        .wrap(Wrap { trim: false });
+
+    frame.render_widget(Clear, popup);
+    frame.render_widget(overlay, popup);
+}
+
+/// Render a server snippet, bolding the terms wrapped in `**` markers
+fn snippet_spans(snippet: &str, max_chars: usize, theme: &Theme) -> Vec<Span<'static>> {
+    let mut spans = Vec::new();
+    let mut remaining = max_chars;
+
+    for (idx, part) in snippet.split("**").enumerate() {
+        if remaining == 0 {
+            break;
+        }
+        let text: String = part.chars().take(remaining).collect();
+        remaining -= text.chars().count();
+
+        let style = if idx % 2 == 1 {
+            theme.text_style().add_modifier(Modifier::BOLD)
+        } else {
+            theme.text_style()
+        };
+        spans.push(Span::styled(text, style));
+    }
+
+    spans
+}
+
+fn centered_rect(area: Rect, percent_x: u16, percent_y: u16) -> Rect {
+    let vertical = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([
+            Constraint::Percentage((100 - percent_y) / 2),
+            Constraint::Percentage(percent_y),
+            Constraint::Percentage((100 - percent_y) / 2),
+        ])
+        .split(area);
+
+    Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([
+            Constraint::Percentage((100 - percent_x) / 2),
+            Constraint::Percentage(percent_x),
+            Constraint::Percentage((100 - percent_x) / 2),
+        ])
+        .split(vertical[1])[1]
+}
+
 fn build_line(
     rendered_line: &RenderedLine,
     index: usize,
     selection_range: Option<(crate::app::SelectionPosition, crate::app::SelectionPosition)>,
-) -> Line<'_> {
+    theme: &Theme,
+) -> Line<'static> {
     let base_style = match rendered_line.kind {
-        LineKind::System => Style::default()
-            .fg(Color::Yellow)
-            .add_modifier(Modifier::ITALIC),
-        LineKind::Own => Style::default().fg(Color::Cyan),
-        LineKind::Other => Style::default().fg(Color::White),
+        LineKind::System => theme.system_style(),
+        LineKind::Own => theme.own_style(),
+        LineKind::Other => theme.text_style(),
+    };
+    let base_style = if rendered_line.highlighted {
+        theme.highlight_style(base_style)
+    } else {
+        base_style
     };
 
     let line_len = rendered_line.text.chars().count();
@@ -218,12 +518,7 @@ fn build_line(
         }
 
         if !selected.is_empty() {
-            spans.push(Span::styled(
-                selected,
-                base_style
-                    .bg(Color::Rgb(90, 90, 90))
-                    .add_modifier(Modifier::BOLD),
-            ));
+            spans.push(Span::styled(selected, theme.selection_style(base_style)));
         }
 
         if !post.is_empty() {
@@ -245,8 +540,7 @@ fn split_for_selection(text: &str, start_col: usize, end_col: usize) -> (String,
     let mut selected = String::new();
     let mut post = String::new();
 
-    let mut idx = 0;
-    for ch in text.chars() {
+    for (idx, ch) in text.chars().enumerate() {
         if idx < start_col {
             pre.push(ch);
         } else if idx < end_col {
@@ -254,7 +548,6 @@ fn split_for_selection(text: &str, start_col: usize, end_col: usize) -> (String,
         } else {
             post.push(ch);
         }
-        idx += 1;
     }
 
     (pre, selected, post)
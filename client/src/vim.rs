@@ -0,0 +1,207 @@
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use tui_textarea::{CursorMove, TextArea};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Normal,
+    Insert,
+    Visual,
+}
+
+impl Mode {
+    pub fn label(&self) -> &'static str {
+        match self {
+            Mode::Normal => "NORMAL",
+            Mode::Insert => "INSERT",
+            Mode::Visual => "VISUAL",
+        }
+    }
+}
+
+/// Modal (vim-style) editing state for the input box
+pub struct Vim {
+    pub mode: Mode,
+    // First key of a two-key command such as `dd`, `yy` or `gg`
+    pending: Option<char>,
+}
+
+impl Vim {
+    pub fn new() -> Self {
+        // Chat is mostly typing, so start ready to insert
+        Self {
+            mode: Mode::Insert,
+            pending: None,
+        }
+    }
+
+    /// Apply a key to the textarea. Returns false when the key should fall
+    /// through to normal editing (everything but Esc in insert mode).
+    pub fn handle_key(&mut self, textarea: &mut TextArea<'_>, key: KeyEvent) -> bool {
+        match self.mode {
+            Mode::Insert => {
+                if key.code == KeyCode::Esc {
+                    textarea.move_cursor(CursorMove::Back);
+                    self.mode = Mode::Normal;
+                    true
+                } else {
+                    false
+                }
+            }
+            Mode::Normal => {
+                self.normal(textarea, key);
+                true
+            }
+            Mode::Visual => {
+                self.visual(textarea, key);
+                true
+            }
+        }
+    }
+
+    fn normal(&mut self, textarea: &mut TextArea<'_>, key: KeyEvent) {
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+
+        if let Some(pending) = self.pending.take() {
+            match (pending, key.code) {
+                ('g', KeyCode::Char('g')) => textarea.move_cursor(CursorMove::Top),
+                ('d', KeyCode::Char('d')) => delete_line(textarea),
+                ('d', KeyCode::Char('w')) => {
+                    textarea.delete_next_word();
+                }
+                ('d', KeyCode::Char('b')) => {
+                    textarea.delete_word();
+                }
+                ('d', KeyCode::Char('$')) => {
+                    textarea.delete_line_by_end();
+                }
+                ('d', KeyCode::Char('0')) => {
+                    textarea.delete_line_by_head();
+                }
+                ('y', KeyCode::Char('y')) => yank_line(textarea),
+                _ => {}
+            }
+            return;
+        }
+
+        if move_for_key(textarea, key) {
+            return;
+        }
+
+        match key.code {
+            KeyCode::Char('r') if ctrl => {
+                textarea.redo();
+            }
+            KeyCode::Char(c @ ('g' | 'd' | 'y')) => self.pending = Some(c),
+            KeyCode::Char('i') => self.mode = Mode::Insert,
+            KeyCode::Char('a') => {
+                textarea.move_cursor(CursorMove::Forward);
+                self.mode = Mode::Insert;
+            }
+            KeyCode::Char('I') => {
+                textarea.move_cursor(CursorMove::Head);
+                self.mode = Mode::Insert;
+            }
+            KeyCode::Char('A') => {
+                textarea.move_cursor(CursorMove::End);
+                self.mode = Mode::Insert;
+            }
+            KeyCode::Char('o') => {
+                textarea.move_cursor(CursorMove::End);
+                textarea.insert_newline();
+                self.mode = Mode::Insert;
+            }
+            KeyCode::Char('O') => {
+                textarea.move_cursor(CursorMove::Head);
+                textarea.insert_newline();
+                textarea.move_cursor(CursorMove::Up);
+                self.mode = Mode::Insert;
+            }
+            KeyCode::Char('x') | KeyCode::Delete => {
+                textarea.delete_next_char();
+            }
+            KeyCode::Char('X') => {
+                textarea.delete_char();
+            }
+            KeyCode::Char('D') => {
+                textarea.delete_line_by_end();
+            }
+            KeyCode::Char('C') => {
+                textarea.delete_line_by_end();
+                self.mode = Mode::Insert;
+            }
+            KeyCode::Char('p') | KeyCode::Char('P') => {
+                textarea.paste();
+            }
+            KeyCode::Char('u') => {
+                textarea.undo();
+            }
+            KeyCode::Char('v') => {
+                textarea.start_selection();
+                self.mode = Mode::Visual;
+            }
+            _ => {}
+        }
+    }
+
+    fn visual(&mut self, textarea: &mut TextArea<'_>, key: KeyEvent) {
+        if move_for_key(textarea, key) {
+            return;
+        }
+
+        match key.code {
+            KeyCode::Char('y') => {
+                textarea.copy();
+                self.mode = Mode::Normal;
+            }
+            KeyCode::Char('d') | KeyCode::Char('x') => {
+                textarea.cut();
+                self.mode = Mode::Normal;
+            }
+            KeyCode::Char('c') => {
+                textarea.cut();
+                self.mode = Mode::Insert;
+            }
+            KeyCode::Esc | KeyCode::Char('v') => {
+                textarea.cancel_selection();
+                self.mode = Mode::Normal;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Cursor motions shared by normal and visual mode
+fn move_for_key(textarea: &mut TextArea<'_>, key: KeyEvent) -> bool {
+    let movement = match key.code {
+        KeyCode::Char('h') | KeyCode::Left => CursorMove::Back,
+        KeyCode::Char('l') | KeyCode::Right => CursorMove::Forward,
+        KeyCode::Char('j') | KeyCode::Down => CursorMove::Down,
+        KeyCode::Char('k') | KeyCode::Up => CursorMove::Up,
+        KeyCode::Char('w') => CursorMove::WordForward,
+        KeyCode::Char('e') => CursorMove::WordEnd,
+        KeyCode::Char('b') => CursorMove::WordBack,
+        KeyCode::Char('0') | KeyCode::Char('^') | KeyCode::Home => CursorMove::Head,
+        KeyCode::Char('$') | KeyCode::End => CursorMove::End,
+        KeyCode::Char('G') => CursorMove::Bottom,
+        _ => return false,
+    };
+    textarea.move_cursor(movement);
+    true
+}
+
+fn delete_line(textarea: &mut TextArea<'_>) {
+    textarea.move_cursor(CursorMove::Head);
+    textarea.delete_line_by_end();
+    // Remove the line break too, joining with the previous line on the last line
+    if !textarea.delete_next_char() {
+        textarea.delete_char();
+    }
+}
+
+fn yank_line(textarea: &mut TextArea<'_>) {
+    let (row, _) = textarea.cursor();
+    if let Some(line) = textarea.lines().get(row) {
+        let line = line.clone();
+        textarea.set_yank_text(line);
+    }
+}
@@ -1,4 +1,7 @@
-import { Container, getContainer } from "@cloudflare/containers";
+import { Container, getContainer, getRandom } from "@cloudflare/containers";
+
+// Keep in step with max_instances in wrangler.jsonc
+const INSTANCE_COUNT = 3;
 
 export class TermaContainer extends Container {
   defaultPort = 8080;
@@ -15,8 +18,12 @@ export class TermaContainer extends Container {
 
 export default {
   async fetch(request: Request, env: WorkerEnv): Promise<Response> {
-    // Use a singleton container instance for all requests
-    const container = getContainer(env.TERMA_CONTAINER, "terma-singleton");
+    // Instances share rooms only through Postgres; any other database is
+    // served by a single instance
+    const clustered = /^postgres(ql)?:\/\//.test(env.DATABASE_URL ?? "");
+    const container = clustered
+      ? await getRandom(env.TERMA_CONTAINER, INSTANCE_COUNT)
+      : getContainer(env.TERMA_CONTAINER, "terma-singleton");
     return container.fetch(request);
   },
 };
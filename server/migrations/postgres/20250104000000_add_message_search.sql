@@ -0,0 +1,9 @@
+-- Give messages a stable public id so clients can reference them (e.g. search results)
+ALTER TABLE messages
+    ADD COLUMN IF NOT EXISTS message_id UUID NOT NULL DEFAULT gen_random_uuid();
+
+CREATE UNIQUE INDEX IF NOT EXISTS idx_messages_message_id ON messages(message_id);
+
+-- Full-text index over message content
+CREATE INDEX IF NOT EXISTS idx_messages_content_fts
+    ON messages USING GIN (to_tsvector('english', content));
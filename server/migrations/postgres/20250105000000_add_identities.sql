@@ -0,0 +1,10 @@
+-- Ed25519 public keys bound to user ids on their first signed join
+CREATE TABLE IF NOT EXISTS identities (
+    user_id TEXT PRIMARY KEY,
+    public_key TEXT NOT NULL,
+    fingerprint TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+-- Fingerprint of the sender's verified key, NULL for unsigned joins
+ALTER TABLE messages ADD COLUMN IF NOT EXISTS fingerprint TEXT;
@@ -0,0 +1,4 @@
+-- Optional room password (argon2 PHC string) and the SHA-256 of the join
+-- token handed out in the room's install command
+ALTER TABLE rooms ADD COLUMN IF NOT EXISTS password_hash TEXT;
+ALTER TABLE rooms ADD COLUMN IF NOT EXISTS join_token_hash TEXT;
@@ -0,0 +1,3 @@
+-- Rooms whose messages are end-to-end encrypted by clients. The server only
+-- stores ciphertext and never sees the room key.
+ALTER TABLE rooms ADD COLUMN IF NOT EXISTS encrypted BOOLEAN NOT NULL DEFAULT FALSE;
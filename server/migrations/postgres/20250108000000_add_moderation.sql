@@ -0,0 +1,41 @@
+-- SHA-256 of the owner token handed to whoever created the room
+ALTER TABLE rooms ADD COLUMN IF NOT EXISTS owner_token_hash TEXT;
+
+-- User ids that claimed a room with its owner token
+CREATE TABLE IF NOT EXISTS room_roles (
+    room_id TEXT NOT NULL REFERENCES rooms(id) ON DELETE CASCADE,
+    user_id TEXT NOT NULL,
+    role TEXT NOT NULL,
+    granted_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (room_id, user_id)
+);
+
+-- Active bans, mutes and post-kick cooldowns. The fingerprint catches a
+-- banned key rejoining under a new user id. NULL expires_at lasts until lifted.
+CREATE TABLE IF NOT EXISTS room_bans (
+    room_id TEXT NOT NULL REFERENCES rooms(id) ON DELETE CASCADE,
+    user_id TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    username TEXT NOT NULL,
+    fingerprint TEXT,
+    reason TEXT,
+    expires_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (room_id, user_id, kind)
+);
+
+CREATE INDEX IF NOT EXISTS idx_room_bans_fingerprint ON room_bans(room_id, fingerprint);
+
+CREATE TABLE IF NOT EXISTS moderation_log (
+    id UUID PRIMARY KEY,
+    room_id TEXT NOT NULL REFERENCES rooms(id) ON DELETE CASCADE,
+    action TEXT NOT NULL,
+    moderator TEXT NOT NULL,
+    target_id TEXT NOT NULL,
+    target TEXT NOT NULL,
+    reason TEXT,
+    expires_at TIMESTAMPTZ,
+    timestamp TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_moderation_log_room ON moderation_log(room_id, timestamp);
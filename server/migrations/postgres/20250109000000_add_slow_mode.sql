@@ -0,0 +1,2 @@
+-- Minimum seconds between each member's messages, 0 when slow mode is off
+ALTER TABLE rooms ADD COLUMN IF NOT EXISTS slow_mode_seconds INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,4 @@
+-- Human-readable room name, current topic and a message of the day shown on join
+ALTER TABLE rooms ADD COLUMN IF NOT EXISTS name TEXT;
+ALTER TABLE rooms ADD COLUMN IF NOT EXISTS topic TEXT;
+ALTER TABLE rooms ADD COLUMN IF NOT EXISTS motd TEXT;
@@ -0,0 +1,20 @@
+-- Optional expiry set at creation, and the last join or message for idle cleanup
+ALTER TABLE rooms ADD COLUMN IF NOT EXISTS expires_at TIMESTAMPTZ;
+ALTER TABLE rooms ADD COLUMN IF NOT EXISTS last_active_at TIMESTAMPTZ NOT NULL DEFAULT NOW();
+
+CREATE INDEX IF NOT EXISTS idx_rooms_expires_at ON rooms(expires_at) WHERE expires_at IS NOT NULL;
+CREATE INDEX IF NOT EXISTS idx_rooms_last_active_at ON rooms(last_active_at);
+
+-- Deleting a room takes its messages with it
+ALTER TABLE messages DROP CONSTRAINT IF EXISTS messages_room_id_fkey;
+ALTER TABLE messages
+    ADD CONSTRAINT messages_room_id_fkey FOREIGN KEY (room_id) REFERENCES rooms(id) ON DELETE CASCADE;
+
+-- Rooms the reaper removed, so late joiners learn why instead of "not found"
+CREATE TABLE IF NOT EXISTS closed_rooms (
+    id TEXT PRIMARY KEY NOT NULL,
+    reason TEXT NOT NULL,
+    closed_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_closed_rooms_closed_at ON closed_rooms(closed_at);
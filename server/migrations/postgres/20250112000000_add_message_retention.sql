@@ -0,0 +1,14 @@
+-- Per-room retention replaces the 1000-message trigger. At most one of these
+-- is set: keep the newest N messages, or keep N days; neither keeps everything.
+ALTER TABLE rooms ADD COLUMN IF NOT EXISTS retention_messages INTEGER DEFAULT 1000;
+ALTER TABLE rooms ADD COLUMN IF NOT EXISTS retention_days INTEGER;
+-- Last time the pruner trimmed the room to its message cap
+ALTER TABLE rooms ADD COLUMN IF NOT EXISTS pruned_at TIMESTAMPTZ;
+
+ALTER TABLE rooms DROP CONSTRAINT IF EXISTS rooms_single_retention;
+ALTER TABLE rooms ADD CONSTRAINT rooms_single_retention
+    CHECK (retention_messages IS NULL OR retention_days IS NULL);
+
+-- Pruning now happens in batches from a background task instead of on every insert
+DROP TRIGGER IF EXISTS enforce_room_message_limit ON messages;
+DROP FUNCTION IF EXISTS enforce_message_limit();
@@ -0,0 +1,26 @@
+-- Server instances sharing this database, kept alive by a heartbeat
+CREATE TABLE IF NOT EXISTS cluster_instances (
+    id TEXT PRIMARY KEY NOT NULL,
+    heartbeat_at TIMESTAMPTZ NOT NULL
+);
+
+-- Who is connected to which instance, so every instance can count a room's
+-- members. Rows go with their instance when its heartbeat stops.
+CREATE TABLE IF NOT EXISTS cluster_presence (
+    instance_id TEXT NOT NULL REFERENCES cluster_instances(id) ON DELETE CASCADE,
+    room_id TEXT NOT NULL,
+    user_id TEXT NOT NULL,
+    username TEXT NOT NULL,
+    PRIMARY KEY (instance_id, room_id, user_id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_cluster_presence_room ON cluster_presence(room_id, username);
+
+-- Room events too large for a NOTIFY payload, fetched by ID by each listener
+CREATE TABLE IF NOT EXISTS cluster_spill (
+    id BIGSERIAL PRIMARY KEY,
+    payload TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_cluster_spill_created_at ON cluster_spill(created_at);
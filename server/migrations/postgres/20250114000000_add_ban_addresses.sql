@@ -0,0 +1,13 @@
+-- The address each user last joined a room from. A ban or mute copies it,
+-- so someone without a key can't shake it off with a new user id.
+CREATE TABLE IF NOT EXISTS room_visits (
+    room_id TEXT NOT NULL REFERENCES rooms(id) ON DELETE CASCADE,
+    user_id TEXT NOT NULL,
+    address TEXT NOT NULL,
+    seen_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (room_id, user_id)
+);
+
+ALTER TABLE room_bans ADD COLUMN IF NOT EXISTS address TEXT;
+
+CREATE INDEX IF NOT EXISTS idx_room_bans_address ON room_bans(room_id, address);
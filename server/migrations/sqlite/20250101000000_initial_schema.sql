@@ -0,0 +1,87 @@
+-- The Postgres schema as of its 20250112 migration. Timestamps are RFC 3339
+-- text written by the server, which sorts and compares correctly as UTC.
+CREATE TABLE IF NOT EXISTS rooms (
+    id TEXT PRIMARY KEY NOT NULL,
+    created_at TEXT NOT NULL,
+    name TEXT,
+    topic TEXT,
+    motd TEXT,
+    password_hash TEXT,
+    join_token_hash TEXT,
+    owner_token_hash TEXT,
+    encrypted BOOLEAN NOT NULL DEFAULT FALSE,
+    slow_mode_seconds INTEGER NOT NULL DEFAULT 0,
+    expires_at TEXT,
+    last_active_at TEXT NOT NULL,
+    retention_messages INTEGER DEFAULT 1000,
+    retention_days INTEGER,
+    pruned_at TEXT,
+    CHECK (retention_messages IS NULL OR retention_days IS NULL)
+);
+
+CREATE INDEX IF NOT EXISTS idx_rooms_expires_at ON rooms(expires_at) WHERE expires_at IS NOT NULL;
+CREATE INDEX IF NOT EXISTS idx_rooms_last_active_at ON rooms(last_active_at);
+
+CREATE TABLE IF NOT EXISTS messages (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    message_id BLOB NOT NULL UNIQUE,
+    room_id TEXT NOT NULL REFERENCES rooms(id) ON DELETE CASCADE,
+    user_id TEXT NOT NULL,
+    username TEXT NOT NULL,
+    content TEXT NOT NULL,
+    timestamp TEXT NOT NULL,
+    fingerprint TEXT
+);
+
+CREATE INDEX IF NOT EXISTS idx_messages_room_timestamp ON messages(room_id, timestamp DESC);
+
+CREATE TABLE IF NOT EXISTS identities (
+    user_id TEXT PRIMARY KEY,
+    public_key TEXT NOT NULL,
+    fingerprint TEXT NOT NULL,
+    created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE IF NOT EXISTS room_roles (
+    room_id TEXT NOT NULL REFERENCES rooms(id) ON DELETE CASCADE,
+    user_id TEXT NOT NULL,
+    role TEXT NOT NULL,
+    granted_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (room_id, user_id)
+);
+
+CREATE TABLE IF NOT EXISTS room_bans (
+    room_id TEXT NOT NULL REFERENCES rooms(id) ON DELETE CASCADE,
+    user_id TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    username TEXT NOT NULL,
+    fingerprint TEXT,
+    reason TEXT,
+    expires_at TEXT,
+    created_at TEXT NOT NULL,
+    PRIMARY KEY (room_id, user_id, kind)
+);
+
+CREATE INDEX IF NOT EXISTS idx_room_bans_fingerprint ON room_bans(room_id, fingerprint);
+
+CREATE TABLE IF NOT EXISTS moderation_log (
+    id BLOB PRIMARY KEY,
+    room_id TEXT NOT NULL REFERENCES rooms(id) ON DELETE CASCADE,
+    action TEXT NOT NULL,
+    moderator TEXT NOT NULL,
+    target_id TEXT NOT NULL,
+    target TEXT NOT NULL,
+    reason TEXT,
+    expires_at TEXT,
+    timestamp TEXT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_moderation_log_room ON moderation_log(room_id, timestamp);
+
+CREATE TABLE IF NOT EXISTS closed_rooms (
+    id TEXT PRIMARY KEY NOT NULL,
+    reason TEXT NOT NULL,
+    closed_at TEXT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_closed_rooms_closed_at ON closed_rooms(closed_at);
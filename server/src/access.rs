@@ -0,0 +1,133 @@
+use anyhow::{anyhow, Result};
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use nanoid::nanoid;
+use sha2::{Digest, Sha256};
+
+/// Credentials guarding a room. The password and join token are `None` for
+/// open rooms; rooms from before ownership have no owner token.
+#[derive(Debug, Clone, Default, sqlx::FromRow)]
+pub struct RoomSecrets {
+    pub password_hash: Option<String>,
+    pub join_token_hash: Option<String>,
+    pub owner_token_hash: Option<String>,
+}
+
+/// Tokens handed to a room's creator; only their hashes are stored
+pub struct IssuedTokens {
+    pub owner_token: String,
+    pub join_token: Option<String>,
+}
+
+impl RoomSecrets {
+    /// Secrets for a new room, plus the tokens to hand to its creator
+    pub fn new(password: Option<&str>) -> Result<(Self, IssuedTokens)> {
+        let owner_token = nanoid!(32);
+        let mut secrets = Self {
+            owner_token_hash: Some(hash_token(&owner_token)),
+            ..Self::default()
+        };
+        let mut tokens = IssuedTokens {
+            owner_token,
+            join_token: None,
+        };
+
+        if let Some(password) = password {
+            let salt = SaltString::generate(&mut OsRng);
+            let password_hash = Argon2::default()
+                .hash_password(password.as_bytes(), &salt)
+                .map_err(|e| anyhow!("Failed to hash password: {}", e))?
+                .to_string();
+            let join_token = nanoid!(32);
+
+            secrets.password_hash = Some(password_hash);
+            secrets.join_token_hash = Some(hash_token(&join_token));
+            tokens.join_token = Some(join_token);
+        }
+
+        Ok((secrets, tokens))
+    }
+
+    pub fn is_protected(&self) -> bool {
+        self.password_hash.is_some()
+    }
+
+    pub fn admits_token(&self, token: &str) -> bool {
+        self.join_token_hash
+            .as_deref()
+            .is_some_and(|hash| hash == hash_token(token))
+    }
+
+    pub fn admits_owner(&self, token: &str) -> bool {
+        self.owner_token_hash
+            .as_deref()
+            .is_some_and(|hash| hash == hash_token(token))
+    }
+
+    /// Argon2 is deliberately slow; call this off the async executor
+    pub fn admits_password(&self, password: &str) -> bool {
+        let Some(hash) = self.password_hash.as_deref() else {
+            return false;
+        };
+        PasswordHash::new(hash).is_ok_and(|hash| {
+            Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok()
+        })
+    }
+
+    /// Accept either the join token or the password, as HTTP clients send one
+    /// secret without saying which it is
+    pub fn admits(&self, secret: &str) -> bool {
+        self.admits_token(secret) || self.admits_password(secret)
+    }
+}
+
+fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn open_rooms_only_have_an_owner_token() {
+        let (secrets, tokens) = RoomSecrets::new(None).unwrap();
+        assert!(!secrets.is_protected());
+        assert!(tokens.join_token.is_none());
+        assert!(secrets.admits_owner(&tokens.owner_token));
+        // The owner token isn't a way in to protected rooms
+        assert!(!secrets.admits(&tokens.owner_token));
+    }
+
+    #[test]
+    fn protected_rooms_take_the_password_or_join_token() {
+        let (secrets, tokens) = RoomSecrets::new(Some("hunter2")).unwrap();
+        let join_token = tokens.join_token.unwrap();
+        assert!(secrets.is_protected());
+        assert!(secrets.admits_password("hunter2"));
+        assert!(secrets.admits_token(&join_token));
+        assert!(secrets.admits("hunter2") && secrets.admits(&join_token));
+        assert!(!secrets.admits("hunter3"));
+        assert!(!secrets.admits_token("hunter2"));
+        assert!(!secrets.admits_owner(&join_token));
+        assert!(!secrets.admits_owner(&tokens.owner_token.to_uppercase()));
+    }
+
+    #[test]
+    fn only_hashes_are_kept() {
+        let (secrets, tokens) = RoomSecrets::new(Some("hunter2")).unwrap();
+        let kept = [
+            secrets.password_hash.unwrap(),
+            secrets.join_token_hash.unwrap(),
+            secrets.owner_token_hash.unwrap(),
+        ];
+        let given = ["hunter2", &tokens.join_token.unwrap(), &tokens.owner_token];
+        for (kept, given) in kept.iter().zip(given) {
+            assert!(!kept.contains(given));
+        }
+    }
+}
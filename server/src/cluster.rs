@@ -0,0 +1,467 @@
+//! Fan-out between server instances that share a Postgres database. Every
+//! room event is published with NOTIFY and each instance LISTENs, applying
+//! events from the others to its own members. Events too large for a NOTIFY
+//! payload are spilled to a table and fetched by ID. Presence lives in the
+//! database so online counts and moderation see members on every instance.
+
+use anyhow::Result;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::{PgListener, PgPool, PgPoolOptions};
+use std::sync::Arc;
+use std::time::Duration;
+use terma_shared::ServerMessage;
+use tokio::sync::mpsc;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::state::{AppState, RoomEvent};
+
+const CHANNEL: &str = "terma_room_events";
+
+/// Postgres caps NOTIFY payloads at 8000 bytes; anything bigger is spilled
+const MAX_PAYLOAD_BYTES: usize = 7900;
+
+/// How often an instance proves it's alive
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Instances silent for this long are presumed dead and their members gone
+const INSTANCE_TIMEOUT_SECONDS: i32 = 30;
+
+/// Spilled payloads are kept long enough for every listener to fetch them
+const SPILL_RETENTION_SECONDS: i32 = 300;
+
+pub struct Cluster {
+    instance_id: String,
+    pool: PgPool,
+    outbox: mpsc::UnboundedSender<(String, RoomEvent)>,
+}
+
+/// What goes over the wire: an event, or where to find one that didn't fit
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Notification {
+    Event(Box<Envelope>),
+    Spilled { from: String, spill_id: i64 },
+}
+
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+    from: String,
+    room_id: String,
+    event: RoomEvent,
+}
+
+/// Join the cluster when the database is Postgres; other backends can only
+/// be served by one instance
+pub async fn attach(mut state: AppState, database_url: &str) -> Result<AppState> {
+    if !(database_url.starts_with("postgres://") || database_url.starts_with("postgresql://")) {
+        return Ok(state);
+    }
+    let cluster = Cluster::connect(database_url).await?;
+    state.cluster = Some(cluster.clone());
+    cluster.spawn(state.clone());
+    Ok(state)
+}
+
+impl Cluster {
+    /// Register this instance and start publishing
+    async fn connect(database_url: &str) -> Result<Arc<Self>> {
+        let pool = PgPoolOptions::new()
+            .max_connections(5)
+            .connect(database_url)
+            .await?;
+        let instance_id = Uuid::new_v4().simple().to_string();
+        sqlx::query("INSERT INTO cluster_instances (id, heartbeat_at) VALUES ($1, NOW())")
+            .bind(&instance_id)
+            .execute(&pool)
+            .await?;
+
+        let (outbox, rx) = mpsc::unbounded_channel();
+        tokio::spawn(publish_loop(pool.clone(), instance_id.clone(), rx));
+        info!("Joined cluster as instance {}", instance_id);
+
+        Ok(Arc::new(Self {
+            instance_id,
+            pool,
+            outbox,
+        }))
+    }
+
+    /// Start listening for other instances' events and heartbeating
+    fn spawn(self: &Arc<Self>, state: AppState) {
+        let cluster = self.clone();
+        let listener_state = state.clone();
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = cluster.listen(&listener_state).await {
+                    error!("Cluster listener failed: {}", e);
+                }
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+        });
+
+        let cluster = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(HEARTBEAT_INTERVAL);
+            loop {
+                interval.tick().await;
+                if let Err(e) = cluster.heartbeat(&state).await {
+                    error!("Cluster heartbeat failed: {}", e);
+                }
+            }
+        });
+    }
+
+    /// Queue an event for the other instances. Events go out one at a time,
+    /// so they arrive in the order they were published.
+    pub fn publish(&self, room_id: &str, event: RoomEvent) {
+        let _ = self.outbox.send((room_id.to_string(), event));
+    }
+
+    pub async fn join(&self, room_id: &str, user_id: &str, username: &str) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO cluster_presence (instance_id, room_id, user_id, username)
+             VALUES ($1, $2, $3, $4)
+             ON CONFLICT (instance_id, room_id, user_id) DO UPDATE SET username = EXCLUDED.username",
+        )
+        .bind(&self.instance_id)
+        .bind(room_id)
+        .bind(user_id)
+        .bind(username)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn leave(&self, room_id: &str, user_id: &str) -> Result<()> {
+        sqlx::query(
+            "DELETE FROM cluster_presence
+             WHERE instance_id = $1 AND room_id = $2 AND user_id = $3",
+        )
+        .bind(&self.instance_id)
+        .bind(room_id)
+        .bind(user_id)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn present_elsewhere(&self, room_id: &str, user_id: &str) -> Result<bool> {
+        let present = sqlx::query_scalar::<_, bool>(
+            "SELECT EXISTS (
+                 SELECT 1 FROM cluster_presence
+                 WHERE room_id = $1 AND user_id = $2 AND instance_id <> $3
+             )",
+        )
+        .bind(room_id)
+        .bind(user_id)
+        .bind(&self.instance_id)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(present)
+    }
+
+    pub async fn online_count(&self, room_id: &str) -> Result<usize> {
+        let count = sqlx::query_scalar::<_, i64>(
+            "SELECT COUNT(DISTINCT user_id) FROM cluster_presence WHERE room_id = $1",
+        )
+        .bind(room_id)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(count as usize)
+    }
+
+    pub async fn user_ids_named(&self, room_id: &str, username: &str) -> Result<Vec<String>> {
+        let user_ids = sqlx::query_scalar::<_, String>(
+            "SELECT DISTINCT user_id FROM cluster_presence WHERE room_id = $1 AND username = $2",
+        )
+        .bind(room_id)
+        .bind(username)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(user_ids)
+    }
+
+    pub async fn active_rooms(&self) -> Result<Vec<String>> {
+        let room_ids =
+            sqlx::query_scalar::<_, String>("SELECT DISTINCT room_id FROM cluster_presence")
+                .fetch_all(&self.pool)
+                .await?;
+        Ok(room_ids)
+    }
+
+    async fn listen(&self, state: &AppState) -> Result<()> {
+        let mut listener = PgListener::connect_with(&self.pool).await?;
+        listener.listen(CHANNEL).await?;
+        loop {
+            // Reconnects by itself; anything sent meanwhile is lost
+            let notification = listener.recv().await?;
+            let envelope = match serde_json::from_str(notification.payload()) {
+                Ok(Notification::Event(envelope)) => *envelope,
+                Ok(Notification::Spilled { from, .. }) if from == self.instance_id => continue,
+                Ok(Notification::Spilled { spill_id, .. }) => match self.unspill(spill_id).await {
+                    Ok(envelope) => envelope,
+                    Err(e) => {
+                        warn!("Lost spilled cluster event {}: {}", spill_id, e);
+                        continue;
+                    }
+                },
+                Err(e) => {
+                    warn!("Ignoring malformed cluster event: {}", e);
+                    continue;
+                }
+            };
+            if envelope.from != self.instance_id {
+                state.deliver(&envelope.room_id, envelope.event);
+            }
+        }
+    }
+
+    async fn unspill(&self, spill_id: i64) -> Result<Envelope> {
+        let payload =
+            sqlx::query_scalar::<_, String>("SELECT payload FROM cluster_spill WHERE id = $1")
+                .bind(spill_id)
+                .fetch_one(&self.pool)
+                .await?;
+        Ok(serde_json::from_str(&payload)?)
+    }
+
+    /// Stay registered, and clear out instances that stopped heartbeating
+    /// and spilled payloads everyone has read. The dead instances' members
+    /// are gone, so their rooms are told they left.
+    async fn heartbeat(&self, state: &AppState) -> Result<()> {
+        let registered = sqlx::query_scalar::<_, bool>(
+            "INSERT INTO cluster_instances (id, heartbeat_at) VALUES ($1, NOW())
+             ON CONFLICT (id) DO UPDATE SET heartbeat_at = NOW()
+             RETURNING xmax = 0",
+        )
+        .bind(&self.instance_id)
+        .fetch_one(&self.pool)
+        .await?;
+        if registered {
+            // Another instance gave us up for dead and took our presence
+            // with it; put it back
+            warn!("Cluster instance {} re-registered", self.instance_id);
+            for (room_id, _) in state.local_rooms() {
+                for (user_id, username) in state.members(&room_id).await {
+                    self.join(&room_id, &user_id, &username).await?;
+                }
+            }
+        }
+        // Locked so only one surviving instance announces each leave
+        let gone = sqlx::query_as::<_, (String, String, String)>(
+            "WITH dead AS (
+                 SELECT id FROM cluster_instances
+                 WHERE heartbeat_at < NOW() - make_interval(secs => $1)
+                 FOR UPDATE SKIP LOCKED
+             ), gone AS (
+                 DELETE FROM cluster_presence
+                 WHERE instance_id IN (SELECT id FROM dead)
+                 RETURNING room_id, user_id, username
+             ), buried AS (
+                 DELETE FROM cluster_instances WHERE id IN (SELECT id FROM dead)
+             )
+             SELECT DISTINCT room_id, user_id, username FROM gone",
+        )
+        .bind(INSTANCE_TIMEOUT_SECONDS)
+        .fetch_all(&self.pool)
+        .await?;
+        for (room_id, user_id, username) in gone {
+            self.announce_leave(state, &room_id, user_id, username)
+                .await?;
+        }
+        sqlx::query(
+            "DELETE FROM cluster_spill WHERE created_at < NOW() - make_interval(secs => $1)",
+        )
+        .bind(SPILL_RETENTION_SECONDS)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Tell a room a member of a dead instance left, unless they're still
+    /// connected somewhere else
+    async fn announce_leave(
+        &self,
+        state: &AppState,
+        room_id: &str,
+        user_id: String,
+        username: String,
+    ) -> Result<()> {
+        let present = sqlx::query_scalar::<_, bool>(
+            "SELECT EXISTS (
+                 SELECT 1 FROM cluster_presence WHERE room_id = $1 AND user_id = $2
+             )",
+        )
+        .bind(room_id)
+        .bind(&user_id)
+        .fetch_one(&self.pool)
+        .await?;
+        if present {
+            return Ok(());
+        }
+        let left_msg = ServerMessage::UserLeft {
+            user_id,
+            username,
+            timestamp: Utc::now(),
+            online_count: self.online_count(room_id).await?,
+        };
+        state.publish(room_id, RoomEvent::broadcast(left_msg));
+        Ok(())
+    }
+}
+
+async fn publish_loop(
+    pool: PgPool,
+    instance_id: String,
+    mut rx: mpsc::UnboundedReceiver<(String, RoomEvent)>,
+) {
+    while let Some((room_id, event)) = rx.recv().await {
+        let envelope = Envelope {
+            from: instance_id.clone(),
+            room_id,
+            event,
+        };
+        if let Err(e) = notify(&pool, &envelope).await {
+            error!(
+                "Failed to publish event for room {}: {}",
+                envelope.room_id, e
+            );
+        }
+    }
+}
+
+async fn notify(pool: &PgPool, envelope: &Envelope) -> Result<()> {
+    let mut payload = serde_json::to_string(envelope)?;
+    if payload.len() > MAX_PAYLOAD_BYTES {
+        let spill_id = sqlx::query_scalar::<_, i64>(
+            "INSERT INTO cluster_spill (payload) VALUES ($1) RETURNING id",
+        )
+        .bind(&payload)
+        .fetch_one(pool)
+        .await?;
+        payload = serde_json::to_string(&Notification::Spilled {
+            from: envelope.from.clone(),
+            spill_id,
+        })?;
+    }
+    sqlx::query("SELECT pg_notify($1, $2)")
+        .bind(CHANNEL)
+        .bind(payload)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::outbox::{self, OutboxReceiver};
+    use axum::extract::ws::Message;
+    use terma_shared::{Codec, Frame, Room};
+
+    fn envelope(content: &str) -> Envelope {
+        let message = ServerMessage::error(terma_shared::ErrorCode::Internal, content.to_string());
+        Envelope {
+            from: "instance-a".to_string(),
+            room_id: "room-1".to_string(),
+            event: RoomEvent::broadcast(message),
+        }
+    }
+
+    #[test]
+    fn events_and_spill_notices_are_told_apart() {
+        let event = serde_json::to_string(&envelope("hi")).unwrap();
+        assert!(matches!(
+            serde_json::from_str(&event).unwrap(),
+            Notification::Event(envelope) if envelope.room_id == "room-1"
+        ));
+        let spilled = serde_json::to_string(&Notification::Spilled {
+            from: "instance-a".to_string(),
+            spill_id: 7,
+        })
+        .unwrap();
+        assert!(spilled.len() < MAX_PAYLOAD_BYTES);
+        assert!(matches!(
+            serde_json::from_str(&spilled).unwrap(),
+            Notification::Spilled { spill_id: 7, .. }
+        ));
+    }
+
+    async fn join(state: &AppState, room: &Room, user_id: &str) -> OutboxReceiver {
+        let (outbox, rx) = outbox::channel(
+            state.outbox,
+            Codec::Json,
+            crate::compression::FrameCompressor::new(None, state.metrics.clone()),
+            state.metrics.clone(),
+            &room.id,
+            user_id,
+        );
+        let (user_id, username) = (user_id.to_string(), user_id.to_string());
+        state
+            .join_room(room, None, user_id, username, false, outbox)
+            .await;
+        rx
+    }
+
+    /// Everyone the room says has left, until it goes quiet
+    async fn leavers(rx: &mut OutboxReceiver) -> Vec<String> {
+        let mut leavers = Vec::new();
+        while let Ok(Message::Text(text)) =
+            tokio::time::timeout(Duration::from_millis(300), rx.recv()).await
+        {
+            if let Ok(ServerMessage::UserLeft { user_id, .. }) =
+                ServerMessage::decode(Codec::Json, Frame::Text(text))
+            {
+                leavers.push(user_id);
+            }
+        }
+        leavers
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Postgres database in TEST_DATABASE_URL"]
+    async fn members_of_dead_instances_leave_once() {
+        let url = std::env::var("TEST_DATABASE_URL").unwrap();
+        let db = crate::db::connect(&url).await.unwrap();
+        let room = Room::new(Uuid::new_v4().simple().to_string());
+        db.create_room(&room, &Default::default()).await.unwrap();
+        let mut state = AppState::new(db);
+        let cluster = Cluster::connect(&url).await.unwrap();
+        state.cluster = Some(cluster.clone());
+        let mut rx = join(&state, &room, "alice").await;
+
+        // Bob and Carol were on an instance that stopped heartbeating, but
+        // Carol is also connected to this one
+        let dead = Uuid::new_v4().simple().to_string();
+        sqlx::query(
+            "INSERT INTO cluster_instances (id, heartbeat_at)
+             VALUES ($1, NOW() - INTERVAL '1 hour')",
+        )
+        .bind(&dead)
+        .execute(&cluster.pool)
+        .await
+        .unwrap();
+        for user_id in ["bob", "carol"] {
+            sqlx::query(
+                "INSERT INTO cluster_presence (instance_id, room_id, user_id, username)
+                 VALUES ($1, $2, $3, $3)",
+            )
+            .bind(&dead)
+            .bind(&room.id)
+            .bind(user_id)
+            .execute(&cluster.pool)
+            .await
+            .unwrap();
+        }
+        cluster.join(&room.id, "carol", "carol").await.unwrap();
+
+        cluster.heartbeat(&state).await.unwrap();
+        assert_eq!(leavers(&mut rx).await, ["bob"]);
+        assert_eq!(cluster.online_count(&room.id).await.unwrap(), 1);
+        // Already buried
+        cluster.heartbeat(&state).await.unwrap();
+        assert!(leavers(&mut rx).await.is_empty());
+    }
+}
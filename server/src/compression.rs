@@ -0,0 +1,180 @@
+//! Compressing frames to clients that offer it in `Join`, for history and
+//! busy rooms over slow links. The format is in `terma_shared::deflate`.
+
+use axum::extract::ws::Message;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use terma_shared::deflate::{Deflater, MAX_WINDOW_BITS, MIN_WINDOW_BITS};
+use terma_shared::Compression;
+use tracing::{debug, warn};
+
+use crate::metrics::Metrics;
+
+/// Frames shorter than this many bytes go uncompressed unless
+/// `COMPRESSION_THRESHOLD` says otherwise
+const DEFAULT_THRESHOLD: usize = 256;
+
+#[derive(Debug, Clone, Copy)]
+pub struct CompressionConfig {
+    /// Largest window to compress with, as a power of two; zero turns
+    /// compression off
+    pub window_bits: u8,
+    pub threshold: usize,
+}
+
+impl CompressionConfig {
+    /// Read `COMPRESSION_WINDOW_BITS` and `COMPRESSION_THRESHOLD`, falling
+    /// back to a 32 KiB window and 256 bytes
+    pub fn from_env() -> Self {
+        let window_bits = match std::env::var("COMPRESSION_WINDOW_BITS") {
+            Ok(value) => match value.parse::<u8>() {
+                Ok(0) => 0,
+                Ok(bits) if (MIN_WINDOW_BITS..=MAX_WINDOW_BITS).contains(&bits) => bits,
+                _ => {
+                    warn!(
+                        "COMPRESSION_WINDOW_BITS must be 0, or {} to {}; using {}",
+                        MIN_WINDOW_BITS, MAX_WINDOW_BITS, MAX_WINDOW_BITS
+                    );
+                    MAX_WINDOW_BITS
+                }
+            },
+            Err(_) => MAX_WINDOW_BITS,
+        };
+        let threshold = std::env::var("COMPRESSION_THRESHOLD")
+            .ok()
+            .and_then(|threshold| threshold.parse().ok())
+            .unwrap_or(DEFAULT_THRESHOLD);
+        Self {
+            window_bits,
+            threshold,
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.window_bits != 0
+    }
+
+    /// How to compress for a client making `offer`, if at all: with the
+    /// smaller of the two windows
+    pub fn negotiate(&self, offer: Option<Compression>) -> Option<Deflater> {
+        let offer = offer.filter(|_| self.enabled())?;
+        Some(Deflater {
+            window_bits: offer
+                .window_bits
+                .clamp(MIN_WINDOW_BITS, MAX_WINDOW_BITS)
+                .min(self.window_bits),
+            threshold: self.threshold,
+        })
+    }
+}
+
+/// Compresses one connection's outgoing frames, if it agreed to
+#[derive(Clone)]
+pub struct FrameCompressor {
+    deflater: Option<Deflater>,
+    metrics: Arc<Metrics>,
+}
+
+impl FrameCompressor {
+    pub fn new(deflater: Option<Deflater>, metrics: Arc<Metrics>) -> Self {
+        Self { deflater, metrics }
+    }
+
+    /// The window frames are compressed with, or zero if they aren't.
+    /// Connections with the same window get the same compressed bytes.
+    pub fn window_bits(&self) -> u8 {
+        self.deflater.map_or(0, |deflater| deflater.window_bits)
+    }
+
+    /// `msg`, compressed if it's a data frame worth compressing
+    pub fn apply(&self, msg: Message) -> Message {
+        let Some(deflater) = &self.deflater else {
+            return msg;
+        };
+        let data = match &msg {
+            Message::Text(text) => text.as_bytes(),
+            Message::Binary(bytes) => bytes.as_slice(),
+            _ => return msg,
+        };
+        let Some(compressed) = deflater.compress(data) else {
+            return msg;
+        };
+        debug!(
+            "Compressed a {} byte frame to {} ({:.1}x)",
+            data.len(),
+            compressed.len(),
+            data.len() as f64 / compressed.len() as f64
+        );
+        let metrics = &self.metrics;
+        metrics
+            .compressed_in
+            .fetch_add(data.len() as u64, Ordering::Relaxed);
+        metrics
+            .compressed_out
+            .fetch_add(compressed.len() as u64, Ordering::Relaxed);
+        Message::Binary(compressed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: CompressionConfig = CompressionConfig {
+        window_bits: 12,
+        threshold: 100,
+    };
+
+    fn offer(window_bits: u8) -> Option<Compression> {
+        Some(Compression { window_bits })
+    }
+
+    #[test]
+    fn agrees_on_the_smaller_window() {
+        let agreed = |bits| CONFIG.negotiate(offer(bits)).unwrap().window_bits;
+        assert_eq!(agreed(10), 10);
+        assert_eq!(agreed(15), 12);
+        // Out of range offers are brought into it
+        assert_eq!(agreed(0), MIN_WINDOW_BITS);
+        assert_eq!(agreed(99), 12);
+        assert_eq!(CONFIG.negotiate(offer(10)).unwrap().threshold, 100);
+    }
+
+    #[test]
+    fn needs_both_sides() {
+        assert_eq!(CONFIG.negotiate(None), None);
+        let off = CompressionConfig {
+            window_bits: 0,
+            ..CONFIG
+        };
+        assert_eq!(off.negotiate(offer(15)), None);
+    }
+
+    #[test]
+    fn compresses_only_data_frames_worth_it() {
+        let metrics = Arc::new(Metrics::default());
+        let compressor = FrameCompressor::new(CONFIG.negotiate(offer(15)), metrics.clone());
+        let text = "hello ".repeat(100);
+        let Message::Binary(compressed) = compressor.apply(Message::Text(text.clone())) else {
+            panic!("not compressed");
+        };
+        assert!(compressed.len() < text.len() / 4);
+        assert_eq!(
+            metrics.compressed_in.load(Ordering::Relaxed),
+            text.len() as u64
+        );
+        assert_eq!(
+            metrics.compressed_out.load(Ordering::Relaxed),
+            compressed.len() as u64
+        );
+
+        let short = Message::Text("hello".to_string());
+        assert_eq!(compressor.apply(short.clone()), short);
+        let ping = Message::Ping(vec![0; 500]);
+        assert_eq!(compressor.apply(ping.clone()), ping);
+        let off = FrameCompressor::new(None, metrics);
+        let long = Message::Text(text);
+        assert_eq!(off.apply(long.clone()), long);
+        assert_eq!(off.window_bits(), 0);
+    }
+}
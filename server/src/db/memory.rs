@@ -0,0 +1,602 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use terma_shared::{ChatMessage, ModerationAction, ModerationEvent, Retention, Room, SearchResult};
+
+use super::{
+    rank_matches, ActiveSanction, ClosedRoom, Sanction, Storage, HISTORY_LIMIT,
+    MODERATION_LOG_LIMIT,
+};
+use crate::access::RoomSecrets;
+
+/// Everything in process memory, for development and throwaway servers.
+/// Nothing survives a restart.
+#[derive(Default)]
+pub struct MemoryStorage {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    rooms: HashMap<String, StoredRoom>,
+    closed_rooms: HashMap<String, ClosedRoom>,
+    /// User ID to (public key, fingerprint)
+    identities: HashMap<String, (String, String)>,
+    /// Every room's messages keyed by insertion sequence
+    messages: BTreeMap<i64, ChatMessage>,
+    next_seq: i64,
+}
+
+struct StoredRoom {
+    room: Room,
+    secrets: RoomSecrets,
+    last_active_at: DateTime<Utc>,
+    pruned_at: Option<DateTime<Utc>>,
+    /// User ID to role
+    roles: HashMap<String, String>,
+    /// User ID to the address they last joined from
+    visits: HashMap<String, String>,
+    /// Keyed by (user ID, kind)
+    bans: HashMap<(String, &'static str), StoredSanction>,
+    moderation_log: Vec<ModerationEvent>,
+}
+
+struct StoredSanction {
+    username: String,
+    fingerprint: Option<String>,
+    address: Option<String>,
+    reason: Option<String>,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+impl Inner {
+    fn room_messages<'a>(
+        &'a self,
+        room_id: &'a str,
+    ) -> impl Iterator<Item = (&'a i64, &'a ChatMessage)> {
+        self.messages
+            .iter()
+            .filter(move |(_, msg)| msg.room_id == room_id)
+    }
+
+    /// Delete rooms matching `gone` along with their messages, remembering
+    /// why. Returns their IDs.
+    fn close_rooms(
+        &mut self,
+        reason: &str,
+        gone: impl Fn(&StoredRoom) -> Option<DateTime<Utc>>,
+    ) -> Vec<String> {
+        let closed: Vec<(String, DateTime<Utc>)> = self
+            .rooms
+            .iter()
+            .filter_map(|(id, room)| Some((id.clone(), gone(room)?)))
+            .collect();
+        for (id, closed_at) in &closed {
+            self.rooms.remove(id);
+            self.messages.retain(|_, msg| &msg.room_id != id);
+            self.closed_rooms.insert(
+                id.clone(),
+                ClosedRoom {
+                    reason: reason.to_string(),
+                    closed_at: *closed_at,
+                },
+            );
+        }
+        closed.into_iter().map(|(id, _)| id).collect()
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn create_room(&self, room: &Room, secrets: &RoomSecrets) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.rooms.contains_key(&room.id) {
+            anyhow::bail!("Room {} already exists", room.id);
+        }
+        inner.rooms.insert(
+            room.id.clone(),
+            StoredRoom {
+                room: room.clone(),
+                secrets: secrets.clone(),
+                last_active_at: Utc::now(),
+                pruned_at: None,
+                roles: HashMap::new(),
+                visits: HashMap::new(),
+                bans: HashMap::new(),
+                moderation_log: Vec::new(),
+            },
+        );
+        Ok(())
+    }
+
+    async fn get_room(&self, room_id: &str) -> Result<Option<Room>> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner.rooms.get(room_id).map(|stored| stored.room.clone()))
+    }
+
+    async fn get_room_secrets(&self, room_id: &str) -> Result<Option<RoomSecrets>> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner
+            .rooms
+            .get(room_id)
+            .map(|stored| stored.secrets.clone()))
+    }
+
+    async fn set_topic(&self, room_id: &str, topic: Option<&str>) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(stored) = inner.rooms.get_mut(room_id) {
+            stored.room.topic = topic.map(str::to_string);
+        }
+        Ok(())
+    }
+
+    async fn set_slow_mode(&self, room_id: &str, seconds: u32) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(stored) = inner.rooms.get_mut(room_id) {
+            stored.room.slow_mode_seconds = seconds;
+        }
+        Ok(())
+    }
+
+    async fn touch_room(&self, room_id: &str) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(stored) = inner.rooms.get_mut(room_id) {
+            stored.last_active_at = Utc::now();
+        }
+        Ok(())
+    }
+
+    async fn get_closed_room(&self, room_id: &str) -> Result<Option<ClosedRoom>> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner.closed_rooms.get(room_id).cloned())
+    }
+
+    async fn delete_expired_rooms(&self) -> Result<Vec<String>> {
+        let now = Utc::now();
+        let mut inner = self.inner.lock().unwrap();
+        Ok(inner.close_rooms("expired", |stored| {
+            stored
+                .room
+                .expires_at
+                .filter(|expires_at| *expires_at <= now)
+        }))
+    }
+
+    async fn delete_idle_rooms(
+        &self,
+        idle: chrono::Duration,
+        active: &[String],
+    ) -> Result<Vec<String>> {
+        let now = Utc::now();
+        let cutoff = now - idle;
+        let mut inner = self.inner.lock().unwrap();
+        Ok(inner.close_rooms("idle", |stored| {
+            (stored.last_active_at < cutoff && !active.contains(&stored.room.id)).then_some(now)
+        }))
+    }
+
+    async fn prune_closed_rooms(&self, before: DateTime<Utc>) -> Result<u64> {
+        let mut inner = self.inner.lock().unwrap();
+        let count = inner.closed_rooms.len();
+        inner
+            .closed_rooms
+            .retain(|_, closed| closed.closed_at >= before);
+        Ok((count - inner.closed_rooms.len()) as u64)
+    }
+
+    async fn save_message(&self, msg: &ChatMessage) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        if !inner.rooms.contains_key(&msg.room_id) {
+            anyhow::bail!("Room {} does not exist", msg.room_id);
+        }
+        inner.next_seq += 1;
+        let seq = inner.next_seq;
+        inner.messages.insert(seq, msg.clone());
+        Ok(())
+    }
+
+    async fn get_message_history(&self, room_id: &str) -> Result<Vec<ChatMessage>> {
+        let inner = self.inner.lock().unwrap();
+        let mut messages: Vec<ChatMessage> = inner
+            .room_messages(room_id)
+            .map(|(_, msg)| msg.clone())
+            .collect();
+        messages.sort_by_key(|msg| msg.timestamp);
+        let skip = messages.len().saturating_sub(HISTORY_LIMIT);
+        Ok(messages.split_off(skip))
+    }
+
+    async fn get_messages_after(
+        &self,
+        room_id: &str,
+        after_seq: i64,
+        limit: i64,
+    ) -> Result<Vec<(i64, ChatMessage)>> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner
+            .messages
+            .range(after_seq + 1..)
+            .filter(|(_, msg)| msg.room_id == room_id)
+            .take(limit.max(0) as usize)
+            .map(|(seq, msg)| (*seq, msg.clone()))
+            .collect())
+    }
+
+    async fn search_messages(
+        &self,
+        room_id: &str,
+        query: &str,
+        limit: usize,
+    ) -> Result<Vec<SearchResult>> {
+        let inner = self.inner.lock().unwrap();
+        let messages = inner.room_messages(room_id).map(|(_, msg)| msg.clone());
+        Ok(rank_matches(messages, query, limit))
+    }
+
+    async fn find_user_ids(&self, room_id: &str, username: &str) -> Result<Vec<String>> {
+        let inner = self.inner.lock().unwrap();
+        let mut user_ids: Vec<String> = inner
+            .room_messages(room_id)
+            .filter(|(_, msg)| msg.username == username)
+            .map(|(_, msg)| msg.user_id.clone())
+            .collect();
+        user_ids.sort();
+        user_ids.dedup();
+        Ok(user_ids)
+    }
+
+    async fn prune_expired_messages(&self, batch: i64) -> Result<u64> {
+        let now = Utc::now();
+        let mut inner = self.inner.lock().unwrap();
+        let cutoffs: HashMap<String, DateTime<Utc>> = inner
+            .rooms
+            .iter()
+            .filter_map(|(id, stored)| match stored.room.retention {
+                Retention::Days(days) => {
+                    Some((id.clone(), now - chrono::Duration::days(days.into())))
+                }
+                _ => None,
+            })
+            .collect();
+        let expired: Vec<i64> = inner
+            .messages
+            .iter()
+            .filter(|(_, msg)| {
+                cutoffs
+                    .get(&msg.room_id)
+                    .is_some_and(|cutoff| msg.timestamp < *cutoff)
+            })
+            .map(|(seq, _)| *seq)
+            .take(batch.max(0) as usize)
+            .collect();
+        for seq in &expired {
+            inner.messages.remove(seq);
+        }
+        Ok(expired.len() as u64)
+    }
+
+    async fn rooms_to_trim(&self) -> Result<Vec<(String, i32)>> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner
+            .rooms
+            .iter()
+            .filter_map(|(id, stored)| match stored.room.retention {
+                Retention::Messages(count)
+                    if stored
+                        .pruned_at
+                        .is_none_or(|pruned_at| stored.last_active_at > pruned_at) =>
+                {
+                    Some((id.clone(), count as i32))
+                }
+                _ => None,
+            })
+            .collect())
+    }
+
+    async fn trim_room_messages(&self, room_id: &str, keep: i32, batch: i64) -> Result<u64> {
+        let mut inner = self.inner.lock().unwrap();
+        let mut messages: Vec<(i64, DateTime<Utc>)> = inner
+            .room_messages(room_id)
+            .map(|(seq, msg)| (*seq, msg.timestamp))
+            .collect();
+        messages.sort_by_key(|(_, timestamp)| std::cmp::Reverse(*timestamp));
+        let excess: Vec<i64> = messages
+            .into_iter()
+            .skip(keep.max(0) as usize)
+            .take(batch.max(0) as usize)
+            .map(|(seq, _)| seq)
+            .collect();
+        for seq in &excess {
+            inner.messages.remove(seq);
+        }
+        Ok(excess.len() as u64)
+    }
+
+    async fn mark_trimmed(&self, room_id: &str, at: DateTime<Utc>) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(stored) = inner.rooms.get_mut(room_id) {
+            stored.pruned_at = Some(at);
+        }
+        Ok(())
+    }
+
+    async fn bind_identity(
+        &self,
+        user_id: &str,
+        public_key: &str,
+        fingerprint: &str,
+    ) -> Result<String> {
+        let mut inner = self.inner.lock().unwrap();
+        let (bound, _) = inner
+            .identities
+            .entry(user_id.to_string())
+            .or_insert_with(|| (public_key.to_string(), fingerprint.to_string()));
+        Ok(bound.clone())
+    }
+
+    async fn identity_exists(&self, user_id: &str) -> Result<bool> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner.identities.contains_key(user_id))
+    }
+
+    async fn grant_role(&self, room_id: &str, user_id: &str, role: &str) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(stored) = inner.rooms.get_mut(room_id) {
+            stored.roles.insert(user_id.to_string(), role.to_string());
+        }
+        Ok(())
+    }
+
+    async fn is_owner(&self, room_id: &str, user_id: &str) -> Result<bool> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner
+            .rooms
+            .get(room_id)
+            .and_then(|stored| stored.roles.get(user_id))
+            .is_some_and(|role| role == "owner"))
+    }
+
+    async fn record_visit(&self, room_id: &str, user_id: &str, address: &str) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(stored) = inner.rooms.get_mut(room_id) {
+            stored
+                .visits
+                .insert(user_id.to_string(), address.to_string());
+        }
+        Ok(())
+    }
+
+    async fn add_sanction(&self, room_id: &str, sanction: &Sanction<'_>) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        let fingerprint = inner
+            .identities
+            .get(sanction.user_id)
+            .map(|(_, fingerprint)| fingerprint.clone());
+        if let Some(stored) = inner.rooms.get_mut(room_id) {
+            // A keyed user is pinned by their key, not their network
+            let address = fingerprint
+                .is_none()
+                .then(|| stored.visits.get(sanction.user_id).cloned())
+                .flatten();
+            stored.bans.insert(
+                (sanction.user_id.to_string(), sanction.action.as_str()),
+                StoredSanction {
+                    username: sanction.username.to_string(),
+                    fingerprint,
+                    address,
+                    reason: sanction.reason.map(str::to_string),
+                    expires_at: sanction.expires_at,
+                },
+            );
+        }
+        Ok(())
+    }
+
+    async fn active_sanction(
+        &self,
+        room_id: &str,
+        user_id: &str,
+        fingerprint: Option<&str>,
+        address: Option<&str>,
+        action: ModerationAction,
+    ) -> Result<Option<ActiveSanction>> {
+        let now = Utc::now();
+        let inner = self.inner.lock().unwrap();
+        let Some(stored) = inner.rooms.get(room_id) else {
+            return Ok(None);
+        };
+        let sanction = stored
+            .bans
+            .iter()
+            .filter(|((banned_id, kind), sanction)| {
+                *kind == action.as_str()
+                    && (banned_id == user_id
+                        || (fingerprint.is_some()
+                            && sanction.fingerprint.as_deref() == fingerprint)
+                        || (address.is_some() && sanction.address.as_deref() == address))
+                    && sanction
+                        .expires_at
+                        .is_none_or(|expires_at| expires_at > now)
+            })
+            // Permanent sanctions outlast any timed one
+            .max_by_key(|(_, sanction)| {
+                sanction
+                    .expires_at
+                    .map_or(DateTime::<Utc>::MAX_UTC, |at| at)
+            })
+            .map(|(_, sanction)| ActiveSanction {
+                reason: sanction.reason.clone(),
+                expires_at: sanction.expires_at,
+            });
+        Ok(sanction)
+    }
+
+    async fn sanctioned_user_ids(&self, room_id: &str, username: &str) -> Result<Vec<String>> {
+        let inner = self.inner.lock().unwrap();
+        let Some(stored) = inner.rooms.get(room_id) else {
+            return Ok(Vec::new());
+        };
+        let mut user_ids: Vec<String> = stored
+            .bans
+            .iter()
+            .filter(|(_, sanction)| sanction.username == username)
+            .map(|((user_id, _), _)| user_id.clone())
+            .collect();
+        user_ids.sort();
+        user_ids.dedup();
+        Ok(user_ids)
+    }
+
+    async fn lift_sanctions(&self, room_id: &str, user_id: &str) -> Result<bool> {
+        let mut inner = self.inner.lock().unwrap();
+        let Some(stored) = inner.rooms.get_mut(room_id) else {
+            return Ok(false);
+        };
+        let count = stored.bans.len();
+        stored.bans.retain(|(banned_id, _), _| banned_id != user_id);
+        Ok(stored.bans.len() < count)
+    }
+
+    async fn log_moderation(&self, room_id: &str, event: &ModerationEvent) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(stored) = inner.rooms.get_mut(room_id) {
+            stored.moderation_log.push(event.clone());
+        }
+        Ok(())
+    }
+
+    async fn get_moderation_log(&self, room_id: &str) -> Result<Vec<ModerationEvent>> {
+        let inner = self.inner.lock().unwrap();
+        let Some(stored) = inner.rooms.get(room_id) else {
+            return Ok(Vec::new());
+        };
+        let mut log = stored.moderation_log.clone();
+        log.sort_by_key(|event| event.timestamp);
+        let skip = log.len().saturating_sub(MODERATION_LOG_LIMIT);
+        Ok(log.split_off(skip))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    async fn store_with(messages: &[(&str, &str, &str)]) -> MemoryStorage {
+        let db = MemoryStorage::default();
+        for room_id in ["room-a", "room-b"] {
+            let room = Room::new(room_id.to_string());
+            db.create_room(&room, &RoomSecrets::default())
+                .await
+                .unwrap();
+        }
+        let start = Utc::now() - Duration::minutes(10);
+        for (i, (room_id, username, content)) in messages.iter().enumerate() {
+            let mut msg = ChatMessage::new(
+                room_id.to_string(),
+                format!("id-{}", username),
+                username.to_string(),
+                content.to_string(),
+            );
+            msg.timestamp = start + Duration::seconds(i as i64);
+            db.save_message(&msg).await.unwrap();
+        }
+        db
+    }
+
+    fn contents(results: &[SearchResult]) -> Vec<&str> {
+        results
+            .iter()
+            .map(|result| result.message.content.as_str())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn search_needs_every_term_and_ranks_by_hits() {
+        let db = store_with(&[
+            ("room-a", "alice", "deploy the build"),
+            (
+                "room-a",
+                "bob",
+                "Deploy failed, deploy again after the build",
+            ),
+            ("room-a", "carol", "deploy tomorrow"),
+            ("room-a", "dave", "lunch?"),
+        ])
+        .await;
+        let results = db
+            .search_messages("room-a", "BUILD deploy", 20)
+            .await
+            .unwrap();
+        assert_eq!(
+            contents(&results),
+            [
+                "Deploy failed, deploy again after the build",
+                "deploy the build"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn search_ties_go_to_the_newest() {
+        let db = store_with(&[
+            ("room-a", "alice", "first release"),
+            ("room-a", "bob", "second release"),
+        ])
+        .await;
+        let results = db.search_messages("room-a", "release", 20).await.unwrap();
+        assert_eq!(contents(&results), ["second release", "first release"]);
+    }
+
+    #[tokio::test]
+    async fn search_stays_in_its_room() {
+        let db = store_with(&[
+            ("room-a", "alice", "secret plans"),
+            ("room-b", "bob", "other plans"),
+        ])
+        .await;
+        let results = db.search_messages("room-b", "plans", 20).await.unwrap();
+        assert_eq!(contents(&results), ["other plans"]);
+        assert!(db
+            .search_messages("room-c", "plans", 20)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn search_highlights_terms_in_the_snippet() {
+        let db = store_with(&[("room-a", "alice", "The Cache is warm, cached twice")]).await;
+        let results = db.search_messages("room-a", "cache", 20).await.unwrap();
+        assert_eq!(
+            results[0].snippet,
+            "The **Cache** is warm, **cached** twice"
+        );
+    }
+
+    #[tokio::test]
+    async fn search_limits_results_and_ignores_empty_queries() {
+        let messages: Vec<(&str, &str, &str)> = (0..150)
+            .map(|_| ("room-a", "alice", "same thing again"))
+            .collect();
+        let db = store_with(&messages).await;
+        assert_eq!(
+            db.search_messages("room-a", "same", 5).await.unwrap().len(),
+            5
+        );
+        // Zero still finds something, and nobody gets more than the cap
+        assert_eq!(
+            db.search_messages("room-a", "same", 0).await.unwrap().len(),
+            1
+        );
+        let all = db.search_messages("room-a", "same", 1000).await.unwrap();
+        assert_eq!(all.len(), crate::db::MAX_SEARCH_LIMIT);
+        assert!(db
+            .search_messages("room-a", " ?! ", 20)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+}
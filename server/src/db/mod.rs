@@ -0,0 +1,264 @@
+//! Persistence behind the `Storage` trait. The backend is picked at startup
+//! from the `DATABASE_URL` scheme: `postgres://` and `sqlite:` (each behind
+//! the cargo feature of the same name) or `memory:`, which keeps everything
+//! in process and forgets it on restart.
+
+mod memory;
+#[cfg(feature = "postgres")]
+mod postgres;
+#[cfg(any(feature = "postgres", feature = "sqlite"))]
+mod rows;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use std::sync::Arc;
+use terma_shared::{ChatMessage, ModerationAction, ModerationEvent, Room, SearchResult};
+
+use crate::access::RoomSecrets;
+
+pub use memory::MemoryStorage;
+
+pub const DEFAULT_SEARCH_LIMIT: usize = 20;
+pub const MAX_SEARCH_LIMIT: usize = 100;
+
+/// Newest messages sent to a client when it joins
+const HISTORY_LIMIT: usize = 1000;
+
+/// Most recent moderation actions replayed on join
+const MODERATION_LOG_LIMIT: usize = 100;
+
+pub type Db = Arc<dyn Storage>;
+
+/// Open the store named by `database_url`, applying its migrations
+pub async fn connect(database_url: &str) -> Result<Db> {
+    if database_url.starts_with("postgres://") || database_url.starts_with("postgresql://") {
+        #[cfg(feature = "postgres")]
+        return Ok(Arc::new(
+            postgres::PostgresStorage::connect(database_url).await?,
+        ));
+        #[cfg(not(feature = "postgres"))]
+        bail!("This server was built without the postgres feature");
+    }
+    if database_url.starts_with("sqlite:") {
+        #[cfg(feature = "sqlite")]
+        return Ok(Arc::new(
+            sqlite::SqliteStorage::connect(database_url).await?,
+        ));
+        #[cfg(not(feature = "sqlite"))]
+        bail!("This server was built without the sqlite feature");
+    }
+    if database_url.starts_with("memory:") {
+        return Ok(Arc::new(MemoryStorage::default()));
+    }
+    bail!(
+        "Unsupported DATABASE_URL {:?}; use postgres://, sqlite: or memory:",
+        database_url
+    )
+}
+
+#[async_trait]
+pub trait Storage: Send + Sync {
+    // Rooms
+
+    async fn create_room(&self, room: &Room, secrets: &RoomSecrets) -> Result<()>;
+    async fn get_room(&self, room_id: &str) -> Result<Option<Room>>;
+    /// A room's password and token hashes, or `None` if the room doesn't exist
+    async fn get_room_secrets(&self, room_id: &str) -> Result<Option<RoomSecrets>>;
+    async fn set_topic(&self, room_id: &str, topic: Option<&str>) -> Result<()>;
+    async fn set_slow_mode(&self, room_id: &str, seconds: u32) -> Result<()>;
+    /// Mark a room as in use so the idle reaper leaves it alone
+    async fn touch_room(&self, room_id: &str) -> Result<()>;
+
+    // Lifecycle
+
+    async fn get_closed_room(&self, room_id: &str) -> Result<Option<ClosedRoom>>;
+    /// Delete rooms past their expiry, messages and all. Returns their IDs.
+    async fn delete_expired_rooms(&self) -> Result<Vec<String>>;
+    /// Delete rooms with no joins or messages for `idle`, except those in
+    /// `active` (rooms with someone connected). Returns their IDs.
+    async fn delete_idle_rooms(
+        &self,
+        idle: chrono::Duration,
+        active: &[String],
+    ) -> Result<Vec<String>>;
+    /// Forget rooms closed before `before`
+    async fn prune_closed_rooms(&self, before: DateTime<Utc>) -> Result<u64>;
+
+    // Messages
+
+    /// Store a message. It doesn't count as activity; the room's writer calls
+    /// `touch_room` now and then instead of on every message.
+    async fn save_message(&self, msg: &ChatMessage) -> Result<()>;
+    /// The newest messages in a room, oldest first
+    async fn get_message_history(&self, room_id: &str) -> Result<Vec<ChatMessage>>;
+    /// One page of a room's messages in insertion order, for streaming exports.
+    /// Returns each message with its sequence number to resume from.
+    async fn get_messages_after(
+        &self,
+        room_id: &str,
+        after_seq: i64,
+        limit: i64,
+    ) -> Result<Vec<(i64, ChatMessage)>>;
+    /// Full-text search over a single room's messages, best matches first
+    async fn search_messages(
+        &self,
+        room_id: &str,
+        query: &str,
+        limit: usize,
+    ) -> Result<Vec<SearchResult>>;
+    /// User IDs that have posted under `username`, for acting on someone who
+    /// has already left
+    async fn find_user_ids(&self, room_id: &str, username: &str) -> Result<Vec<String>>;
+
+    // Retention
+
+    /// Delete up to `batch` messages older than their room's day limit
+    async fn prune_expired_messages(&self, batch: i64) -> Result<u64>;
+    /// Rooms with a message cap that have had activity since they were last
+    /// trimmed, with their cap
+    async fn rooms_to_trim(&self) -> Result<Vec<(String, i32)>>;
+    /// Delete up to `batch` of a room's messages beyond the newest `keep`
+    async fn trim_room_messages(&self, room_id: &str, keep: i32, batch: i64) -> Result<u64>;
+    async fn mark_trimmed(&self, room_id: &str, at: DateTime<Utc>) -> Result<()>;
+
+    // Identities
+
+    /// Bind `user_id` to a public key on first use. Returns the key the user
+    /// ID is bound to, which differs from `public_key` if someone else
+    /// claimed it.
+    async fn bind_identity(
+        &self,
+        user_id: &str,
+        public_key: &str,
+        fingerprint: &str,
+    ) -> Result<String>;
+    async fn identity_exists(&self, user_id: &str) -> Result<bool>;
+
+    // Moderation
+
+    async fn grant_role(&self, room_id: &str, user_id: &str, role: &str) -> Result<()>;
+    async fn is_owner(&self, room_id: &str, user_id: &str) -> Result<bool>;
+    /// Remember the address a user joined a room from
+    async fn record_visit(&self, room_id: &str, user_id: &str, address: &str) -> Result<()>;
+    /// Record a sanction, replacing any earlier one of the same kind. The
+    /// user's key fingerprint is copied from their identity, so a new user ID
+    /// won't dodge it. Users without a key are pinned by the address of their
+    /// last visit instead; a keyed user's address is left out, since it would
+    /// catch everyone keyless behind the same NAT.
+    async fn add_sanction(&self, room_id: &str, sanction: &Sanction<'_>) -> Result<()>;
+    /// The longest-lasting unexpired sanction of `action`'s kind on a user ID,
+    /// a key or an address. Pass the address only for users without a key.
+    async fn active_sanction(
+        &self,
+        room_id: &str,
+        user_id: &str,
+        fingerprint: Option<&str>,
+        address: Option<&str>,
+        action: ModerationAction,
+    ) -> Result<Option<ActiveSanction>>;
+    /// User IDs with a sanction recorded under `username`
+    async fn sanctioned_user_ids(&self, room_id: &str, username: &str) -> Result<Vec<String>>;
+    /// Lift every sanction on a user ID; returns whether there were any
+    async fn lift_sanctions(&self, room_id: &str, user_id: &str) -> Result<bool>;
+    async fn log_moderation(&self, room_id: &str, event: &ModerationEvent) -> Result<()>;
+    /// The most recent moderation actions in a room, oldest first
+    async fn get_moderation_log(&self, room_id: &str) -> Result<Vec<ModerationEvent>>;
+}
+
+/// Why the reaper removed a room, and when
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct ClosedRoom {
+    pub reason: String,
+    pub closed_at: DateTime<Utc>,
+}
+
+/// A ban, mute or kick cooldown on one user ID
+pub struct Sanction<'a> {
+    pub user_id: &'a str,
+    pub username: &'a str,
+    pub action: ModerationAction,
+    pub reason: Option<&'a str>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct ActiveSanction {
+    pub reason: Option<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// Lowercase words of a search query
+fn search_terms(query: &str) -> Vec<String> {
+    query
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+/// Plain substring search for the backends without a full-text index: every
+/// term must appear. Returns a snippet with the terms wrapped in `**`, and a
+/// score (total term occurrences) to rank by.
+fn match_message(content: &str, terms: &[String]) -> Option<(String, usize)> {
+    const SNIPPET_WORDS: usize = 24;
+
+    if terms.is_empty() {
+        return None;
+    }
+    let lowered = content.to_lowercase();
+    if !terms.iter().all(|term| lowered.contains(term.as_str())) {
+        return None;
+    }
+
+    let words: Vec<&str> = content.split_whitespace().collect();
+    let hits = |word: &str| {
+        let word = word.to_lowercase();
+        terms.iter().any(|term| word.contains(term.as_str()))
+    };
+    let score = words.iter().filter(|word| hits(word)).count();
+    let first = words.iter().position(|word| hits(word)).unwrap_or(0);
+    let start = first.saturating_sub(SNIPPET_WORDS / 3);
+    let snippet = words
+        .iter()
+        .skip(start)
+        .take(SNIPPET_WORDS)
+        .map(|word| {
+            if hits(word) {
+                format!("**{}**", word)
+            } else {
+                word.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ");
+    Some((snippet, score))
+}
+
+/// Rank substring matches and keep the best `limit`
+fn rank_matches(
+    messages: impl IntoIterator<Item = ChatMessage>,
+    query: &str,
+    limit: usize,
+) -> Vec<SearchResult> {
+    let terms = search_terms(query);
+    let mut matches: Vec<(usize, SearchResult)> = messages
+        .into_iter()
+        .filter_map(|message| {
+            let (snippet, score) = match_message(&message.content, &terms)?;
+            Some((score, SearchResult { message, snippet }))
+        })
+        .collect();
+    matches.sort_by(|(a_score, a), (b_score, b)| {
+        b_score
+            .cmp(a_score)
+            .then(b.message.timestamp.cmp(&a.message.timestamp))
+    });
+    matches
+        .into_iter()
+        .take(limit.clamp(1, MAX_SEARCH_LIMIT))
+        .map(|(_, result)| result)
+        .collect()
+}
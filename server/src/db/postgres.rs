@@ -0,0 +1,528 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+use terma_shared::{ChatMessage, ModerationAction, ModerationEvent, Room, SearchResult};
+use uuid::Uuid;
+
+use super::rows::{retention_columns, MessageRow, ModerationRow, PagedMessageRow, RoomRow};
+use super::{
+    ActiveSanction, ClosedRoom, Sanction, Storage, HISTORY_LIMIT, MAX_SEARCH_LIMIT,
+    MODERATION_LOG_LIMIT,
+};
+use crate::access::RoomSecrets;
+
+pub struct PostgresStorage {
+    pool: PgPool,
+}
+
+impl PostgresStorage {
+    pub async fn connect(database_url: &str) -> Result<Self> {
+        let pool = PgPool::connect(database_url).await?;
+
+        sqlx::migrate!("./migrations/postgres").run(&pool).await?;
+
+        Ok(Self { pool })
+    }
+}
+
+#[async_trait]
+impl Storage for PostgresStorage {
+    async fn create_room(&self, room: &Room, secrets: &RoomSecrets) -> Result<()> {
+        let (retention_messages, retention_days) = retention_columns(room.retention);
+        sqlx::query(
+            "INSERT INTO rooms (id, created_at, name, topic, motd, expires_at, password_hash,
+                                join_token_hash, owner_token_hash, encrypted, retention_messages,
+                                retention_days)
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)",
+        )
+        .bind(&room.id)
+        .bind(room.created_at)
+        .bind(&room.name)
+        .bind(&room.topic)
+        .bind(&room.motd)
+        .bind(room.expires_at)
+        .bind(&secrets.password_hash)
+        .bind(&secrets.join_token_hash)
+        .bind(&secrets.owner_token_hash)
+        .bind(room.encrypted)
+        .bind(retention_messages)
+        .bind(retention_days)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn get_room(&self, room_id: &str) -> Result<Option<Room>> {
+        let room = sqlx::query_as::<_, RoomRow>(
+            "SELECT id, created_at, name, topic, motd, expires_at,
+                    password_hash IS NOT NULL AS password_protected, encrypted, slow_mode_seconds,
+                    retention_messages, retention_days
+             FROM rooms WHERE id = $1",
+        )
+        .bind(room_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(room.map(Room::from))
+    }
+
+    async fn get_closed_room(&self, room_id: &str) -> Result<Option<ClosedRoom>> {
+        let closed = sqlx::query_as::<_, ClosedRoom>(
+            "SELECT reason, closed_at FROM closed_rooms WHERE id = $1",
+        )
+        .bind(room_id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(closed)
+    }
+
+    async fn touch_room(&self, room_id: &str) -> Result<()> {
+        sqlx::query("UPDATE rooms SET last_active_at = NOW() WHERE id = $1")
+            .bind(room_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn delete_expired_rooms(&self) -> Result<Vec<String>> {
+        let ids = sqlx::query_scalar::<_, String>(
+            "WITH gone AS (
+                 DELETE FROM rooms WHERE expires_at <= NOW() RETURNING id, expires_at
+             )
+             INSERT INTO closed_rooms (id, reason, closed_at)
+             SELECT id, 'expired', expires_at FROM gone
+             ON CONFLICT (id) DO UPDATE SET reason = EXCLUDED.reason, closed_at = EXCLUDED.closed_at
+             RETURNING id",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(ids)
+    }
+
+    async fn delete_idle_rooms(
+        &self,
+        idle: chrono::Duration,
+        active: &[String],
+    ) -> Result<Vec<String>> {
+        let ids = sqlx::query_scalar::<_, String>(
+            "WITH gone AS (
+                 DELETE FROM rooms
+                 WHERE last_active_at < $1 AND id <> ALL($2)
+                 RETURNING id
+             )
+             INSERT INTO closed_rooms (id, reason, closed_at)
+             SELECT id, 'idle', NOW() FROM gone
+             ON CONFLICT (id) DO UPDATE SET reason = EXCLUDED.reason, closed_at = EXCLUDED.closed_at
+             RETURNING id",
+        )
+        .bind(Utc::now() - idle)
+        .bind(active)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(ids)
+    }
+
+    async fn prune_closed_rooms(&self, before: DateTime<Utc>) -> Result<u64> {
+        let result = sqlx::query("DELETE FROM closed_rooms WHERE closed_at < $1")
+            .bind(before)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn set_topic(&self, room_id: &str, topic: Option<&str>) -> Result<()> {
+        sqlx::query("UPDATE rooms SET topic = $1 WHERE id = $2")
+            .bind(topic)
+            .bind(room_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn set_slow_mode(&self, room_id: &str, seconds: u32) -> Result<()> {
+        sqlx::query("UPDATE rooms SET slow_mode_seconds = $1 WHERE id = $2")
+            .bind(seconds as i32)
+            .bind(room_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_room_secrets(&self, room_id: &str) -> Result<Option<RoomSecrets>> {
+        let secrets = sqlx::query_as::<_, RoomSecrets>(
+            "SELECT password_hash, join_token_hash, owner_token_hash FROM rooms WHERE id = $1",
+        )
+        .bind(room_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(secrets)
+    }
+
+    async fn save_message(&self, msg: &ChatMessage) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO messages (message_id, room_id, user_id, username, content, timestamp, fingerprint)
+             VALUES ($1, $2, $3, $4, $5, $6, $7)",
+        )
+        .bind(msg.id)
+        .bind(&msg.room_id)
+        .bind(&msg.user_id)
+        .bind(&msg.username)
+        .bind(&msg.content)
+        .bind(msg.timestamp)
+        .bind(&msg.fingerprint)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn get_message_history(&self, room_id: &str) -> Result<Vec<ChatMessage>> {
+        let messages = sqlx::query_as::<_, MessageRow>(
+            "SELECT * FROM (
+                 SELECT message_id, room_id, user_id, username, content, timestamp, fingerprint
+                 FROM messages
+                 WHERE room_id = $1
+                 ORDER BY timestamp DESC
+                 LIMIT $2
+             ) recent
+             ORDER BY timestamp ASC",
+        )
+        .bind(room_id)
+        .bind(HISTORY_LIMIT as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(messages.into_iter().map(ChatMessage::from).collect())
+    }
+
+    async fn prune_expired_messages(&self, batch: i64) -> Result<u64> {
+        let result = sqlx::query(
+            "DELETE FROM messages WHERE id IN (
+                 SELECT m.id FROM messages m JOIN rooms r ON r.id = m.room_id
+                 WHERE r.retention_days IS NOT NULL
+                   AND m.timestamp < NOW() - make_interval(days => r.retention_days)
+                 LIMIT $1
+             )",
+        )
+        .bind(batch)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn rooms_to_trim(&self) -> Result<Vec<(String, i32)>> {
+        let rooms = sqlx::query_as::<_, (String, i32)>(
+            "SELECT id, retention_messages FROM rooms
+             WHERE retention_messages IS NOT NULL
+               AND last_active_at > COALESCE(pruned_at, '-infinity')",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rooms)
+    }
+
+    async fn trim_room_messages(&self, room_id: &str, keep: i32, batch: i64) -> Result<u64> {
+        let result = sqlx::query(
+            "DELETE FROM messages WHERE id IN (
+                 SELECT id FROM messages
+                 WHERE room_id = $1
+                 ORDER BY timestamp DESC
+                 OFFSET $2
+                 LIMIT $3
+             )",
+        )
+        .bind(room_id)
+        .bind(i64::from(keep))
+        .bind(batch)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn mark_trimmed(&self, room_id: &str, at: DateTime<Utc>) -> Result<()> {
+        sqlx::query("UPDATE rooms SET pruned_at = $1 WHERE id = $2")
+            .bind(at)
+            .bind(room_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn bind_identity(
+        &self,
+        user_id: &str,
+        public_key: &str,
+        fingerprint: &str,
+    ) -> Result<String> {
+        sqlx::query(
+            "INSERT INTO identities (user_id, public_key, fingerprint)
+             VALUES ($1, $2, $3)
+             ON CONFLICT (user_id) DO NOTHING",
+        )
+        .bind(user_id)
+        .bind(public_key)
+        .bind(fingerprint)
+        .execute(&self.pool)
+        .await?;
+
+        let bound =
+            sqlx::query_scalar::<_, String>("SELECT public_key FROM identities WHERE user_id = $1")
+                .bind(user_id)
+                .fetch_one(&self.pool)
+                .await?;
+
+        Ok(bound)
+    }
+
+    async fn identity_exists(&self, user_id: &str) -> Result<bool> {
+        let count =
+            sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM identities WHERE user_id = $1")
+                .bind(user_id)
+                .fetch_one(&self.pool)
+                .await?;
+
+        Ok(count > 0)
+    }
+
+    async fn grant_role(&self, room_id: &str, user_id: &str, role: &str) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO room_roles (room_id, user_id, role)
+             VALUES ($1, $2, $3)
+             ON CONFLICT (room_id, user_id) DO UPDATE SET role = EXCLUDED.role",
+        )
+        .bind(room_id)
+        .bind(user_id)
+        .bind(role)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn is_owner(&self, room_id: &str, user_id: &str) -> Result<bool> {
+        let count = sqlx::query_scalar::<_, i64>(
+            "SELECT COUNT(*) FROM room_roles WHERE room_id = $1 AND user_id = $2 AND role = 'owner'",
+        )
+        .bind(room_id)
+        .bind(user_id)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(count > 0)
+    }
+
+    async fn record_visit(&self, room_id: &str, user_id: &str, address: &str) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO room_visits (room_id, user_id, address) VALUES ($1, $2, $3)
+             ON CONFLICT (room_id, user_id) DO UPDATE
+             SET address = EXCLUDED.address, seen_at = NOW()",
+        )
+        .bind(room_id)
+        .bind(user_id)
+        .bind(address)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn add_sanction(&self, room_id: &str, sanction: &Sanction<'_>) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO room_bans
+                 (room_id, user_id, kind, username, fingerprint, address, reason, expires_at)
+             VALUES ($1, $2, $3, $4,
+                     (SELECT fingerprint FROM identities WHERE user_id = $2),
+                     (SELECT address FROM room_visits
+                      WHERE room_id = $1 AND user_id = $2
+                        AND NOT EXISTS (SELECT 1 FROM identities WHERE user_id = $2)),
+                     $5, $6)
+             ON CONFLICT (room_id, user_id, kind) DO UPDATE
+             SET username = EXCLUDED.username, fingerprint = EXCLUDED.fingerprint,
+                 address = EXCLUDED.address, reason = EXCLUDED.reason,
+                 expires_at = EXCLUDED.expires_at, created_at = NOW()",
+        )
+        .bind(room_id)
+        .bind(sanction.user_id)
+        .bind(sanction.action.as_str())
+        .bind(sanction.username)
+        .bind(sanction.reason)
+        .bind(sanction.expires_at)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn active_sanction(
+        &self,
+        room_id: &str,
+        user_id: &str,
+        fingerprint: Option<&str>,
+        address: Option<&str>,
+        action: ModerationAction,
+    ) -> Result<Option<ActiveSanction>> {
+        let sanction = sqlx::query_as::<_, ActiveSanction>(
+            "SELECT reason, expires_at FROM room_bans
+             WHERE room_id = $1
+               AND (user_id = $2 OR fingerprint = $3 OR address = $5)
+               AND kind = $4
+               AND (expires_at IS NULL OR expires_at > NOW())
+             ORDER BY expires_at DESC NULLS FIRST
+             LIMIT 1",
+        )
+        .bind(room_id)
+        .bind(user_id)
+        .bind(fingerprint)
+        .bind(action.as_str())
+        .bind(address)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(sanction)
+    }
+
+    async fn sanctioned_user_ids(&self, room_id: &str, username: &str) -> Result<Vec<String>> {
+        let user_ids = sqlx::query_scalar::<_, String>(
+            "SELECT DISTINCT user_id FROM room_bans WHERE room_id = $1 AND username = $2",
+        )
+        .bind(room_id)
+        .bind(username)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(user_ids)
+    }
+
+    async fn lift_sanctions(&self, room_id: &str, user_id: &str) -> Result<bool> {
+        let result = sqlx::query("DELETE FROM room_bans WHERE room_id = $1 AND user_id = $2")
+            .bind(room_id)
+            .bind(user_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn find_user_ids(&self, room_id: &str, username: &str) -> Result<Vec<String>> {
+        let user_ids = sqlx::query_scalar::<_, String>(
+            "SELECT DISTINCT user_id FROM messages WHERE room_id = $1 AND username = $2",
+        )
+        .bind(room_id)
+        .bind(username)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(user_ids)
+    }
+
+    async fn log_moderation(&self, room_id: &str, event: &ModerationEvent) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO moderation_log
+                 (id, room_id, action, moderator, target_id, target, reason, expires_at, timestamp)
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)",
+        )
+        .bind(Uuid::new_v4())
+        .bind(room_id)
+        .bind(event.action.as_str())
+        .bind(&event.moderator)
+        .bind(&event.target_id)
+        .bind(&event.target)
+        .bind(&event.reason)
+        .bind(event.expires_at)
+        .bind(event.timestamp)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn get_moderation_log(&self, room_id: &str) -> Result<Vec<ModerationEvent>> {
+        let rows = sqlx::query_as::<_, ModerationRow>(
+            "SELECT action, moderator, target_id, target, reason, expires_at, timestamp
+             FROM (
+                 SELECT * FROM moderation_log
+                 WHERE room_id = $1
+                 ORDER BY timestamp DESC
+                 LIMIT $2
+             ) recent
+             ORDER BY timestamp ASC",
+        )
+        .bind(room_id)
+        .bind(MODERATION_LOG_LIMIT as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .filter_map(ModerationRow::into_event)
+            .collect())
+    }
+
+    async fn get_messages_after(
+        &self,
+        room_id: &str,
+        after_seq: i64,
+        limit: i64,
+    ) -> Result<Vec<(i64, ChatMessage)>> {
+        let rows = sqlx::query_as::<_, PagedMessageRow>(
+            "SELECT id AS seq, message_id, room_id, user_id, username, content, timestamp, fingerprint
+             FROM messages
+             WHERE room_id = $1 AND id > $2
+             ORDER BY id ASC
+             LIMIT $3",
+        )
+        .bind(room_id)
+        .bind(after_seq)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| (row.seq, row.message.into()))
+            .collect())
+    }
+
+    async fn search_messages(
+        &self,
+        room_id: &str,
+        query: &str,
+        limit: usize,
+    ) -> Result<Vec<SearchResult>> {
+        let limit = limit.clamp(1, MAX_SEARCH_LIMIT) as i64;
+
+        let rows = sqlx::query_as::<_, SearchRow>(
+            "SELECT m.message_id, m.room_id, m.user_id, m.username, m.content, m.timestamp, m.fingerprint,
+                    ts_headline('english', m.content, q,
+                        'StartSel=**, StopSel=**, MaxWords=24, MinWords=8, MaxFragments=1') AS snippet
+             FROM messages m, websearch_to_tsquery('english', $2) q
+             WHERE m.room_id = $1
+               AND to_tsvector('english', m.content) @@ q
+             ORDER BY ts_rank(to_tsvector('english', m.content), q) DESC, m.timestamp DESC
+             LIMIT $3",
+        )
+        .bind(room_id)
+        .bind(query)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| SearchResult {
+                snippet: row.snippet,
+                message: row.message.into(),
+            })
+            .collect())
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct SearchRow {
+    #[sqlx(flatten)]
+    message: MessageRow,
+    snippet: String,
+}
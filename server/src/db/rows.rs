@@ -0,0 +1,113 @@
+//! Rows shared by the SQL backends
+
+use chrono::{DateTime, Utc};
+use terma_shared::{ChatMessage, ModerationAction, ModerationEvent, Retention, Room};
+use uuid::Uuid;
+
+#[derive(sqlx::FromRow)]
+pub(super) struct RoomRow {
+    id: String,
+    created_at: DateTime<Utc>,
+    name: Option<String>,
+    topic: Option<String>,
+    motd: Option<String>,
+    expires_at: Option<DateTime<Utc>>,
+    password_protected: bool,
+    encrypted: bool,
+    slow_mode_seconds: i32,
+    retention_messages: Option<i32>,
+    retention_days: Option<i32>,
+}
+
+#[derive(sqlx::FromRow)]
+pub(super) struct MessageRow {
+    message_id: Uuid,
+    room_id: String,
+    user_id: String,
+    username: String,
+    content: String,
+    timestamp: DateTime<Utc>,
+    fingerprint: Option<String>,
+}
+
+#[derive(sqlx::FromRow)]
+pub(super) struct ModerationRow {
+    action: String,
+    moderator: String,
+    target_id: String,
+    target: String,
+    reason: Option<String>,
+    expires_at: Option<DateTime<Utc>>,
+    timestamp: DateTime<Utc>,
+}
+
+#[derive(sqlx::FromRow)]
+pub(super) struct PagedMessageRow {
+    pub(super) seq: i64,
+    #[sqlx(flatten)]
+    pub(super) message: MessageRow,
+}
+
+impl From<RoomRow> for Room {
+    fn from(row: RoomRow) -> Self {
+        Room {
+            id: row.id,
+            created_at: row.created_at,
+            name: row.name,
+            topic: row.topic,
+            motd: row.motd,
+            expires_at: row.expires_at,
+            password_protected: row.password_protected,
+            encrypted: row.encrypted,
+            slow_mode_seconds: row.slow_mode_seconds.max(0) as u32,
+            retention: retention_from_columns(row.retention_messages, row.retention_days),
+        }
+    }
+}
+
+impl ModerationRow {
+    /// `None` for actions this build doesn't know about
+    pub(super) fn into_event(self) -> Option<ModerationEvent> {
+        Some(ModerationEvent {
+            action: ModerationAction::parse(&self.action)?,
+            moderator: self.moderator,
+            target_id: self.target_id,
+            target: self.target,
+            reason: self.reason,
+            expires_at: self.expires_at,
+            timestamp: self.timestamp,
+        })
+    }
+}
+
+impl From<MessageRow> for ChatMessage {
+    fn from(row: MessageRow) -> Self {
+        ChatMessage {
+            id: row.message_id,
+            room_id: row.room_id,
+            user_id: row.user_id,
+            username: row.username,
+            content: row.content,
+            timestamp: row.timestamp,
+            fingerprint: row.fingerprint,
+        }
+    }
+}
+
+/// A retention policy as the `retention_messages` and `retention_days`
+/// columns; neither set means unlimited
+pub(super) fn retention_columns(retention: Retention) -> (Option<i32>, Option<i32>) {
+    match retention {
+        Retention::Messages(count) => (Some(count as i32), None),
+        Retention::Days(days) => (None, Some(days as i32)),
+        Retention::Unlimited => (None, None),
+    }
+}
+
+fn retention_from_columns(messages: Option<i32>, days: Option<i32>) -> Retention {
+    match (messages, days) {
+        (Some(count), _) => Retention::Messages(count.max(0) as u32),
+        (None, Some(days)) => Retention::Days(days.max(0) as u32),
+        (None, None) => Retention::Unlimited,
+    }
+}
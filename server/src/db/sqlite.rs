@@ -0,0 +1,573 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePool};
+use sqlx::QueryBuilder;
+use std::str::FromStr;
+use terma_shared::{ChatMessage, ModerationAction, ModerationEvent, Room, SearchResult};
+use uuid::Uuid;
+
+use super::rows::{retention_columns, MessageRow, ModerationRow, PagedMessageRow, RoomRow};
+use super::{
+    rank_matches, search_terms, ActiveSanction, ClosedRoom, Sanction, Storage, HISTORY_LIMIT,
+    MODERATION_LOG_LIMIT,
+};
+use crate::access::RoomSecrets;
+
+/// Messages scanned per search before ranking. SQLite has no full-text index
+/// here, so matches are filtered with LIKE and ranked in Rust.
+const SEARCH_SCAN_LIMIT: i64 = 5000;
+
+const MESSAGE_COLUMNS: &str =
+    "message_id, room_id, user_id, username, content, timestamp, fingerprint";
+
+pub struct SqliteStorage {
+    pool: SqlitePool,
+}
+
+impl SqliteStorage {
+    pub async fn connect(database_url: &str) -> Result<Self> {
+        let options = SqliteConnectOptions::from_str(database_url)?
+            .create_if_missing(true)
+            .foreign_keys(true)
+            .journal_mode(SqliteJournalMode::Wal);
+        let pool = SqlitePool::connect_with(options).await?;
+
+        sqlx::migrate!("./migrations/sqlite").run(&pool).await?;
+
+        Ok(Self { pool })
+    }
+
+    /// Delete rooms and record why and when they closed, in one transaction
+    async fn close_rooms(
+        &self,
+        closed: Vec<(String, DateTime<Utc>)>,
+        reason: &str,
+    ) -> Result<Vec<String>> {
+        let mut tx = self.pool.begin().await?;
+        for (id, closed_at) in &closed {
+            sqlx::query("DELETE FROM rooms WHERE id = ?")
+                .bind(id)
+                .execute(&mut *tx)
+                .await?;
+            sqlx::query(
+                "INSERT INTO closed_rooms (id, reason, closed_at) VALUES (?, ?, ?)
+                 ON CONFLICT (id) DO UPDATE SET reason = excluded.reason, closed_at = excluded.closed_at",
+            )
+            .bind(id)
+            .bind(reason)
+            .bind(closed_at)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(closed.into_iter().map(|(id, _)| id).collect())
+    }
+}
+
+#[async_trait]
+impl Storage for SqliteStorage {
+    async fn create_room(&self, room: &Room, secrets: &RoomSecrets) -> Result<()> {
+        let (retention_messages, retention_days) = retention_columns(room.retention);
+        sqlx::query(
+            "INSERT INTO rooms (id, created_at, name, topic, motd, expires_at, password_hash,
+                                join_token_hash, owner_token_hash, encrypted, retention_messages,
+                                retention_days, last_active_at)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(&room.id)
+        .bind(room.created_at)
+        .bind(&room.name)
+        .bind(&room.topic)
+        .bind(&room.motd)
+        .bind(room.expires_at)
+        .bind(&secrets.password_hash)
+        .bind(&secrets.join_token_hash)
+        .bind(&secrets.owner_token_hash)
+        .bind(room.encrypted)
+        .bind(retention_messages)
+        .bind(retention_days)
+        .bind(Utc::now())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn get_room(&self, room_id: &str) -> Result<Option<Room>> {
+        let room = sqlx::query_as::<_, RoomRow>(
+            "SELECT id, created_at, name, topic, motd, expires_at,
+                    password_hash IS NOT NULL AS password_protected, encrypted, slow_mode_seconds,
+                    retention_messages, retention_days
+             FROM rooms WHERE id = ?",
+        )
+        .bind(room_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(room.map(Room::from))
+    }
+
+    async fn get_room_secrets(&self, room_id: &str) -> Result<Option<RoomSecrets>> {
+        let secrets = sqlx::query_as::<_, RoomSecrets>(
+            "SELECT password_hash, join_token_hash, owner_token_hash FROM rooms WHERE id = ?",
+        )
+        .bind(room_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(secrets)
+    }
+
+    async fn set_topic(&self, room_id: &str, topic: Option<&str>) -> Result<()> {
+        sqlx::query("UPDATE rooms SET topic = ? WHERE id = ?")
+            .bind(topic)
+            .bind(room_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn set_slow_mode(&self, room_id: &str, seconds: u32) -> Result<()> {
+        sqlx::query("UPDATE rooms SET slow_mode_seconds = ? WHERE id = ?")
+            .bind(seconds as i32)
+            .bind(room_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn touch_room(&self, room_id: &str) -> Result<()> {
+        sqlx::query("UPDATE rooms SET last_active_at = ? WHERE id = ?")
+            .bind(Utc::now())
+            .bind(room_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_closed_room(&self, room_id: &str) -> Result<Option<ClosedRoom>> {
+        let closed = sqlx::query_as::<_, ClosedRoom>(
+            "SELECT reason, closed_at FROM closed_rooms WHERE id = ?",
+        )
+        .bind(room_id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(closed)
+    }
+
+    async fn delete_expired_rooms(&self) -> Result<Vec<String>> {
+        let expired = sqlx::query_as::<_, (String, DateTime<Utc>)>(
+            "SELECT id, expires_at FROM rooms WHERE expires_at <= ?",
+        )
+        .bind(Utc::now())
+        .fetch_all(&self.pool)
+        .await?;
+        self.close_rooms(expired, "expired").await
+    }
+
+    async fn delete_idle_rooms(
+        &self,
+        idle: chrono::Duration,
+        active: &[String],
+    ) -> Result<Vec<String>> {
+        let now = Utc::now();
+        let idle = sqlx::query_scalar::<_, String>(
+            "SELECT id FROM rooms
+             WHERE last_active_at < ? AND id NOT IN (SELECT value FROM json_each(?))",
+        )
+        .bind(now - idle)
+        .bind(serde_json::to_string(active)?)
+        .fetch_all(&self.pool)
+        .await?;
+        self.close_rooms(idle.into_iter().map(|id| (id, now)).collect(), "idle")
+            .await
+    }
+
+    async fn prune_closed_rooms(&self, before: DateTime<Utc>) -> Result<u64> {
+        let result = sqlx::query("DELETE FROM closed_rooms WHERE closed_at < ?")
+            .bind(before)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn save_message(&self, msg: &ChatMessage) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO messages (message_id, room_id, user_id, username, content, timestamp, fingerprint)
+             VALUES (?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(msg.id)
+        .bind(&msg.room_id)
+        .bind(&msg.user_id)
+        .bind(&msg.username)
+        .bind(&msg.content)
+        .bind(msg.timestamp)
+        .bind(&msg.fingerprint)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn get_message_history(&self, room_id: &str) -> Result<Vec<ChatMessage>> {
+        let messages = sqlx::query_as::<_, MessageRow>(&format!(
+            "SELECT * FROM (
+                 SELECT {MESSAGE_COLUMNS}
+                 FROM messages
+                 WHERE room_id = ?
+                 ORDER BY timestamp DESC
+                 LIMIT ?
+             ) recent
+             ORDER BY timestamp ASC"
+        ))
+        .bind(room_id)
+        .bind(HISTORY_LIMIT as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(messages.into_iter().map(ChatMessage::from).collect())
+    }
+
+    async fn get_messages_after(
+        &self,
+        room_id: &str,
+        after_seq: i64,
+        limit: i64,
+    ) -> Result<Vec<(i64, ChatMessage)>> {
+        let rows = sqlx::query_as::<_, PagedMessageRow>(&format!(
+            "SELECT id AS seq, {MESSAGE_COLUMNS}
+             FROM messages
+             WHERE room_id = ? AND id > ?
+             ORDER BY id ASC
+             LIMIT ?"
+        ))
+        .bind(room_id)
+        .bind(after_seq)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| (row.seq, row.message.into()))
+            .collect())
+    }
+
+    async fn search_messages(
+        &self,
+        room_id: &str,
+        query: &str,
+        limit: usize,
+    ) -> Result<Vec<SearchResult>> {
+        let terms = search_terms(query);
+        if terms.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        // Terms are alphanumeric, so none of them are LIKE wildcards
+        let mut builder = QueryBuilder::new(format!(
+            "SELECT {MESSAGE_COLUMNS} FROM messages WHERE room_id = "
+        ));
+        builder.push_bind(room_id);
+        for term in &terms {
+            builder
+                .push(" AND content LIKE ")
+                .push_bind(format!("%{}%", term));
+        }
+        builder
+            .push(" ORDER BY timestamp DESC LIMIT ")
+            .push_bind(SEARCH_SCAN_LIMIT);
+
+        let rows = builder
+            .build_query_as::<MessageRow>()
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(rank_matches(
+            rows.into_iter().map(ChatMessage::from),
+            query,
+            limit,
+        ))
+    }
+
+    async fn find_user_ids(&self, room_id: &str, username: &str) -> Result<Vec<String>> {
+        let user_ids = sqlx::query_scalar::<_, String>(
+            "SELECT DISTINCT user_id FROM messages WHERE room_id = ? AND username = ?",
+        )
+        .bind(room_id)
+        .bind(username)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(user_ids)
+    }
+
+    async fn prune_expired_messages(&self, batch: i64) -> Result<u64> {
+        // Cutoffs are computed here so they compare as the same RFC 3339 text
+        let rooms = sqlx::query_as::<_, (String, i32)>(
+            "SELECT id, retention_days FROM rooms WHERE retention_days IS NOT NULL",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let now = Utc::now();
+        let mut deleted = 0;
+        for (room_id, days) in rooms {
+            if deleted >= batch as u64 {
+                break;
+            }
+            let result = sqlx::query(
+                "DELETE FROM messages WHERE id IN (
+                     SELECT id FROM messages WHERE room_id = ? AND timestamp < ? LIMIT ?
+                 )",
+            )
+            .bind(&room_id)
+            .bind(now - chrono::Duration::days(days.into()))
+            .bind(batch - deleted as i64)
+            .execute(&self.pool)
+            .await?;
+            deleted += result.rows_affected();
+        }
+        Ok(deleted)
+    }
+
+    async fn rooms_to_trim(&self) -> Result<Vec<(String, i32)>> {
+        let rooms = sqlx::query_as::<_, (String, i32)>(
+            "SELECT id, retention_messages FROM rooms
+             WHERE retention_messages IS NOT NULL
+               AND last_active_at > COALESCE(pruned_at, '')",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rooms)
+    }
+
+    async fn trim_room_messages(&self, room_id: &str, keep: i32, batch: i64) -> Result<u64> {
+        let result = sqlx::query(
+            "DELETE FROM messages WHERE id IN (
+                 SELECT id FROM messages
+                 WHERE room_id = ?
+                 ORDER BY timestamp DESC
+                 LIMIT ? OFFSET ?
+             )",
+        )
+        .bind(room_id)
+        .bind(batch)
+        .bind(i64::from(keep))
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn mark_trimmed(&self, room_id: &str, at: DateTime<Utc>) -> Result<()> {
+        sqlx::query("UPDATE rooms SET pruned_at = ? WHERE id = ?")
+            .bind(at)
+            .bind(room_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn bind_identity(
+        &self,
+        user_id: &str,
+        public_key: &str,
+        fingerprint: &str,
+    ) -> Result<String> {
+        sqlx::query(
+            "INSERT INTO identities (user_id, public_key, fingerprint)
+             VALUES (?, ?, ?)
+             ON CONFLICT (user_id) DO NOTHING",
+        )
+        .bind(user_id)
+        .bind(public_key)
+        .bind(fingerprint)
+        .execute(&self.pool)
+        .await?;
+
+        let bound =
+            sqlx::query_scalar::<_, String>("SELECT public_key FROM identities WHERE user_id = ?")
+                .bind(user_id)
+                .fetch_one(&self.pool)
+                .await?;
+
+        Ok(bound)
+    }
+
+    async fn identity_exists(&self, user_id: &str) -> Result<bool> {
+        let count =
+            sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM identities WHERE user_id = ?")
+                .bind(user_id)
+                .fetch_one(&self.pool)
+                .await?;
+
+        Ok(count > 0)
+    }
+
+    async fn grant_role(&self, room_id: &str, user_id: &str, role: &str) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO room_roles (room_id, user_id, role)
+             VALUES (?, ?, ?)
+             ON CONFLICT (room_id, user_id) DO UPDATE SET role = excluded.role",
+        )
+        .bind(room_id)
+        .bind(user_id)
+        .bind(role)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn is_owner(&self, room_id: &str, user_id: &str) -> Result<bool> {
+        let count = sqlx::query_scalar::<_, i64>(
+            "SELECT COUNT(*) FROM room_roles WHERE room_id = ? AND user_id = ? AND role = 'owner'",
+        )
+        .bind(room_id)
+        .bind(user_id)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(count > 0)
+    }
+
+    async fn record_visit(&self, room_id: &str, user_id: &str, address: &str) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO room_visits (room_id, user_id, address, seen_at) VALUES (?, ?, ?, ?)
+             ON CONFLICT (room_id, user_id) DO UPDATE
+             SET address = excluded.address, seen_at = excluded.seen_at",
+        )
+        .bind(room_id)
+        .bind(user_id)
+        .bind(address)
+        .bind(Utc::now())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn add_sanction(&self, room_id: &str, sanction: &Sanction<'_>) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO room_bans
+                 (room_id, user_id, kind, username, fingerprint, address, reason, expires_at,
+                  created_at)
+             VALUES (?1, ?2, ?3, ?4,
+                     (SELECT fingerprint FROM identities WHERE user_id = ?2),
+                     (SELECT address FROM room_visits
+                      WHERE room_id = ?1 AND user_id = ?2
+                        AND NOT EXISTS (SELECT 1 FROM identities WHERE user_id = ?2)),
+                     ?5, ?6, ?7)
+             ON CONFLICT (room_id, user_id, kind) DO UPDATE
+             SET username = excluded.username, fingerprint = excluded.fingerprint,
+                 address = excluded.address, reason = excluded.reason,
+                 expires_at = excluded.expires_at, created_at = excluded.created_at",
+        )
+        .bind(room_id)
+        .bind(sanction.user_id)
+        .bind(sanction.action.as_str())
+        .bind(sanction.username)
+        .bind(sanction.reason)
+        .bind(sanction.expires_at)
+        .bind(Utc::now())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn active_sanction(
+        &self,
+        room_id: &str,
+        user_id: &str,
+        fingerprint: Option<&str>,
+        address: Option<&str>,
+        action: ModerationAction,
+    ) -> Result<Option<ActiveSanction>> {
+        let sanction = sqlx::query_as::<_, ActiveSanction>(
+            "SELECT reason, expires_at FROM room_bans
+             WHERE room_id = ?
+               AND (user_id = ? OR fingerprint = ? OR address = ?)
+               AND kind = ?
+               AND (expires_at IS NULL OR expires_at > ?)
+             ORDER BY expires_at DESC NULLS FIRST
+             LIMIT 1",
+        )
+        .bind(room_id)
+        .bind(user_id)
+        .bind(fingerprint)
+        .bind(address)
+        .bind(action.as_str())
+        .bind(Utc::now())
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(sanction)
+    }
+
+    async fn sanctioned_user_ids(&self, room_id: &str, username: &str) -> Result<Vec<String>> {
+        let user_ids = sqlx::query_scalar::<_, String>(
+            "SELECT DISTINCT user_id FROM room_bans WHERE room_id = ? AND username = ?",
+        )
+        .bind(room_id)
+        .bind(username)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(user_ids)
+    }
+
+    async fn lift_sanctions(&self, room_id: &str, user_id: &str) -> Result<bool> {
+        let result = sqlx::query("DELETE FROM room_bans WHERE room_id = ? AND user_id = ?")
+            .bind(room_id)
+            .bind(user_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn log_moderation(&self, room_id: &str, event: &ModerationEvent) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO moderation_log
+                 (id, room_id, action, moderator, target_id, target, reason, expires_at, timestamp)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(Uuid::new_v4())
+        .bind(room_id)
+        .bind(event.action.as_str())
+        .bind(&event.moderator)
+        .bind(&event.target_id)
+        .bind(&event.target)
+        .bind(&event.reason)
+        .bind(event.expires_at)
+        .bind(event.timestamp)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn get_moderation_log(&self, room_id: &str) -> Result<Vec<ModerationEvent>> {
+        let rows = sqlx::query_as::<_, ModerationRow>(
+            "SELECT action, moderator, target_id, target, reason, expires_at, timestamp
+             FROM (
+                 SELECT * FROM moderation_log
+                 WHERE room_id = ?
+                 ORDER BY timestamp DESC
+                 LIMIT ?
+             ) recent
+             ORDER BY timestamp ASC",
+        )
+        .bind(room_id)
+        .bind(MODERATION_LOG_LIMIT as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .filter_map(ModerationRow::into_event)
+            .collect())
+    }
+}
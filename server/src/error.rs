@@ -0,0 +1,36 @@
+//! Errors bound for a client. Anything can be returned as an `anyhow::Error`
+//! as usual; wrapping the message in `ClientError` keeps the code it should
+//! go out under.
+
+use std::fmt;
+use terma_shared::ErrorCode;
+
+#[derive(Debug)]
+pub struct ClientError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl ClientError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+/// The code an error goes out under: its own, if it carries one, or else
+/// `Internal`, since anything not anticipated is the server's fault
+pub fn code_of(e: &anyhow::Error) -> ErrorCode {
+    e.downcast_ref::<ClientError>()
+        .map_or(ErrorCode::Internal, |e| e.code)
+}
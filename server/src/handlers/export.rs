@@ -0,0 +1,124 @@
+use std::net::SocketAddr;
+
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use chrono::Utc;
+use futures::stream;
+use serde::Deserialize;
+use terma_shared::export::{ExportFormat, Transcript};
+use tracing::error;
+
+use super::authorize_room;
+use crate::{db::Db, rate_limit, state::AppState};
+
+// Messages fetched per database round-trip while streaming
+const EXPORT_PAGE_SIZE: i64 = 200;
+
+#[derive(Debug, Deserialize)]
+pub struct ExportParams {
+    pub format: Option<String>,
+}
+
+pub async fn export_room(
+    State(state): State<AppState>,
+    Path(room_id): Path<String>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(params): Query<ExportParams>,
+    headers: HeaderMap,
+) -> Response {
+    let format = match params
+        .format
+        .as_deref()
+        .unwrap_or("json")
+        .parse::<ExportFormat>()
+    {
+        Ok(format) => format,
+        Err(e) => return (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+    };
+
+    let address = rate_limit::client_ip(&headers, addr, state.trust_proxy);
+    if let Err(refused) = authorize_room(&state, &room_id, &headers, address).await {
+        return refused.into_response();
+    }
+
+    let disposition = format!(
+        "attachment; filename=\"terma-{}.{}\"",
+        room_id,
+        format.extension()
+    );
+    let body = Body::from_stream(transcript_stream(state.db.clone(), room_id, format));
+
+    (
+        StatusCode::OK,
+        [
+            (header::CONTENT_TYPE, format.content_type().to_string()),
+            (header::CONTENT_DISPOSITION, disposition),
+        ],
+        body,
+    )
+        .into_response()
+}
+
+enum ExportStep {
+    Header,
+    Page { after_seq: i64 },
+    Footer,
+    Done,
+}
+
+/// Stream the transcript page by page so memory stays flat for large rooms
+fn transcript_stream(
+    db: Db,
+    room_id: String,
+    format: ExportFormat,
+) -> impl futures::Stream<Item = anyhow::Result<String>> {
+    let transcript = Transcript::new(format);
+
+    stream::unfold(
+        (ExportStep::Header, transcript),
+        move |(step, mut transcript)| {
+            let db = db.clone();
+            let room_id = room_id.clone();
+            async move {
+                match step {
+                    ExportStep::Header => {
+                        let chunk = transcript.header(&room_id, Utc::now());
+                        Some((Ok(chunk), (ExportStep::Page { after_seq: 0 }, transcript)))
+                    }
+                    ExportStep::Page { after_seq } => {
+                        let page = match db
+                            .get_messages_after(&room_id, after_seq, EXPORT_PAGE_SIZE)
+                            .await
+                        {
+                            Ok(page) => page,
+                            Err(e) => {
+                                error!("Export of room {} failed: {}", room_id, e);
+                                return Some((Err(e), (ExportStep::Done, transcript)));
+                            }
+                        };
+
+                        let next = match page.last() {
+                            Some((seq, _)) if page.len() as i64 == EXPORT_PAGE_SIZE => {
+                                ExportStep::Page { after_seq: *seq }
+                            }
+                            _ => ExportStep::Footer,
+                        };
+
+                        let chunk: String =
+                            page.iter().map(|(_, msg)| transcript.entry(msg)).collect();
+                        Some((Ok(chunk), (next, transcript)))
+                    }
+                    ExportStep::Footer => {
+                        let chunk = transcript.footer();
+                        Some((Ok(chunk), (ExportStep::Done, transcript)))
+                    }
+                    ExportStep::Done => None,
+                }
+            }
+        },
+    )
+}
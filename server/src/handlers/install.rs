@@ -1,15 +1,32 @@
 use axum::{
-    extract::Path,
+    extract::{Path, Query},
     http::{header, StatusCode},
     response::{IntoResponse, Response},
 };
+use serde::Deserialize;
+
+#[derive(Debug, Deserialize)]
+pub struct InstallParams {
+    /// Join token for password-protected rooms
+    pub token: Option<String>,
+}
+
+pub async fn install_script(
+    Path(room_id): Path<String>,
+    Query(params): Query<InstallParams>,
+) -> Response {
+    // Both values end up inside the shell script, so only allow the nanoid alphabet
+    let token = params.token.unwrap_or_default();
+    if !is_nanoid(&room_id) || !(token.is_empty() || is_nanoid(&token)) {
+        return (StatusCode::BAD_REQUEST, "Invalid room or token").into_response();
+    }
 
-pub async fn install_script(Path(room_id): Path<String>) -> Response {
     let script = format!(
         r#"#!/bin/sh
 set -e
 
 ROOM_ID="{}"
+JOIN_TOKEN="{}"
 
 PLATFORM="$(uname -s | tr 'A-Z' 'a-z')"
 ARCH="$(uname -m)"
@@ -70,10 +87,14 @@ esac
 echo "✓ Connecting to room $ROOM_ID..."
 echo ""
 
-# Now stdin is the real TTY, so we can exec directly
-exec "$BINARY_PATH" "$ROOM_ID"
+# Now stdin is the real TTY, so we can exec directly. Arguments after
+# `sh -c "..." sh` (e.g. --key for encrypted rooms) are passed through.
+if [ -n "$JOIN_TOKEN" ]; then
+    exec "$BINARY_PATH" "$@" --token "$JOIN_TOKEN" "$ROOM_ID"
+fi
+exec "$BINARY_PATH" "$@" "$ROOM_ID"
 "#,
-        room_id
+        room_id, token
     );
 
     (
@@ -83,3 +104,28 @@ exec "$BINARY_PATH" "$ROOM_ID"
     )
         .into_response()
 }
+
+/// The one-liner that installs the latest client and joins a room
+pub fn install_command(room_id: &str, join_token: Option<&str>) -> String {
+    let host = std::env::var("HOST").unwrap_or_else(|_| "localhost:3000".to_string());
+    let protocol = if host.starts_with("localhost") {
+        "http"
+    } else {
+        "https"
+    };
+    let query = match join_token {
+        Some(token) => format!("?token={}", token),
+        None => String::new(),
+    };
+    format!(
+        r#"sh -c "$(curl -fsSL {}://{}/join/{}{})""#,
+        protocol, host, room_id, query
+    )
+}
+
+fn is_nanoid(value: &str) -> bool {
+    !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
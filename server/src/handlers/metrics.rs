@@ -0,0 +1,12 @@
+use axum::{extract::State, http::header, response::IntoResponse};
+
+use crate::state::AppState;
+
+/// Prometheus scrape endpoint
+pub async fn get_metrics(State(state): State<AppState>) -> impl IntoResponse {
+    let rooms = state.local_rooms().len();
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state.metrics.render(rooms),
+    )
+}
@@ -1,9 +1,15 @@
 pub mod download;
+pub mod export;
 pub mod install;
+pub mod metrics;
 pub mod rooms;
+pub mod search;
 pub mod web;
 
 pub use download::*;
+pub use export::*;
 pub use install::*;
+pub use metrics::*;
 pub use rooms::*;
+pub use search::*;
 pub use web::*;
@@ -1,30 +1,380 @@
-use axum::{extract::State, http::StatusCode, Json};
+use std::net::IpAddr;
+
+use anyhow::bail;
+use axum::{
+    body::Bytes,
+    extract::{Path, State},
+    http::{header, HeaderMap, StatusCode},
+    Json,
+};
+use chrono::Utc;
 use nanoid::nanoid;
 use serde::{Deserialize, Serialize};
+use terma_shared::{identity, ErrorCode, Retention, Room};
+use tracing::error;
+
+use crate::{
+    access::RoomSecrets, error, error::ClientError, handlers::install_command, lifecycle,
+    moderation, state::AppState,
+};
 
-use crate::{db, state::AppState};
+#[derive(Debug, Default, Deserialize)]
+pub struct CreateRoomRequest {
+    pub name: Option<String>,
+    pub topic: Option<String>,
+    /// Message of the day, shown to everyone who joins
+    pub motd: Option<String>,
+    pub password: Option<String>,
+    /// Clients encrypt content with a key the server never sees
+    #[serde(default)]
+    pub encrypted: bool,
+    /// Delete the room and its messages this many seconds from now
+    pub ttl_seconds: Option<u64>,
+    /// How much history to keep; the newest 1000 messages by default
+    pub retention: Option<Retention>,
+}
+
+/// Shortest and longest lifetime a room can be created with
+const MIN_TTL_SECONDS: u64 = 60;
+const MAX_TTL_SECONDS: u64 = 365 * 24 * 60 * 60;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CreateRoomResponse {
     pub room_id: String,
     pub install_command: String,
+    /// Lets invitees into a password-protected room without typing the password
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub join_token: Option<String>,
+    /// Lets the creator moderate the room; never shared with invitees
+    pub owner_token: String,
 }
 
 pub async fn create_room(
     State(state): State<AppState>,
-) -> Result<Json<CreateRoomResponse>, StatusCode> {
-    let room_id = nanoid!(10);
+    body: Bytes,
+) -> Result<Json<CreateRoomResponse>, (StatusCode, String)> {
+    let internal_error = || (StatusCode::INTERNAL_SERVER_ERROR, String::new());
+
+    // The body is optional; an empty POST creates an open room
+    let request: CreateRoomRequest = if body.is_empty() {
+        CreateRoomRequest::default()
+    } else {
+        serde_json::from_slice(&body).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?
+    };
+    let invalid = |e: anyhow::Error| (StatusCode::BAD_REQUEST, e.to_string());
+    let mut room = Room::new(nanoid!(10));
+    room.name = room_text(request.name, "Room name", Room::MAX_NAME_LENGTH).map_err(invalid)?;
+    room.topic = room_text(request.topic, "Topic", Room::MAX_TOPIC_LENGTH).map_err(invalid)?;
+    room.motd =
+        room_text(request.motd, "Message of the day", Room::MAX_MOTD_LENGTH).map_err(invalid)?;
+    room.encrypted = request.encrypted;
+    if let Some(retention) = request.retention {
+        if !retention.is_valid() {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!(
+                    "Retention must keep 1 to {} messages or 1 to {} days",
+                    Retention::MAX_MESSAGES,
+                    Retention::MAX_DAYS
+                ),
+            ));
+        }
+        room.retention = retention;
+    }
+    if let Some(ttl) = request.ttl_seconds {
+        if !(MIN_TTL_SECONDS..=MAX_TTL_SECONDS).contains(&ttl) {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!(
+                    "ttl_seconds must be between {} and {}",
+                    MIN_TTL_SECONDS, MAX_TTL_SECONDS
+                ),
+            ));
+        }
+        room.expires_at = Some(room.created_at + chrono::Duration::seconds(ttl as i64));
+    }
+    let password = request
+        .password
+        .clone()
+        .filter(|password| !password.is_empty());
 
-    db::create_room(&state.db, room_id.clone())
-        .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let (secrets, tokens) =
+        tokio::task::spawn_blocking(move || RoomSecrets::new(password.as_deref()))
+            .await
+            .map_err(|_| internal_error())?
+            .map_err(|e| {
+                error!("Failed to create room secrets: {}", e);
+                internal_error()
+            })?;
+    room.password_protected = secrets.is_protected();
 
-    let host = std::env::var("HOST").unwrap_or_else(|_| "localhost:3000".to_string());
-    let protocol = if host.starts_with("localhost") { "http" } else { "https" };
-    let install_command = format!(r#"sh -c "$(curl -fsSL {}://{}/join/{})""#, protocol, host, room_id);
+    state.db.create_room(&room, &secrets).await.map_err(|e| {
+        error!("Failed to create room: {}", e);
+        internal_error()
+    })?;
+    let room_id = room.id;
+
+    let install_command = install_command(&room_id, tokens.join_token.as_deref());
 
     Ok(Json(CreateRoomResponse {
         room_id,
         install_command,
+        join_token: tokens.join_token,
+        owner_token: tokens.owner_token,
     }))
 }
+
+/// Trim a room name, topic or message of the day; blank means none
+pub fn room_text(
+    text: Option<String>,
+    what: &str,
+    max_chars: usize,
+) -> anyhow::Result<Option<String>> {
+    let Some(text) = text.map(|text| text.trim().to_string()) else {
+        return Ok(None);
+    };
+    if text.chars().count() > max_chars {
+        anyhow::bail!(
+            "{} is too long. Maximum length is {} characters.",
+            what,
+            max_chars
+        );
+    }
+    Ok((!text.is_empty()).then_some(text))
+}
+
+/// The room, or 410 Gone with the reason once it expired or was reaped.
+/// A protected room's name, topic and message of the day are left out
+/// unless the request carries its password or join token.
+pub async fn get_room(
+    State(state): State<AppState>,
+    Path(room_id): Path<String>,
+    headers: HeaderMap,
+) -> Result<Json<Room>, (StatusCode, String)> {
+    let internal_error = |_| (StatusCode::INTERNAL_SERVER_ERROR, String::new());
+    let room = state.db.get_room(&room_id).await.map_err(internal_error)?;
+    match room {
+        Some(mut room) if !room.is_expired() => {
+            if room.password_protected && check_bearer(&state, &room_id, &headers).await.is_err() {
+                room.name = None;
+                room.topic = None;
+                room.motd = None;
+            }
+            Ok(Json(room))
+        }
+        room => {
+            let reason = lifecycle::gone_reason(&state, &room_id, room.as_ref())
+                .await
+                .map_err(internal_error)?;
+            Err(match reason {
+                Some(reason) => (StatusCode::GONE, reason),
+                None => (StatusCode::NOT_FOUND, format!("Room {} not found", room_id)),
+            })
+        }
+    }
+}
+
+/// How far a signed request's timestamp may be from the server's clock
+const REQUEST_CLOCK_SKEW_SECONDS: u64 = 5 * 60;
+
+/// Check an HTTP request the way a join is checked. The room must still be
+/// open, and protected rooms need `Authorization: Bearer <password or join
+/// token>` plus the caller's user ID in `X-Terma-User`, signed if a key has
+/// claimed it. Open rooms take anonymous requests. Banned and kicked users
+/// are turned away, matched by `address` too if they have no key.
+pub async fn authorize_room(
+    state: &AppState,
+    room_id: &str,
+    headers: &HeaderMap,
+    address: IpAddr,
+) -> Result<(), (StatusCode, String)> {
+    let admitted = async {
+        match state.db.get_room(room_id).await? {
+            Some(room) if !room.is_expired() => {}
+            room => {
+                let reason = lifecycle::gone_reason(state, room_id, room.as_ref()).await?;
+                bail!(match reason {
+                    Some(reason) => ClientError::new(ErrorCode::RoomGone, reason),
+                    None => ClientError::new(
+                        ErrorCode::RoomNotFound,
+                        format!("Room {} not found", room_id)
+                    ),
+                });
+            }
+        }
+
+        let protected = check_bearer(state, room_id, headers).await?;
+
+        let Some(user_id) = header_value(headers, identity::USER_HEADER) else {
+            if protected {
+                bail!(unauthorized(format!(
+                    "Give your user ID in the {} header",
+                    identity::USER_HEADER
+                )));
+            }
+            // Anonymous callers can still be banned by address
+            return moderation::check_admitted(state, room_id, "", None, address).await;
+        };
+        let payload = match header_value(headers, identity::TIMESTAMP_HEADER) {
+            Some(timestamp) => {
+                let timestamp: i64 = timestamp
+                    .parse()
+                    .map_err(|_| unauthorized("Malformed request timestamp"))?;
+                if Utc::now().timestamp().abs_diff(timestamp) > REQUEST_CLOCK_SKEW_SECONDS {
+                    bail!(unauthorized(
+                        "The request timestamp is too far from the server's clock"
+                    ));
+                }
+                identity::request_payload(timestamp, room_id, user_id)
+            }
+            None => Vec::new(),
+        };
+        let public_key = header_value(headers, identity::PUBLIC_KEY_HEADER).map(str::to_string);
+        let signature = header_value(headers, identity::SIGNATURE_HEADER).map(str::to_string);
+        let fingerprint =
+            crate::identity::authenticate(state, user_id, public_key, signature, &payload).await?;
+
+        if !state.db.is_owner(room_id, user_id).await? {
+            let fingerprint = fingerprint.as_deref();
+            moderation::check_admitted(state, room_id, user_id, fingerprint, address).await?;
+        }
+        anyhow::Ok(())
+    };
+
+    admitted.await.map_err(|e| {
+        let code = error::code_of(&e);
+        if code == ErrorCode::Internal {
+            error!("Failed to authorize a request for room {}: {}", room_id, e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, String::new());
+        }
+        (http_status(code), e.to_string())
+    })
+}
+
+/// Protected rooms need `Authorization: Bearer <password or join token>`.
+/// Returns whether the room is protected.
+async fn check_bearer(
+    state: &AppState,
+    room_id: &str,
+    headers: &HeaderMap,
+) -> anyhow::Result<bool> {
+    let secrets = state
+        .db
+        .get_room_secrets(room_id)
+        .await?
+        .unwrap_or_default();
+    if !secrets.is_protected() {
+        return Ok(false);
+    }
+    let Some(secret) = header_value(headers, header::AUTHORIZATION.as_str())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::to_string)
+    else {
+        bail!(unauthorized("Room password required"));
+    };
+    let admitted = tokio::task::spawn_blocking(move || secrets.admits(&secret)).await?;
+    if !admitted {
+        bail!(unauthorized("Incorrect room password or token"));
+    }
+    Ok(true)
+}
+
+fn header_value<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+fn unauthorized(message: impl Into<String>) -> ClientError {
+    ClientError::new(ErrorCode::Unauthorized, message)
+}
+
+/// The HTTP status matching an error refused to a client
+fn http_status(code: ErrorCode) -> StatusCode {
+    match code {
+        ErrorCode::RoomNotFound => StatusCode::NOT_FOUND,
+        ErrorCode::RoomGone => StatusCode::GONE,
+        ErrorCode::Unauthorized | ErrorCode::IdentityMismatch => StatusCode::UNAUTHORIZED,
+        ErrorCode::Banned | ErrorCode::Kicked | ErrorCode::Forbidden => StatusCode::FORBIDDEN,
+        ErrorCode::InvalidRequest => StatusCode::BAD_REQUEST,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{MemoryStorage, Sanction, Storage};
+    use std::net::Ipv4Addr;
+    use std::sync::Arc;
+    use terma_shared::ModerationAction;
+
+    const ROOM: &str = "room-1";
+    const HOME: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+
+    async fn open_room() -> AppState {
+        let db = MemoryStorage::default();
+        db.create_room(&Room::new(ROOM.to_string()), &Default::default())
+            .await
+            .unwrap();
+        AppState::new(Arc::new(db))
+    }
+
+    #[tokio::test]
+    async fn far_off_timestamps_are_refused() {
+        let state = open_room().await;
+        for timestamp in [i64::MIN, i64::MAX, Utc::now().timestamp() - 3600] {
+            let mut headers = HeaderMap::new();
+            headers.insert(identity::USER_HEADER, "alice-id".parse().unwrap());
+            headers.insert(identity::TIMESTAMP_HEADER, timestamp.into());
+            let (status, _) = authorize_room(&state, ROOM, &headers, HOME)
+                .await
+                .unwrap_err();
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+        }
+    }
+
+    #[tokio::test]
+    async fn open_rooms_take_anonymous_requests() {
+        let state = open_room().await;
+        let headers = HeaderMap::new();
+        assert!(authorize_room(&state, ROOM, &headers, HOME).await.is_ok());
+
+        // Unless that address is banned
+        state
+            .db
+            .record_visit(ROOM, "bob-id", &HOME.to_string())
+            .await
+            .unwrap();
+        let ban = Sanction {
+            user_id: "bob-id",
+            username: "bob",
+            action: ModerationAction::Ban,
+            reason: None,
+            expires_at: None,
+        };
+        state.db.add_sanction(ROOM, &ban).await.unwrap();
+        let (status, _) = authorize_room(&state, ROOM, &headers, HOME)
+            .await
+            .unwrap_err();
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn protected_rooms_need_a_user_id() {
+        let db = MemoryStorage::default();
+        let (secrets, _) = RoomSecrets::new(Some("hunter2")).unwrap();
+        db.create_room(&Room::new(ROOM.to_string()), &secrets)
+            .await
+            .unwrap();
+        let state = AppState::new(Arc::new(db));
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, "Bearer hunter2".parse().unwrap());
+        let (status, message) = authorize_room(&state, ROOM, &headers, HOME)
+            .await
+            .unwrap_err();
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert!(message.contains(identity::USER_HEADER));
+        headers.insert(identity::USER_HEADER, "alice-id".parse().unwrap());
+        assert!(authorize_room(&state, ROOM, &headers, HOME).await.is_ok());
+    }
+}
@@ -0,0 +1,58 @@
+use std::net::SocketAddr;
+
+use axum::{
+    extract::{ConnectInfo, Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use terma_shared::SearchResult;
+use tracing::error;
+
+use super::authorize_room;
+use crate::{db, rate_limit, state::AppState};
+
+#[derive(Debug, Deserialize)]
+pub struct SearchParams {
+    pub q: String,
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SearchResponse {
+    pub room_id: String,
+    pub query: String,
+    pub results: Vec<SearchResult>,
+}
+
+pub async fn search_room(
+    State(state): State<AppState>,
+    Path(room_id): Path<String>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(params): Query<SearchParams>,
+    headers: HeaderMap,
+) -> Result<Json<SearchResponse>, (StatusCode, String)> {
+    let query = params.q.trim();
+    if query.is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "Give a query in q".to_string()));
+    }
+
+    let address = rate_limit::client_ip(&headers, addr, state.trust_proxy);
+    authorize_room(&state, &room_id, &headers, address).await?;
+
+    let limit = params.limit.unwrap_or(db::DEFAULT_SEARCH_LIMIT);
+    let results = state
+        .db
+        .search_messages(&room_id, query, limit)
+        .await
+        .map_err(|e| {
+            error!("Search failed in room {}: {}", room_id, e);
+            (StatusCode::INTERNAL_SERVER_ERROR, String::new())
+        })?;
+
+    Ok(Json(SearchResponse {
+        room_id,
+        query: query.to_string(),
+        results,
+    }))
+}
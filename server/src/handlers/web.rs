@@ -126,6 +126,28 @@ pub async fn index() -> Response {
             margin-bottom: 0.75rem;
         }
 
+        .text-input {
+            width: 100%;
+            font-family: inherit;
+            font-size: 0.875rem;
+            padding: 0.75rem 1rem;
+            border: 1px solid #e5e5e5;
+            background: #ffffff;
+            margin-bottom: 1rem;
+        }
+
+        .text-input:focus {
+            outline: none;
+            border-color: #000000;
+        }
+
+        .option {
+            display: block;
+            font-size: 0.875rem;
+            color: #666666;
+            margin-bottom: 1.5rem;
+        }
+
         .copy-btn {
             font-size: 0.75rem;
             padding: 0.5rem 1rem;
@@ -156,6 +178,21 @@ pub async fn index() -> Response {
         <p>Real-time terminal chat. Create a room, share the link, and start chatting instantly from your terminal.</p>
 
         <div class="card">
+            <input id="name" class="text-input" type="text" placeholder="Room name (optional)" maxlength="64">
+            <input id="password" class="text-input" type="password" placeholder="Room password (optional)" autocomplete="new-password">
+            <select id="expiry" class="text-input">
+                <option value="">Keep the room until it goes unused</option>
+                <option value="3600">Delete after 1 hour</option>
+                <option value="86400">Delete after 1 day</option>
+                <option value="604800">Delete after 1 week</option>
+            </select>
+            <select id="retention" class="text-input">
+                <option value="">Keep the newest 1000 messages</option>
+                <option value="days:7">Keep 7 days of messages</option>
+                <option value="days:30">Keep 30 days of messages</option>
+                <option value="unlimited">Keep every message</option>
+            </select>
+            <label id="encryptedOption" class="option"><input id="encrypted" type="checkbox"> End-to-end encrypted (the key stays in the link)</label>
             <button id="createBtn" onclick="createRoom()">Create New Room</button>
 
             <div id="commandBox" class="command-box">
@@ -169,6 +206,11 @@ pub async fn index() -> Response {
                     <button id="copyLinkBtn" class="copy-btn" onclick="copyLink()">Copy Link</button>
                 </div>
 
+                <div id="ownerBox" style="display: none; margin-top: 2rem;">
+                    <div class="label">To moderate, join once with this (keep it private)</div>
+                    <div id="ownerCommand" class="command"></div>
+                </div>
+
                 <div class="divider"></div>
                 <button id="createAnotherBtn" style="display: none;" onclick="createRoom()">Create Another Room</button>
             </div>
@@ -189,14 +231,34 @@ pub async fn index() -> Response {
             activeBtn.textContent = 'Creating...';
 
             try {
+                const name = document.getElementById('name').value.trim();
+                const password = document.getElementById('password').value;
+                const encrypted = document.getElementById('encrypted').checked;
+                const expiry = document.getElementById('expiry').value;
+                const retention = document.getElementById('retention').value;
+                const body = {};
+                if (name) body.name = name;
+                if (password) body.password = password;
+                if (encrypted) body.encrypted = true;
+                if (expiry) body.ttl_seconds = Number(expiry);
+                if (retention === 'unlimited') body.retention = 'unlimited';
+                else if (retention) body.retention = { days: Number(retention.split(':')[1]) };
                 const response = await fetch('/api/rooms', {
                     method: 'POST',
+                    headers: { 'Content-Type': 'application/json' },
+                    body: JSON.stringify(body),
                 });
 
-                if (!response.ok) throw new Error('Failed to create room');
+                if (!response.ok) throw new Error((await response.text()) || 'Failed to create room');
 
                 const data = await response.json();
-                displayRoom(data.room_id, data.install_command);
+                // The key is made here and only ever shared in the link fragment,
+                // which browsers never send to the server
+                const key = encrypted ? generateRoomKey() : null;
+                displayRoom(data.room_id, data.install_command, key);
+                document.getElementById('ownerCommand').textContent =
+                    'terma --owner-token ' + data.owner_token + ' ' + data.room_id;
+                document.getElementById('ownerBox').style.display = 'block';
             } catch (error) {
                 alert('Error creating room: ' + error.message);
                 activeBtn.textContent = btn.style.display === 'none' ? 'Create Another Room' : 'Create New Room';
@@ -205,13 +267,31 @@ pub async fn index() -> Response {
             }
         }
 
-        function displayRoom(roomId, installCommand) {
-            document.getElementById('command').textContent = installCommand;
-            document.getElementById('shareLink').textContent = window.location.origin + '/#' + roomId;
+        function generateRoomKey() {
+            const bytes = crypto.getRandomValues(new Uint8Array(32));
+            return btoa(String.fromCharCode(...bytes))
+                .replace(/\+/g, '-').replace(/\//g, '_').replace(/=+$/, '');
+        }
+
+        function displayRoom(roomId, installCommand, key) {
+            const fragment = key ? roomId + ':' + key : roomId;
+            document.getElementById('command').textContent = key ? installCommand + ' sh --key ' + key : installCommand;
+            document.getElementById('shareLink').textContent = window.location.origin + '/#' + fragment;
             document.getElementById('commandBox').classList.add('active');
+            document.getElementById('ownerBox').style.display = 'none';
             document.getElementById('createBtn').style.display = 'none';
+            document.getElementById('name').style.display = 'none';
+            document.getElementById('name').value = '';
+            document.getElementById('password').style.display = 'none';
+            document.getElementById('password').value = '';
+            document.getElementById('expiry').style.display = 'none';
+            document.getElementById('expiry').value = '';
+            document.getElementById('retention').style.display = 'none';
+            document.getElementById('retention').value = '';
+            document.getElementById('encryptedOption').style.display = 'none';
+            document.getElementById('encrypted').checked = false;
             document.getElementById('createAnotherBtn').style.display = 'block';
-            window.location.hash = roomId;
+            window.location.hash = fragment;
         }
 
         function copyCommand() {
@@ -238,14 +318,23 @@ pub async fn index() -> Response {
             }, 2000);
         }
 
+        // Both end up in a shell command the visitor copies, so only accept
+        // the nanoid alphabet and an unpadded base64url 32-byte key
+        const ROOM_ID_PATTERN = /^[A-Za-z0-9_-]+$/;
+        const KEY_PATTERN = /^[A-Za-z0-9_-]{43}$/;
+
         // Handle hash on page load
         window.addEventListener('DOMContentLoaded', () => {
-            const hash = window.location.hash.slice(1);
-            if (hash) {
+            const parts = window.location.hash.slice(1).split(':');
+            const [roomId, key] = parts;
+            const valid = parts.length <= 2
+                && ROOM_ID_PATTERN.test(roomId)
+                && (key === undefined || KEY_PATTERN.test(key));
+            if (valid) {
                 const host = window.location.host;
                 const protocol = window.location.protocol;
-                const installCommand = `sh -c "$(curl -fsSL ${protocol}//${host}/join/${hash})"`;
-                displayRoom(hash, installCommand);
+                const installCommand = `sh -c "$(curl -fsSL ${protocol}//${host}/join/${roomId})"`;
+                displayRoom(roomId, installCommand, key);
             }
         });
     </script>
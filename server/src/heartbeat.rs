@@ -0,0 +1,135 @@
+//! WebSocket pings, so connections whose other end vanished without a word
+//! (a dead laptop, a dropped NAT mapping) are noticed and reaped instead of
+//! counting as online forever.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::time::{Interval, MissedTickBehavior};
+
+/// Ping this often unless `PING_INTERVAL_SECONDS` says otherwise
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(20);
+/// Give up on a connection silent this long unless `PING_TIMEOUT_SECONDS`
+/// says otherwise
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+/// Give a new connection this long to join unless `JOIN_TIMEOUT_SECONDS`
+/// says otherwise
+const DEFAULT_JOIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy)]
+pub struct HeartbeatConfig {
+    /// Zero turns pinging, and so reaping, off
+    pub interval: Duration,
+    pub timeout: Duration,
+    /// How long a new connection may take to send its `Join`
+    pub join_timeout: Duration,
+}
+
+impl HeartbeatConfig {
+    /// Read `PING_INTERVAL_SECONDS`, `PING_TIMEOUT_SECONDS` and
+    /// `JOIN_TIMEOUT_SECONDS`, falling back to 20, 60 and 10 seconds
+    pub fn from_env() -> Self {
+        let seconds = |name: &str| {
+            std::env::var(name)
+                .ok()
+                .and_then(|seconds| seconds.parse().ok())
+                .map(Duration::from_secs)
+        };
+        Self {
+            interval: seconds("PING_INTERVAL_SECONDS").unwrap_or(DEFAULT_INTERVAL),
+            timeout: seconds("PING_TIMEOUT_SECONDS").unwrap_or(DEFAULT_TIMEOUT),
+            join_timeout: seconds("JOIN_TIMEOUT_SECONDS").unwrap_or(DEFAULT_JOIN_TIMEOUT),
+        }
+    }
+
+    /// A timer for when to ping, or `None` if pinging is off
+    pub fn ticker(&self) -> Option<Interval> {
+        if self.interval.is_zero() {
+            return None;
+        }
+        let mut ticker = tokio::time::interval(self.interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        Some(ticker)
+    }
+
+    /// Nothing heard, not even a pong, for longer than the timeout: the
+    /// other end is gone
+    pub fn gone_silent(&self, last_seen: &LastSeen) -> bool {
+        last_seen.elapsed() > self.timeout
+    }
+}
+
+/// Wait for the next ping, or forever if pinging is off
+pub async fn tick(ticker: &mut Option<Interval>) {
+    match ticker {
+        Some(ticker) => {
+            ticker.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// When a connection last sent anything, pongs included
+#[derive(Clone)]
+pub struct LastSeen(Arc<Mutex<Instant>>);
+
+impl Default for LastSeen {
+    fn default() -> Self {
+        Self(Arc::new(Mutex::new(Instant::now())))
+    }
+}
+
+impl LastSeen {
+    pub fn touch(&self) {
+        *self.0.lock().unwrap() = Instant::now();
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.0.lock().unwrap().elapsed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(interval_ms: u64, timeout_ms: u64) -> HeartbeatConfig {
+        HeartbeatConfig {
+            interval: Duration::from_millis(interval_ms),
+            timeout: Duration::from_millis(timeout_ms),
+            join_timeout: DEFAULT_JOIN_TIMEOUT,
+        }
+    }
+
+    #[tokio::test]
+    async fn a_zero_interval_never_pings() {
+        let mut ticker = config(0, 50).ticker();
+        assert!(ticker.is_none());
+        let ticked = tokio::time::timeout(Duration::from_millis(50), tick(&mut ticker)).await;
+        assert!(ticked.is_err());
+    }
+
+    #[tokio::test]
+    async fn pings_come_every_interval() {
+        let mut ticker = config(20, 1000).ticker();
+        let started = Instant::now();
+        // The first tick is immediate
+        tick(&mut ticker).await;
+        tick(&mut ticker).await;
+        tick(&mut ticker).await;
+        assert!(started.elapsed() >= Duration::from_millis(40));
+    }
+
+    #[tokio::test]
+    async fn connections_silent_past_the_timeout_are_gone() {
+        let heartbeat = config(10, 50);
+        let last_seen = LastSeen::default();
+        // The receiving half touches a clone of what the pinging half checks
+        let receiver = last_seen.clone();
+        assert!(!heartbeat.gone_silent(&last_seen));
+
+        tokio::time::sleep(Duration::from_millis(80)).await;
+        assert!(heartbeat.gone_silent(&last_seen));
+        receiver.touch();
+        assert!(!heartbeat.gone_silent(&last_seen));
+    }
+}
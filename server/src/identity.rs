@@ -0,0 +1,147 @@
+use anyhow::{anyhow, bail, Result};
+use ed25519_dalek::{Signature, VerifyingKey};
+use terma_shared::{identity, ErrorCode};
+
+use crate::error::ClientError;
+use crate::state::AppState;
+
+/// Check a signature over `payload`. Returns the key's fingerprint.
+pub fn verify(public_key: &str, signature: &str, payload: &[u8]) -> Result<String> {
+    let key_bytes: [u8; 32] = hex::decode(public_key)?
+        .try_into()
+        .map_err(|_| anyhow!("public key must be 32 bytes"))?;
+    let signature_bytes: [u8; 64] = hex::decode(signature)?
+        .try_into()
+        .map_err(|_| anyhow!("signature must be 64 bytes"))?;
+
+    let key = VerifyingKey::from_bytes(&key_bytes)?;
+    let signature = Signature::from_bytes(&signature_bytes);
+    key.verify_strict(payload, &signature)?;
+
+    Ok(identity::fingerprint(&key_bytes))
+}
+
+/// Verify a signed join or request and bind the user ID to its key on first
+/// use. Unsigned ones are only accepted for user IDs no key has claimed.
+/// Returns the key's fingerprint, if signed.
+pub async fn authenticate(
+    state: &AppState,
+    user_id: &str,
+    public_key: Option<String>,
+    signature: Option<String>,
+    payload: &[u8],
+) -> Result<Option<String>> {
+    match (public_key, signature) {
+        (Some(public_key), Some(signature)) => {
+            let public_key = public_key.to_ascii_lowercase();
+            let fingerprint = verify(&public_key, &signature, payload)
+                .map_err(|e| identity_mismatch(format!("Identity verification failed: {}", e)))?;
+
+            let bound = state
+                .db
+                .bind_identity(user_id, &public_key, &fingerprint)
+                .await?;
+            if bound != public_key {
+                bail!(identity_mismatch(format!(
+                    "User ID {} is registered to a different key",
+                    user_id
+                )));
+            }
+            Ok(Some(fingerprint))
+        }
+        (None, None) => {
+            if state.db.identity_exists(user_id).await? {
+                bail!(identity_mismatch(format!(
+                    "User ID {} is registered to a key. Sign with it to get in.",
+                    user_id
+                )));
+            }
+            Ok(None)
+        }
+        _ => bail!(identity_mismatch(
+            "A signed identity needs both a public key and a signature"
+        )),
+    }
+}
+
+fn identity_mismatch(message: impl Into<String>) -> ClientError {
+    ClientError::new(ErrorCode::IdentityMismatch, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::MemoryStorage;
+    use ed25519_dalek::{Signer, SigningKey};
+    use std::sync::Arc;
+
+    const PAYLOAD: &[u8] = b"terma-join-v1\nnonce\nroom-1\nalice-id";
+
+    /// A key's public half and its signature over `payload`, hex encoded
+    fn signed(seed: u8, payload: &[u8]) -> (Option<String>, Option<String>) {
+        let key = SigningKey::from_bytes(&[seed; 32]);
+        let public_key = hex::encode(key.verifying_key().to_bytes());
+        let signature = hex::encode(key.sign(payload).to_bytes());
+        (Some(public_key), Some(signature))
+    }
+
+    async fn check(
+        state: &AppState,
+        (public_key, signature): (Option<String>, Option<String>),
+    ) -> Result<Option<String>> {
+        authenticate(state, "alice-id", public_key, signature, PAYLOAD).await
+    }
+
+    fn code(result: Result<Option<String>>) -> ErrorCode {
+        crate::error::code_of(&result.unwrap_err())
+    }
+
+    #[tokio::test]
+    async fn the_first_key_claims_a_user_id() {
+        let state = AppState::new(Arc::new(MemoryStorage::default()));
+        // Unclaimed IDs get in unsigned
+        assert_eq!(check(&state, (None, None)).await.unwrap(), None);
+
+        let fingerprint = check(&state, signed(1, PAYLOAD)).await.unwrap();
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let expected = identity::fingerprint(key.verifying_key().as_bytes());
+        assert_eq!(fingerprint, Some(expected));
+        // The same key again is fine, in upper case hex too
+        let (public_key, signature) = signed(1, PAYLOAD);
+        let upper = (public_key.map(|key| key.to_uppercase()), signature);
+        assert!(check(&state, upper).await.is_ok());
+
+        assert_eq!(
+            code(check(&state, signed(2, PAYLOAD)).await),
+            ErrorCode::IdentityMismatch
+        );
+        assert_eq!(
+            code(check(&state, (None, None)).await),
+            ErrorCode::IdentityMismatch
+        );
+    }
+
+    #[tokio::test]
+    async fn forged_and_half_signed_joins_are_refused() {
+        let state = AppState::new(Arc::new(MemoryStorage::default()));
+        // Signed over something else
+        let forged = signed(1, b"another room");
+        assert_eq!(
+            code(check(&state, forged).await),
+            ErrorCode::IdentityMismatch
+        );
+        let (public_key, _) = signed(1, PAYLOAD);
+        assert_eq!(
+            code(check(&state, (public_key, None)).await),
+            ErrorCode::IdentityMismatch
+        );
+        let (public_key, _) = signed(1, PAYLOAD);
+        let garbage = (public_key, Some("00ff".to_string()));
+        assert_eq!(
+            code(check(&state, garbage).await),
+            ErrorCode::IdentityMismatch
+        );
+        // None of those bound the key
+        assert!(!state.db.identity_exists("alice-id").await.unwrap());
+    }
+}
@@ -0,0 +1,209 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use std::time::Duration;
+use terma_shared::Room;
+use tracing::{error, info};
+
+use crate::room::RoomCommand;
+use crate::state::{AppState, RoomEvent};
+
+/// How often the reaper looks for rooms to warn about or delete
+const REAP_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How far ahead members are told their room is about to expire
+const EXPIRY_WARNING: chrono::Duration = chrono::Duration::minutes(10);
+
+/// Rooms nobody joins or posts in for this long are deleted, unless
+/// `ROOM_IDLE_DAYS` says otherwise (0 keeps idle rooms forever)
+const DEFAULT_IDLE_DAYS: i64 = 30;
+
+/// How long joiners are told why a room is gone before it's just "not found"
+const CLOSED_ROOM_MEMORY: chrono::Duration = chrono::Duration::days(30);
+
+/// Start the background task that deletes expired and idle rooms
+pub fn spawn_reaper(state: AppState) {
+    let idle_days = std::env::var("ROOM_IDLE_DAYS")
+        .ok()
+        .and_then(|days| days.parse().ok())
+        .unwrap_or(DEFAULT_IDLE_DAYS);
+    let idle = (idle_days > 0).then(|| chrono::Duration::days(idle_days));
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(REAP_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = reap(&state, idle).await {
+                error!("Room reaper failed: {}", e);
+            }
+        }
+    });
+}
+
+async fn reap(state: &AppState, idle: Option<chrono::Duration>) -> Result<()> {
+    warn_expiring(state);
+
+    let expired = state.db.delete_expired_rooms().await?;
+    if !expired.is_empty() {
+        info!("Deleted {} expired room(s)", expired.len());
+        close_live_rooms(state, &expired, "This room has expired.").await;
+    }
+
+    if let Some(idle) = idle {
+        let active = state.active_rooms().await?;
+        let deleted = state.db.delete_idle_rooms(idle, &active).await?;
+        if !deleted.is_empty() {
+            info!("Deleted {} idle room(s)", deleted.len());
+        }
+    }
+
+    state
+        .db
+        .prune_closed_rooms(Utc::now() - CLOSED_ROOM_MEMORY)
+        .await?;
+    Ok(())
+}
+
+/// Tell everyone in a room that expires soon, once
+fn warn_expiring(state: &AppState) {
+    let deadline = Utc::now() + EXPIRY_WARNING;
+    for (_, room) in state.local_rooms() {
+        room.send(RoomCommand::WarnExpiring { deadline });
+    }
+}
+
+/// Tell members of deleted rooms why, then hang up on them
+async fn close_live_rooms(state: &AppState, room_ids: &[String], reason: &str) {
+    for room_id in room_ids {
+        let close = RoomEvent::Close {
+            reason: reason.to_string(),
+        };
+        state.publish(room_id, close);
+    }
+}
+
+/// Why a room that can't be joined is gone. `room` is what the database
+/// returned for `room_id`: missing, or past its expiry. `None` means the
+/// room never existed, or was closed too long ago to remember.
+pub async fn gone_reason(
+    state: &AppState,
+    room_id: &str,
+    room: Option<&Room>,
+) -> Result<Option<String>> {
+    if let Some(expires_at) = room.and_then(|room| room.expires_at) {
+        return Ok(Some(format!("This room expired {}.", when(expires_at))));
+    }
+    let Some(closed) = state.db.get_closed_room(room_id).await? else {
+        return Ok(None);
+    };
+    Ok(Some(match closed.reason.as_str() {
+        "idle" => format!(
+            "This room was deleted {} after going unused.",
+            when(closed.closed_at)
+        ),
+        _ => format!("This room expired {}.", when(closed.closed_at)),
+    }))
+}
+
+fn when(at: DateTime<Utc>) -> String {
+    format!("on {}", at.format("%Y-%m-%d at %H:%M UTC"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{MemoryStorage, Storage};
+    use crate::outbox::{self, OutboxReceiver};
+    use axum::extract::ws::Message;
+    use std::sync::Arc;
+    use terma_shared::{Codec, Frame, ServerMessage};
+
+    async fn state_with(rooms: &[&Room]) -> AppState {
+        let db = MemoryStorage::default();
+        for room in rooms {
+            db.create_room(room, &Default::default()).await.unwrap();
+        }
+        AppState::new(Arc::new(db))
+    }
+
+    fn expiring_in(id: &str, minutes: i64) -> Room {
+        Room {
+            expires_at: Some(Utc::now() + chrono::Duration::minutes(minutes)),
+            ..Room::new(id.to_string())
+        }
+    }
+
+    async fn join(state: &AppState, room: &Room) -> OutboxReceiver {
+        let (outbox, rx) = outbox::channel(
+            state.outbox,
+            Codec::Json,
+            crate::compression::FrameCompressor::new(None, state.metrics.clone()),
+            state.metrics.clone(),
+            &room.id,
+            "alice-id",
+        );
+        let (user_id, username) = ("alice-id".to_string(), "alice".to_string());
+        state
+            .join_room(room, None, user_id, username, false, outbox)
+            .await;
+        rx
+    }
+
+    /// The next message queued for a member, if one comes soon
+    async fn next(rx: &mut OutboxReceiver) -> Option<ServerMessage> {
+        let msg = tokio::time::timeout(Duration::from_millis(200), rx.recv()).await;
+        match msg.ok()? {
+            Message::Text(text) => ServerMessage::decode(Codec::Json, Frame::Text(text)).ok(),
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn members_are_warned_once_before_expiry() {
+        let soon = expiring_in("soon", 5);
+        let later = expiring_in("later", 60);
+        let state = state_with(&[&soon, &later]).await;
+        let mut soon_rx = join(&state, &soon).await;
+        let mut later_rx = join(&state, &later).await;
+
+        reap(&state, None).await.unwrap();
+        assert!(matches!(
+            next(&mut soon_rx).await,
+            Some(ServerMessage::RoomExpiring { .. })
+        ));
+        assert!(next(&mut later_rx).await.is_none());
+        reap(&state, None).await.unwrap();
+        assert!(next(&mut soon_rx).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn expired_rooms_close_and_say_why() {
+        let room = expiring_in("gone", -1);
+        let state = state_with(&[&room]).await;
+        let mut rx = join(&state, &room).await;
+
+        reap(&state, None).await.unwrap();
+        let mut closed = false;
+        while let Some(msg) = next(&mut rx).await {
+            closed |= matches!(msg, ServerMessage::RoomClosed { .. });
+        }
+        assert!(closed);
+        assert!(state.db.get_room("gone").await.unwrap().is_none());
+        let reason = gone_reason(&state, "gone", None).await.unwrap().unwrap();
+        assert!(reason.starts_with("This room expired on "));
+        assert_eq!(gone_reason(&state, "never", None).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn only_rooms_nobody_is_in_go_idle() {
+        let empty = Room::new("empty".to_string());
+        let busy = Room::new("busy".to_string());
+        let state = state_with(&[&empty, &busy]).await;
+        let _rx = join(&state, &busy).await;
+
+        reap(&state, Some(chrono::Duration::zero())).await.unwrap();
+        assert!(state.db.get_room("empty").await.unwrap().is_none());
+        assert!(state.db.get_room("busy").await.unwrap().is_some());
+        let reason = gone_reason(&state, "empty", None).await.unwrap().unwrap();
+        assert!(reason.contains("after going unused"));
+    }
+}
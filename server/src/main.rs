@@ -1,13 +1,29 @@
+mod access;
+#[cfg(feature = "postgres")]
+mod cluster;
+mod compression;
 mod db;
+mod error;
 mod handlers;
+mod heartbeat;
+mod identity;
+mod lifecycle;
+mod metrics;
+mod moderation;
+mod outbox;
+mod rate_limit;
+mod retention;
+mod room;
 mod state;
 mod ws;
 
 use axum::{
+    middleware,
     routing::{get, post},
     Router,
 };
 use state::AppState;
+use std::net::SocketAddr;
 use tower_http::trace::TraceLayer;
 use tracing::info;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
@@ -27,19 +43,34 @@ async fn main() -> anyhow::Result<()> {
     let database_url =
         std::env::var("DATABASE_URL").unwrap_or_else(|_| "sqlite:terma.db".to_string());
 
-    let db = db::init_db(&database_url).await?;
+    let db = db::connect(&database_url).await?;
     info!("Database initialized");
 
     // Create app state
     let state = AppState::new(db);
+    // With Postgres, any number of instances can serve the same rooms
+    #[cfg(feature = "postgres")]
+    let state = cluster::attach(state, &database_url).await?;
+    lifecycle::spawn_reaper(state.clone());
+    retention::spawn_pruner(state.db.clone());
 
     // Build router
     let app = Router::new()
         .route("/", get(handlers::index))
-        .route("/api/rooms", post(handlers::create_room))
+        .route(
+            "/api/rooms",
+            post(handlers::create_room).route_layer(middleware::from_fn_with_state(
+                state.clone(),
+                rate_limit::limit_room_creation,
+            )),
+        )
+        .route("/api/rooms/:room_id", get(handlers::get_room))
+        .route("/api/rooms/:room_id/search", get(handlers::search_room))
+        .route("/api/rooms/:room_id/export", get(handlers::export_room))
         .route("/join/:room_id", get(handlers::install_script))
         .route("/download/:filename", get(handlers::download_binary))
         .route("/ws/:room_id", get(ws::websocket_handler))
+        .route("/metrics", get(handlers::get_metrics))
         .with_state(state)
         .layer(TraceLayer::new_for_http());
 
@@ -49,7 +80,12 @@ async fn main() -> anyhow::Result<()> {
     info!("Server starting on {}", addr);
 
     let listener = tokio::net::TcpListener::bind(&addr).await?;
-    axum::serve(listener, app).await?;
+    // Client addresses feed the rate limiters
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await?;
 
     Ok(())
 }
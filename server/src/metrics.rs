@@ -0,0 +1,131 @@
+//! Server health in the Prometheus text format, served at `/metrics`
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+
+use crate::outbox;
+
+#[derive(Default)]
+pub struct Metrics {
+    next_id: AtomicU64,
+    /// Every open connection's send queue
+    outboxes: Mutex<HashMap<u64, Weak<outbox::Shared>>>,
+    /// Messages discarded from full queues to make room
+    pub dropped_oldest: AtomicU64,
+    /// Queued updates discarded because a newer one replaced them
+    pub coalesced: AtomicU64,
+    /// Connections closed for falling too far behind
+    pub slow_disconnects: AtomicU64,
+    /// Connections dropped for not answering pings
+    pub reaped: AtomicU64,
+    /// Bytes of frames before and after compression, counted once however
+    /// many connections a frame goes to
+    pub compressed_in: AtomicU64,
+    pub compressed_out: AtomicU64,
+}
+
+impl Metrics {
+    pub fn next_id(&self) -> u64 {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    pub fn register(&self, id: u64, outbox: &Arc<outbox::Shared>) {
+        let mut outboxes = self.outboxes.lock().unwrap();
+        outboxes.insert(id, Arc::downgrade(outbox));
+    }
+
+    pub fn unregister(&self, id: u64) {
+        self.outboxes.lock().unwrap().remove(&id);
+    }
+
+    /// Everything as Prometheus exposition text
+    pub fn render(&self, rooms: usize) -> String {
+        let outboxes: Vec<_> = {
+            let outboxes = self.outboxes.lock().unwrap();
+            outboxes.values().filter_map(Weak::upgrade).collect()
+        };
+        let depths: Vec<usize> = outboxes.iter().map(|outbox| outbox.depth()).collect();
+        // Half full is well past what a healthy client ever has queued
+        let lagging = outboxes
+            .iter()
+            .zip(&depths)
+            .filter(|(outbox, depth)| **depth * 2 >= outbox.capacity())
+            .count();
+
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: u64| {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            let _ = writeln!(out, "{} {}", name, value);
+        };
+        metric(
+            "terma_rooms_active",
+            "gauge",
+            "Rooms with members connected to this instance",
+            rooms as u64,
+        );
+        metric(
+            "terma_connections",
+            "gauge",
+            "Open WebSocket connections",
+            outboxes.len() as u64,
+        );
+        metric(
+            "terma_outbox_queued",
+            "gauge",
+            "Messages waiting to be written, across all connections",
+            depths.iter().sum::<usize>() as u64,
+        );
+        metric(
+            "terma_outbox_depth_max",
+            "gauge",
+            "Messages waiting on the most backed up connection",
+            depths.iter().copied().max().unwrap_or(0) as u64,
+        );
+        metric(
+            "terma_outbox_lagging",
+            "gauge",
+            "Connections whose queue is at least half full",
+            lagging as u64,
+        );
+        metric(
+            "terma_outbox_dropped_total",
+            "counter",
+            "Messages discarded from full queues",
+            self.dropped_oldest.load(Ordering::Relaxed),
+        );
+        metric(
+            "terma_outbox_coalesced_total",
+            "counter",
+            "Queued updates replaced by newer ones",
+            self.coalesced.load(Ordering::Relaxed),
+        );
+        metric(
+            "terma_slow_client_disconnects_total",
+            "counter",
+            "Connections closed for falling too far behind",
+            self.slow_disconnects.load(Ordering::Relaxed),
+        );
+        metric(
+            "terma_reaped_connections_total",
+            "counter",
+            "Connections dropped for going silent past the ping timeout",
+            self.reaped.load(Ordering::Relaxed),
+        );
+        metric(
+            "terma_compression_input_bytes_total",
+            "counter",
+            "Bytes of frames compressed, before compression",
+            self.compressed_in.load(Ordering::Relaxed),
+        );
+        metric(
+            "terma_compression_output_bytes_total",
+            "counter",
+            "Bytes of frames compressed, after compression",
+            self.compressed_out.load(Ordering::Relaxed),
+        );
+        out
+    }
+}
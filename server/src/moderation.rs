@@ -0,0 +1,618 @@
+use std::net::IpAddr;
+
+use anyhow::{bail, Result};
+use chrono::{DateTime, Duration, Utc};
+use terma_shared::{ErrorCode, ModerationAction, ModerationEvent, Room, ServerMessage};
+use tracing::info;
+
+use crate::{
+    db::Sanction,
+    error::ClientError,
+    handlers::room_text,
+    state::{AppState, RoomEvent},
+};
+
+/// How long a kicked user has to wait before rejoining
+const KICK_COOLDOWN_MINUTES: i64 = 5;
+
+/// Who a moderation command is aimed at: a name, and optionally the start
+/// of a user ID to pick one of several people using it
+pub struct Target<'a> {
+    pub username: &'a str,
+    pub user_id: Option<&'a str>,
+}
+
+/// Characters of a user ID shown to tell people with the same name apart
+const USER_TAG_LENGTH: usize = 8;
+
+/// Apply a moderation action from `moderator_id`, who must own the room, to
+/// one user. Everyone in the room sees the result, and a kicked or banned
+/// socket is closed.
+pub async fn moderate(
+    state: &AppState,
+    room_id: &str,
+    moderator_id: &str,
+    action: ModerationAction,
+    target: Target<'_>,
+    reason: Option<String>,
+    minutes: Option<u32>,
+) -> Result<()> {
+    if !state.db.is_owner(room_id, moderator_id).await? {
+        bail!(ClientError::new(
+            ErrorCode::Forbidden,
+            format!("Only the room owner can {} people", action.as_str())
+        ));
+    }
+    let username = target.username.trim();
+    if username.is_empty() {
+        bail!(invalid(format!(
+            "Who should be {}? Give a username.",
+            action.as_str()
+        )));
+    }
+    let reason = reason
+        .map(|reason| reason.trim().to_string())
+        .filter(|reason| !reason.is_empty());
+
+    let candidates = match action {
+        ModerationAction::Unban => state.db.sanctioned_user_ids(room_id, username).await?,
+        _ => {
+            let mut candidates = state.user_ids_named(room_id, username).await?;
+            // Bans and mutes also reach someone who already left
+            if matches!(action, ModerationAction::Ban | ModerationAction::Mute) {
+                candidates.extend(state.db.find_user_ids(room_id, username).await?);
+            }
+            candidates
+        }
+    };
+    let target_id = pick_target(candidates, username, target.user_id, action)?;
+
+    let now = Utc::now();
+    let expires_at = match action {
+        ModerationAction::Kick => Some(now + Duration::minutes(KICK_COOLDOWN_MINUTES)),
+        ModerationAction::Mute => minutes.map(|minutes| now + Duration::minutes(minutes.into())),
+        ModerationAction::Ban | ModerationAction::Unban => None,
+    };
+
+    if action == ModerationAction::Unban {
+        if !state.db.lift_sanctions(room_id, &target_id).await? {
+            bail!(invalid(format!(
+                "{} is not banned, muted or kicked",
+                username
+            )));
+        }
+    } else {
+        if target_id == moderator_id {
+            bail!(invalid(format!("You can't {} yourself", action.as_str())));
+        }
+        if state.db.is_owner(room_id, &target_id).await? {
+            bail!(invalid(format!("{} also owns this room", username)));
+        }
+        let sanction = Sanction {
+            user_id: &target_id,
+            username,
+            action,
+            reason: reason.as_deref(),
+            expires_at,
+        };
+        state.db.add_sanction(room_id, &sanction).await?;
+    }
+
+    let event = ModerationEvent {
+        action,
+        moderator: moderator_name(state, room_id, moderator_id).await,
+        target_id,
+        target: username.to_string(),
+        reason,
+        expires_at,
+        timestamp: now,
+    };
+    info!("Room {}: {}", room_id, event.describe());
+    state.db.log_moderation(room_id, &event).await?;
+
+    let target_id = event.target_id.clone();
+    let notice = format!("Removed from the room: {}", event.describe());
+    let msg = ServerMessage::Moderation { event };
+    state.publish(room_id, RoomEvent::broadcast(msg));
+    if matches!(action, ModerationAction::Kick | ModerationAction::Ban) {
+        let code = match action {
+            ModerationAction::Ban => ErrorCode::Banned,
+            _ => ErrorCode::Kicked,
+        };
+        let disconnect = RoomEvent::Disconnect {
+            user_id: target_id,
+            code,
+            reason: notice,
+        };
+        state.publish(room_id, disconnect);
+    }
+
+    Ok(())
+}
+
+/// Narrow the user IDs going by `username` to the one the owner meant.
+/// Refuses rather than guess when more than one fits.
+fn pick_target(
+    mut candidates: Vec<String>,
+    username: &str,
+    prefix: Option<&str>,
+    action: ModerationAction,
+) -> Result<String> {
+    let prefix = prefix.map(str::trim).filter(|prefix| !prefix.is_empty());
+    if let Some(prefix) = prefix {
+        candidates.retain(|user_id| user_id.starts_with(prefix));
+    }
+    candidates.sort();
+    candidates.dedup();
+
+    if candidates.len() > 1 {
+        let tags: Vec<String> = candidates
+            .iter()
+            .map(|user_id| format!("{}#{}", username, user_tag(user_id)))
+            .collect();
+        bail!(invalid(format!(
+            "{} people go by {}. Say which: /{} {}",
+            candidates.len(),
+            username,
+            action.as_str(),
+            tags.join(" or ")
+        )));
+    }
+    match (candidates.pop(), prefix) {
+        (Some(user_id), _) => Ok(user_id),
+        (None, _) if action == ModerationAction::Unban => bail!(invalid(format!(
+            "{} is not banned, muted or kicked",
+            username
+        ))),
+        (None, Some(prefix)) => bail!(invalid(format!(
+            "No one called {}#{} is in this room",
+            username, prefix
+        ))),
+        (None, None) => bail!(invalid(format!(
+            "No one called {} is in this room",
+            username
+        ))),
+    }
+}
+
+/// The start of a user ID, enough to tell people with one name apart
+fn user_tag(user_id: &str) -> &str {
+    user_id
+        .char_indices()
+        .nth(USER_TAG_LENGTH)
+        .map_or(user_id, |(end, _)| &user_id[..end])
+}
+
+/// Longest slow mode interval an owner can set
+const MAX_SLOW_MODE_SECONDS: u32 = 3600;
+
+/// Set the minimum gap between each member's messages; zero turns slow mode
+/// off. Owners are never slowed down.
+pub async fn set_slow_mode(
+    state: &AppState,
+    room_id: &str,
+    moderator_id: &str,
+    seconds: u32,
+) -> Result<()> {
+    if !state.db.is_owner(room_id, moderator_id).await? {
+        bail!(ClientError::new(
+            ErrorCode::Forbidden,
+            "Only the room owner can change slow mode"
+        ));
+    }
+    if seconds > MAX_SLOW_MODE_SECONDS {
+        bail!(invalid(format!(
+            "Slow mode can be at most {} seconds",
+            MAX_SLOW_MODE_SECONDS
+        )));
+    }
+    state.db.set_slow_mode(room_id, seconds).await?;
+
+    let moderator = moderator_name(state, room_id, moderator_id).await;
+    info!(
+        "Room {}: {} set slow mode to {}s",
+        room_id, moderator, seconds
+    );
+
+    let msg = ServerMessage::SlowMode {
+        seconds,
+        moderator,
+        timestamp: Utc::now(),
+    };
+    state.publish(room_id, RoomEvent::SlowMode { seconds });
+    state.publish(room_id, RoomEvent::broadcast(msg));
+    Ok(())
+}
+
+/// Change the topic, which only the owner may do. Everyone in the room sees
+/// the new one.
+pub async fn set_topic(
+    state: &AppState,
+    room_id: &str,
+    moderator_id: &str,
+    topic: String,
+) -> Result<()> {
+    if !state.db.is_owner(room_id, moderator_id).await? {
+        bail!(ClientError::new(
+            ErrorCode::Forbidden,
+            "Only the room owner can change the topic"
+        ));
+    }
+    let topic = room_text(Some(topic), "Topic", Room::MAX_TOPIC_LENGTH)
+        .map_err(|e| invalid(e.to_string()))?;
+    state.db.set_topic(room_id, topic.as_deref()).await?;
+
+    let changed_by = moderator_name(state, room_id, moderator_id).await;
+    info!("Room {}: {} changed the topic", room_id, changed_by);
+
+    let msg = ServerMessage::TopicChanged {
+        topic,
+        changed_by,
+        timestamp: Utc::now(),
+    };
+    state.publish(room_id, RoomEvent::broadcast(msg));
+    Ok(())
+}
+
+/// The name a moderator joined under; they're always connected here
+async fn moderator_name(state: &AppState, room_id: &str, moderator_id: &str) -> String {
+    state
+        .username(room_id, moderator_id)
+        .await
+        .unwrap_or_else(|| "Unknown".to_string())
+}
+
+/// Refuse a join from someone banned, or kicked within the cooldown. People
+/// without a key are also matched by the address they connect from.
+pub async fn check_admitted(
+    state: &AppState,
+    room_id: &str,
+    user_id: &str,
+    fingerprint: Option<&str>,
+    address: IpAddr,
+) -> Result<()> {
+    let address = keyless_address(fingerprint, address);
+    let address = address.as_deref();
+    let sanction = state
+        .db
+        .active_sanction(
+            room_id,
+            user_id,
+            fingerprint,
+            address,
+            ModerationAction::Ban,
+        )
+        .await?;
+    if let Some(ban) = sanction {
+        bail!(ClientError::new(
+            ErrorCode::Banned,
+            format!(
+                "You are banned from this room{}",
+                reason_suffix(&ban.reason)
+            )
+        ));
+    }
+
+    let sanction = state
+        .db
+        .active_sanction(
+            room_id,
+            user_id,
+            fingerprint,
+            address,
+            ModerationAction::Kick,
+        )
+        .await?;
+    if let Some(kick) = sanction {
+        bail!(ClientError::new(
+            ErrorCode::Kicked,
+            format!(
+                "You were kicked from this room{}.{}",
+                reason_suffix(&kick.reason),
+                until(" You can rejoin", kick.expires_at)
+            )
+        ));
+    }
+    Ok(())
+}
+
+/// Refuse a message from someone muted
+pub async fn check_can_post(
+    state: &AppState,
+    room_id: &str,
+    user_id: &str,
+    fingerprint: Option<&str>,
+    address: IpAddr,
+) -> Result<()> {
+    let address = keyless_address(fingerprint, address);
+    let sanction = state
+        .db
+        .active_sanction(
+            room_id,
+            user_id,
+            fingerprint,
+            address.as_deref(),
+            ModerationAction::Mute,
+        )
+        .await?;
+    if let Some(mute) = sanction {
+        bail!(ClientError::new(
+            ErrorCode::Muted,
+            format!(
+                "You are muted in this room{}.{}",
+                reason_suffix(&mute.reason),
+                until(" You can post again", mute.expires_at)
+            )
+        ));
+    }
+    Ok(())
+}
+
+/// A key follows its owner to any user ID, so only keyless users are held to
+/// their address
+fn keyless_address(fingerprint: Option<&str>, address: IpAddr) -> Option<String> {
+    fingerprint.is_none().then(|| address.to_string())
+}
+
+fn invalid(message: String) -> ClientError {
+    ClientError::new(ErrorCode::InvalidRequest, message)
+}
+
+fn reason_suffix(reason: &Option<String>) -> String {
+    match reason {
+        Some(reason) => format!(": {}", reason),
+        None => String::new(),
+    }
+}
+
+/// e.g. " You can rejoin at 14:05 UTC.", or nothing for open-ended sanctions
+fn until(prefix: &str, expires_at: Option<DateTime<Utc>>) -> String {
+    match expires_at {
+        Some(expires_at) => format!("{} at {}.", prefix, expires_at.format("%H:%M UTC")),
+        None => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{MemoryStorage, Storage};
+    use crate::error;
+    use std::net::Ipv4Addr;
+    use std::sync::Arc;
+    use terma_shared::ChatMessage;
+
+    const ROOM: &str = "room-1";
+    const OWNER: &str = "owner-id";
+    const HOME: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+
+    /// A room with an owner, and messages posted by each `(user_id, username)`
+    async fn room_with(posters: &[(&str, &str)]) -> AppState {
+        let db = MemoryStorage::default();
+        db.create_room(&Room::new(ROOM.to_string()), &Default::default())
+            .await
+            .unwrap();
+        db.grant_role(ROOM, OWNER, "owner").await.unwrap();
+        for (user_id, username) in posters {
+            let msg = ChatMessage::new(
+                ROOM.to_string(),
+                user_id.to_string(),
+                username.to_string(),
+                "hi".to_string(),
+            );
+            db.save_message(&msg).await.unwrap();
+        }
+        AppState::new(Arc::new(db))
+    }
+
+    async fn act(
+        state: &AppState,
+        moderator_id: &str,
+        action: ModerationAction,
+        username: &str,
+    ) -> Result<()> {
+        let target = Target {
+            username,
+            user_id: None,
+        };
+        moderate(state, ROOM, moderator_id, action, target, None, None).await
+    }
+
+    async fn admitted(state: &AppState, user_id: &str) -> Result<()> {
+        check_admitted(state, ROOM, user_id, Some("fingerprint"), HOME).await
+    }
+
+    fn code(result: Result<()>) -> ErrorCode {
+        error::code_of(&result.unwrap_err())
+    }
+
+    #[tokio::test]
+    async fn only_owners_moderate() {
+        let state = room_with(&[("bob-id", "bob")]).await;
+        let result = act(&state, "bob-id", ModerationAction::Ban, "bob").await;
+        assert_eq!(code(result), ErrorCode::Forbidden);
+        let result = set_slow_mode(&state, ROOM, "bob-id", 10).await;
+        assert_eq!(code(result), ErrorCode::Forbidden);
+        let result = set_topic(&state, ROOM, "bob-id", "mine now".to_string()).await;
+        assert_eq!(code(result), ErrorCode::Forbidden);
+    }
+
+    #[tokio::test]
+    async fn owners_cannot_remove_themselves_or_each_other() {
+        let state = room_with(&[(OWNER, "olive"), ("co-owner", "carl")]).await;
+        state
+            .db
+            .grant_role(ROOM, "co-owner", "owner")
+            .await
+            .unwrap();
+        let result = act(&state, OWNER, ModerationAction::Ban, "olive").await;
+        assert_eq!(code(result), ErrorCode::InvalidRequest);
+        let result = act(&state, OWNER, ModerationAction::Mute, "carl").await;
+        assert_eq!(code(result), ErrorCode::InvalidRequest);
+        let result = act(&state, OWNER, ModerationAction::Ban, "carl").await;
+        assert_eq!(code(result), ErrorCode::InvalidRequest);
+    }
+
+    #[tokio::test]
+    async fn bans_hold_until_lifted() {
+        let state = room_with(&[("bob-id", "bob")]).await;
+        act(&state, OWNER, ModerationAction::Ban, "bob")
+            .await
+            .unwrap();
+        assert_eq!(code(admitted(&state, "bob-id").await), ErrorCode::Banned);
+        assert!(admitted(&state, "carol-id").await.is_ok());
+
+        let log = state.db.get_moderation_log(ROOM).await.unwrap();
+        assert_eq!(log.len(), 1);
+        assert_eq!(log[0].target_id, "bob-id");
+
+        act(&state, OWNER, ModerationAction::Unban, "bob")
+            .await
+            .unwrap();
+        assert!(admitted(&state, "bob-id").await.is_ok());
+        let result = act(&state, OWNER, ModerationAction::Unban, "bob").await;
+        assert_eq!(code(result), ErrorCode::InvalidRequest);
+    }
+
+    #[tokio::test]
+    async fn mutes_stop_posting_but_not_joining() {
+        let state = room_with(&[("bob-id", "bob")]).await;
+        let target = Target {
+            username: "bob",
+            user_id: None,
+        };
+        let reason = Some(" spam ".to_string());
+        moderate(
+            &state,
+            ROOM,
+            OWNER,
+            ModerationAction::Mute,
+            target,
+            reason,
+            Some(5),
+        )
+        .await
+        .unwrap();
+        assert!(admitted(&state, "bob-id").await.is_ok());
+        let e = check_can_post(&state, ROOM, "bob-id", Some("fingerprint"), HOME)
+            .await
+            .unwrap_err();
+        assert_eq!(error::code_of(&e), ErrorCode::Muted);
+        assert!(e
+            .to_string()
+            .starts_with("You are muted in this room: spam. You can post again at"));
+        assert!(check_can_post(&state, ROOM, "carol-id", None, HOME)
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn slow_mode_is_capped() {
+        let state = room_with(&[]).await;
+        set_slow_mode(&state, ROOM, OWNER, MAX_SLOW_MODE_SECONDS)
+            .await
+            .unwrap();
+        let room = state.db.get_room(ROOM).await.unwrap().unwrap();
+        assert_eq!(room.slow_mode_seconds, MAX_SLOW_MODE_SECONDS);
+        let result = set_slow_mode(&state, ROOM, OWNER, MAX_SLOW_MODE_SECONDS + 1).await;
+        assert_eq!(code(result), ErrorCode::InvalidRequest);
+    }
+
+    #[test]
+    fn picks_the_one_user_id_meant() {
+        let candidates = || vec!["aaaa1111-x".to_string(), "bbbb2222-y".to_string()];
+        let e = pick_target(candidates(), "bob", None, ModerationAction::Ban).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "2 people go by bob. Say which: /ban bob#aaaa1111 or bob#bbbb2222"
+        );
+        let picked = pick_target(candidates(), "bob", Some("bbbb"), ModerationAction::Ban);
+        assert_eq!(picked.unwrap(), "bbbb2222-y");
+        let e = pick_target(candidates(), "bob", Some("cc"), ModerationAction::Kick).unwrap_err();
+        assert_eq!(e.to_string(), "No one called bob#cc is in this room");
+        // The same person seen twice, connected and in the history, is one
+        let twice = vec!["aaaa1111-x".to_string(), "aaaa1111-x".to_string()];
+        assert_eq!(
+            pick_target(twice, "bob", None, ModerationAction::Mute).unwrap(),
+            "aaaa1111-x"
+        );
+    }
+
+    #[tokio::test]
+    async fn names_shared_by_several_people_need_an_id() {
+        let state = room_with(&[("aaaa1111-x", "bob"), ("bbbb2222-y", "bob")]).await;
+        let result = act(&state, OWNER, ModerationAction::Ban, "bob").await;
+        assert_eq!(code(result), ErrorCode::InvalidRequest);
+        assert!(state.db.get_moderation_log(ROOM).await.unwrap().is_empty());
+
+        let target = Target {
+            username: "bob",
+            user_id: Some("bbbb"),
+        };
+        moderate(
+            &state,
+            ROOM,
+            OWNER,
+            ModerationAction::Ban,
+            target,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            code(admitted(&state, "bbbb2222-y").await),
+            ErrorCode::Banned
+        );
+        assert!(admitted(&state, "aaaa1111-x").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn keyless_bans_follow_the_address() {
+        let state = room_with(&[("bob-id", "bob")]).await;
+        let elsewhere = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+        state
+            .db
+            .record_visit(ROOM, "bob-id", &HOME.to_string())
+            .await
+            .unwrap();
+        act(&state, OWNER, ModerationAction::Ban, "bob")
+            .await
+            .unwrap();
+
+        // A fresh user ID from the same address, still without a key
+        let result = check_admitted(&state, ROOM, "bob-again", None, HOME).await;
+        assert_eq!(code(result), ErrorCode::Banned);
+        assert!(check_admitted(&state, ROOM, "bob-again", None, elsewhere)
+            .await
+            .is_ok());
+        // Someone with a key on the same network is judged by their key
+        let keyed = check_admitted(&state, ROOM, "carol-id", Some("carol-key"), HOME).await;
+        assert!(keyed.is_ok());
+    }
+
+    #[tokio::test]
+    async fn keyed_bans_leave_the_address_alone() {
+        let state = room_with(&[("bob-id", "bob")]).await;
+        state
+            .db
+            .bind_identity("bob-id", "bob-public-key", "bob-key")
+            .await
+            .unwrap();
+        state
+            .db
+            .record_visit(ROOM, "bob-id", &HOME.to_string())
+            .await
+            .unwrap();
+        act(&state, OWNER, ModerationAction::Ban, "bob")
+            .await
+            .unwrap();
+
+        let result = check_admitted(&state, ROOM, "bob-id", Some("bob-key"), HOME).await;
+        assert_eq!(code(result), ErrorCode::Banned);
+        // Someone keyless behind the same NAT is still let in
+        assert!(check_admitted(&state, ROOM, "dave-id", None, HOME)
+            .await
+            .is_ok());
+    }
+}
@@ -0,0 +1,551 @@
+//! Bounded per-connection send queues. A client that reads slower than its
+//! room talks fills its queue; what happens then is set by
+//! `SLOW_CLIENT_POLICY`, so one stalled socket can't grow the server's memory
+//! without limit.
+
+use axum::extract::ws::{CloseFrame, Message};
+use std::borrow::Cow;
+use std::collections::{HashSet, VecDeque};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use terma_shared::{Codec, ErrorCode, Frame, ServerMessage};
+use tokio::sync::Notify;
+use tracing::warn;
+
+use crate::compression::FrameCompressor;
+use crate::metrics::Metrics;
+
+/// Messages queued per connection unless `OUTBOX_CAPACITY` says otherwise
+const DEFAULT_CAPACITY: usize = 256;
+
+/// What to do when a connection's queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlowClientPolicy {
+    /// Discard the oldest queued message to make room
+    DropOldest,
+    /// Discard queued updates a newer one supersedes (presence, topic, slow
+    /// mode), then the oldest message if that wasn't enough
+    Coalesce,
+    /// Hang up, telling the client it was too slow
+    Disconnect,
+}
+
+impl SlowClientPolicy {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "drop-oldest" => Some(Self::DropOldest),
+            "coalesce" => Some(Self::Coalesce),
+            "disconnect" => Some(Self::Disconnect),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct OutboxConfig {
+    pub capacity: usize,
+    pub policy: SlowClientPolicy,
+}
+
+impl OutboxConfig {
+    /// Read `OUTBOX_CAPACITY` and `SLOW_CLIENT_POLICY`, falling back to 256
+    /// messages and disconnecting
+    pub fn from_env() -> Self {
+        let capacity = std::env::var("OUTBOX_CAPACITY")
+            .ok()
+            .and_then(|capacity| capacity.parse().ok())
+            .filter(|capacity| *capacity > 0)
+            .unwrap_or(DEFAULT_CAPACITY);
+        let policy = match std::env::var("SLOW_CLIENT_POLICY") {
+            Ok(value) => SlowClientPolicy::parse(&value).unwrap_or_else(|| {
+                warn!("Unknown SLOW_CLIENT_POLICY {:?}; disconnecting", value);
+                SlowClientPolicy::Disconnect
+            }),
+            Err(_) => SlowClientPolicy::Disconnect,
+        };
+        Self { capacity, policy }
+    }
+}
+
+/// A message ready to queue, encoded and compressed at most once per wire
+/// format and compression window however many sockets it goes to
+#[derive(Clone)]
+pub struct Outgoing {
+    payload: Payload,
+    /// Messages with the same key supersede one another
+    key: Option<String>,
+}
+
+#[derive(Clone)]
+enum Payload {
+    Message(Arc<Encoded>),
+    Close(Message),
+}
+
+struct Encoded {
+    message: ServerMessage,
+    /// Frames made so far, by codec and compression window (zero for
+    /// uncompressed)
+    frames: Mutex<Vec<((Codec, u8), Message)>>,
+}
+
+impl Outgoing {
+    pub fn new(msg: &ServerMessage) -> Self {
+        Self {
+            payload: Payload::Message(Arc::new(Encoded {
+                message: msg.clone(),
+                frames: Mutex::new(Vec::new()),
+            })),
+            key: coalesce_key(msg),
+        }
+    }
+
+    fn into_message(self, codec: Codec, compressor: &FrameCompressor) -> Message {
+        let encoded = match self.payload {
+            Payload::Message(encoded) => encoded,
+            Payload::Close(message) => return message,
+        };
+        let key = (codec, compressor.window_bits());
+        let mut frames = encoded.frames.lock().unwrap();
+        if let Some((_, message)) = frames.iter().find(|(made_for, _)| *made_for == key) {
+            return message.clone();
+        }
+        let message = compressor.apply(encode(codec, &encoded.message));
+        frames.push((key, message.clone()));
+        message
+    }
+}
+
+/// A message as a frame in a connection's wire format
+pub fn encode(codec: Codec, msg: &ServerMessage) -> Message {
+    match msg.encode(codec).unwrap() {
+        Frame::Text(text) => Message::Text(text),
+        Frame::Binary(bytes) => Message::Binary(bytes),
+    }
+}
+
+/// Only the newest of these matters to a client that's behind
+fn coalesce_key(msg: &ServerMessage) -> Option<String> {
+    match msg {
+        ServerMessage::UserJoined { user_id, .. } | ServerMessage::UserLeft { user_id, .. } => {
+            Some(format!("presence:{}", user_id))
+        }
+        ServerMessage::TopicChanged { .. } => Some("topic".to_string()),
+        ServerMessage::SlowMode { .. } => Some("slow_mode".to_string()),
+        ServerMessage::RoomExpiring { .. } => Some("expiring".to_string()),
+        ServerMessage::Pong => Some("pong".to_string()),
+        _ => None,
+    }
+}
+
+/// One connection's queue, shared by its senders, its receiver and metrics
+pub struct Shared {
+    /// Registration with `Metrics`, for queue depth
+    id: u64,
+    queue: Mutex<Queue>,
+    ready: Notify,
+    config: OutboxConfig,
+    /// The connection's wire format
+    codec: Codec,
+    compressor: FrameCompressor,
+    metrics: Arc<Metrics>,
+    room_id: String,
+    user_id: String,
+}
+
+struct Queue {
+    items: VecDeque<Outgoing>,
+    /// A close frame is queued or the socket is gone; nothing more is sent
+    closed: bool,
+}
+
+/// The sending half of a connection's queue
+#[derive(Clone)]
+pub struct Outbox {
+    shared: Arc<Shared>,
+}
+
+/// The receiving half, drained by the task writing to the socket
+pub struct OutboxReceiver {
+    shared: Arc<Shared>,
+}
+
+pub fn channel(
+    config: OutboxConfig,
+    codec: Codec,
+    compressor: FrameCompressor,
+    metrics: Arc<Metrics>,
+    room_id: &str,
+    user_id: &str,
+) -> (Outbox, OutboxReceiver) {
+    let shared = Arc::new(Shared {
+        id: metrics.next_id(),
+        queue: Mutex::new(Queue {
+            items: VecDeque::new(),
+            closed: false,
+        }),
+        ready: Notify::new(),
+        config,
+        codec,
+        compressor,
+        metrics,
+        room_id: room_id.to_string(),
+        user_id: user_id.to_string(),
+    });
+    shared.metrics.register(shared.id, &shared);
+    (
+        Outbox {
+            shared: shared.clone(),
+        },
+        OutboxReceiver { shared },
+    )
+}
+
+impl Shared {
+    /// Messages waiting to be written to the socket
+    pub fn depth(&self) -> usize {
+        self.queue.lock().unwrap().items.len()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.config.capacity
+    }
+}
+
+impl Outbox {
+    pub fn send(&self, msg: &ServerMessage) {
+        self.push(Outgoing::new(msg));
+    }
+
+    /// Queue a message, applying the slow client policy if the queue is full
+    pub fn push(&self, outgoing: Outgoing) {
+        let shared = &self.shared;
+        let mut queue = shared.queue.lock().unwrap();
+        if queue.closed {
+            return;
+        }
+        if queue.items.len() >= shared.config.capacity {
+            let metrics = &shared.metrics;
+            match shared.config.policy {
+                SlowClientPolicy::DropOldest => {
+                    queue.items.pop_front();
+                    metrics.dropped_oldest.fetch_add(1, Ordering::Relaxed);
+                }
+                SlowClientPolicy::Coalesce => {
+                    let merged = coalesce(&mut queue.items, outgoing.key.as_deref());
+                    metrics
+                        .coalesced
+                        .fetch_add(merged as u64, Ordering::Relaxed);
+                    if queue.items.len() >= shared.config.capacity {
+                        queue.items.pop_front();
+                        metrics.dropped_oldest.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+                SlowClientPolicy::Disconnect => {
+                    warn!(
+                        "Disconnecting {} from room {}: {} messages behind",
+                        shared.user_id,
+                        shared.room_id,
+                        queue.items.len()
+                    );
+                    metrics.slow_disconnects.fetch_add(1, Ordering::Relaxed);
+                    // Skip the backlog so the close goes out straight away
+                    queue.items.clear();
+                    queue.items.push_back(close_message(
+                        ErrorCode::TooSlow,
+                        "Too slow: fell too far behind the room",
+                    ));
+                    queue.closed = true;
+                    shared.ready.notify_one();
+                    return;
+                }
+            }
+        }
+        queue.items.push_back(outgoing);
+        shared.ready.notify_one();
+    }
+
+    /// Close the socket after whatever is already queued, telling the
+    /// client why
+    pub fn close(&self, code: ErrorCode, reason: String) {
+        let mut queue = self.shared.queue.lock().unwrap();
+        if queue.closed {
+            return;
+        }
+        queue.items.push_back(close_message(code, reason));
+        queue.closed = true;
+        self.shared.ready.notify_one();
+    }
+}
+
+impl OutboxReceiver {
+    /// Wait for the next message to write to the socket
+    pub async fn recv(&mut self) -> Message {
+        loop {
+            if let Some(outgoing) = self.shared.queue.lock().unwrap().items.pop_front() {
+                return outgoing.into_message(self.shared.codec, &self.shared.compressor);
+            }
+            self.shared.ready.notified().await;
+        }
+    }
+}
+
+impl Drop for OutboxReceiver {
+    /// The socket is gone; stop queueing for it
+    fn drop(&mut self) {
+        let mut queue = self.shared.queue.lock().unwrap();
+        queue.closed = true;
+        queue.items.clear();
+        drop(queue);
+        self.shared.metrics.unregister(self.shared.id);
+    }
+}
+
+fn close_message(code: ErrorCode, reason: impl Into<Cow<'static, str>>) -> Outgoing {
+    Outgoing {
+        payload: Payload::Close(close_frame(code, reason)),
+        key: None,
+    }
+}
+
+/// Longest close reason that fits in a control frame
+const MAX_CLOSE_REASON: usize = 123;
+
+/// A close frame with the error's code, its reason cut to fit
+pub fn close_frame(code: ErrorCode, reason: impl Into<Cow<'static, str>>) -> Message {
+    let mut reason = reason.into();
+    if reason.len() > MAX_CLOSE_REASON {
+        let mut end = MAX_CLOSE_REASON;
+        while !reason.is_char_boundary(end) {
+            end -= 1;
+        }
+        reason = Cow::Owned(reason[..end].to_string());
+    }
+    Message::Close(Some(CloseFrame {
+        code: code.close_code(),
+        reason,
+    }))
+}
+
+/// Drop queued messages superseded by a later one with the same key, or by
+/// `incoming`. Returns how many were dropped.
+fn coalesce(items: &mut VecDeque<Outgoing>, incoming: Option<&str>) -> usize {
+    let before = items.len();
+    let mut seen: HashSet<String> = incoming.map(str::to_string).into_iter().collect();
+    let mut keep = VecDeque::with_capacity(items.len());
+    for outgoing in items.drain(..).rev() {
+        match &outgoing.key {
+            Some(key) if !seen.insert(key.clone()) => {}
+            _ => keep.push_front(outgoing),
+        }
+    }
+    *items = keep;
+    before - items.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use terma_shared::deflate::{Deflater, FRAME_MARKER};
+
+    fn queue(capacity: usize, policy: SlowClientPolicy) -> (Outbox, OutboxReceiver) {
+        queue_in(capacity, policy, Codec::Json)
+    }
+
+    fn queue_in(
+        capacity: usize,
+        policy: SlowClientPolicy,
+        codec: Codec,
+    ) -> (Outbox, OutboxReceiver) {
+        let metrics = Arc::new(Metrics::default());
+        let compressor = FrameCompressor::new(None, metrics.clone());
+        let config = OutboxConfig { capacity, policy };
+        channel(config, codec, compressor, metrics, "room", "alice")
+    }
+
+    fn numbered(n: u32) -> ServerMessage {
+        ServerMessage::error(ErrorCode::InvalidRequest, n.to_string())
+    }
+
+    fn topic(topic: &str) -> ServerMessage {
+        ServerMessage::TopicChanged {
+            topic: Some(topic.to_string()),
+            changed_by: "olive".to_string(),
+            timestamp: Utc::now(),
+        }
+    }
+
+    /// Everything queued, as a short label each
+    async fn drain(rx: &mut OutboxReceiver) -> Vec<String> {
+        let mut labels = Vec::new();
+        while rx.shared.depth() > 0 {
+            labels.push(match rx.recv().await {
+                Message::Text(text) => {
+                    match ServerMessage::decode(Codec::Json, Frame::Text(text)) {
+                        Ok(ServerMessage::Error { message, .. }) => message,
+                        Ok(ServerMessage::TopicChanged { topic, .. }) => topic.unwrap(),
+                        other => panic!("unexpected {:?}", other),
+                    }
+                }
+                Message::Close(Some(frame)) => format!("close {}", frame.code),
+                other => panic!("unexpected {:?}", other),
+            });
+        }
+        labels
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_keeps_the_newest() {
+        let (outbox, mut rx) = queue(2, SlowClientPolicy::DropOldest);
+        for n in 1..=4 {
+            outbox.send(&numbered(n));
+        }
+        assert_eq!(drain(&mut rx).await, ["3", "4"]);
+        assert_eq!(
+            outbox.shared.metrics.dropped_oldest.load(Ordering::Relaxed),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn coalesce_drops_superseded_updates_first() {
+        let (outbox, mut rx) = queue(3, SlowClientPolicy::Coalesce);
+        outbox.send(&topic("old"));
+        outbox.send(&numbered(1));
+        outbox.send(&numbered(2));
+        outbox.send(&topic("new"));
+        assert_eq!(drain(&mut rx).await, ["1", "2", "new"]);
+
+        // Nothing to merge, so the oldest goes
+        for n in 3..=6 {
+            outbox.send(&numbered(n));
+        }
+        assert_eq!(drain(&mut rx).await, ["4", "5", "6"]);
+        let metrics = &outbox.shared.metrics;
+        assert_eq!(metrics.coalesced.load(Ordering::Relaxed), 1);
+        assert_eq!(metrics.dropped_oldest.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn disconnect_skips_the_backlog_and_hangs_up() {
+        let (outbox, mut rx) = queue(2, SlowClientPolicy::Disconnect);
+        for n in 1..=3 {
+            outbox.send(&numbered(n));
+        }
+        outbox.send(&numbered(4));
+        let too_slow = format!("close {}", ErrorCode::TooSlow.close_code());
+        assert_eq!(drain(&mut rx).await, [too_slow]);
+        assert_eq!(
+            outbox
+                .shared
+                .metrics
+                .slow_disconnects
+                .load(Ordering::Relaxed),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn close_comes_after_what_is_queued() {
+        let (outbox, mut rx) = queue(4, SlowClientPolicy::Disconnect);
+        outbox.send(&numbered(1));
+        outbox.close(ErrorCode::Banned, "Banned".to_string());
+        outbox.send(&numbered(2));
+        let banned = format!("close {}", ErrorCode::Banned.close_code());
+        assert_eq!(drain(&mut rx).await, ["1".to_string(), banned]);
+    }
+
+    #[tokio::test]
+    async fn nothing_queues_once_the_socket_is_gone() {
+        let (outbox, rx) = queue(4, SlowClientPolicy::Disconnect);
+        drop(rx);
+        outbox.send(&numbered(1));
+        assert_eq!(outbox.shared.depth(), 0);
+    }
+
+    #[tokio::test]
+    async fn one_message_encodes_once_per_codec() {
+        let (json, mut json_rx) = queue_in(4, SlowClientPolicy::Disconnect, Codec::Json);
+        let (msgpack, mut msgpack_rx) =
+            queue_in(4, SlowClientPolicy::Disconnect, Codec::MessagePack);
+        let outgoing = Outgoing::new(&numbered(1));
+        json.push(outgoing.clone());
+        msgpack.push(outgoing.clone());
+        assert!(matches!(json_rx.recv().await, Message::Text(_)));
+        assert!(matches!(msgpack_rx.recv().await, Message::Binary(_)));
+        let Payload::Message(encoded) = &outgoing.payload else {
+            panic!("not a message");
+        };
+        assert_eq!(encoded.frames.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn one_message_compresses_once_per_window() {
+        let metrics = Arc::new(Metrics::default());
+        let config = OutboxConfig {
+            capacity: 4,
+            policy: SlowClientPolicy::Disconnect,
+        };
+        let queues: Vec<(Outbox, OutboxReceiver)> = [15, 15, 10]
+            .into_iter()
+            .map(|window_bits| {
+                let deflater = Deflater {
+                    window_bits,
+                    threshold: 0,
+                };
+                let compressor = FrameCompressor::new(Some(deflater), metrics.clone());
+                channel(
+                    config,
+                    Codec::Json,
+                    compressor,
+                    metrics.clone(),
+                    "room",
+                    "alice",
+                )
+            })
+            .collect();
+        let big = ServerMessage::error(ErrorCode::InvalidRequest, "x".repeat(2000));
+        let outgoing = Outgoing::new(&big);
+        let mut frames = Vec::new();
+        for (outbox, mut rx) in queues {
+            outbox.push(outgoing.clone());
+            frames.push(rx.recv().await);
+        }
+
+        let Message::Binary(compressed) = &frames[0] else {
+            panic!("not compressed");
+        };
+        assert_eq!(compressed[0], FRAME_MARKER);
+        assert_eq!(frames[0], frames[1]);
+        let Payload::Message(encoded) = &outgoing.payload else {
+            panic!("not a message");
+        };
+        let keys: Vec<(Codec, u8)> = encoded
+            .frames
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(key, _)| *key)
+            .collect();
+        assert_eq!(keys, [(Codec::Json, 15), (Codec::Json, 10)]);
+        let raw = encode(Codec::Json, &big);
+        let Message::Text(raw) = raw else {
+            panic!("not text");
+        };
+        assert_eq!(
+            metrics.compressed_in.load(Ordering::Relaxed),
+            2 * raw.len() as u64
+        );
+    }
+
+    #[test]
+    fn close_reasons_are_cut_to_fit() {
+        let reason = "é".repeat(100);
+        let Message::Close(Some(frame)) = close_frame(ErrorCode::Kicked, reason) else {
+            panic!("not a close frame");
+        };
+        assert!(frame.reason.len() <= MAX_CLOSE_REASON);
+        assert_eq!(frame.reason.len(), 122);
+    }
+}
@@ -0,0 +1,210 @@
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::state::AppState;
+
+/// WebSocket messages one connection may send in a burst, then per second
+pub const CONNECTION_BURST: u32 = 5;
+pub const CONNECTION_RATE: f64 = 1.0;
+
+/// WebSocket messages shared by every connection from one address
+pub const IP_BURST: u32 = 20;
+pub const IP_RATE: f64 = 5.0;
+
+/// Rooms one address may create in a burst, then one a minute
+pub const ROOM_CREATE_BURST: u32 = 5;
+pub const ROOM_CREATE_RATE: f64 = 1.0 / 60.0;
+
+/// Tracked addresses before idle (full) buckets are dropped
+const PRUNE_THRESHOLD: usize = 10_000;
+
+/// Holds up to `capacity` tokens, refilled continuously at `rate` per second.
+/// Each request takes one.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    capacity: f64,
+    rate: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(capacity: u32, rate: f64) -> Self {
+        Self {
+            capacity: capacity.into(),
+            rate,
+            tokens: capacity.into(),
+            updated: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.updated = now;
+    }
+
+    /// Take a token, or say how long until one is available
+    pub fn try_take(&mut self) -> Result<(), Duration> {
+        self.refill(Instant::now());
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / self.rate))
+        }
+    }
+
+    fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= self.capacity
+    }
+}
+
+/// A token bucket per remote address
+pub struct IpRateLimiter {
+    capacity: u32,
+    rate: f64,
+    buckets: Mutex<HashMap<IpAddr, TokenBucket>>,
+}
+
+impl IpRateLimiter {
+    pub fn new(capacity: u32, rate: f64) -> Self {
+        Self {
+            capacity,
+            rate,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn check(&self, ip: IpAddr) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= PRUNE_THRESHOLD {
+            // A full bucket is the same as no bucket
+            let now = Instant::now();
+            buckets.retain(|_, bucket| !bucket.is_full(now));
+        }
+        buckets
+            .entry(ip)
+            .or_insert_with(|| TokenBucket::new(self.capacity, self.rate))
+            .try_take()
+    }
+}
+
+/// The caller's address. Behind a reverse proxy (`TRUST_PROXY` set) that's
+/// the last `X-Forwarded-For` entry, the one the proxy appended; anything
+/// before it came from the client and can be forged.
+pub fn client_ip(headers: &HeaderMap, addr: SocketAddr, trust_proxy: bool) -> IpAddr {
+    let forwarded = trust_proxy
+        .then(|| {
+            headers
+                .get_all("x-forwarded-for")
+                .iter()
+                .next_back()?
+                .to_str()
+                .ok()
+        })
+        .flatten()
+        .and_then(|value| value.rsplit(',').next())
+        .and_then(|ip| ip.trim().parse().ok());
+    forwarded.unwrap_or_else(|| addr.ip())
+}
+
+/// Whole seconds to wait, rounded up so "0s" never shows
+pub fn seconds(wait: Duration) -> u64 {
+    wait.as_millis().div_ceil(1000).max(1) as u64
+}
+
+/// Middleware for `POST /api/rooms`: 429 with `Retry-After` when an address
+/// creates rooms too quickly
+pub async fn limit_room_creation(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let ip = client_ip(request.headers(), addr, state.trust_proxy);
+    if let Err(wait) = state.room_limiter.check(ip) {
+        let wait = seconds(wait);
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(header::RETRY_AFTER, wait.to_string())],
+            format!("Too many rooms created. Try again in {} second(s).", wait),
+        )
+            .into_response();
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets_allow_a_burst_then_refill() {
+        let mut bucket = TokenBucket::new(3, 2.0);
+        for _ in 0..3 {
+            assert!(bucket.try_take().is_ok());
+        }
+        let wait = bucket.try_take().unwrap_err();
+        assert!(wait <= Duration::from_millis(500) && wait > Duration::from_millis(400));
+
+        // Half a second at two a second is one more token, and no more
+        bucket.updated -= Duration::from_millis(500);
+        assert!(bucket.try_take().is_ok());
+        assert!(bucket.try_take().is_err());
+
+        // Never more than the capacity, however long it sits
+        bucket.updated -= Duration::from_secs(60);
+        for _ in 0..3 {
+            assert!(bucket.try_take().is_ok());
+        }
+        assert!(bucket.try_take().is_err());
+    }
+
+    #[test]
+    fn each_address_has_its_own_bucket() {
+        let limiter = IpRateLimiter::new(1, 0.001);
+        let a: IpAddr = "10.0.0.1".parse().unwrap();
+        let b: IpAddr = "10.0.0.2".parse().unwrap();
+        assert!(limiter.check(a).is_ok());
+        assert!(limiter.check(a).is_err());
+        assert!(limiter.check(b).is_ok());
+    }
+
+    #[test]
+    fn forwarded_addresses_count_only_behind_a_proxy() {
+        let mut headers = HeaderMap::new();
+        // The client sent its own header; the proxy appended the real address
+        headers.insert("x-forwarded-for", "10.9.9.9, 203.0.113.7".parse().unwrap());
+        let proxy: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+        assert_eq!(client_ip(&headers, proxy, false), proxy.ip());
+        assert_eq!(
+            client_ip(&headers, proxy, true),
+            "203.0.113.7".parse::<IpAddr>().unwrap()
+        );
+        headers.append("x-forwarded-for", "198.51.100.2".parse().unwrap());
+        assert_eq!(
+            client_ip(&headers, proxy, true),
+            "198.51.100.2".parse::<IpAddr>().unwrap()
+        );
+        headers.insert("x-forwarded-for", "not an address".parse().unwrap());
+        assert_eq!(client_ip(&headers, proxy, true), proxy.ip());
+    }
+
+    #[test]
+    fn waits_round_up_to_whole_seconds() {
+        assert_eq!(seconds(Duration::ZERO), 1);
+        assert_eq!(seconds(Duration::from_millis(1)), 1);
+        assert_eq!(seconds(Duration::from_millis(1000)), 1);
+        assert_eq!(seconds(Duration::from_millis(1001)), 2);
+    }
+}
@@ -0,0 +1,134 @@
+use anyhow::Result;
+use chrono::Utc;
+use std::time::Duration;
+use tracing::{error, info};
+
+use crate::db::Db;
+
+/// How often rooms are trimmed back to their retention policy
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Messages deleted per statement, so pruning never holds long locks
+const PRUNE_BATCH: i64 = 5000;
+
+/// Start the background task that enforces each room's retention policy
+pub fn spawn_pruner(db: Db) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+        loop {
+            interval.tick().await;
+            match prune(&db).await {
+                Ok(0) => {}
+                Ok(removed) => info!("Pruned {} message(s) past retention", removed),
+                Err(e) => error!("Message pruning failed: {}", e),
+            }
+        }
+    });
+}
+
+async fn prune(db: &Db) -> Result<u64> {
+    let mut removed = 0;
+
+    // Rooms that keep N days
+    loop {
+        let deleted = db.prune_expired_messages(PRUNE_BATCH).await?;
+        removed += deleted;
+        if deleted < PRUNE_BATCH as u64 {
+            break;
+        }
+    }
+
+    // Rooms that keep the newest N messages and have had new ones since
+    for (room_id, keep) in db.rooms_to_trim().await? {
+        let started = Utc::now();
+        loop {
+            let deleted = db.trim_room_messages(&room_id, keep, PRUNE_BATCH).await?;
+            removed += deleted;
+            if deleted < PRUNE_BATCH as u64 {
+                break;
+            }
+        }
+        db.mark_trimmed(&room_id, started).await?;
+    }
+
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::MemoryStorage;
+    use std::sync::Arc;
+    use terma_shared::{ChatMessage, Retention, Room};
+
+    async fn room(db: &Db, room_id: &str, retention: Retention) {
+        let room = Room {
+            retention,
+            ..Room::new(room_id.to_string())
+        };
+        db.create_room(&room, &Default::default()).await.unwrap();
+    }
+
+    /// Post `count` messages, `age_days` old
+    async fn post(db: &Db, room_id: &str, count: usize, age_days: i64) {
+        for i in 0..count {
+            let mut msg = ChatMessage::new(
+                room_id.to_string(),
+                "alice".to_string(),
+                "alice".to_string(),
+                format!("message {}", i),
+            );
+            msg.timestamp =
+                Utc::now() - chrono::Duration::days(age_days) + chrono::Duration::seconds(i as i64);
+            db.save_message(&msg).await.unwrap();
+        }
+    }
+
+    async fn contents(db: &Db, room_id: &str) -> Vec<String> {
+        let history = db.get_message_history(room_id).await.unwrap();
+        history.into_iter().map(|msg| msg.content).collect()
+    }
+
+    #[tokio::test]
+    async fn keeps_each_rooms_newest_messages() {
+        let db: Db = Arc::new(MemoryStorage::default());
+        room(&db, "capped", Retention::Messages(3)).await;
+        room(&db, "forever", Retention::Unlimited).await;
+        post(&db, "capped", 5, 0).await;
+        post(&db, "forever", 5, 400).await;
+
+        assert_eq!(prune(&db).await.unwrap(), 2);
+        assert_eq!(
+            contents(&db, "capped").await,
+            ["message 2", "message 3", "message 4"]
+        );
+        assert_eq!(contents(&db, "forever").await.len(), 5);
+    }
+
+    #[tokio::test]
+    async fn drops_messages_older_than_the_rooms_days() {
+        let db: Db = Arc::new(MemoryStorage::default());
+        room(&db, "week", Retention::Days(7)).await;
+        post(&db, "week", 2, 8).await;
+        post(&db, "week", 2, 1).await;
+
+        assert_eq!(prune(&db).await.unwrap(), 2);
+        assert_eq!(contents(&db, "week").await.len(), 2);
+        assert_eq!(prune(&db).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn only_trims_rooms_active_since_the_last_trim() {
+        let db: Db = Arc::new(MemoryStorage::default());
+        room(&db, "capped", Retention::Messages(1)).await;
+        post(&db, "capped", 2, 0).await;
+        assert_eq!(prune(&db).await.unwrap(), 1);
+        assert!(db.rooms_to_trim().await.unwrap().is_empty());
+
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        post(&db, "capped", 2, 0).await;
+        db.touch_room("capped").await.unwrap();
+        assert_eq!(prune(&db).await.unwrap(), 2);
+        assert_eq!(contents(&db, "capped").await, ["message 1"]);
+    }
+}
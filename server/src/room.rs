@@ -0,0 +1,794 @@
+//! One task per room with members on this instance. The task owns the room's
+//! connections and applies commands one at a time, so no lock is shared
+//! between rooms. Each connection is a session with a server-issued ID, and
+//! one user may have several, from different devices. A session whose
+//! connection drops lingers for a grace period, so a quick reconnect picks
+//! it up again without the room seeing the user leave and rejoin.
+//!
+//! Messages are saved by a second task per room, which broadcasts each one
+//! once it's stored, so a slow insert only holds up the room it belongs to.
+
+use chrono::{DateTime, Utc};
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+use terma_shared::{ChatMessage, ErrorCode, Room, ServerMessage, ENCRYPTED_CONTENT_PREFIX};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{error, info};
+
+use crate::outbox::{Outbox, Outgoing};
+use crate::rate_limit;
+use crate::state::{AppState, RoomEvent};
+
+/// Posting keeps a room from being reaped as idle, but it's recorded at most
+/// this often
+const ACTIVITY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// What a room's task can be asked to do
+pub enum RoomCommand {
+    /// Add a session, or resume one of the user's lingering sessions
+    Join {
+        session_id: String,
+        /// The session the client would like back
+        resume: Option<String>,
+        user_id: String,
+        username: String,
+        owner: bool,
+        outbox: Outbox,
+        reply: oneshot::Sender<Joined>,
+    },
+    /// Drop a session. One whose connection was `lost`, rather than closed
+    /// by either side, lingers for the grace period first.
+    Leave {
+        session_id: String,
+        lost: bool,
+    },
+    /// The grace period of a lingering session is over
+    Expire {
+        session_id: String,
+        generation: u64,
+    },
+    /// Check a chat message against the room's rules, then save and send it
+    Post {
+        session_id: String,
+        user_id: String,
+        content: String,
+        fingerprint: Option<String>,
+    },
+    Event(RoomEvent),
+    /// Warn members, once, if the room expires before `deadline`
+    WarnExpiring {
+        deadline: DateTime<Utc>,
+    },
+    Username {
+        user_id: String,
+        reply: oneshot::Sender<Option<String>>,
+    },
+    /// Replies with `(user_id, username)` for everyone connected here
+    Members {
+        reply: oneshot::Sender<Vec<(String, String)>>,
+    },
+}
+
+/// What a session learns when it joins
+#[derive(Default)]
+pub struct Joined {
+    /// A resumed session keeps its ID
+    pub session_id: String,
+    pub resumed: bool,
+    pub slow_mode_seconds: u32,
+    /// The user had no other session here, so they've just arrived
+    pub first_session: bool,
+}
+
+/// Where to send a room's task commands
+#[derive(Clone)]
+pub struct RoomHandle {
+    commands: mpsc::UnboundedSender<RoomCommand>,
+}
+
+impl RoomHandle {
+    pub fn send(&self, command: RoomCommand) {
+        let _ = self.commands.send(command);
+    }
+
+    /// Ask the room something; `None` if its task has already finished
+    pub async fn ask<T>(
+        &self,
+        command: impl FnOnce(oneshot::Sender<T>) -> RoomCommand,
+    ) -> Option<T> {
+        let (reply, answer) = oneshot::channel();
+        self.commands.send(command(reply)).ok()?;
+        answer.await.ok()
+    }
+}
+
+/// Start the tasks for a room. The caller must hold the room registry lock
+/// and register the handle before releasing it.
+pub fn spawn(state: AppState, room: &Room) -> RoomHandle {
+    let (commands, rx) = mpsc::unbounded_channel();
+    let (writer, messages) = mpsc::unbounded_channel();
+    tokio::spawn(write_messages(state.clone(), room.id.clone(), messages));
+
+    let actor = RoomActor {
+        room_id: room.id.clone(),
+        handle: RoomHandle {
+            commands: commands.clone(),
+        },
+        grace: state.presence_grace,
+        state,
+        commands: rx,
+        writer,
+        sessions: HashMap::new(),
+        members: HashMap::new(),
+        encrypted: room.encrypted,
+        owners: HashSet::new(),
+        slow_mode: Duration::from_secs(room.slow_mode_seconds.into()),
+        last_posted: HashMap::new(),
+        expires_at: room.expires_at,
+        expiry_warned: false,
+    };
+    tokio::spawn(actor.run());
+    RoomHandle { commands }
+}
+
+struct RoomActor {
+    room_id: String,
+    /// For lingering sessions to expire themselves
+    handle: RoomHandle,
+    /// How long a dropped session waits for its user to reconnect
+    grace: Duration,
+    state: AppState,
+    commands: mpsc::UnboundedReceiver<RoomCommand>,
+    /// Accepted messages, in order, for the writer to save and broadcast
+    writer: mpsc::UnboundedSender<ChatMessage>,
+    /// Connections by session ID
+    sessions: HashMap<String, Session>,
+    /// People by user ID
+    members: HashMap<String, Member>,
+    /// Only ciphertext may be posted to end-to-end encrypted rooms
+    encrypted: bool,
+    /// Connected user IDs that own the room
+    owners: HashSet<String>,
+    /// Minimum time between each member's messages; zero when off
+    slow_mode: Duration,
+    last_posted: HashMap<String, Instant>,
+    /// When the reaper deletes the room, if it has an expiry
+    expires_at: Option<DateTime<Utc>>,
+    /// Members were already told the room expires soon
+    expiry_warned: bool,
+}
+
+struct Session {
+    user_id: String,
+    /// `None` while lingering after the connection dropped
+    outbox: Option<Outbox>,
+    /// Bumped whenever the session is dropped, so only the latest grace
+    /// period can expire it
+    generation: u64,
+}
+
+/// Someone in the room, however many devices they're connected from
+struct Member {
+    username: String,
+    sessions: HashSet<String>,
+}
+
+impl RoomActor {
+    async fn run(mut self) {
+        while let Some(command) = self.commands.recv().await {
+            self.handle(command);
+            if self.sessions.is_empty() && self.retire() {
+                break;
+            }
+        }
+    }
+
+    /// Leave the registry once the last member is gone. Joins are queued
+    /// while the registry is locked, so an empty queue here means none can
+    /// still arrive.
+    fn retire(&self) -> bool {
+        let mut rooms = self.state.rooms.lock().unwrap();
+        if !self.commands.is_empty() {
+            return false;
+        }
+        rooms.remove(&self.room_id);
+        true
+    }
+
+    fn handle(&mut self, command: RoomCommand) {
+        match command {
+            RoomCommand::Join {
+                session_id,
+                resume,
+                user_id,
+                username,
+                owner,
+                outbox,
+                reply,
+            } => {
+                if owner {
+                    self.owners.insert(user_id.clone());
+                }
+                let lingering = self.lingering_session(&user_id, resume);
+                let member = self
+                    .members
+                    .entry(user_id.clone())
+                    .or_insert_with(|| Member {
+                        username: username.clone(),
+                        sessions: HashSet::new(),
+                    });
+                // The latest device's name wins
+                member.username = username;
+
+                let joined = match lingering {
+                    Some(session_id) => {
+                        let session = self.sessions.get_mut(&session_id).unwrap();
+                        session.outbox = Some(outbox);
+                        Joined {
+                            session_id,
+                            resumed: true,
+                            slow_mode_seconds: self.slow_mode.as_secs() as u32,
+                            first_session: false,
+                        }
+                    }
+                    None => {
+                        member.sessions.insert(session_id.clone());
+                        let first_session = member.sessions.len() == 1;
+                        let session = Session {
+                            user_id,
+                            outbox: Some(outbox),
+                            generation: 0,
+                        };
+                        self.sessions.insert(session_id.clone(), session);
+                        Joined {
+                            session_id,
+                            resumed: false,
+                            slow_mode_seconds: self.slow_mode.as_secs() as u32,
+                            first_session,
+                        }
+                    }
+                };
+                let _ = reply.send(joined);
+            }
+            RoomCommand::Leave { session_id, lost } => self.leave(session_id, lost),
+            RoomCommand::Expire {
+                session_id,
+                generation,
+            } => {
+                let expired = self.sessions.get(&session_id).is_some_and(|session| {
+                    session.outbox.is_none() && session.generation == generation
+                });
+                if expired {
+                    self.remove_session(&session_id);
+                }
+            }
+            RoomCommand::Post {
+                session_id,
+                user_id,
+                content,
+                fingerprint,
+            } => self.post(&session_id, user_id, content, fingerprint),
+            RoomCommand::Event(event) => self.apply(&event),
+            RoomCommand::WarnExpiring { deadline } => self.warn_expiring(deadline),
+            RoomCommand::Username { user_id, reply } => {
+                let username = self.members.get(&user_id).map(|m| m.username.clone());
+                let _ = reply.send(username);
+            }
+            RoomCommand::Members { reply } => {
+                let members = self
+                    .members
+                    .iter()
+                    .map(|(user_id, member)| (user_id.clone(), member.username.clone()))
+                    .collect();
+                let _ = reply.send(members);
+            }
+        }
+    }
+
+    /// A dropped session of the user's to pick up again: the one asked for,
+    /// or failing that any of theirs
+    fn lingering_session(&self, user_id: &str, resume: Option<String>) -> Option<String> {
+        let lingering = |session_id: &String| {
+            self.sessions
+                .get(session_id)
+                .is_some_and(|session| session.user_id == user_id && session.outbox.is_none())
+        };
+        if let Some(session_id) = resume.filter(lingering) {
+            return Some(session_id);
+        }
+        let member = self.members.get(user_id)?;
+        member.sessions.iter().find(|id| lingering(id)).cloned()
+    }
+
+    fn leave(&mut self, session_id: String, lost: bool) {
+        let Some(session) = self.sessions.get_mut(&session_id) else {
+            return;
+        };
+        if !lost || self.grace.is_zero() {
+            self.remove_session(&session_id);
+            return;
+        }
+        session.outbox = None;
+        session.generation += 1;
+        let generation = session.generation;
+        let (handle, grace) = (self.handle.clone(), self.grace);
+        tokio::spawn(async move {
+            tokio::time::sleep(grace).await;
+            handle.send(RoomCommand::Expire {
+                session_id,
+                generation,
+            });
+        });
+    }
+
+    /// Forget a session, and announce the user's departure if it was their
+    /// last
+    fn remove_session(&mut self, session_id: &str) {
+        let Some(session) = self.sessions.remove(session_id) else {
+            return;
+        };
+        let user_id = session.user_id;
+        let Some(member) = self.members.get_mut(&user_id) else {
+            return;
+        };
+        member.sessions.remove(session_id);
+        if !member.sessions.is_empty() {
+            return;
+        }
+        self.owners.remove(&user_id);
+        self.last_posted.remove(&user_id);
+        if let Some(member) = self.members.remove(&user_id) {
+            let state = self.state.clone();
+            let room_id = self.room_id.clone();
+            tokio::spawn(announce_leave(state, room_id, user_id, member.username));
+        }
+    }
+
+    fn post(
+        &mut self,
+        session_id: &str,
+        user_id: String,
+        content: String,
+        fingerprint: Option<String>,
+    ) {
+        if let Err(wait) = self.take_slow_mode_turn(&user_id) {
+            let error_msg = ServerMessage::Error {
+                code: ErrorCode::RateLimited,
+                message: format!(
+                    "Slow mode is on. You can send again in {}s.",
+                    rate_limit::seconds(wait)
+                ),
+                retry_after_ms: Some(wait.as_millis() as u64),
+            };
+            self.send_to_session(session_id, &error_msg);
+            return;
+        }
+
+        if self.encrypted && !content.starts_with(ENCRYPTED_CONTENT_PREFIX) {
+            let error_msg = ServerMessage::error(
+                ErrorCode::EncryptionRequired,
+                "This room is end-to-end encrypted. Plaintext messages are rejected.",
+            );
+            self.send_to_session(session_id, &error_msg);
+            return;
+        }
+
+        let username = self
+            .members
+            .get(&user_id)
+            .map_or_else(|| "Unknown".to_string(), |m| m.username.clone());
+        let mut chat_msg = ChatMessage::new(self.room_id.clone(), user_id, username, content);
+        chat_msg.fingerprint = fingerprint;
+        let _ = self.writer.send(chat_msg);
+    }
+
+    /// Record a post under slow mode, or say how long the user must wait.
+    /// Owners are exempt.
+    fn take_slow_mode_turn(&mut self, user_id: &str) -> Result<(), Duration> {
+        if self.slow_mode.is_zero() || self.owners.contains(user_id) {
+            return Ok(());
+        }
+        let now = Instant::now();
+        if let Some(last) = self.last_posted.get(user_id) {
+            let next = *last + self.slow_mode;
+            if next > now {
+                return Err(next - now);
+            }
+        }
+        self.last_posted.insert(user_id.to_string(), now);
+        Ok(())
+    }
+
+    fn apply(&mut self, event: &RoomEvent) {
+        match event {
+            RoomEvent::Broadcast {
+                message,
+                exclude_user,
+            } => self.broadcast(message, exclude_user.as_deref()),
+            RoomEvent::Disconnect {
+                user_id,
+                code,
+                reason,
+            } => self.disconnect(user_id, *code, reason.clone()),
+            RoomEvent::SlowMode { seconds } => {
+                self.slow_mode = Duration::from_secs((*seconds).into());
+            }
+            RoomEvent::Close { reason } => {
+                let msg = ServerMessage::RoomClosed {
+                    reason: reason.clone(),
+                };
+                self.broadcast(&msg, None);
+                for outbox in self.sessions.values().filter_map(|s| s.outbox.as_ref()) {
+                    outbox.close(ErrorCode::RoomGone, reason.clone());
+                }
+            }
+        }
+    }
+
+    fn warn_expiring(&mut self, deadline: DateTime<Utc>) {
+        let Some(expires_at) = self.expires_at else {
+            return;
+        };
+        if self.expiry_warned || expires_at > deadline {
+            return;
+        }
+        self.expiry_warned = true;
+        info!("Room {} expires at {}", self.room_id, expires_at);
+        let msg = ServerMessage::RoomExpiring { expires_at };
+        self.broadcast(&msg, None);
+    }
+
+    fn broadcast(&self, msg: &ServerMessage, exclude_user: Option<&str>) {
+        let outgoing = Outgoing::new(msg);
+        for session in self.sessions.values() {
+            if exclude_user == Some(session.user_id.as_str()) {
+                continue;
+            }
+            if let Some(outbox) = &session.outbox {
+                outbox.push(outgoing.clone());
+            }
+        }
+    }
+
+    fn send_to_session(&self, session_id: &str, msg: &ServerMessage) {
+        if let Some(outbox) = self
+            .sessions
+            .get(session_id)
+            .and_then(|s| s.outbox.as_ref())
+        {
+            outbox.send(msg);
+        }
+    }
+
+    /// Close every one of a user's sockets, telling their client why
+    fn disconnect(&self, user_id: &str, code: ErrorCode, reason: String) {
+        let Some(member) = self.members.get(user_id) else {
+            return;
+        };
+        for session_id in &member.sessions {
+            if let Some(outbox) = self
+                .sessions
+                .get(session_id)
+                .and_then(|s| s.outbox.as_ref())
+            {
+                outbox.close(code, reason.clone());
+            }
+        }
+    }
+}
+
+/// Tell the room, on every instance, that a user's last session is gone
+async fn announce_leave(state: AppState, room_id: String, user_id: String, username: String) {
+    state.track_leave(&room_id, &user_id).await;
+    if state.present_elsewhere(&room_id, &user_id).await {
+        return;
+    }
+    let left_msg = ServerMessage::UserLeft {
+        user_id,
+        username,
+        timestamp: Utc::now(),
+        online_count: state.online_count(&room_id).await,
+    };
+    state.publish(&room_id, RoomEvent::broadcast(left_msg));
+}
+
+/// Save a room's messages in the order they were accepted, publishing each
+/// once it's stored. Runs until the room's task finishes and the queue is
+/// drained. The room is marked active at most once per `ACTIVITY_INTERVAL`,
+/// and once more at the end, rather than a row update per message.
+async fn write_messages(
+    state: AppState,
+    room_id: String,
+    mut messages: mpsc::UnboundedReceiver<ChatMessage>,
+) {
+    let mut last_touched: Option<Instant> = None;
+    let mut untouched = false;
+    while let Some(chat_msg) = messages.recv().await {
+        if let Err(e) = state.db.save_message(&chat_msg).await {
+            error!("Failed to save message to database: {}", e);
+            // Continue broadcasting even if save fails
+        }
+        untouched = true;
+        if last_touched.is_none_or(|at| at.elapsed() >= ACTIVITY_INTERVAL) {
+            touch(&state, &room_id).await;
+            last_touched = Some(Instant::now());
+            untouched = false;
+        }
+        let server_msg = ServerMessage::Message { message: chat_msg };
+        state.publish(&room_id, RoomEvent::broadcast(server_msg));
+    }
+    // Retention trims rooms active since their last trim, so the newest
+    // messages mustn't go unrecorded
+    if untouched {
+        touch(&state, &room_id).await;
+    }
+}
+
+async fn touch(state: &AppState, room_id: &str) {
+    if let Err(e) = state.db.touch_room(room_id).await {
+        error!("Failed to record activity in room {}: {}", room_id, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{MemoryStorage, Storage};
+    use crate::outbox::{self, OutboxReceiver};
+    use axum::extract::ws::Message;
+    use std::sync::Arc;
+    use terma_shared::{Codec, Frame};
+
+    async fn state_with(room: &Room) -> AppState {
+        let db = MemoryStorage::default();
+        db.create_room(room, &Default::default()).await.unwrap();
+        AppState::new(Arc::new(db))
+    }
+
+    /// Join as a new session, returning it and its queue
+    async fn join(state: &AppState, room: &Room, user_id: &str) -> (Joined, OutboxReceiver) {
+        connect(state, room, user_id, false, None).await
+    }
+
+    async fn connect(
+        state: &AppState,
+        room: &Room,
+        user_id: &str,
+        owner: bool,
+        resume: Option<String>,
+    ) -> (Joined, OutboxReceiver) {
+        let (outbox, rx) = outbox::channel(
+            state.outbox,
+            Codec::Json,
+            crate::compression::FrameCompressor::new(None, state.metrics.clone()),
+            state.metrics.clone(),
+            &room.id,
+            user_id,
+        );
+        let joined = state
+            .join_room(
+                room,
+                resume,
+                user_id.to_string(),
+                user_id.to_string(),
+                owner,
+                outbox,
+            )
+            .await;
+        (joined, rx)
+    }
+
+    async fn next(rx: &mut OutboxReceiver) -> ServerMessage {
+        let msg = tokio::time::timeout(Duration::from_secs(1), rx.recv())
+            .await
+            .expect("nothing was sent");
+        match msg {
+            Message::Text(text) => ServerMessage::decode(Codec::Json, Frame::Text(text)).unwrap(),
+            other => panic!("expected a message, got {:?}", other),
+        }
+    }
+
+    /// Whoever left, if the room says someone did
+    async fn left(rx: &mut OutboxReceiver) -> Option<String> {
+        let msg = tokio::time::timeout(Duration::from_millis(200), rx.recv()).await;
+        match msg.ok()? {
+            Message::Text(text) => match ServerMessage::decode(Codec::Json, Frame::Text(text)) {
+                Ok(ServerMessage::UserLeft { user_id, .. }) => Some(user_id),
+                other => panic!("expected someone to leave, got {:?}", other),
+            },
+            other => panic!("expected a message, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn encrypted_rooms_refuse_plaintext() {
+        let room = Room {
+            encrypted: true,
+            ..Room::new("secret".to_string())
+        };
+        let state = state_with(&room).await;
+        let (joined, mut rx) = join(&state, &room, "alice").await;
+
+        state.post(
+            &room.id,
+            &joined.session_id,
+            "alice",
+            "hello".to_string(),
+            None,
+        );
+        match next(&mut rx).await {
+            ServerMessage::Error { code, .. } => assert_eq!(code, ErrorCode::EncryptionRequired),
+            other => panic!("expected an error, got {:?}", other),
+        }
+
+        let sealed = format!("{}c2VhbGVk", ENCRYPTED_CONTENT_PREFIX);
+        state.post(&room.id, &joined.session_id, "alice", sealed.clone(), None);
+        match next(&mut rx).await {
+            ServerMessage::Message { message } => assert_eq!(message.content, sealed),
+            other => panic!("expected the message, got {:?}", other),
+        }
+        let saved = state.db.get_message_history(&room.id).await.unwrap();
+        assert_eq!(saved.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn slow_mode_spaces_out_members_but_not_owners() {
+        let room = Room {
+            slow_mode_seconds: 30,
+            ..Room::new("slow".to_string())
+        };
+        let state = state_with(&room).await;
+        let (owner, mut owner_rx) = connect(&state, &room, "olive", true, None).await;
+        let (member, mut member_rx) = join(&state, &room, "bob").await;
+
+        for content in ["one", "two"] {
+            state.post(
+                &room.id,
+                &owner.session_id,
+                "olive",
+                content.to_string(),
+                None,
+            );
+            assert!(matches!(
+                next(&mut owner_rx).await,
+                ServerMessage::Message { .. }
+            ));
+        }
+
+        state.post(
+            &room.id,
+            &member.session_id,
+            "bob",
+            "first".to_string(),
+            None,
+        );
+        // The owner's two messages, then the member's own
+        for _ in 0..3 {
+            assert!(matches!(
+                next(&mut member_rx).await,
+                ServerMessage::Message { .. }
+            ));
+        }
+        state.post(
+            &room.id,
+            &member.session_id,
+            "bob",
+            "second".to_string(),
+            None,
+        );
+        match next(&mut member_rx).await {
+            ServerMessage::Error {
+                code,
+                retry_after_ms: Some(wait),
+                ..
+            } => {
+                assert_eq!(code, ErrorCode::RateLimited);
+                assert!(wait > 29_000 && wait <= 30_000, "{}", wait);
+            }
+            other => panic!("expected slow mode, got {:?}", other),
+        }
+
+        // Turning it off lets them straight back in
+        state.publish(&room.id, RoomEvent::SlowMode { seconds: 0 });
+        state.post(
+            &room.id,
+            &member.session_id,
+            "bob",
+            "third".to_string(),
+            None,
+        );
+        match next(&mut member_rx).await {
+            ServerMessage::Message { message } => assert_eq!(message.content, "third"),
+            other => panic!("expected the message, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn people_leave_with_their_last_session() {
+        let room = Room::new("devices".to_string());
+        let state = state_with(&room).await;
+        let (_, mut watcher) = join(&state, &room, "watcher").await;
+        let (laptop, _laptop_rx) = join(&state, &room, "alice").await;
+        let (phone, _phone_rx) = join(&state, &room, "alice").await;
+        assert!(laptop.first_session);
+        assert!(!phone.first_session);
+        assert_ne!(laptop.session_id, phone.session_id);
+        assert_eq!(state.online_count(&room.id).await, 2);
+
+        state.leave_room(&room.id, &laptop.session_id, false);
+        assert_eq!(left(&mut watcher).await, None);
+        state.leave_room(&room.id, &phone.session_id, false);
+        assert_eq!(left(&mut watcher).await.as_deref(), Some("alice"));
+        assert_eq!(state.online_count(&room.id).await, 1);
+    }
+
+    #[tokio::test]
+    async fn dropped_sessions_resume_within_the_grace_period() {
+        let room = Room::new("flaky".to_string());
+        let mut state = state_with(&room).await;
+        state.presence_grace = Duration::from_millis(300);
+        let (_, mut watcher) = join(&state, &room, "watcher").await;
+        let (first, _) = join(&state, &room, "alice").await;
+
+        state.leave_room(&room.id, &first.session_id, true);
+        let (again, _rx) = connect(
+            &state,
+            &room,
+            "alice",
+            false,
+            Some(first.session_id.clone()),
+        )
+        .await;
+        assert!(again.resumed);
+        assert_eq!(again.session_id, first.session_id);
+        // Nobody saw them go, even once the grace period is over
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        assert_eq!(left(&mut watcher).await, None);
+    }
+
+    #[tokio::test]
+    async fn only_lingering_sessions_of_the_same_user_resume() {
+        let room = Room::new("strict".to_string());
+        let mut state = state_with(&room).await;
+        state.presence_grace = Duration::from_secs(60);
+        let (alice, _alice_rx) = join(&state, &room, "alice").await;
+
+        // Still connected, so not up for grabs
+        let (second, _) = connect(
+            &state,
+            &room,
+            "alice",
+            false,
+            Some(alice.session_id.clone()),
+        )
+        .await;
+        assert!(!second.resumed);
+
+        state.leave_room(&room.id, &alice.session_id, true);
+        let (mallory, _) = connect(
+            &state,
+            &room,
+            "mallory",
+            false,
+            Some(alice.session_id.clone()),
+        )
+        .await;
+        assert!(!mallory.resumed);
+        assert_ne!(mallory.session_id, alice.session_id);
+    }
+
+    #[tokio::test]
+    async fn lingering_sessions_expire_after_the_grace_period() {
+        let room = Room::new("gone".to_string());
+        let mut state = state_with(&room).await;
+        state.presence_grace = Duration::from_millis(50);
+        let (_, mut watcher) = join(&state, &room, "watcher").await;
+        let (alice, _) = join(&state, &room, "alice").await;
+
+        state.leave_room(&room.id, &alice.session_id, true);
+        assert_eq!(left(&mut watcher).await.as_deref(), Some("alice"));
+        let (back, _rx) = connect(&state, &room, "alice", false, Some(alice.session_id)).await;
+        assert!(!back.resumed);
+        assert!(back.first_session);
+    }
+}
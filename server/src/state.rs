@@ -1,87 +1,309 @@
-use axum::extract::ws::Message;
-use sqlx::{Pool, Postgres};
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::sync::Arc;
-use tokio::sync::{mpsc, RwLock};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use terma_shared::{ErrorCode, Room, ServerMessage};
+use tokio::sync::oneshot;
+#[cfg(feature = "postgres")]
+use tracing::error;
+use uuid::Uuid;
 
-// Maximum messages per room (enforced by database trigger)
-#[allow(dead_code)]
-const MAX_MESSAGE_HISTORY: usize = 1000;
+#[cfg(feature = "postgres")]
+use crate::cluster::Cluster;
+use crate::compression::CompressionConfig;
+use crate::db::Db;
+use crate::heartbeat::HeartbeatConfig;
+use crate::metrics::Metrics;
+use crate::outbox::{Outbox, OutboxConfig};
+use crate::rate_limit::{self, IpRateLimiter};
+use crate::room::{self, Joined, RoomCommand, RoomHandle};
+
+/// How long a dropped connection's session waits for its user to come back,
+/// unless `PRESENCE_GRACE_SECONDS` says otherwise
+const DEFAULT_PRESENCE_GRACE: Duration = Duration::from_secs(10);
 
 #[derive(Clone)]
 pub struct AppState {
-    pub db: Pool<Postgres>,
-    pub rooms: Arc<RwLock<HashMap<String, RoomState>>>,
+    pub db: Db,
+    /// Tasks for the rooms with members connected to this instance. Only
+    /// held to look up or register a room, never across an await.
+    pub rooms: Arc<Mutex<HashMap<String, RoomHandle>>>,
+    /// Fan-out to other instances sharing the database, when it's Postgres
+    #[cfg(feature = "postgres")]
+    pub cluster: Option<Arc<Cluster>>,
+    /// WebSocket messages per remote address
+    pub message_limiter: Arc<IpRateLimiter>,
+    /// `POST /api/rooms` per remote address
+    pub room_limiter: Arc<IpRateLimiter>,
+    /// Take client addresses from the last `X-Forwarded-For` entry
+    pub trust_proxy: bool,
+    /// Size of each connection's send queue, and what to do when it fills
+    pub outbox: OutboxConfig,
+    pub metrics: Arc<Metrics>,
+    /// A user whose connection drops and who reconnects within this long
+    /// never left, as far as the room is concerned. Zero turns it off.
+    pub presence_grace: Duration,
+    /// How often to ping each connection, and how long one may stay silent
+    pub heartbeat: HeartbeatConfig,
+    /// Frame compression for clients that offer it
+    pub compression: CompressionConfig,
 }
 
-pub struct RoomState {
-    pub connections: HashMap<String, mpsc::UnboundedSender<Message>>,
-    pub usernames: HashMap<String, String>,
+/// Something every instance must apply to its own members of a room
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RoomEvent {
+    /// Send to everyone in the room, except perhaps the user who caused it
+    Broadcast {
+        message: Box<ServerMessage>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        exclude_user: Option<String>,
+    },
+    /// Close a member's socket, telling their client why
+    Disconnect {
+        user_id: String,
+        #[serde(default)]
+        code: ErrorCode,
+        reason: String,
+    },
+    SlowMode {
+        seconds: u32,
+    },
+    /// The room was deleted: say why and hang up on everyone
+    Close {
+        reason: String,
+    },
+}
+
+impl RoomEvent {
+    pub fn broadcast(message: ServerMessage) -> Self {
+        RoomEvent::Broadcast {
+            message: Box::new(message),
+            exclude_user: None,
+        }
+    }
 }
 
-impl RoomState {
-    pub fn new() -> Self {
+impl AppState {
+    pub fn new(db: Db) -> Self {
         Self {
-            connections: HashMap::new(),
-            usernames: HashMap::new(),
+            db,
+            rooms: Arc::new(Mutex::new(HashMap::new())),
+            #[cfg(feature = "postgres")]
+            cluster: None,
+            message_limiter: Arc::new(IpRateLimiter::new(
+                rate_limit::IP_BURST,
+                rate_limit::IP_RATE,
+            )),
+            room_limiter: Arc::new(IpRateLimiter::new(
+                rate_limit::ROOM_CREATE_BURST,
+                rate_limit::ROOM_CREATE_RATE,
+            )),
+            trust_proxy: std::env::var("TRUST_PROXY").is_ok_and(|value| !value.is_empty()),
+            outbox: OutboxConfig::from_env(),
+            metrics: Arc::new(Metrics::default()),
+            presence_grace: std::env::var("PRESENCE_GRACE_SECONDS")
+                .ok()
+                .and_then(|seconds| seconds.parse().ok())
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_PRESENCE_GRACE),
+            heartbeat: HeartbeatConfig::from_env(),
+            compression: CompressionConfig::from_env(),
         }
     }
 
-    pub fn online_count(&self) -> usize {
-        self.connections.len()
+    /// A room's task, if anyone in it is connected to this instance
+    pub fn room(&self, room_id: &str) -> Option<RoomHandle> {
+        self.rooms.lock().unwrap().get(room_id).cloned()
+    }
+
+    pub fn local_rooms(&self) -> Vec<(String, RoomHandle)> {
+        let rooms = self.rooms.lock().unwrap();
+        rooms
+            .iter()
+            .map(|(room_id, handle)| (room_id.clone(), handle.clone()))
+            .collect()
     }
 
-    pub fn add_connection(
-        &mut self,
+    /// Add a session to a room under a new ID, starting the room's task if
+    /// it's the first here. If the user has a session lingering after a
+    /// dropped connection, preferably `resume`, that one is picked up instead.
+    pub async fn join_room(
+        &self,
+        room: &Room,
+        resume: Option<String>,
         user_id: String,
         username: String,
-        tx: mpsc::UnboundedSender<Message>,
+        owner: bool,
+        outbox: Outbox,
+    ) -> Joined {
+        let session_id = Uuid::new_v4().simple().to_string();
+        let (reply, joined) = oneshot::channel();
+        {
+            let mut rooms = self.rooms.lock().unwrap();
+            let handle = rooms
+                .entry(room.id.clone())
+                .or_insert_with(|| room::spawn(self.clone(), room));
+            handle.send(RoomCommand::Join {
+                session_id: session_id.clone(),
+                resume,
+                user_id,
+                username,
+                owner,
+                outbox,
+                reply,
+            });
+        }
+        joined.await.unwrap_or(Joined {
+            session_id,
+            ..Joined::default()
+        })
+    }
+
+    /// Drop a session. The room announces the user's departure once their
+    /// last session is gone, which for a `lost` connection is after the
+    /// grace period.
+    pub fn leave_room(&self, room_id: &str, session_id: &str, lost: bool) {
+        if let Some(handle) = self.room(room_id) {
+            handle.send(RoomCommand::Leave {
+                session_id: session_id.to_string(),
+                lost,
+            });
+        }
+    }
+
+    /// Hand a chat message to its room, which checks and sends it
+    pub fn post(
+        &self,
+        room_id: &str,
+        session_id: &str,
+        user_id: &str,
+        content: String,
+        fingerprint: Option<&str>,
     ) {
-        self.connections.insert(user_id.clone(), tx);
-        self.usernames.insert(user_id, username);
+        if let Some(handle) = self.room(room_id) {
+            handle.send(RoomCommand::Post {
+                session_id: session_id.to_string(),
+                user_id: user_id.to_string(),
+                content,
+                fingerprint: fingerprint.map(str::to_string),
+            });
+        }
+    }
+
+    /// The name a user joined under, if they're connected here
+    pub async fn username(&self, room_id: &str, user_id: &str) -> Option<String> {
+        let handle = self.room(room_id)?;
+        let user_id = user_id.to_string();
+        handle
+            .ask(|reply| RoomCommand::Username { user_id, reply })
+            .await
+            .flatten()
     }
 
-    pub fn remove_connection(&mut self, user_id: &str) -> Option<String> {
-        self.connections.remove(user_id)?;
-        self.usernames.remove(user_id)
+    /// `(user_id, username)` for everyone in a room on this instance
+    pub async fn members(&self, room_id: &str) -> Vec<(String, String)> {
+        let Some(handle) = self.room(room_id) else {
+            return Vec::new();
+        };
+        handle
+            .ask(|reply| RoomCommand::Members { reply })
+            .await
+            .unwrap_or_default()
     }
 
-    pub fn get_username(&self, user_id: &str) -> Option<String> {
-        self.usernames.get(user_id).cloned()
+    /// Apply an event to this instance's members of a room, then pass it on
+    /// to every other instance
+    pub fn publish(&self, room_id: &str, event: RoomEvent) {
+        self.deliver(room_id, event.clone());
+        #[cfg(feature = "postgres")]
+        if let Some(cluster) = &self.cluster {
+            cluster.publish(room_id, event);
+        }
     }
 
-    pub fn broadcast(&self, message: Message, exclude_user: Option<&str>) {
-        for (user_id, tx) in &self.connections {
-            if let Some(excluded) = exclude_user {
-                if user_id == excluded {
-                    continue;
-                }
+    /// Apply an event to this instance's members of a room only
+    pub fn deliver(&self, room_id: &str, event: RoomEvent) {
+        if let Some(handle) = self.room(room_id) {
+            handle.send(RoomCommand::Event(event));
+        }
+    }
+
+    /// Record that a user is connected here, for presence across instances
+    pub async fn track_join(&self, room_id: &str, user_id: &str, username: &str) {
+        #[cfg(feature = "postgres")]
+        if let Some(cluster) = &self.cluster {
+            if let Err(e) = cluster.join(room_id, user_id, username).await {
+                error!("Failed to record presence in room {}: {}", room_id, e);
             }
-            let _ = tx.send(message.clone());
         }
+        #[cfg(not(feature = "postgres"))]
+        let _ = (room_id, user_id, username);
     }
 
-    pub fn send_to_user(&self, user_id: &str, message: Message) {
-        if let Some(tx) = self.connections.get(user_id) {
-            let _ = tx.send(message);
+    pub async fn track_leave(&self, room_id: &str, user_id: &str) {
+        #[cfg(feature = "postgres")]
+        if let Some(cluster) = &self.cluster {
+            if let Err(e) = cluster.leave(room_id, user_id).await {
+                error!("Failed to clear presence in room {}: {}", room_id, e);
+            }
         }
+        #[cfg(not(feature = "postgres"))]
+        let _ = (room_id, user_id);
     }
-}
 
-impl AppState {
-    pub fn new(db: Pool<Postgres>) -> Self {
-        Self {
-            db,
-            rooms: Arc::new(RwLock::new(HashMap::new())),
+    /// Whether a user is also in a room through another instance
+    pub async fn present_elsewhere(&self, room_id: &str, user_id: &str) -> bool {
+        #[cfg(feature = "postgres")]
+        if let Some(cluster) = &self.cluster {
+            match cluster.present_elsewhere(room_id, user_id).await {
+                Ok(present) => return present,
+                Err(e) => error!("Failed to check presence in room {}: {}", room_id, e),
+            }
         }
+        #[cfg(not(feature = "postgres"))]
+        let _ = (room_id, user_id);
+        false
     }
-}
 
-impl Clone for RoomState {
-    fn clone(&self) -> Self {
-        Self {
-            connections: HashMap::new(),
-            usernames: self.usernames.clone(),
+    /// People online in a room across every instance, however many devices
+    /// each is connected from
+    pub async fn online_count(&self, room_id: &str) -> usize {
+        #[cfg(feature = "postgres")]
+        if let Some(cluster) = &self.cluster {
+            match cluster.online_count(room_id).await {
+                Ok(count) => return count,
+                Err(e) => error!("Failed to count members of room {}: {}", room_id, e),
+            }
+        }
+        self.members(room_id).await.len()
+    }
+
+    /// User IDs connected under `username` on any instance
+    pub async fn user_ids_named(
+        &self,
+        room_id: &str,
+        username: &str,
+    ) -> anyhow::Result<Vec<String>> {
+        #[cfg(feature = "postgres")]
+        if let Some(cluster) = &self.cluster {
+            return cluster.user_ids_named(room_id, username).await;
+        }
+        Ok(self
+            .members(room_id)
+            .await
+            .into_iter()
+            .filter(|(_, name)| name == username)
+            .map(|(user_id, _)| user_id)
+            .collect())
+    }
+
+    /// Rooms someone is connected to on any instance
+    pub async fn active_rooms(&self) -> anyhow::Result<Vec<String>> {
+        #[cfg(feature = "postgres")]
+        if let Some(cluster) = &self.cluster {
+            return cluster.active_rooms().await;
         }
+        Ok(self.rooms.lock().unwrap().keys().cloned().collect())
     }
 }
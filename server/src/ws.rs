@@ -1,80 +1,301 @@
+use anyhow::bail;
 use axum::{
     extract::{
         ws::{Message, WebSocket},
-        Path, State, WebSocketUpgrade,
+        ConnectInfo, Path, State, WebSocketUpgrade,
     },
+    http::HeaderMap,
     response::Response,
 };
 use chrono::Utc;
-use futures::{SinkExt, StreamExt};
-use terma_shared::{ChatMessage, ClientMessage, ServerMessage};
-use tokio::sync::mpsc;
+use futures::{Sink, SinkExt, StreamExt};
+use std::collections::BTreeSet;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use terma_shared::{
+    capability, deflate::Inflater, ClientMessage, Codec, Compression, ErrorCode, Frame, Limits,
+    ModerationAction, ServerMessage, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
 use tracing::{error, info, warn};
-
-use crate::{db, state::AppState};
+use uuid::Uuid;
+
+use crate::{
+    access::RoomSecrets,
+    compression::FrameCompressor,
+    db,
+    error::{self, ClientError},
+    handlers,
+    heartbeat::{self, LastSeen},
+    identity, lifecycle, moderation,
+    outbox::{self, Outbox},
+    rate_limit::{self, TokenBucket},
+    state::{AppState, RoomEvent},
+};
 
 const MAX_MESSAGE_LENGTH: usize = 4096;
 
+/// Most a compressed frame from a client may inflate to: a message at the
+/// length limit with every character escaped in JSON, plus the envelope
+const MAX_INFLATED_LENGTH: usize = MAX_MESSAGE_LENGTH * 6 + 1024;
+
 pub async fn websocket_handler(
     ws: WebSocketUpgrade,
     Path(room_id): Path<String>,
     State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
 ) -> Response {
-    ws.on_upgrade(move |socket| handle_socket(socket, room_id, state))
+    let ip = rate_limit::client_ip(&headers, addr, state.trust_proxy);
+    // Clients offering none of the codecs' subprotocols speak JSON
+    ws.protocols(Codec::ALL.map(|codec| codec.subprotocol()))
+        .on_upgrade(move |socket| handle_socket(socket, room_id, state, ip))
 }
 
-async fn handle_socket(socket: WebSocket, room_id: String, state: AppState) {
-    // Verify room exists
-    let room_exists = match db::room_exists(&state.db, &room_id).await {
-        Ok(exists) => exists,
+async fn handle_socket(mut socket: WebSocket, room_id: String, state: AppState, ip: IpAddr) {
+    let codec = Codec::for_subprotocol(socket.protocol().and_then(|p| p.to_str().ok()));
+    // Verify room exists and hasn't expired
+    let room = match state.db.get_room(&room_id).await {
+        Ok(Some(room)) if !room.is_expired() => room,
+        Ok(room) => {
+            let reason = lifecycle::gone_reason(&state, &room_id, room.as_ref())
+                .await
+                .unwrap_or_else(|e| {
+                    error!("Failed to look up closed room: {}", e);
+                    None
+                });
+            let (code, message) = match reason {
+                Some(reason) => (ErrorCode::RoomGone, reason),
+                None => (
+                    ErrorCode::RoomNotFound,
+                    format!("Room {} not found", room_id),
+                ),
+            };
+            refuse(&mut socket, codec, code, message).await;
+            return;
+        }
         Err(e) => {
             error!("Failed to check room existence: {}", e);
+            refuse(
+                &mut socket,
+                codec,
+                ErrorCode::Internal,
+                "Failed to look up the room",
+            )
+            .await;
             return;
         }
     };
+    let secrets = match state.db.get_room_secrets(&room_id).await {
+        Ok(secrets) => secrets.unwrap_or_default(),
+        Err(e) => {
+            error!("Failed to load room secrets: {}", e);
+            refuse(
+                &mut socket,
+                codec,
+                ErrorCode::Internal,
+                "Failed to look up the room",
+            )
+            .await;
+            return;
+        }
+    };
+
+    let (mut sender, mut receiver) = socket.split();
 
-    if !room_exists {
-        let _ = socket.close().await;
+    // Challenge the client to prove it holds the key it joins with
+    let nonce = Uuid::new_v4().simple().to_string();
+    let challenge = ServerMessage::Challenge {
+        nonce: nonce.clone(),
+    };
+    if sender
+        .send(outbox::encode(codec, &challenge))
+        .await
+        .is_err()
+    {
         return;
     }
 
-    let (mut sender, mut receiver) = socket.split();
-
-    // Create channel for this connection
-    let (tx, mut rx) = mpsc::unbounded_channel::<Message>();
-
-    // Wait for Join message
-    let (user_id, username) = loop {
-        match receiver.next().await {
-            Some(Ok(Message::Text(text))) => {
-                if let Ok(ClientMessage::Join { user_id, username }) =
-                    ClientMessage::from_json(&text)
-                {
-                    break (user_id, username);
-                }
+    // Wait for Join message, turning away clients too old to talk to and
+    // connections that never get round to joining
+    let join_deadline = tokio::time::Instant::now() + state.heartbeat.join_timeout;
+    let (
+        user_id,
+        username,
+        public_key,
+        signature,
+        password,
+        join_token,
+        owner_token,
+        resume,
+        offer,
+    ) = loop {
+        let next = match tokio::time::timeout_at(join_deadline, receiver.next()).await {
+            Ok(next) => next,
+            Err(_) => {
+                warn!("No join in room {} from {}; closing", room_id, ip);
+                let message = "Timed out waiting for a join";
+                refuse(&mut sender, codec, ErrorCode::InvalidRequest, message).await;
+                return;
             }
+        };
+        let frame = match next {
             Some(Ok(Message::Close(_))) | None => return,
-            _ => continue,
+            Some(Ok(msg)) => match data_frame(msg) {
+                Some(frame) => frame,
+                None => continue,
+            },
+            Some(Err(_)) => continue,
+        };
+        match ClientMessage::decode(codec, frame) {
+            Ok(ClientMessage::Join {
+                protocol_version,
+                client_version,
+                user_id,
+                username,
+                public_key,
+                signature,
+                password,
+                join_token,
+                owner_token,
+                session_id,
+                compression,
+            }) => {
+                let client_version = client_version.as_deref().unwrap_or("unknown");
+                if let Err(e) = check_protocol(&room_id, protocol_version) {
+                    warn!(
+                        "Refused terma {} (protocol {}) in room {}",
+                        client_version, protocol_version, room_id
+                    );
+                    refuse(&mut sender, codec, e.code, e.message).await;
+                    return;
+                }
+                info!(
+                    "User {} connecting to room {} with terma {} (protocol {}, {})",
+                    user_id,
+                    room_id,
+                    client_version,
+                    protocol_version,
+                    codec.subprotocol()
+                );
+                break (
+                    user_id,
+                    username,
+                    public_key,
+                    signature,
+                    password,
+                    join_token,
+                    owner_token,
+                    session_id,
+                    compression,
+                );
+            }
+            Ok(_) => continue,
+            Err(e) => {
+                warn!("Unreadable join in room {}: {}", room_id, e);
+                refuse(
+                    &mut sender,
+                    codec,
+                    ErrorCode::UnsupportedMessage,
+                    unreadable_message(&e),
+                )
+                .await;
+                return;
+            }
         }
     };
 
-    info!("User {} joined room {}", user_id, room_id);
-
-    // Add connection to room
-    let mut rooms = state.rooms.write().await;
-    let room = rooms
-        .entry(room_id.clone())
-        .or_insert_with(crate::state::RoomState::new);
-
-    room.add_connection(user_id.clone(), username.clone(), tx);
-
-    // Get online count before releasing lock
-    let online_count = room.online_count();
+    let admitted = async {
+        let claims_owner = match &owner_token {
+            Some(token) if secrets.admits_owner(token) => true,
+            Some(_) => bail!(unauthorized("Invalid owner token")),
+            None => false,
+        };
+        if !claims_owner {
+            check_access(secrets, password, join_token).await?;
+        }
+        let payload = terma_shared::identity::join_payload(&nonce, &room_id, &user_id);
+        let fingerprint =
+            identity::authenticate(&state, &user_id, public_key, signature, &payload).await?;
 
-    drop(rooms);
+        if claims_owner {
+            state.db.grant_role(&room_id, &user_id, "owner").await?;
+            return Ok((fingerprint, true));
+        }
+        let owner = state.db.is_owner(&room_id, &user_id).await?;
+        if !owner {
+            let fingerprint = fingerprint.as_deref();
+            moderation::check_admitted(&state, &room_id, &user_id, fingerprint, ip).await?;
+        }
+        Ok((fingerprint, owner))
+    };
+    let (fingerprint, owner) = match admitted.await {
+        Ok(admitted) => admitted,
+        Err(e) => {
+            warn!("Rejected join by {} in room {}: {}", user_id, room_id, e);
+            refuse(&mut sender, codec, error::code_of(&e), e.to_string()).await;
+            return;
+        }
+    };
 
-    // Send welcome message with history from database
-    let history = db::get_message_history(&state.db, &room_id)
+    if let Err(e) = state.db.touch_room(&room_id).await {
+        error!("Failed to record room activity: {}", e);
+    }
+    if let Err(e) = state
+        .db
+        .record_visit(&room_id, &user_id, &ip.to_string())
+        .await
+    {
+        error!("Failed to record where {} joined from: {}", user_id, e);
+    }
+    // Compression starts with the welcome; a client that offered it can
+    // inflate any frame, and only one that agreed to it may send them
+    let deflater = state.compression.negotiate(offer);
+    let compressor = FrameCompressor::new(deflater, state.metrics.clone());
+    let inflater = deflater.map(|_| Inflater {
+        max_size: MAX_INFLATED_LENGTH,
+    });
+    // Create a bounded send queue for this connection, then add it to the
+    // room. Each connection is its own session, unless it picks up one left
+    // by a connection that just dropped.
+    let (outbox, mut rx) = outbox::channel(
+        state.outbox,
+        codec,
+        compressor.clone(),
+        state.metrics.clone(),
+        &room_id,
+        &user_id,
+    );
+    let joined = state
+        .join_room(
+            &room,
+            resume,
+            user_id.clone(),
+            username.clone(),
+            owner,
+            outbox.clone(),
+        )
+        .await;
+    let session_id = joined.session_id.clone();
+    info!(
+        "User {} {} room {} in session {} ({})",
+        user_id,
+        if joined.resumed { "rejoined" } else { "joined" },
+        room_id,
+        session_id,
+        fingerprint.as_deref().unwrap_or("unverified")
+    );
+    // Only announce people, not each extra device they join from
+    let arrived = joined.first_session && !state.present_elsewhere(&room_id, &user_id).await;
+    state.track_join(&room_id, &user_id, &username).await;
+    let online_count = state.online_count(&room_id).await;
+
+    // Send welcome message with history from database. It's loaded after
+    // joining so nothing posted in between is missed; a message that lands
+    // in both the history and the outbox is dropped by the client.
+    let history = state
+        .db
+        .get_message_history(&room_id)
         .await
         .unwrap_or_else(|e| {
             error!("Failed to load message history: {}", e);
@@ -82,49 +303,107 @@ async fn handle_socket(socket: WebSocket, room_id: String, state: AppState) {
         });
 
     let welcome = ServerMessage::Welcome {
+        protocol_version: PROTOCOL_VERSION,
+        capabilities: capabilities(&state),
+        limits: Some(limits(&state)),
+        compression: deflater.map(|deflater| Compression {
+            window_bits: deflater.window_bits,
+        }),
         room_id: room_id.clone(),
         user_id: user_id.clone(),
+        session_id: session_id.clone(),
         online_count,
+        owner,
+        slow_mode_seconds: joined.slow_mode_seconds,
+        name: room.name,
+        topic: room.topic,
+        motd: room.motd,
+        expires_at: room.expires_at,
     };
 
-    if sender
-        .send(Message::Text(welcome.to_json().unwrap()))
-        .await
-        .is_err()
-    {
+    let welcome = compressor.apply(outbox::encode(codec, &welcome));
+    if sender.send(welcome).await.is_err() {
+        state.leave_room(&room_id, &session_id, true);
         return;
     }
 
     if !history.is_empty() {
         let history_msg = ServerMessage::History { messages: history };
         if sender
-            .send(Message::Text(history_msg.to_json().unwrap()))
+            .send(compressor.apply(outbox::encode(codec, &history_msg)))
             .await
             .is_err()
         {
+            state.leave_room(&room_id, &session_id, true);
+            return;
+        }
+    }
+
+    let moderation_log = state
+        .db
+        .get_moderation_log(&room_id)
+        .await
+        .unwrap_or_else(|e| {
+            error!("Failed to load moderation log: {}", e);
+            Vec::new()
+        });
+    if !moderation_log.is_empty() {
+        let log_msg = ServerMessage::ModerationLog {
+            events: moderation_log,
+        };
+        let log_msg = compressor.apply(outbox::encode(codec, &log_msg));
+        if sender.send(log_msg).await.is_err() {
+            state.leave_room(&room_id, &session_id, true);
             return;
         }
     }
 
     // Broadcast user joined
-    let rooms = state.rooms.read().await;
-    if let Some(room) = rooms.get(&room_id) {
-        let online_count = room.online_count();
+    if arrived {
         let joined_msg = ServerMessage::UserJoined {
             user_id: user_id.clone(),
             username: username.clone(),
             timestamp: Utc::now(),
-            online_count,
+            online_count: state.online_count(&room_id).await,
+            fingerprint: fingerprint.clone(),
         };
-        room.broadcast(Message::Text(joined_msg.to_json().unwrap()), Some(&user_id));
+        let joined = RoomEvent::Broadcast {
+            message: Box::new(joined_msg),
+            exclude_user: Some(user_id.clone()),
+        };
+        state.publish(&room_id, joined);
     }
-    drop(rooms);
 
-    // Spawn task to send messages to client
+    // Spawn task to send messages to client, pinging it whenever the
+    // heartbeat is due. It finishes with whether the connection was lost,
+    // rather than closed on purpose.
+    let last_seen = LastSeen::default();
+    let last_seen_clone = last_seen.clone();
+    let heartbeat = state.heartbeat;
+    let metrics = state.metrics.clone();
+    let reaped_label = format!("{} in room {}", user_id, room_id);
     let mut send_task = tokio::spawn(async move {
-        while let Some(msg) = rx.recv().await {
+        let mut ticker = heartbeat.ticker();
+        loop {
+            let msg = tokio::select! {
+                msg = rx.recv() => msg,
+                _ = heartbeat::tick(&mut ticker) => {
+                    if heartbeat.gone_silent(&last_seen_clone) {
+                        warn!("Reaping silent connection of {}", reaped_label);
+                        metrics.reaped.fetch_add(1, Ordering::Relaxed);
+                        return true;
+                    }
+                    Message::Ping(Vec::new())
+                }
+            };
+            // A close frame here means a moderator removed this user, or
+            // they fell too far behind
+            let closing = matches!(msg, Message::Close(_));
             if sender.send(msg).await.is_err() {
-                break;
+                return true;
+            }
+            if closing {
+                return false;
             }
         }
     });
@@ -133,59 +412,214 @@ async fn handle_socket(socket: WebSocket, room_id: String, state: AppState) {
     let state_clone = state.clone();
     let room_id_clone = room_id.clone();
     let user_id_clone = user_id.clone();
+    let session_id_clone = session_id.clone();
 
     let mut recv_task = tokio::spawn(async move {
+        let mut bucket =
+            TokenBucket::new(rate_limit::CONNECTION_BURST, rate_limit::CONNECTION_RATE);
         while let Some(Ok(msg)) = receiver.next().await {
-            match msg {
-                Message::Text(text) => {
-                    if let Ok(client_msg) = ClientMessage::from_json(&text) {
-                        handle_client_message(
-                            client_msg,
-                            &room_id_clone,
-                            &user_id_clone,
-                            &state_clone,
-                        )
-                        .await;
-                    }
+            last_seen.touch();
+            let frame = match msg {
+                // The client said goodbye
+                Message::Close(_) => return false,
+                msg => match data_frame(msg) {
+                    Some(frame) => frame,
+                    None => continue,
+                },
+            };
+            let decoded = match inflater {
+                Some(inflater) => inflater.expand(frame),
+                None => Ok(frame),
+            }
+            .and_then(|frame| ClientMessage::decode(codec, frame));
+            let client_msg = match decoded {
+                Ok(client_msg) => client_msg,
+                Err(e) => {
+                    let message = unreadable_message(&e);
+                    send_error(&outbox, ErrorCode::UnsupportedMessage, message);
+                    continue;
+                }
+            };
+            // Keepalives don't count against the limits
+            if !matches!(client_msg, ClientMessage::Ping) {
+                let allowed = bucket
+                    .try_take()
+                    .and_then(|_| state_clone.message_limiter.check(ip));
+                if let Err(wait) = allowed {
+                    let message = format!(
+                        "You're sending too fast. Try again in {}s.",
+                        rate_limit::seconds(wait)
+                    );
+                    send_retry_error(&outbox, message, wait);
+                    continue;
                 }
-                Message::Close(_) => break,
-                _ => {}
             }
+            let caller = Caller {
+                session_id: &session_id_clone,
+                user_id: &user_id_clone,
+                fingerprint: fingerprint.as_deref(),
+                ip,
+            };
+            handle_client_message(client_msg, &room_id_clone, caller, &state_clone, &outbox).await;
         }
+        // The connection dropped without a close frame
+        true
     });
 
     // Wait for either task to finish
-    tokio::select! {
-        _ = (&mut send_task) => recv_task.abort(),
-        _ = (&mut recv_task) => send_task.abort(),
+    let lost = tokio::select! {
+        lost = (&mut send_task) => {
+            recv_task.abort();
+            lost.unwrap_or(true)
+        }
+        lost = (&mut recv_task) => {
+            send_task.abort();
+            lost.unwrap_or(true)
+        }
+    };
+
+    // User disconnected - clean up. A lost connection's session lingers in
+    // case they're back in a moment; the room announces them leaving once
+    // their last session is gone.
+    info!(
+        "User {} {} room {} from session {}",
+        user_id,
+        if lost { "dropped out of" } else { "left" },
+        room_id,
+        session_id
+    );
+    state.leave_room(&room_id, &session_id, lost);
+}
+
+/// Say why a connection can't join, then hang up with the matching close code
+async fn refuse<S>(sender: &mut S, codec: Codec, code: ErrorCode, message: impl Into<String>)
+where
+    S: Sink<Message> + Unpin,
+{
+    let message = message.into();
+    let error = ServerMessage::error(code, message.clone());
+    let _ = sender.send(outbox::encode(codec, &error)).await;
+    let _ = sender.send(outbox::close_frame(code, message)).await;
+}
+
+/// The payload of a text or binary frame; everything else is control
+fn data_frame(msg: Message) -> Option<Frame> {
+    match msg {
+        Message::Text(text) => Some(Frame::Text(text)),
+        Message::Binary(bytes) => Some(Frame::Binary(bytes)),
+        _ => None,
     }
+}
 
-    // User disconnected - clean up
-    info!("User {} left room {}", user_id, room_id);
+fn unauthorized(message: impl Into<String>) -> ClientError {
+    ClientError::new(ErrorCode::Unauthorized, message)
+}
 
-    let mut rooms = state.rooms.write().await;
-    if let Some(room) = rooms.get_mut(&room_id) {
-        if let Some(username) = room.remove_connection(&user_id) {
-            let online_count = room.online_count();
+/// Refuse clients older than this server supports, telling them how to get
+/// a current one
+fn check_protocol(room_id: &str, protocol_version: u32) -> Result<(), ClientError> {
+    if protocol_version >= MIN_PROTOCOL_VERSION {
+        return Ok(());
+    }
+    let message = format!(
+        "This version of terma is too old for the server (protocol {}, which needs {} or newer). \
+         Upgrade with: {}",
+        protocol_version,
+        MIN_PROTOCOL_VERSION,
+        handlers::install_command(room_id, None)
+    );
+    Err(ClientError::new(ErrorCode::UpgradeRequired, message))
+}
 
-            let left_msg = ServerMessage::UserLeft {
-                user_id: user_id.clone(),
-                username,
-                timestamp: Utc::now(),
-                online_count,
-            };
+/// Most likely a client newer than this server, sending something it has
+/// never heard of
+fn unreadable_message(e: &anyhow::Error) -> String {
+    format!(
+        "The server didn't understand that ({}). It speaks terma protocol {}; \
+         your client may be newer than it.",
+        e, PROTOCOL_VERSION
+    )
+}
 
-            room.broadcast(Message::Text(left_msg.to_json().unwrap()), None);
-        }
+/// Features this server offers, for `Welcome`
+fn capabilities(state: &AppState) -> BTreeSet<String> {
+    let mut capabilities: BTreeSet<String> = [
+        capability::SEARCH,
+        capability::EXPORT,
+        capability::MODERATION,
+        capability::SLOW_MODE,
+        capability::TOPICS,
+        capability::ENCRYPTION,
+    ]
+    .into_iter()
+    .map(String::from)
+    .collect();
+    if !state.presence_grace.is_zero() {
+        capabilities.insert(capability::SESSION_RESUME.to_string());
+    }
+    if !state.heartbeat.interval.is_zero() {
+        capabilities.insert(capability::HEARTBEAT.to_string());
+    }
+    if state.compression.enabled() {
+        capabilities.insert(capability::APP_COMPRESSION.to_string());
+    }
+    capabilities
+}
 
-        // Clean up empty rooms
-        if room.connections.is_empty() {
-            rooms.remove(&room_id);
-        }
+fn limits(state: &AppState) -> Limits {
+    Limits {
+        max_message_length: MAX_MESSAGE_LENGTH,
+        message_burst: rate_limit::CONNECTION_BURST,
+        messages_per_second: rate_limit::CONNECTION_RATE,
+        session_grace_seconds: state.presence_grace.as_secs(),
     }
 }
 
-async fn handle_client_message(msg: ClientMessage, room_id: &str, user_id: &str, state: &AppState) {
+/// Protected rooms need their join token or password in `Join`
+async fn check_access(
+    secrets: RoomSecrets,
+    password: Option<String>,
+    join_token: Option<String>,
+) -> anyhow::Result<()> {
+    if !secrets.is_protected() {
+        return Ok(());
+    }
+    if join_token.is_some_and(|token| secrets.admits_token(&token)) {
+        return Ok(());
+    }
+
+    let Some(password) = password else {
+        bail!(unauthorized("This room is password protected"));
+    };
+    let admitted = tokio::task::spawn_blocking(move || secrets.admits_password(&password)).await?;
+    if !admitted {
+        bail!(unauthorized("Incorrect room password"));
+    }
+    Ok(())
+}
+
+/// The connection a client message arrived on
+#[derive(Clone, Copy)]
+struct Caller<'a> {
+    session_id: &'a str,
+    user_id: &'a str,
+    fingerprint: Option<&'a str>,
+    ip: IpAddr,
+}
+
+async fn handle_client_message(
+    msg: ClientMessage,
+    room_id: &str,
+    caller: Caller<'_>,
+    state: &AppState,
+    outbox: &Outbox,
+) {
+    let Caller {
+        session_id,
+        user_id,
+        fingerprint,
+        ip,
+    } = caller;
     match msg {
         ClientMessage::SendMessage { content } => {
             if content.trim().is_empty() {
@@ -194,48 +628,194 @@ async fn handle_client_message(msg: ClientMessage, room_id: &str, user_id: &str,
 
             // Validate message length
             if content.len() > MAX_MESSAGE_LENGTH {
-                let rooms = state.rooms.read().await;
-                if let Some(room) = rooms.get(room_id) {
-                    let error_msg = ServerMessage::Error {
-                        message: format!(
-                            "Message too long. Maximum length is {} characters.",
-                            MAX_MESSAGE_LENGTH
-                        ),
-                    };
-                    room.send_to_user(user_id, Message::Text(error_msg.to_json().unwrap()));
-                }
+                let message = format!(
+                    "Message too long. Maximum length is {} characters.",
+                    MAX_MESSAGE_LENGTH
+                );
+                send_error(outbox, ErrorCode::MessageTooLong, message);
                 return;
             }
 
-            let rooms = state.rooms.read().await;
-            if let Some(room) = rooms.get(room_id) {
-                let username = room
-                    .get_username(user_id)
-                    .unwrap_or_else(|| "Unknown".to_string());
+            let allowed =
+                moderation::check_can_post(state, room_id, user_id, fingerprint, ip).await;
+            report(outbox, &allowed);
+            if allowed.is_err() {
+                return;
+            }
 
-                let chat_msg =
-                    ChatMessage::new(room_id.to_string(), user_id.to_string(), username, content);
+            // The room's task applies slow mode and encryption, then saves
+            // and broadcasts
+            state.post(room_id, session_id, user_id, content, fingerprint);
+        }
+        ClientMessage::Search { query, limit } => {
+            let query = query.trim().to_string();
+            if query.is_empty() {
+                return;
+            }
 
-                // Save message to database
-                if let Err(e) = db::save_message(&state.db, &chat_msg).await {
-                    error!("Failed to save message to database: {}", e);
-                    // Continue broadcasting even if save fails
+            // Search is always scoped to the room this socket joined
+            let limit = limit.unwrap_or(db::DEFAULT_SEARCH_LIMIT);
+            let response = match state.db.search_messages(room_id, &query, limit).await {
+                Ok(results) => ServerMessage::SearchResults { query, results },
+                Err(e) => {
+                    error!("Search failed in room {}: {}", room_id, e);
+                    ServerMessage::error(ErrorCode::Internal, "Search failed. Please try again.")
                 }
+            };
 
-                let server_msg = ServerMessage::Message { message: chat_msg };
-
-                room.broadcast(Message::Text(server_msg.to_json().unwrap()), None);
-            }
+            outbox.send(&response);
+        }
+        ClientMessage::Kick {
+            username,
+            target_id,
+            reason,
+        } => {
+            let action = ModerationAction::Kick;
+            let target = target(&username, &target_id);
+            let result =
+                moderation::moderate(state, room_id, user_id, action, target, reason, None);
+            report(outbox, &result.await);
+        }
+        ClientMessage::Ban {
+            username,
+            target_id,
+            reason,
+        } => {
+            let action = ModerationAction::Ban;
+            let target = target(&username, &target_id);
+            let result =
+                moderation::moderate(state, room_id, user_id, action, target, reason, None);
+            report(outbox, &result.await);
+        }
+        ClientMessage::Mute {
+            username,
+            target_id,
+            minutes,
+            reason,
+        } => {
+            let action = ModerationAction::Mute;
+            let target = target(&username, &target_id);
+            let result =
+                moderation::moderate(state, room_id, user_id, action, target, reason, minutes);
+            report(outbox, &result.await);
+        }
+        ClientMessage::Unban {
+            username,
+            target_id,
+        } => {
+            let action = ModerationAction::Unban;
+            let target = target(&username, &target_id);
+            let result = moderation::moderate(state, room_id, user_id, action, target, None, None);
+            report(outbox, &result.await);
+        }
+        ClientMessage::SetSlowMode { seconds } => {
+            let result = moderation::set_slow_mode(state, room_id, user_id, seconds).await;
+            report(outbox, &result);
+        }
+        ClientMessage::SetTopic { topic } => {
+            let result = moderation::set_topic(state, room_id, user_id, topic).await;
+            report(outbox, &result);
         }
         ClientMessage::Ping => {
-            let rooms = state.rooms.read().await;
-            if let Some(room) = rooms.get(room_id) {
-                let pong = ServerMessage::Pong;
-                room.send_to_user(user_id, Message::Text(pong.to_json().unwrap()));
-            }
+            outbox.send(&ServerMessage::Pong);
         }
         ClientMessage::Join { .. } => {
             warn!("Received Join message after connection established");
         }
     }
 }
+
+fn target<'a>(username: &'a str, target_id: &'a Option<String>) -> moderation::Target<'a> {
+    moderation::Target {
+        username,
+        user_id: target_id.as_deref(),
+    }
+}
+
+/// Tell the sender why their command failed, if it did
+fn report(outbox: &Outbox, result: &anyhow::Result<()>) {
+    if let Err(e) = result {
+        send_error(outbox, error::code_of(e), e.to_string());
+    }
+}
+
+fn send_error(outbox: &Outbox, code: ErrorCode, message: String) {
+    outbox.send(&ServerMessage::error(code, message));
+}
+
+/// A rate limit error the client can retry once `wait` has passed
+fn send_retry_error(outbox: &Outbox, message: String, wait: Duration) {
+    outbox.send(&ServerMessage::Error {
+        code: ErrorCode::RateLimited,
+        message,
+        retry_after_ms: Some(wait.as_millis() as u64),
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn join(json: &str) -> ClientMessage {
+        ClientMessage::decode(Codec::Json, Frame::Text(json.to_string())).unwrap()
+    }
+
+    fn protocol_version(msg: ClientMessage) -> u32 {
+        match msg {
+            ClientMessage::Join {
+                protocol_version, ..
+            } => protocol_version,
+            _ => panic!("not a join"),
+        }
+    }
+
+    #[test]
+    fn admits_current_clients() {
+        assert!(check_protocol("room", PROTOCOL_VERSION).is_ok());
+        assert!(check_protocol("room", MIN_PROTOCOL_VERSION).is_ok());
+        assert!(check_protocol("room", PROTOCOL_VERSION + 1).is_ok());
+    }
+
+    #[test]
+    fn refuses_clients_from_before_the_wire_changed() {
+        for version in [1, MIN_PROTOCOL_VERSION - 1] {
+            let e = check_protocol("abc123", version).unwrap_err();
+            assert_eq!(e.code, ErrorCode::UpgradeRequired);
+            assert!(e.message.contains(&format!("protocol {}", version)));
+            assert!(e.message.contains("/join/abc123"), "{}", e.message);
+        }
+    }
+
+    #[test]
+    fn refuses_unversioned_joins() {
+        let msg = join(r#"{"type":"join","user_id":"u","username":"alice"}"#);
+        let version = protocol_version(msg);
+        assert_eq!(version, 1);
+        let e = check_protocol("room", version).unwrap_err();
+        assert_eq!(e.code, ErrorCode::UpgradeRequired);
+    }
+
+    #[tokio::test]
+    async fn protected_rooms_need_their_password_or_token() {
+        let (secrets, tokens) = RoomSecrets::new(Some("hunter2")).unwrap();
+        let join_token = tokens.join_token;
+        let check = |password: Option<&str>, join_token: Option<&str>| {
+            check_access(
+                secrets.clone(),
+                password.map(str::to_string),
+                join_token.map(str::to_string),
+            )
+        };
+        assert!(check(Some("hunter2"), None).await.is_ok());
+        assert!(check(None, join_token.as_deref()).await.is_ok());
+        // A wrong token falls back to the password
+        assert!(check(Some("hunter2"), Some("nope")).await.is_ok());
+        for (password, token) in [(None, None), (Some("hunter3"), None), (None, Some("nope"))] {
+            let refused = check(password, token).await.unwrap_err();
+            assert_eq!(error::code_of(&refused), ErrorCode::Unauthorized);
+        }
+
+        let (open, _) = RoomSecrets::new(None).unwrap();
+        assert!(check_access(open, None, None).await.is_ok());
+    }
+}
@@ -0,0 +1,233 @@
+//! How protocol messages go over the wire. JSON text frames are the default,
+//! readable in any WebSocket tool and all a third-party client needs to
+//! speak. Clients can ask for MessagePack binary frames instead, which skip
+//! the quoting and escaping, by offering its WebSocket subprotocol.
+
+use anyhow::{bail, Result};
+use serde::{de::DeserializeOwned, Serialize};
+
+/// One WebSocket data frame, whichever library sends it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Frame {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+/// A wire format for protocol messages
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Codec {
+    Json,
+    /// Maps keyed by field name, with timestamps and IDs as strings, so a
+    /// message has the same shape as its JSON
+    MessagePack,
+}
+
+/// Nesting deeper than any protocol message, so hostile input can't
+/// exhaust the stack
+const MAX_DEPTH: usize = 32;
+
+impl Codec {
+    /// Every codec, most preferred first. A connection that names none of
+    /// them speaks JSON.
+    pub const ALL: [Codec; 2] = [Codec::MessagePack, Codec::Json];
+
+    /// The WebSocket subprotocol that selects this codec
+    pub fn subprotocol(self) -> &'static str {
+        match self {
+            Codec::Json => "terma.json",
+            Codec::MessagePack => "terma.msgpack",
+        }
+    }
+
+    /// The codec a negotiated subprotocol selects, or JSON without one
+    pub fn for_subprotocol(subprotocol: Option<&str>) -> Self {
+        subprotocol
+            .and_then(|name| {
+                Self::ALL
+                    .into_iter()
+                    .find(|codec| codec.subprotocol() == name)
+            })
+            .unwrap_or(Codec::Json)
+    }
+
+    pub fn encode<T: Serialize>(self, msg: &T) -> Result<Frame> {
+        match self {
+            Codec::Json => Ok(Frame::Text(serde_json::to_string(msg)?)),
+            Codec::MessagePack => {
+                let mut bytes = Vec::new();
+                let mut serializer = rmp_serde::Serializer::new(&mut bytes)
+                    .with_struct_map()
+                    .with_human_readable();
+                msg.serialize(&mut serializer)?;
+                Ok(Frame::Binary(bytes))
+            }
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(self, frame: Frame) -> Result<T> {
+        match (self, frame) {
+            (Codec::Json, Frame::Text(text)) => Ok(serde_json::from_str(&text)?),
+            (Codec::Json, Frame::Binary(bytes)) => Ok(serde_json::from_slice(&bytes)?),
+            (Codec::MessagePack, Frame::Binary(bytes)) => {
+                let mut deserializer =
+                    rmp_serde::Deserializer::new(bytes.as_slice()).with_human_readable();
+                deserializer.set_max_depth(MAX_DEPTH);
+                let msg = T::deserialize(&mut deserializer)?;
+                if !deserializer.get_ref().is_empty() {
+                    bail!("Trailing bytes after MessagePack value");
+                }
+                Ok(msg)
+            }
+            (Codec::MessagePack, Frame::Text(_)) => bail!("Expected a binary MessagePack frame"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ChatMessage, ClientMessage, ErrorCode, ServerMessage};
+    use chrono::Utc;
+    use serde_json::{json, Value};
+    use uuid::Uuid;
+
+    fn join() -> ClientMessage {
+        ClientMessage::Join {
+            protocol_version: crate::PROTOCOL_VERSION,
+            client_version: Some("1.2.3".to_string()),
+            user_id: "user-1".to_string(),
+            username: "alice".to_string(),
+            public_key: None,
+            signature: None,
+            password: Some("hunter2".to_string()),
+            join_token: None,
+            owner_token: None,
+            session_id: None,
+            compression: None,
+        }
+    }
+
+    fn history() -> ServerMessage {
+        ServerMessage::History {
+            messages: vec![ChatMessage {
+                id: Uuid::new_v4(),
+                room_id: "room".to_string(),
+                user_id: "user-1".to_string(),
+                username: "alice".to_string(),
+                content: "héllo \"there\"".to_string(),
+                timestamp: Utc::now(),
+                fingerprint: Some("ab:cd".to_string()),
+            }],
+        }
+    }
+
+    fn as_json(msg: &impl Serialize) -> Value {
+        serde_json::to_value(msg).unwrap()
+    }
+
+    #[test]
+    fn round_trips_both_ways_in_every_codec() {
+        for codec in Codec::ALL {
+            let frame = codec.encode(&join()).unwrap();
+            let decoded: ClientMessage = codec.decode(frame).unwrap();
+            assert_eq!(as_json(&decoded), as_json(&join()), "{:?}", codec);
+
+            let sent = history();
+            let frame = sent.encode(codec).unwrap();
+            let decoded = ServerMessage::decode(codec, frame).unwrap();
+            assert_eq!(as_json(&decoded), as_json(&sent), "{:?}", codec);
+        }
+    }
+
+    #[test]
+    fn json_is_text_and_msgpack_binary() {
+        assert!(matches!(
+            Codec::Json.encode(&join()).unwrap(),
+            Frame::Text(_)
+        ));
+        assert!(matches!(
+            Codec::MessagePack.encode(&join()).unwrap(),
+            Frame::Binary(_)
+        ));
+    }
+
+    #[test]
+    fn msgpack_has_the_same_shape_as_json() {
+        let sent = history();
+        let Frame::Binary(bytes) = Codec::MessagePack.encode(&sent).unwrap() else {
+            panic!("expected a binary frame");
+        };
+        // Field names, not positions, and IDs and timestamps as strings
+        let value: Value = Codec::MessagePack.decode(Frame::Binary(bytes)).unwrap();
+        assert_eq!(value, as_json(&sent));
+    }
+
+    #[test]
+    fn json_takes_fields_it_does_not_know_and_codes_it_does_not_know() {
+        let text = json!({
+            "type": "error",
+            "code": "from_the_future",
+            "message": "nope",
+            "extra": 1
+        })
+        .to_string();
+        let msg = ServerMessage::decode(Codec::Json, Frame::Text(text)).unwrap();
+        assert!(matches!(
+            msg,
+            ServerMessage::Error {
+                code: ErrorCode::Unknown,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn json_reads_binary_frames_too() {
+        let bytes = json!({ "type": "ping" }).to_string().into_bytes();
+        let msg = ClientMessage::decode(Codec::Json, Frame::Binary(bytes)).unwrap();
+        assert!(matches!(msg, ClientMessage::Ping));
+    }
+
+    #[test]
+    fn msgpack_refuses_text_frames() {
+        let text = json!({ "type": "ping" }).to_string();
+        assert!(Codec::MessagePack
+            .decode::<ClientMessage>(Frame::Text(text))
+            .is_err());
+    }
+
+    #[test]
+    fn msgpack_refuses_trailing_and_truncated_bytes() {
+        let Frame::Binary(mut bytes) = Codec::MessagePack.encode(&join()).unwrap() else {
+            panic!("expected a binary frame");
+        };
+        let truncated = bytes[..bytes.len() - 1].to_vec();
+        bytes.push(0xc0);
+        for bytes in [bytes, truncated] {
+            assert!(Codec::MessagePack
+                .decode::<ClientMessage>(Frame::Binary(bytes))
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn msgpack_refuses_deep_nesting() {
+        // A thousand nested one-element arrays
+        let mut bytes = vec![0x91; 1000];
+        bytes.push(0xc0);
+        assert!(Codec::MessagePack
+            .decode::<Value>(Frame::Binary(bytes))
+            .is_err());
+    }
+
+    #[test]
+    fn picks_the_codec_for_a_subprotocol() {
+        assert_eq!(
+            Codec::for_subprotocol(Some("terma.msgpack")),
+            Codec::MessagePack
+        );
+        assert_eq!(Codec::for_subprotocol(Some("terma.json")), Codec::Json);
+        assert_eq!(Codec::for_subprotocol(Some("graphql-ws")), Codec::Json);
+        assert_eq!(Codec::for_subprotocol(None), Codec::Json);
+    }
+}
@@ -0,0 +1,219 @@
+//! App-level compression of terma frames with raw DEFLATE (RFC 1951), by
+//! way of `flate2`. Each frame is compressed on its own, so either side can
+//! decompress any frame without state.
+//!
+//! This is terma's own format, not the permessage-deflate WebSocket
+//! extension (RFC 7692): the WebSocket libraries we use can't negotiate
+//! that. A compressed frame is a binary frame starting with `0xc1`, a byte
+//! that is never valid MessagePack or UTF-8, so it can't be mistaken for an
+//! uncompressed frame. Generic WebSocket clients never see one unless they
+//! offer compression in `Join`.
+
+use anyhow::{bail, Context, Result};
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+
+use crate::codec::Frame;
+
+/// First byte of a compressed frame
+pub const FRAME_MARKER: u8 = 0xc1;
+
+/// Smallest and largest LZ77 window, as powers of two. zlib has no 256 byte
+/// window for raw DEFLATE, so the smallest is 512 bytes.
+pub const MIN_WINDOW_BITS: u8 = 9;
+pub const MAX_WINDOW_BITS: u8 = 15;
+
+/// How one side compresses the frames it sends
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Deflater {
+    /// How far back matches may reach, as a power of two
+    pub window_bits: u8,
+    /// Frames shorter than this many bytes go uncompressed
+    pub threshold: usize,
+}
+
+impl Deflater {
+    /// The compressed frame for `data`, or `None` if it's under the
+    /// threshold or compressing it doesn't save anything
+    pub fn compress(&self, data: &[u8]) -> Option<Vec<u8>> {
+        if data.len() < self.threshold {
+            return None;
+        }
+        let window_bits = self.window_bits.clamp(MIN_WINDOW_BITS, MAX_WINDOW_BITS);
+        let mut deflate =
+            Compress::new_with_window_bits(Compression::default(), false, window_bits);
+        // No room for output as long as the input, so a frame that doesn't
+        // shrink never finishes
+        let mut out = Vec::with_capacity(data.len());
+        out.push(FRAME_MARKER);
+        match deflate.compress_vec(data, &mut out, FlushCompress::Finish) {
+            Ok(Status::StreamEnd) => Some(out),
+            _ => None,
+        }
+    }
+}
+
+/// How one side inflates the frames it receives, once compression is agreed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Inflater {
+    /// Most a frame may inflate to; bigger ones are refused rather than
+    /// let a small frame claim a lot of memory
+    pub max_size: usize,
+}
+
+impl Inflater {
+    /// `frame` inflated if it's compressed, or as it is if not
+    pub fn expand(&self, frame: Frame) -> Result<Frame> {
+        match &frame {
+            Frame::Binary(data) if data.first() == Some(&FRAME_MARKER) => {
+                Ok(Frame::Binary(self.inflate(&data[1..])?))
+            }
+            _ => Ok(frame),
+        }
+    }
+
+    fn inflate(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let mut inflate = Decompress::new(false);
+        // Grow the output as it fills, so memory follows what the frame
+        // really holds. One byte past the limit shows it was crossed.
+        let mut out = Vec::with_capacity(data.len().saturating_mul(4).min(self.max_size) + 1);
+        loop {
+            let input = &data[inflate.total_in() as usize..];
+            let status = inflate
+                .decompress_vec(input, &mut out, FlushDecompress::Finish)
+                .context("Malformed compressed frame")?;
+            if status == Status::StreamEnd {
+                break;
+            }
+            if out.len() > self.max_size {
+                bail!("Compressed frame inflates past {} bytes", self.max_size);
+            }
+            if out.len() < out.capacity() {
+                // Room to spare, so the input ran out
+                bail!("Truncated compressed frame");
+            }
+            let room = out.len().max(1024).min(self.max_size + 1 - out.len());
+            out.reserve(room);
+        }
+        if out.len() > self.max_size {
+            bail!("Compressed frame inflates past {} bytes", self.max_size);
+        }
+        if (inflate.total_in() as usize) < data.len() {
+            bail!("Trailing bytes after compressed frame");
+        }
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INFLATER: Inflater = Inflater { max_size: 1 << 20 };
+
+    fn deflater(window_bits: u8) -> Deflater {
+        Deflater {
+            window_bits,
+            threshold: 64,
+        }
+    }
+
+    fn chatter(len: usize) -> Vec<u8> {
+        let line = br#"{"type":"new_message","username":"alice","content":"hello room"}"#;
+        line.iter().copied().cycle().take(len).collect()
+    }
+
+    fn noise(len: usize) -> Vec<u8> {
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn round_trips_at_every_window() {
+        let data = chatter(100_000);
+        for window_bits in MIN_WINDOW_BITS..=MAX_WINDOW_BITS {
+            let compressed = deflater(window_bits).compress(&data).unwrap();
+            assert_eq!(compressed[0], FRAME_MARKER);
+            assert!(compressed.len() < data.len() / 4, "window {}", window_bits);
+            let frame = INFLATER.expand(Frame::Binary(compressed)).unwrap();
+            assert_eq!(frame, Frame::Binary(data.clone()), "window {}", window_bits);
+        }
+    }
+
+    #[test]
+    fn out_of_range_windows_are_clamped() {
+        let data = chatter(4096);
+        for window_bits in [0, 8, 16] {
+            let compressed = deflater(window_bits).compress(&data).unwrap();
+            let frame = INFLATER.expand(Frame::Binary(compressed)).unwrap();
+            assert_eq!(frame, Frame::Binary(data.clone()));
+        }
+    }
+
+    #[test]
+    fn leaves_short_and_incompressible_frames_alone() {
+        assert_eq!(deflater(15).compress(&chatter(63)), None);
+        assert_eq!(deflater(15).compress(&noise(4096)), None);
+    }
+
+    #[test]
+    fn passes_uncompressed_frames_through() {
+        let text = Frame::Text("{\"type\":\"ping\"}".to_string());
+        assert_eq!(INFLATER.expand(text.clone()).unwrap(), text);
+        let binary = Frame::Binary(vec![0x81, 0xa4, b't', b'y', b'p', b'e']);
+        assert_eq!(INFLATER.expand(binary.clone()).unwrap(), binary);
+        let empty = Frame::Binary(Vec::new());
+        assert_eq!(INFLATER.expand(empty.clone()).unwrap(), empty);
+    }
+
+    #[test]
+    fn refuses_malformed_frames() {
+        // Block type 3 is reserved
+        let reserved = vec![FRAME_MARKER, 0x07, 0x00];
+        assert!(INFLATER.expand(Frame::Binary(reserved)).is_err());
+        // A stored block whose length and its complement disagree
+        let mismatched = vec![FRAME_MARKER, 0x01, 0x05, 0x00, 0x00, 0x00];
+        assert!(INFLATER.expand(Frame::Binary(mismatched)).is_err());
+        let mut garbage = noise(512);
+        garbage[0] = FRAME_MARKER;
+        assert!(INFLATER.expand(Frame::Binary(garbage)).is_err());
+    }
+
+    #[test]
+    fn refuses_truncated_frames() {
+        let compressed = deflater(15).compress(&chatter(10_000)).unwrap();
+        for len in [1, 2, compressed.len() / 2, compressed.len() - 1] {
+            let truncated = compressed[..len].to_vec();
+            assert!(
+                INFLATER.expand(Frame::Binary(truncated)).is_err(),
+                "{}",
+                len
+            );
+        }
+    }
+
+    #[test]
+    fn refuses_trailing_bytes() {
+        let mut compressed = deflater(15).compress(&chatter(10_000)).unwrap();
+        compressed.extend_from_slice(b"extra");
+        assert!(INFLATER.expand(Frame::Binary(compressed)).is_err());
+    }
+
+    #[test]
+    fn refuses_frames_inflating_past_the_limit() {
+        let inflater = Inflater { max_size: 10_000 };
+        let at_limit = deflater(15).compress(&vec![0; 10_000]).unwrap();
+        assert!(inflater.expand(Frame::Binary(at_limit)).is_ok());
+        // A few hundred bytes claiming a megabyte
+        let bomb = deflater(15).compress(&vec![0; 1 << 20]).unwrap();
+        assert!(bomb.len() < 2048);
+        let err = inflater.expand(Frame::Binary(bomb)).unwrap_err();
+        assert!(err.to_string().contains("10000"), "{}", err);
+    }
+}
@@ -0,0 +1,269 @@
+use crate::models::ChatMessage;
+use chrono::{DateTime, Utc};
+use std::fmt;
+use std::str::FromStr;
+
+/// Output formats for room transcripts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Json,
+    Markdown,
+    Text,
+    Html,
+}
+
+impl ExportFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Json => "application/json",
+            ExportFormat::Markdown => "text/markdown; charset=utf-8",
+            ExportFormat::Text => "text/plain; charset=utf-8",
+            ExportFormat::Html => "text/html; charset=utf-8",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Json => "json",
+            ExportFormat::Markdown => "md",
+            ExportFormat::Text => "txt",
+            ExportFormat::Html => "html",
+        }
+    }
+}
+
+impl FromStr for ExportFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "json" => Ok(ExportFormat::Json),
+            "md" | "markdown" => Ok(ExportFormat::Markdown),
+            "txt" | "text" => Ok(ExportFormat::Text),
+            "html" | "htm" => Ok(ExportFormat::Html),
+            other => Err(anyhow::anyhow!(
+                "Unknown export format '{}'. Expected json, md, txt or html.",
+                other
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ExportFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.extension())
+    }
+}
+
+/// Incremental transcript writer, so large rooms can be streamed
+/// as header, one chunk per message, then footer.
+pub struct Transcript {
+    format: ExportFormat,
+    count: usize,
+}
+
+impl Transcript {
+    pub fn new(format: ExportFormat) -> Self {
+        Self { format, count: 0 }
+    }
+
+    pub fn header(&self, room_id: &str, exported_at: DateTime<Utc>) -> String {
+        let exported = exported_at.format("%Y-%m-%d %H:%M:%S UTC");
+        match self.format {
+            ExportFormat::Json => format!(
+                "{{\"room_id\":{},\"exported_at\":{},\"messages\":[",
+                json_string(room_id),
+                json_string(&exported_at.to_rfc3339())
+            ),
+            ExportFormat::Markdown => {
+                format!("# Terma room {}\n\n_Exported {}_\n\n", room_id, exported)
+            }
+            ExportFormat::Text => format!("Terma room {}\nExported {}\n\n", room_id, exported),
+            ExportFormat::Html => format!(
+                "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"UTF-8\">\n\
+                 <title>Terma room {room}</title>\n<style>\n\
+                 body {{ font-family: -apple-system, BlinkMacSystemFont, sans-serif; max-width: 48rem; margin: 2rem auto; padding: 0 1rem; }}\n\
+                 .msg {{ margin-bottom: 1rem; }}\n\
+                 .meta {{ color: #666666; font-size: 0.875rem; }}\n\
+                 .user {{ font-weight: 600; color: #000000; }}\n\
+                 .content {{ white-space: pre-wrap; word-break: break-word; }}\n\
+                 </style>\n</head>\n<body>\n<h1>Terma room {room}</h1>\n\
+                 <p class=\"meta\">Exported {exported}</p>\n",
+                room = html_escape(room_id),
+                exported = exported
+            ),
+        }
+    }
+
+    pub fn entry(&mut self, msg: &ChatMessage) -> String {
+        let first = self.count == 0;
+        self.count += 1;
+
+        let time = msg.timestamp.format("%Y-%m-%d %H:%M:%S UTC");
+        match self.format {
+            ExportFormat::Json => {
+                let json = serde_json::to_string(msg).unwrap_or_else(|_| "null".to_string());
+                if first {
+                    json
+                } else {
+                    format!(",{}", json)
+                }
+            }
+            ExportFormat::Markdown => {
+                let quoted: Vec<String> = msg
+                    .content
+                    .split('\n')
+                    .map(|line| format!("> {}", line))
+                    .collect();
+                format!(
+                    "**{}** · {}\n\n{}\n\n",
+                    msg.username,
+                    time,
+                    quoted.join("\n")
+                )
+            }
+            ExportFormat::Text => {
+                let mut lines = msg.content.split('\n');
+                let mut out = format!(
+                    "[{}] {}: {}\n",
+                    time,
+                    msg.username,
+                    lines.next().unwrap_or_default()
+                );
+                for line in lines {
+                    out.push_str("    ");
+                    out.push_str(line);
+                    out.push('\n');
+                }
+                out
+            }
+            ExportFormat::Html => format!(
+                "<div class=\"msg\"><div class=\"meta\"><span class=\"user\">{}</span> {}</div>\
+                 <div class=\"content\">{}</div></div>\n",
+                html_escape(&msg.username),
+                time,
+                html_escape(&msg.content)
+            ),
+        }
+    }
+
+    pub fn footer(&self) -> String {
+        match self.format {
+            ExportFormat::Json => "]}\n".to_string(),
+            ExportFormat::Markdown | ExportFormat::Text => String::new(),
+            ExportFormat::Html => "</body>\n</html>\n".to_string(),
+        }
+    }
+}
+
+/// Render a complete transcript in one go
+pub fn render(format: ExportFormat, room_id: &str, messages: &[ChatMessage]) -> String {
+    let mut transcript = Transcript::new(format);
+    let mut out = transcript.header(room_id, Utc::now());
+    for msg in messages {
+        out.push_str(&transcript.entry(msg));
+    }
+    out.push_str(&transcript.footer());
+    out
+}
+
+fn json_string(s: &str) -> String {
+    serde_json::to_string(s).unwrap_or_else(|_| "\"\"".to_string())
+}
+
+fn html_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for ch in s.chars() {
+        match ch {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(ch),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use serde_json::Value;
+
+    fn message(username: &str, content: &str) -> ChatMessage {
+        let mut msg = ChatMessage::new(
+            "room-1".to_string(),
+            format!("id-{}", username),
+            username.to_string(),
+            content.to_string(),
+        );
+        msg.timestamp = Utc.with_ymd_and_hms(2025, 1, 2, 3, 4, 5).unwrap();
+        msg
+    }
+
+    #[test]
+    fn json_streams_into_one_document() {
+        let messages = [message("alice", "hi"), message("bob", "\"quoted\"\nline")];
+        let json: Value = serde_json::from_str(&render(ExportFormat::Json, "room-1", &messages))
+            .expect("valid JSON");
+        assert_eq!(json["room_id"], "room-1");
+        let exported: Vec<ChatMessage> = serde_json::from_value(json["messages"].clone()).unwrap();
+        assert_eq!(exported.len(), 2);
+        assert_eq!(exported[1].content, "\"quoted\"\nline");
+
+        let empty: Value =
+            serde_json::from_str(&render(ExportFormat::Json, "room-1", &[])).unwrap();
+        assert_eq!(empty["messages"], Value::Array(Vec::new()));
+    }
+
+    #[test]
+    fn markdown_quotes_every_line() {
+        let out = render(
+            ExportFormat::Markdown,
+            "room-1",
+            &[message("alice", "one\ntwo")],
+        );
+        assert!(out.starts_with("# Terma room room-1\n"));
+        assert!(out.contains("**alice** · 2025-01-02 03:04:05 UTC\n\n> one\n> two\n"));
+    }
+
+    #[test]
+    fn text_indents_continuation_lines() {
+        let out = render(
+            ExportFormat::Text,
+            "room-1",
+            &[message("alice", "one\ntwo")],
+        );
+        assert!(out.ends_with("[2025-01-02 03:04:05 UTC] alice: one\n    two\n"));
+    }
+
+    #[test]
+    fn html_escapes_names_and_content() {
+        let out = render(
+            ExportFormat::Html,
+            "room-1",
+            &[message("<b>", "<script>alert('x') & \"y\"</script>")],
+        );
+        assert!(!out.contains("<script>"));
+        assert!(out.contains("&lt;b&gt;"));
+        assert!(out.contains("&lt;script&gt;alert(&#39;x&#39;) &amp; &quot;y&quot;&lt;/script&gt;"));
+        assert!(out.ends_with("</body>\n</html>\n"));
+    }
+
+    #[test]
+    fn formats_parse_from_names_and_extensions() {
+        for (name, format) in [
+            ("json", ExportFormat::Json),
+            ("MD", ExportFormat::Markdown),
+            ("markdown", ExportFormat::Markdown),
+            ("txt", ExportFormat::Text),
+            ("htm", ExportFormat::Html),
+        ] {
+            assert_eq!(name.parse::<ExportFormat>().unwrap(), format);
+            assert_eq!(format.to_string().parse::<ExportFormat>().unwrap(), format);
+        }
+        assert!("pdf".parse::<ExportFormat>().is_err());
+    }
+}
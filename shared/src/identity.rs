@@ -0,0 +1,30 @@
+use sha2::{Digest, Sha256};
+
+/// Short, human-comparable form of a public key, e.g. `3f9a:07c1:b2e4:5d10`
+pub fn fingerprint(public_key: &[u8]) -> String {
+    let digest = Sha256::digest(public_key);
+    digest[..8]
+        .chunks(2)
+        .map(|pair| format!("{:02x}{:02x}", pair[0], pair[1]))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// The bytes a client signs to answer a join challenge. Binding the room and
+/// user ID stops a signature from being replayed for another join.
+pub fn join_payload(nonce: &str, room_id: &str, user_id: &str) -> Vec<u8> {
+    format!("terma-join-v1\n{}\n{}\n{}", nonce, room_id, user_id).into_bytes()
+}
+
+/// Headers an HTTP request carries to say who is making it, signed like a
+/// join but over a timestamp the server checks for freshness
+pub const USER_HEADER: &str = "x-terma-user";
+pub const PUBLIC_KEY_HEADER: &str = "x-terma-public-key";
+pub const TIMESTAMP_HEADER: &str = "x-terma-timestamp";
+pub const SIGNATURE_HEADER: &str = "x-terma-signature";
+
+/// The bytes a client signs to identify itself on an HTTP request made at
+/// `timestamp` (Unix seconds). The prefix keeps it from passing as a join.
+pub fn request_payload(timestamp: i64, room_id: &str, user_id: &str) -> Vec<u8> {
+    format!("terma-request-v1\n{}\n{}\n{}", timestamp, room_id, user_id).into_bytes()
+}
@@ -1,5 +1,17 @@
+pub mod codec;
+pub mod deflate;
+pub mod export;
+pub mod identity;
 pub mod models;
 pub mod protocol;
 
-pub use models::{ChatMessage, Room, User};
-pub use protocol::{ClientMessage, ServerMessage};
+pub use codec::{Codec, Frame};
+pub use export::ExportFormat;
+pub use models::{
+    ChatMessage, ModerationAction, ModerationEvent, Retention, Room, SearchResult, User,
+    ENCRYPTED_CONTENT_PREFIX,
+};
+pub use protocol::{
+    capability, ClientMessage, Compression, ErrorCode, Limits, ServerMessage, MIN_PROTOCOL_VERSION,
+    PROTOCOL_VERSION,
+};
@@ -1,13 +1,128 @@
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
 use uuid::Uuid;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Room {
     pub id: String,
     pub created_at: DateTime<Utc>,
+    /// Human-readable name; the ID stands in when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub topic: Option<String>,
+    /// Message of the day, shown to everyone who joins
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub motd: Option<String>,
+    /// When the room and its messages are deleted; rooms without one live
+    /// until they go idle
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub password_protected: bool,
+    /// Message content is end-to-end encrypted; the server only sees ciphertext
+    #[serde(default)]
+    pub encrypted: bool,
+    /// Minimum seconds between each member's messages, 0 when off
+    #[serde(default)]
+    pub slow_mode_seconds: u32,
+    #[serde(default)]
+    pub retention: Retention,
 }
 
+/// How much history a room keeps. Older messages are pruned in the background.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Retention {
+    /// The newest N messages
+    Messages(u32),
+    /// Messages from the last N days
+    Days(u32),
+    Unlimited,
+}
+
+impl Retention {
+    pub const DEFAULT_MESSAGES: u32 = 1000;
+    pub const MAX_MESSAGES: u32 = 1_000_000;
+    pub const MAX_DAYS: u32 = 3650;
+
+    pub fn is_valid(&self) -> bool {
+        match *self {
+            Retention::Messages(count) => (1..=Self::MAX_MESSAGES).contains(&count),
+            Retention::Days(days) => (1..=Self::MAX_DAYS).contains(&days),
+            Retention::Unlimited => true,
+        }
+    }
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Retention::Messages(Self::DEFAULT_MESSAGES)
+    }
+}
+
+impl fmt::Display for Retention {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Retention::Messages(count) => write!(f, "the newest {} messages", count),
+            Retention::Days(days) => write!(f, "{} days of messages", days),
+            Retention::Unlimited => write!(f, "every message"),
+        }
+    }
+}
+
+/// "500" (messages), "30d" (days) or "unlimited"
+impl FromStr for Retention {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let retention =
+            if s == "unlimited" {
+                Retention::Unlimited
+            } else if let Some(days) = s.strip_suffix('d') {
+                Retention::Days(
+                    days.parse()
+                        .map_err(|_| format!("invalid day count {:?}", days))?,
+                )
+            } else {
+                Retention::Messages(s.parse().map_err(|_| {
+                    format!("expected a message count, Nd or unlimited, not {:?}", s)
+                })?)
+            };
+        if !retention.is_valid() {
+            return Err(format!(
+                "keep between 1 and {} messages, or 1 and {} days",
+                Self::MAX_MESSAGES,
+                Self::MAX_DAYS
+            ));
+        }
+        Ok(retention)
+    }
+}
+
+impl Room {
+    /// Longest name, topic and message of the day, in characters
+    pub const MAX_NAME_LENGTH: usize = 64;
+    pub const MAX_TOPIC_LENGTH: usize = 256;
+    pub const MAX_MOTD_LENGTH: usize = 1024;
+
+    /// The name if the room has one, otherwise its ID
+    pub fn display_name(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.id)
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| expires_at <= Utc::now())
+    }
+}
+
+/// Prefix of message content sealed with a room key in encrypted rooms
+pub const ENCRYPTED_CONTENT_PREFIX: &str = "e2ee:v1:";
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChatMessage {
     pub id: Uuid,
@@ -16,6 +131,16 @@ pub struct ChatMessage {
     pub username: String,
     pub content: String,
     pub timestamp: DateTime<Utc>,
+    /// Key fingerprint of the sender when they joined with a verified signature
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fingerprint: Option<String>,
+}
+
+/// A full-text search hit: the matching message plus a highlighted excerpt.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SearchResult {
+    pub message: ChatMessage,
+    pub snippet: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -33,6 +158,7 @@ impl ChatMessage {
             username,
             content,
             timestamp: Utc::now(),
+            fingerprint: None,
         }
     }
 }
@@ -42,6 +168,111 @@ impl Room {
         Self {
             id,
             created_at: Utc::now(),
+            name: None,
+            topic: None,
+            motd: None,
+            expires_at: None,
+            password_protected: false,
+            encrypted: false,
+            slow_mode_seconds: 0,
+            retention: Retention::default(),
+        }
+    }
+}
+
+/// What a room owner can do to a member
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ModerationAction {
+    Kick,
+    Ban,
+    Mute,
+    Unban,
+}
+
+impl ModerationAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ModerationAction::Kick => "kick",
+            ModerationAction::Ban => "ban",
+            ModerationAction::Mute => "mute",
+            ModerationAction::Unban => "unban",
         }
     }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "kick" => Some(ModerationAction::Kick),
+            "ban" => Some(ModerationAction::Ban),
+            "mute" => Some(ModerationAction::Mute),
+            "unban" => Some(ModerationAction::Unban),
+            _ => None,
+        }
+    }
+
+    fn past_tense(&self) -> &'static str {
+        match self {
+            ModerationAction::Kick => "kicked",
+            ModerationAction::Ban => "banned",
+            ModerationAction::Mute => "muted",
+            ModerationAction::Unban => "unbanned",
+        }
+    }
+}
+
+/// An entry in a room's moderation log
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModerationEvent {
+    pub action: ModerationAction,
+    pub moderator: String,
+    pub target_id: String,
+    pub target: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    /// When a mute or post-kick cooldown ends; `None` lasts until lifted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<DateTime<Utc>>,
+    pub timestamp: DateTime<Utc>,
+}
+
+impl ModerationEvent {
+    /// e.g. "troll was muted by alice for 10 minute(s): spam"
+    pub fn describe(&self) -> String {
+        let mut text = format!(
+            "{} was {} by {}",
+            self.target,
+            self.action.past_tense(),
+            self.moderator
+        );
+        if let (ModerationAction::Mute, Some(expires_at)) = (self.action, self.expires_at) {
+            let minutes = (expires_at - self.timestamp).num_minutes().max(1);
+            text.push_str(&format!(" for {} minute(s)", minutes));
+        }
+        if let Some(reason) = &self.reason {
+            text.push_str(": ");
+            text.push_str(reason);
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retention_parses_counts_days_and_unlimited() {
+        assert_eq!("500".parse(), Ok(Retention::Messages(500)));
+        assert_eq!(" 30d ".parse(), Ok(Retention::Days(30)));
+        assert_eq!("unlimited".parse(), Ok(Retention::Unlimited));
+        for invalid in ["", "0", "0d", "-5", "xd", "1000001", "3651d", "forever"] {
+            assert!(invalid.parse::<Retention>().is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn retention_reads_as_a_phrase() {
+        assert_eq!(Retention::default().to_string(), "the newest 1000 messages");
+        assert_eq!(Retention::Days(7).to_string(), "7 days of messages");
+    }
 }
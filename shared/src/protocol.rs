@@ -1,13 +1,247 @@
-use crate::models::ChatMessage;
+use crate::codec::{Codec, Frame};
+use crate::models::{ChatMessage, ModerationEvent, SearchResult};
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+
+/// The protocol this build speaks. Bump it when a change would confuse
+/// peers that don't know about it.
+pub const PROTOCOL_VERSION: u32 = 3;
+/// The oldest protocol this build still works with. Version 1 is everything
+/// from before peers said which version they speak; version 2 encoded
+/// MessagePack and compressed frames in ways this build can't read.
+pub const MIN_PROTOCOL_VERSION: u32 = 3;
+
+fn unversioned() -> u32 {
+    1
+}
+
+/// Optional features a server advertises in `Welcome`
+pub mod capability {
+    /// `Search` over the room's history
+    pub const SEARCH: &str = "search";
+    /// `GET /api/rooms/<id>/export`
+    pub const EXPORT: &str = "export";
+    /// Kick, ban, mute and unban
+    pub const MODERATION: &str = "moderation";
+    pub const SLOW_MODE: &str = "slow_mode";
+    pub const TOPICS: &str = "topics";
+    /// Rooms whose messages only members can read
+    pub const ENCRYPTION: &str = "encryption";
+    /// `Join` can pick up the session a dropped connection left behind
+    pub const SESSION_RESUME: &str = "session_resume";
+    /// The server pings and drops connections that stop answering
+    pub const HEARTBEAT: &str = "heartbeat";
+    /// Frames can be compressed in terma's own format, see `crate::deflate`.
+    /// This is not the permessage-deflate WebSocket extension.
+    pub const APP_COMPRESSION: &str = "app_compression";
+}
+
+/// What went wrong, in `Error` and as the WebSocket close code, so clients
+/// needn't parse the message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    RoomNotFound,
+    /// The room expired or was deleted
+    RoomGone,
+    /// Missing or wrong password, join token or owner token
+    Unauthorized,
+    /// The user ID belongs to another key, or the join signature is bad
+    IdentityMismatch,
+    Banned,
+    /// Kicked, and still within the cooldown
+    Kicked,
+    Muted,
+    /// Only the room's owner may do that
+    Forbidden,
+    MessageTooLong,
+    /// Too fast for the rate limit or slow mode; `retry_after_ms` says when
+    /// to try again
+    RateLimited,
+    /// Plaintext sent to an end-to-end encrypted room
+    EncryptionRequired,
+    /// A request that can't be carried out, like kicking someone who isn't
+    /// there
+    InvalidRequest,
+    /// The client's protocol is older than the server supports
+    UpgradeRequired,
+    /// A message the server couldn't parse
+    UnsupportedMessage,
+    /// The connection fell too far behind the room
+    TooSlow,
+    /// Something went wrong on the server
+    Internal,
+    /// From a server older than error codes, or newer than this client
+    #[default]
+    #[serde(other)]
+    Unknown,
+}
+
+impl ErrorCode {
+    /// The WebSocket close code for a connection ended by this error. Most
+    /// are 4000 plus the nearest HTTP status.
+    pub fn close_code(self) -> u16 {
+        match self {
+            ErrorCode::InvalidRequest => 4400,
+            ErrorCode::Unauthorized => 4401,
+            ErrorCode::Banned => 4403,
+            ErrorCode::RoomNotFound => 4404,
+            ErrorCode::Forbidden => 4405,
+            ErrorCode::IdentityMismatch => 4409,
+            ErrorCode::RoomGone => 4410,
+            ErrorCode::MessageTooLong => 4413,
+            ErrorCode::UnsupportedMessage => 4415,
+            ErrorCode::EncryptionRequired => 4422,
+            ErrorCode::Kicked => 4423,
+            ErrorCode::Muted => 4424,
+            ErrorCode::UpgradeRequired => 4426,
+            ErrorCode::RateLimited => 4429,
+            // Standard codes: try again later, internal error, policy
+            ErrorCode::TooSlow => 1013,
+            ErrorCode::Internal => 1011,
+            ErrorCode::Unknown => 1008,
+        }
+    }
+
+    /// The error a close code stands for, if it's one of `close_code`'s
+    pub fn from_close_code(code: u16) -> Option<Self> {
+        const ALL: [ErrorCode; 17] = [
+            ErrorCode::RoomNotFound,
+            ErrorCode::RoomGone,
+            ErrorCode::Unauthorized,
+            ErrorCode::IdentityMismatch,
+            ErrorCode::Banned,
+            ErrorCode::Kicked,
+            ErrorCode::Muted,
+            ErrorCode::Forbidden,
+            ErrorCode::MessageTooLong,
+            ErrorCode::RateLimited,
+            ErrorCode::EncryptionRequired,
+            ErrorCode::InvalidRequest,
+            ErrorCode::UpgradeRequired,
+            ErrorCode::UnsupportedMessage,
+            ErrorCode::TooSlow,
+            ErrorCode::Internal,
+            ErrorCode::Unknown,
+        ];
+        ALL.into_iter().find(|error| error.close_code() == code)
+    }
+}
+
+/// What a server allows each connection, sent in `Welcome`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Limits {
+    /// Longest `SendMessage` content accepted, in bytes
+    pub max_message_length: usize,
+    /// Messages a connection may send in a burst
+    pub message_burst: u32,
+    /// Messages per second a connection may keep up after its burst
+    pub messages_per_second: f64,
+    /// Seconds a dropped connection's session waits to be resumed
+    #[serde(default)]
+    pub session_grace_seconds: u64,
+}
+
+/// App-level frame compression one side offers in `Join`, or the server
+/// agrees to in `Welcome`. Both sides then compress frames over their
+/// threshold with the smaller of the two windows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Compression {
+    /// Largest LZ77 window, as a power of two from 9 to 15
+    pub window_bits: u8,
+}
 
 /// Messages sent from client to server
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum ClientMessage {
-    Join { user_id: String, username: String },
-    SendMessage { content: String },
+    Join {
+        /// The protocol the client speaks; missing from clients older than
+        /// version negotiation
+        #[serde(default = "unversioned")]
+        protocol_version: u32,
+        /// The client's release, for the server's logs
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        client_version: Option<String>,
+        user_id: String,
+        username: String,
+        /// Hex-encoded Ed25519 public key
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        public_key: Option<String>,
+        /// Hex-encoded signature over `identity::join_payload` for the challenge nonce
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        signature: Option<String>,
+        /// Password for protected rooms
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        password: Option<String>,
+        /// Token from the room's install command, in place of the password
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        join_token: Option<String>,
+        /// Token handed to the room's creator; makes this user ID an owner
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        owner_token: Option<String>,
+        /// Session from `Welcome` to pick up again, if this client's
+        /// connection just dropped
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        session_id: Option<String>,
+        /// Set if this client can take compressed frames
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        compression: Option<Compression>,
+    },
+    SendMessage {
+        content: String,
+    },
+    Search {
+        query: String,
+        limit: Option<usize>,
+    },
+    /// Owner only: disconnect someone, who may rejoin after a short cooldown
+    Kick {
+        username: String,
+        /// The user ID of the person meant, or its first few characters,
+        /// for when more than one person uses the name
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        target_id: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reason: Option<String>,
+    },
+    /// Owner only: disconnect someone and keep them out
+    Ban {
+        username: String,
+        /// As for `Kick`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        target_id: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reason: Option<String>,
+    },
+    /// Owner only: stop someone posting, for `minutes` or until unbanned
+    Mute {
+        username: String,
+        /// As for `Kick`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        target_id: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        minutes: Option<u32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reason: Option<String>,
+    },
+    /// Owner only: lift a ban, mute or kick cooldown
+    Unban {
+        username: String,
+        /// As for `Kick`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        target_id: Option<String>,
+    },
+    /// Owner only: minimum seconds between each member's messages; 0 turns
+    /// slow mode off
+    SetSlowMode {
+        seconds: u32,
+    },
+    /// Owner only: change the room topic; empty clears it
+    SetTopic {
+        topic: String,
+    },
     Ping,
 }
 
@@ -15,10 +249,45 @@ pub enum ClientMessage {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum ServerMessage {
+    /// Sent as soon as the socket opens; the client signs the nonce in its `Join`
+    Challenge {
+        nonce: String,
+    },
     Welcome {
+        /// The protocol the server speaks, which may be older than the
+        /// client's
+        #[serde(default = "unversioned")]
+        protocol_version: u32,
+        /// Features from `capability` this server offers
+        #[serde(default)]
+        capabilities: BTreeSet<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        limits: Option<Limits>,
+        /// Set if frames from here on may be compressed, both ways
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        compression: Option<Compression>,
         room_id: String,
         user_id: String,
+        /// Server-issued ID for this connection; a user may have several
+        #[serde(default)]
+        session_id: String,
         online_count: usize,
+        /// This user owns the room and may moderate it
+        #[serde(default)]
+        owner: bool,
+        /// Minimum seconds between each member's messages, 0 when off
+        #[serde(default)]
+        slow_mode_seconds: u32,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        name: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        topic: Option<String>,
+        /// Message of the day
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        motd: Option<String>,
+        /// When the room will be deleted, if it has an expiry
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires_at: Option<DateTime<Utc>>,
     },
     History {
         messages: Vec<ChatMessage>,
@@ -31,6 +300,8 @@ pub enum ServerMessage {
         username: String,
         timestamp: DateTime<Utc>,
         online_count: usize,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        fingerprint: Option<String>,
     },
     UserLeft {
         user_id: String,
@@ -38,8 +309,48 @@ pub enum ServerMessage {
         timestamp: DateTime<Utc>,
         online_count: usize,
     },
+    /// A moderation action taken just now
+    Moderation {
+        event: ModerationEvent,
+    },
+    /// The owner changed slow mode
+    SlowMode {
+        seconds: u32,
+        moderator: String,
+        timestamp: DateTime<Utc>,
+    },
+    /// The owner changed the topic; `None` when it was cleared
+    TopicChanged {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        topic: Option<String>,
+        changed_by: String,
+        timestamp: DateTime<Utc>,
+    },
+    /// The room will be deleted soon, along with its messages
+    RoomExpiring {
+        expires_at: DateTime<Utc>,
+    },
+    /// The room was deleted; the server closes the socket next
+    RoomClosed {
+        reason: String,
+    },
+    /// Recent moderation actions, sent after `History`
+    ModerationLog {
+        events: Vec<ModerationEvent>,
+    },
+    SearchResults {
+        query: String,
+        results: Vec<SearchResult>,
+    },
     Error {
+        /// Missing from servers older than error codes
+        #[serde(default)]
+        code: ErrorCode,
         message: String,
+        /// Set when a request was refused for coming too fast: how long
+        /// until the client may try again
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        retry_after_ms: Option<u64>,
     },
     Pong,
 }
@@ -52,9 +363,25 @@ impl ClientMessage {
     pub fn from_json(s: &str) -> anyhow::Result<Self> {
         Ok(serde_json::from_str(s)?)
     }
+
+    pub fn encode(&self, codec: Codec) -> anyhow::Result<Frame> {
+        codec.encode(self)
+    }
+
+    pub fn decode(codec: Codec, frame: Frame) -> anyhow::Result<Self> {
+        codec.decode(frame)
+    }
 }
 
 impl ServerMessage {
+    pub fn error(code: ErrorCode, message: impl Into<String>) -> Self {
+        ServerMessage::Error {
+            code,
+            message: message.into(),
+            retry_after_ms: None,
+        }
+    }
+
     pub fn to_json(&self) -> anyhow::Result<String> {
         Ok(serde_json::to_string(self)?)
     }
@@ -62,4 +389,85 @@ impl ServerMessage {
     pub fn from_json(s: &str) -> anyhow::Result<Self> {
         Ok(serde_json::from_str(s)?)
     }
+
+    pub fn encode(&self, codec: Codec) -> anyhow::Result<Frame> {
+        codec.encode(self)
+    }
+
+    pub fn decode(codec: Codec, frame: Frame) -> anyhow::Result<Self> {
+        codec.decode(frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn unversioned_peers_read_as_version_one() {
+        let welcome = json!({
+            "type": "welcome",
+            "room_id": "room",
+            "user_id": "alice",
+            "session_id": "s1",
+            "online_count": 1,
+        });
+        let welcome = ServerMessage::from_json(&welcome.to_string()).unwrap();
+        let ServerMessage::Welcome {
+            protocol_version,
+            capabilities,
+            limits,
+            compression,
+            ..
+        } = welcome
+        else {
+            panic!("not a welcome");
+        };
+        assert_eq!(protocol_version, 1);
+        assert!(capabilities.is_empty());
+        assert!(limits.is_none());
+        assert!(compression.is_none());
+    }
+
+    #[test]
+    fn joins_leave_out_what_they_do_not_use() {
+        let join = ClientMessage::Join {
+            protocol_version: PROTOCOL_VERSION,
+            client_version: None,
+            user_id: "alice".to_string(),
+            username: "alice".to_string(),
+            public_key: None,
+            signature: None,
+            password: None,
+            join_token: None,
+            owner_token: None,
+            session_id: None,
+            compression: None,
+        };
+        let json: serde_json::Value = serde_json::from_str(&join.to_json().unwrap()).unwrap();
+        let mut fields: Vec<&str> = json
+            .as_object()
+            .unwrap()
+            .keys()
+            .map(String::as_str)
+            .collect();
+        fields.sort();
+        assert_eq!(fields, ["protocol_version", "type", "user_id", "username"]);
+    }
+
+    #[test]
+    fn close_codes_map_back_to_their_errors() {
+        let mut seen = Vec::new();
+        for code in 1000..5000 {
+            if let Some(error) = ErrorCode::from_close_code(code) {
+                assert_eq!(error.close_code(), code);
+                assert!(!seen.contains(&error), "{:?} has two codes", error);
+                seen.push(error);
+            }
+        }
+        assert_eq!(seen.len(), 17);
+        assert_eq!(ErrorCode::from_close_code(1000), None);
+        assert_eq!(ErrorCode::UpgradeRequired.close_code(), 4426);
+    }
 }